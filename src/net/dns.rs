@@ -0,0 +1,725 @@
+//! Looking things up in the DNS.
+//!
+//! Lookups happen through the `Resolver` trait so that the source of
+//! answers can be swapped. `StubResolver` asks the recursive name servers
+//! configured for the system while `Zone` answers from records held in
+//! memory which is mostly useful for testing.
+//!
+//! All lookups block. They must therefore not happen on the event loop
+//! but rather be run on a worker pool.
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream,
+               UdpSocket};
+use std::time::Duration;
+use openssl::crypto::rand::rand_bytes;
+
+
+//------------ Resolver ------------------------------------------------------
+
+/// Something that answers DNS queries.
+pub trait Resolver: Send + Sync {
+    /// Returns all records of type *rtype* for *name*.
+    ///
+    /// If the name exists but has no records of the type, the result
+    /// is an empty vector. If the name doesn’t exist at all, the error
+    /// is `Error::NotFound`.
+    fn query(&self, name: &[u8], rtype: RecordType)
+             -> Result<Vec<Record>, Error>;
+
+    /// Returns the IPv4 addresses of *name*.
+    fn lookup_a(&self, name: &[u8]) -> Result<Vec<Ipv4Addr>, Error> {
+        Ok(try!(self.query(name, RecordType::A)).into_iter()
+               .filter_map(|record| match record {
+                   Record::A(addr) => Some(addr),
+                   _ => None
+               }).collect())
+    }
+
+    /// Returns the IPv6 addresses of *name*.
+    fn lookup_aaaa(&self, name: &[u8]) -> Result<Vec<Ipv6Addr>, Error> {
+        Ok(try!(self.query(name, RecordType::Aaaa)).into_iter()
+               .filter_map(|record| match record {
+                   Record::Aaaa(addr) => Some(addr),
+                   _ => None
+               }).collect())
+    }
+
+    /// Returns the mail exchangers of *name* ordered by preference.
+    fn lookup_mx(&self, name: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let mut res: Vec<_> = try!(self.query(name, RecordType::Mx))
+                                  .into_iter()
+                                  .filter_map(|record| match record {
+                                      Record::Mx(pref, name) => {
+                                          Some((pref, name))
+                                      }
+                                      _ => None
+                                  }).collect();
+        res.sort_by(|left, right| left.0.cmp(&right.0));
+        Ok(res.into_iter().map(|(_, name)| name).collect())
+    }
+
+    /// Returns the names *addr* points back to.
+    fn lookup_ptr(&self, addr: IpAddr) -> Result<Vec<Vec<u8>>, Error> {
+        let zone = match addr {
+            IpAddr::V4(_) => &b"in-addr.arpa"[..],
+            IpAddr::V6(_) => &b"ip6.arpa"[..],
+        };
+        Ok(try!(self.query(&reverse_name(addr, zone), RecordType::Ptr))
+               .into_iter()
+               .filter_map(|record| match record {
+                   Record::Ptr(name) => Some(name),
+                   _ => None
+               }).collect())
+    }
+
+    /// Returns the text of the TXT records of *name*.
+    ///
+    /// The character strings of each record are joined.
+    fn lookup_txt(&self, name: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(try!(self.query(name, RecordType::Txt)).into_iter()
+               .filter_map(|record| match record {
+                   Record::Txt(text) => Some(text),
+                   _ => None
+               }).collect())
+    }
+}
+
+
+//------------ RecordType ----------------------------------------------------
+
+/// The types of records that can be looked up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Mx,
+    Ptr,
+    Txt,
+}
+
+impl RecordType {
+    fn to_int(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+            RecordType::Mx => 15,
+            RecordType::Ptr => 12,
+            RecordType::Txt => 16,
+        }
+    }
+}
+
+
+//------------ Record --------------------------------------------------------
+
+/// The data of a record.
+///
+/// Domain names are given without the final dot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Mx(u16, Vec<u8>),
+    Ptr(Vec<u8>),
+    Txt(Vec<u8>),
+}
+
+impl Record {
+    pub fn record_type(&self) -> RecordType {
+        match *self {
+            Record::A(_) => RecordType::A,
+            Record::Aaaa(_) => RecordType::Aaaa,
+            Record::Mx(..) => RecordType::Mx,
+            Record::Ptr(_) => RecordType::Ptr,
+            Record::Txt(_) => RecordType::Txt,
+        }
+    }
+}
+
+
+//------------ reverse_name --------------------------------------------------
+
+/// Returns the name of *addr* in the reverse tree below *zone*.
+///
+/// The octets of IPv4 addresses and the nibbles of IPv6 addresses are
+/// given in reverse order followed by the zone. This is used for PTR
+/// lookups but also by blocklists.
+pub fn reverse_name(addr: IpAddr, zone: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(zone.len() + 64);
+    match addr {
+        IpAddr::V4(addr) => {
+            for octet in addr.octets().iter().rev() {
+                res.extend_from_slice(octet.to_string().as_bytes());
+                res.push(b'.');
+            }
+        }
+        IpAddr::V6(addr) => {
+            for seg in addr.segments().iter().rev() {
+                for shift in &[0, 4, 8, 12] {
+                    res.push(b"0123456789abcdef"[(seg >> shift) as usize
+                                                 & 0x0f]);
+                    res.push(b'.');
+                }
+            }
+        }
+    }
+    res.extend_from_slice(zone);
+    res
+}
+
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug)]
+pub enum Error {
+    /// The name does not exist.
+    NotFound,
+
+    /// The name cannot be expressed in the DNS.
+    InvalidName,
+
+    /// The server answered with the given error code.
+    Server(u8),
+
+    /// The answer could not be understood.
+    Malformed,
+
+    /// None of the servers answered in time.
+    Timeout,
+
+    /// Talking to the servers failed.
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::NotFound => "name not found",
+            Error::InvalidName => "invalid domain name",
+            Error::Server(_) => "server failure",
+            Error::Malformed => "malformed answer",
+            Error::Timeout => "timeout",
+            Error::Io(ref err) => err.description(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Server(rcode) => write!(f, "server failure (rcode {})",
+                                           rcode),
+            Error::Io(ref err) => err.fmt(f),
+            _ => f.write_str(error::Error::description(self))
+        }
+    }
+}
+
+
+//------------ StubResolver --------------------------------------------------
+
+/// A resolver asking recursive name servers.
+///
+/// Queries are sent via UDP to each server in turn until one of them
+/// answers. Truncated answers are repeated via TCP.
+#[derive(Clone, Debug)]
+pub struct StubResolver {
+    servers: Vec<SocketAddr>,
+    timeout: Duration,
+    attempts: usize,
+}
+
+impl StubResolver {
+    /// Creates a resolver using the given servers.
+    pub fn new(servers: Vec<SocketAddr>) -> Self {
+        StubResolver { servers: servers, timeout: Duration::from_secs(5),
+                       attempts: 2 }
+    }
+
+    /// Creates a resolver using the servers in /etc/resolv.conf.
+    ///
+    /// If the file doesn’t list any servers, a server on localhost is
+    /// used.
+    pub fn from_resolv_conf() -> io::Result<Self> {
+        let file = BufReader::new(try!(File::open("/etc/resolv.conf")));
+        let mut servers = Vec::new();
+        for line in file.lines() {
+            let line = try!(line);
+            let mut words = line.split_whitespace();
+            if words.next() != Some("nameserver") {
+                continue
+            }
+            // Scoped IPv6 addresses aren’t supported, skip them.
+            if let Some(Ok(addr)) = words.next().map(str::parse::<IpAddr>) {
+                servers.push(SocketAddr::new(addr, 53))
+            }
+        }
+        if servers.is_empty() {
+            servers.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0,
+                                                                   0, 1)),
+                                         53))
+        }
+        Ok(StubResolver::new(servers))
+    }
+
+    /// Sets how long to wait for an answer from a server.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+
+    /// Sets how often to go through the list of servers.
+    pub fn set_attempts(&mut self, attempts: usize) {
+        self.attempts = attempts
+    }
+
+    fn query_udp(&self, server: &SocketAddr, id: u16, query: &[u8])
+                 -> io::Result<Option<Vec<u8>>> {
+        let local = match *server {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let sock = try!(UdpSocket::bind(local));
+        try!(sock.set_read_timeout(Some(self.timeout)));
+        try!(sock.send_to(query, server));
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, from) = match sock.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                             || err.kind() == io::ErrorKind::TimedOut => {
+                    return Ok(None)
+                }
+                Err(err) => return Err(err)
+            };
+            // Ignore anything that isn’t the answer we are waiting for.
+            if from == *server && len >= 12 && get_u16(&buf, 0) == id {
+                return Ok(Some(buf[..len].into()))
+            }
+        }
+    }
+
+    fn query_tcp(&self, server: &SocketAddr, id: u16, query: &[u8])
+                 -> io::Result<Vec<u8>> {
+        let mut sock = try!(TcpStream::connect(server));
+        try!(sock.set_read_timeout(Some(self.timeout)));
+        try!(sock.set_write_timeout(Some(self.timeout)));
+        let len = query.len();
+        try!(sock.write_all(&[(len >> 8) as u8, len as u8]));
+        try!(sock.write_all(query));
+        let mut len = [0u8; 2];
+        try!(sock.read_exact(&mut len));
+        let mut res = vec![0u8; get_u16(&len, 0) as usize];
+        try!(sock.read_exact(&mut res));
+        if res.len() < 12 || get_u16(&res, 0) != id {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "unexpected answer"))
+        }
+        Ok(res)
+    }
+}
+
+impl Resolver for StubResolver {
+    fn query(&self, name: &[u8], rtype: RecordType)
+             -> Result<Vec<Record>, Error> {
+        let id = next_id();
+        let query = try!(compose_query(id, name, rtype));
+        let mut err = Error::Timeout;
+        for _ in 0..self.attempts {
+            for server in &self.servers {
+                let answer = match self.query_udp(server, id, &query) {
+                    Ok(Some(answer)) => answer,
+                    Ok(None) => continue,
+                    Err(e) => { err = Error::Io(e); continue }
+                };
+                let answer = if is_truncated(&answer) {
+                    match self.query_tcp(server, id, &query) {
+                        Ok(answer) => answer,
+                        Err(e) => { err = Error::Io(e); continue }
+                    }
+                }
+                else { answer };
+                match parse_answer(&answer, name, rtype) {
+                    // Another server may know better.
+                    Err(Error::Server(rcode)) => err = Error::Server(rcode),
+                    res => return res
+                }
+            }
+        }
+        Err(err)
+    }
+}
+
+
+//------------ Zone ----------------------------------------------------------
+
+/// A resolver answering from records in memory.
+#[derive(Clone, Debug, Default)]
+pub struct Zone {
+    /// The records keyed by lower-case name without the final dot.
+    records: HashMap<Vec<u8>, Vec<Record>>,
+}
+
+impl Zone {
+    pub fn new() -> Self {
+        Zone { records: HashMap::new() }
+    }
+
+    /// Adds a record for *name*.
+    pub fn insert(&mut self, name: &[u8], record: Record) {
+        self.records.entry(zone_key(name)).or_insert_with(Vec::new)
+                    .push(record)
+    }
+}
+
+impl Resolver for Zone {
+    fn query(&self, name: &[u8], rtype: RecordType)
+             -> Result<Vec<Record>, Error> {
+        match self.records.get(&zone_key(name)) {
+            Some(records) => {
+                Ok(records.iter()
+                          .filter(|record| record.record_type() == rtype)
+                          .cloned().collect())
+            }
+            None => Err(Error::NotFound)
+        }
+    }
+}
+
+fn zone_key(name: &[u8]) -> Vec<u8> {
+    let name = if name.ends_with(b".") { &name[..name.len() - 1] }
+               else { name };
+    name.to_ascii_lowercase()
+}
+
+
+//------------ Messages ------------------------------------------------------
+
+/// Returns a random message ID.
+fn next_id() -> u16 {
+    get_u16(&rand_bytes(2), 0)
+}
+
+/// Creates a query message asking for *rtype* records of *name*.
+fn compose_query(id: u16, name: &[u8], rtype: RecordType)
+                 -> Result<Vec<u8>, Error> {
+    let mut res = Vec::with_capacity(name.len() + 18);
+    put_u16(&mut res, id);
+    put_u16(&mut res, 0x0100); // RD
+    put_u16(&mut res, 1);
+    put_u16(&mut res, 0);
+    put_u16(&mut res, 0);
+    put_u16(&mut res, 0);
+    let name = if name.ends_with(b".") { &name[..name.len() - 1] }
+               else { name };
+    if name.len() > 253 {
+        return Err(Error::InvalidName)
+    }
+    for label in name.split(|ch| *ch == b'.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::InvalidName)
+        }
+        res.push(label.len() as u8);
+        res.extend_from_slice(label);
+    }
+    res.push(0);
+    put_u16(&mut res, rtype.to_int());
+    put_u16(&mut res, 1); // IN
+    Ok(res)
+}
+
+fn is_truncated(msg: &[u8]) -> bool {
+    msg[2] & 0x02 != 0
+}
+
+/// Takes the records of *rtype* from the answer section of *msg*.
+///
+/// The answer must repeat the question for *name* and *rtype* or it
+/// belongs to some other query and is rejected. Since the recursive
+/// server follows CNAME records for us, the owner names of the records
+/// aren’t checked.
+fn parse_answer(msg: &[u8], name: &[u8], rtype: RecordType)
+                -> Result<Vec<Record>, Error> {
+    if msg.len() < 12 || msg[2] & 0x80 == 0 || get_u16(msg, 4) != 1 {
+        return Err(Error::Malformed)
+    }
+    let (qname, mut pos) = try!(read_name(msg, 12));
+    if pos + 4 > msg.len() {
+        return Err(Error::Malformed)
+    }
+    let name = if name.ends_with(b".") { &name[..name.len() - 1] }
+               else { name };
+    if !qname.eq_ignore_ascii_case(name) ||
+            get_u16(msg, pos) != rtype.to_int() || get_u16(msg, pos + 2) != 1 {
+        return Err(Error::Malformed)
+    }
+    pos += 4;
+    match msg[3] & 0x0f {
+        0 => { }
+        3 => return Err(Error::NotFound),
+        rcode => return Err(Error::Server(rcode))
+    }
+    let ancount = get_u16(msg, 6);
+    let mut res = Vec::new();
+    for _ in 0..ancount {
+        pos = try!(skip_name(msg, pos));
+        if pos + 10 > msg.len() {
+            return Err(Error::Malformed)
+        }
+        let atype = get_u16(msg, pos);
+        let class = get_u16(msg, pos + 2);
+        let rdlen = get_u16(msg, pos + 8) as usize;
+        pos += 10;
+        if pos + rdlen > msg.len() {
+            return Err(Error::Malformed)
+        }
+        let start = pos;
+        pos += rdlen;
+        if atype != rtype.to_int() || class != 1 {
+            continue
+        }
+        res.push(try!(parse_record(msg, start, rdlen, rtype)));
+    }
+    Ok(res)
+}
+
+/// Parses the record data of *len* octets at *pos* in *msg*.
+///
+/// Domain names may point elsewhere in the message, so the whole
+/// message is needed.
+fn parse_record(msg: &[u8], pos: usize, len: usize, rtype: RecordType)
+                -> Result<Record, Error> {
+    let rdata = &msg[pos..pos + len];
+    match rtype {
+        RecordType::A => {
+            if rdata.len() != 4 {
+                return Err(Error::Malformed)
+            }
+            Ok(Record::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2],
+                                       rdata[3])))
+        }
+        RecordType::Aaaa => {
+            if rdata.len() != 16 {
+                return Err(Error::Malformed)
+            }
+            let seg = |i: usize| get_u16(rdata, i * 2);
+            Ok(Record::Aaaa(Ipv6Addr::new(seg(0), seg(1), seg(2), seg(3),
+                                          seg(4), seg(5), seg(6), seg(7))))
+        }
+        RecordType::Mx => {
+            if rdata.len() < 3 {
+                return Err(Error::Malformed)
+            }
+            let (name, end) = try!(read_name(msg, pos + 2));
+            if end != pos + len {
+                return Err(Error::Malformed)
+            }
+            Ok(Record::Mx(get_u16(rdata, 0), name))
+        }
+        RecordType::Ptr => {
+            let (name, end) = try!(read_name(msg, pos));
+            if end != pos + len {
+                return Err(Error::Malformed)
+            }
+            Ok(Record::Ptr(name))
+        }
+        RecordType::Txt => {
+            let mut text = Vec::with_capacity(rdata.len());
+            let mut data = rdata;
+            while !data.is_empty() {
+                let len = data[0] as usize;
+                if len + 1 > data.len() {
+                    return Err(Error::Malformed)
+                }
+                text.extend_from_slice(&data[1..len + 1]);
+                data = &data[len + 1..];
+            }
+            Ok(Record::Txt(text))
+        }
+    }
+}
+
+/// Returns the position after the name starting at *pos*.
+fn skip_name(msg: &[u8], mut pos: usize) -> Result<usize, Error> {
+    loop {
+        if pos >= msg.len() {
+            return Err(Error::Malformed)
+        }
+        let len = msg[pos] as usize;
+        if len == 0 {
+            return Ok(pos + 1)
+        }
+        else if len & 0xc0 == 0xc0 {
+            // A compression pointer ends the name.
+            return Ok(pos + 2)
+        }
+        else if len & 0xc0 != 0 {
+            return Err(Error::Malformed)
+        }
+        pos += len + 1;
+    }
+}
+
+/// Reads the possibly compressed name starting at *pos*.
+///
+/// Returns the name in dotted form and the position after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(Vec<u8>, usize), Error> {
+    let mut name = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        if pos >= msg.len() {
+            return Err(Error::Malformed)
+        }
+        let len = msg[pos] as usize;
+        if len == 0 {
+            return Ok((name, end.unwrap_or(pos + 1)))
+        }
+        else if len & 0xc0 == 0xc0 {
+            if pos + 1 >= msg.len() || jumps > 64 {
+                return Err(Error::Malformed)
+            }
+            if end.is_none() {
+                end = Some(pos + 2)
+            }
+            jumps += 1;
+            pos = (len & 0x3f) << 8 | msg[pos + 1] as usize;
+            continue
+        }
+        else if len & 0xc0 != 0 || pos + len >= msg.len() {
+            return Err(Error::Malformed)
+        }
+        if !name.is_empty() {
+            name.push(b'.')
+        }
+        name.extend_from_slice(&msg[pos + 1..pos + len + 1]);
+        pos += len + 1;
+    }
+}
+
+fn get_u16(data: &[u8], pos: usize) -> u16 {
+    (data[pos] as u16) << 8 | data[pos + 1] as u16
+}
+
+fn put_u16(target: &mut Vec<u8>, value: u16) {
+    target.push((value >> 8) as u8);
+    target.push(value as u8);
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use super::*;
+    use super::{compose_query, parse_answer};
+
+    #[test]
+    fn query() {
+        let query = compose_query(0x1234, b"Example.com.", RecordType::A)
+                        .unwrap();
+        assert_eq!(query, &b"\x12\x34\x01\x00\x00\x01\x00\x00\
+                             \x00\x00\x00\x00\
+                             \x07Example\x03com\x00\x00\x01\x00\x01"[..]);
+        assert!(compose_query(1, b"a..b", RecordType::A).is_err());
+        assert!(compose_query(1, &[b'a'; 64], RecordType::A).is_err());
+    }
+
+    #[test]
+    fn answer() {
+        // Question for 2.0.0.127.bl.test, a CNAME pointing elsewhere
+        // and two A records using compressed names, then a TXT record.
+        let msg = b"\x12\x34\x81\x80\x00\x01\x00\x04\x00\x00\x00\x00\
+                    \x012\x010\x010\x03127\x02bl\x04test\x00\x00\x01\x00\x01\
+                    \xc0\x0c\x00\x05\x00\x01\x00\x00\x0e\x10\x00\x02\xc0\x16\
+                    \xc0\x0c\x00\x01\x00\x01\x00\x00\x0e\x10\x00\x04\
+                    \x7f\x00\x00\x02\
+                    \xc0\x0c\x00\x01\x00\x01\x00\x00\x0e\x10\x00\x04\
+                    \x7f\x00\x00\x04\
+                    \xc0\x0c\x00\x10\x00\x01\x00\x00\x0e\x10\x00\x06\
+                    \x02ab\x02cd";
+        let name = b"2.0.0.127.bl.test";
+        assert_eq!(parse_answer(msg, name, RecordType::A).unwrap(),
+                   [Record::A(Ipv4Addr::new(127, 0, 0, 2)),
+                    Record::A(Ipv4Addr::new(127, 0, 0, 4))]);
+        assert_eq!(parse_answer(msg, b"2.0.0.127.BL.test.", RecordType::A)
+                       .unwrap().len(), 2);
+        assert!(parse_answer(&msg[..60], name, RecordType::A).is_err());
+
+        // The same answer for a different query.
+        assert!(parse_answer(msg, b"3.0.0.127.bl.test", RecordType::A)
+                    .is_err());
+        assert!(parse_answer(msg, b"0.0.127.bl.test", RecordType::A)
+                    .is_err());
+        assert!(parse_answer(msg, name, RecordType::Txt).is_err());
+        let mut txt = msg.to_vec();
+        txt[32] = 0x10;
+        assert_eq!(parse_answer(&txt, name, RecordType::Txt).unwrap(),
+                   [Record::Txt(b"abcd".to_vec())]);
+
+        // Two MX records and a PTR record with names built from labels
+        // and pointers to the question.
+        let msg = b"\x12\x34\x81\x80\x00\x01\x00\x03\x00\x00\x00\x00\
+                    \x02mx\x07example\x00\x00\x0f\x00\x01\
+                    \xc0\x0c\x00\x0f\x00\x01\x00\x00\x0e\x10\x00\x08\
+                    \x00\x0a\x03mx2\xc0\x0f\
+                    \x00\x00\x0f\x00\x01\x00\x00\x0e\x10\x00\x04\
+                    \x00\x05\xc0\x0c\
+                    \x00\x00\x0c\x00\x01\x00\x00\x0e\x10\x00\x04\
+                    \x01a\xc0\x0c";
+        assert_eq!(parse_answer(msg, b"mx.example", RecordType::Mx).unwrap(),
+                   [Record::Mx(10, b"mx2.example".to_vec()),
+                    Record::Mx(5, b"mx.example".to_vec())]);
+        let mut ptr = msg.to_vec();
+        ptr[25] = 0x0c;
+        assert_eq!(parse_answer(&ptr, b"mx.example", RecordType::Ptr)
+                       .unwrap(),
+                   [Record::Ptr(b"a.mx.example".to_vec())]);
+
+        let mut nxdomain = msg.to_vec();
+        nxdomain[3] = 0x83;
+        match parse_answer(&nxdomain, b"mx.example", RecordType::Mx) {
+            Err(Error::NotFound) => { }
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+
+    #[test]
+    fn zone() {
+        let mut zone = Zone::new();
+        zone.insert(b"mx.Example.com.", Record::A(Ipv4Addr::new(192, 0, 2,
+                                                                 1)));
+        zone.insert(b"example.com", Record::Txt(b"v=spf1 mx".to_vec()));
+        assert_eq!(zone.lookup_a(b"MX.example.com").unwrap(),
+                   [Ipv4Addr::new(192, 0, 2, 1)]);
+        assert_eq!(zone.lookup_txt(b"example.com").unwrap(),
+                   [b"v=spf1 mx".to_vec()]);
+        assert!(zone.lookup_a(b"example.com").unwrap().is_empty());
+        zone.insert(b"example.com",
+                    Record::Mx(20, b"mx2.example.com".to_vec()));
+        zone.insert(b"example.com",
+                    Record::Mx(10, b"mx.example.com".to_vec()));
+        assert_eq!(zone.lookup_mx(b"example.com").unwrap(),
+                   [b"mx.example.com".to_vec(), b"mx2.example.com".to_vec()]);
+        zone.insert(b"1.2.0.192.in-addr.arpa",
+                    Record::Ptr(b"mx.example.com".to_vec()));
+        assert_eq!(zone.lookup_ptr("192.0.2.1".parse().unwrap()).unwrap(),
+                   [b"mx.example.com".to_vec()]);
+        match zone.lookup_a(b"www.example.com") {
+            Err(Error::NotFound) => { }
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+}
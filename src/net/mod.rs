@@ -1,5 +1,6 @@
 
+pub mod dns;
 pub mod tls;
-#[cfg(test)] pub mod test;
+#[cfg(any(test, feature = "test-harness"))] pub mod test;
 
 
@@ -1,3 +1,69 @@
 //! Testing tools for networking
 
+use netmachines::sockets::Certificate;
+use rotor::{self, EventSet, Loop, Machine, Notifier, Response, Scope, Void};
 
+
+//------------ notifier ------------------------------------------------------
+
+/// Creates a notifier for use outside of a running loop.
+///
+/// Rotor only hands out notifiers to machines inside a loop, so this
+/// creates a loop with a machine that does nothing and takes its
+/// notifier. The loop is never run, so wakeups through the notifier go
+/// nowhere. It needs to be kept alive for as long as the notifier is in
+/// use, though.
+///
+pub fn notifier() -> (Loop<Idle>, Notifier) {
+    let mut res = None;
+    let mut lp = Loop::new(&rotor::Config::new()).unwrap();
+    lp.add_machine_with(|scope| {
+        res = Some(scope.notifier());
+        Response::ok(Idle)
+    }).unwrap();
+    (lp, res.unwrap())
+}
+
+
+//------------ Idle ----------------------------------------------------------
+
+/// A machine that does nothing at all.
+pub struct Idle;
+
+impl Machine for Idle {
+    type Context = ();
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<()>) -> Response<Self, Void> {
+        match seed { }
+    }
+
+    fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+             -> Response<Self, Void> {
+        Response::ok(self)
+    }
+
+    fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+        Response::ok(self)
+    }
+
+    fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+        Response::ok(self)
+    }
+
+    fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+        Response::ok(self)
+    }
+}
+
+
+//------------ FakeCertificate -----------------------------------------------
+
+/// A peer certificate that isn’t.
+///
+/// Use this for pretending that a TLS handshake has happened.
+///
+#[derive(Clone, Debug)]
+pub struct FakeCertificate;
+
+impl Certificate for FakeCertificate { }
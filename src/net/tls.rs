@@ -3,7 +3,7 @@ use std::io::{self, Read, Write};
 use std::net::SocketAddr;
 use rotor::mio;
 use rotor::mio::tcp::{TcpListener, TcpStream};
-use openssl::ssl::{MaybeSslStream, SslContext, SslStream};
+use openssl::ssl::{MaybeSslStream, Ssl, SslContext, SslStream};
 use openssl::ssl::error::SslError;
 use openssl::x509::X509;
 
@@ -139,3 +139,31 @@ impl mio::Evented for StartTlsStream {
     }
 }
 
+
+/// A stream that can describe the TLS cipher it negotiated.
+///
+/// The description has the protocol version, the name of the cipher,
+/// and its strength, such as `TLSv1.2 with cipher AES256-SHA (256/256
+/// bits)`. It ends up in the Received field of incoming mail.
+///
+pub trait TlsInfo {
+    /// Returns the description if TLS is running.
+    fn current_cipher(&self) -> Option<String>;
+}
+
+// The SMTP server runs on netmachines’ stream, not the one above.
+impl TlsInfo for ::netmachines::sockets::openssl::StartTlsStream {
+    fn current_cipher(&self) -> Option<String> {
+        self.ssl().and_then(describe_cipher)
+    }
+}
+
+/// Returns a description of the cipher negotiated for *ssl*.
+fn describe_cipher(ssl: &Ssl) -> Option<String> {
+    ssl.get_current_cipher().map(|cipher| {
+        let bits = cipher.bits();
+        format!("{} with cipher {} ({}/{} bits)", cipher.version(),
+                cipher.name(), bits.secret,
+                bits.algorithm.unwrap_or(bits.secret))
+    })
+}
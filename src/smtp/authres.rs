@@ -0,0 +1,303 @@
+//! Authentication-Results header fields.
+//!
+//! The Authentication-Results header field defined in RFC 8601 records
+//! the outcome of checks such as DKIM for the benefit of whoever reads
+//! the message later. Each field names the host that did the checking,
+//! the authserv-id, followed by a list of results, one for each check.
+//!
+//! Since downstream readers trust fields carrying our authserv-id, such
+//! fields already present in an incoming message can only be forged. The
+//! `Filter` removes them as RFC 8601, section 5 asks.
+
+use std::ascii::AsciiExt;
+use std::fmt;
+use ::util::text::trim_fws;
+
+
+//------------ AuthResults ---------------------------------------------------
+
+/// An Authentication-Results header field under construction.
+#[derive(Clone, Debug)]
+pub struct AuthResults {
+    authserv_id: Vec<u8>,
+
+    /// The results, each already formatted.
+    results: Vec<Vec<u8>>,
+}
+
+impl AuthResults {
+    /// Creates a field without any results for *authserv_id*.
+    pub fn new(authserv_id: &[u8]) -> Self {
+        AuthResults { authserv_id: authserv_id.into(), results: Vec::new() }
+    }
+
+    /// Adds a result.
+    ///
+    /// The *method* is the name of the check, such as `dkim`, and the
+    /// *result* its outcome. Each property is given as a pair of its
+    /// name, such as `header.d`, and its value.
+    pub fn add(&mut self, method: &str, result: &str, reason: Option<&str>,
+               props: &[(&str, &[u8])]) {
+        let mut res = Vec::new();
+        res.extend_from_slice(method.as_bytes());
+        res.push(b'=');
+        res.extend_from_slice(result.as_bytes());
+        if let Some(reason) = reason {
+            res.extend_from_slice(b" reason=");
+            push_quoted(&mut res, reason.as_bytes());
+        }
+        for &(name, value) in props {
+            res.push(b' ');
+            res.extend_from_slice(name.as_bytes());
+            res.push(b'=');
+            if !value.is_empty() && value.iter().all(|&ch| is_pvalue(ch)) {
+                res.extend_from_slice(value)
+            }
+            else {
+                push_quoted(&mut res, value)
+            }
+        }
+        self.results.push(res)
+    }
+
+    /// Returns whether no results have been added.
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// Returns the complete header field including the final line break.
+    ///
+    /// Each result goes on a line of its own.
+    pub fn to_field(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        res.extend_from_slice(b"Authentication-Results: ");
+        res.extend_from_slice(&self.authserv_id);
+        if self.results.is_empty() {
+            res.extend_from_slice(b"; none");
+        }
+        for result in &self.results {
+            res.extend_from_slice(b";\r\n\t");
+            res.extend_from_slice(result);
+        }
+        res.extend_from_slice(b"\r\n");
+        res
+    }
+}
+
+impl fmt::Display for AuthResults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.to_field()))
+    }
+}
+
+
+//------------ Filter --------------------------------------------------------
+
+/// Removes our own Authentication-Results fields from a message.
+///
+/// The filter works on the message as it streams past. Only the header
+/// field currently passing through is kept in memory.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    authserv_id: Vec<u8>,
+
+    /// The header field so far.
+    field: Vec<u8>,
+
+    /// Has the empty line ending the header been seen?
+    body: bool,
+}
+
+impl Filter {
+    /// Creates a filter for the fields of *authserv_id*.
+    pub fn new(authserv_id: &[u8]) -> Self {
+        Filter { authserv_id: authserv_id.into(), field: Vec::new(),
+                 body: false }
+    }
+
+    /// Filters the next chunk of the message.
+    ///
+    /// Returns the part of *data* that is to be kept so far. The last
+    /// header field is held back until the next line shows that it is
+    /// complete.
+    pub fn chunk(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.body {
+            return data.into()
+        }
+        let mut res = Vec::new();
+        for (i, &ch) in data.iter().enumerate() {
+            if self.field.last() == Some(&b'\n') && ch != b' ' && ch != b'\t' {
+                self.flush(&mut res)
+            }
+            self.field.push(ch);
+            if ch == b'\n' && (self.field == b"\r\n" || self.field == b"\n") {
+                self.flush(&mut res);
+                self.body = true;
+                res.extend_from_slice(&data[i + 1..]);
+                break
+            }
+        }
+        res
+    }
+
+    /// Finishes the message, returning whatever is left to be kept.
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut res = Vec::new();
+        self.flush(&mut res);
+        res
+    }
+
+    /// Moves the current field to *target* unless it is one of ours.
+    fn flush(&mut self, target: &mut Vec<u8>) {
+        if !self.is_ours() {
+            target.extend_from_slice(&self.field)
+        }
+        self.field.clear()
+    }
+
+    fn is_ours(&self) -> bool {
+        let colon = match self.field.iter().position(|ch| *ch == b':') {
+            Some(colon) => colon,
+            None => return false
+        };
+        if !trim_fws(&self.field[..colon])
+                .eq_ignore_ascii_case(b"Authentication-Results") {
+            return false
+        }
+        authserv_id(&self.field[colon + 1..])
+            .eq_ignore_ascii_case(&self.authserv_id)
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the authserv-id at the start of a field *value*.
+///
+/// Comments before the authserv-id are skipped and quotes around it
+/// removed.
+fn authserv_id(mut value: &[u8]) -> &[u8] {
+    loop {
+        value = trim_fws(value);
+        if value.first() != Some(&b'(') {
+            break
+        }
+        let mut depth = 0;
+        let mut escaped = false;
+        let mut end = value.len();
+        for (i, &ch) in value.iter().enumerate() {
+            match ch {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'(' => depth += 1,
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = i + 1;
+                        break
+                    }
+                }
+                _ => { }
+            }
+        }
+        value = &value[end..];
+    }
+    if value.first() == Some(&b'"') {
+        let value = &value[1..];
+        let end = value.iter().position(|ch| *ch == b'"')
+                       .unwrap_or(value.len());
+        return &value[..end]
+    }
+    let end = value.iter().position(|&ch| {
+        ch == b';' || ch == b'(' || ch == b' ' || ch == b'\t'
+            || ch == b'\r' || ch == b'\n'
+    }).unwrap_or(value.len());
+    &value[..end]
+}
+
+
+/// Returns whether *ch* can appear in an unquoted property value.
+///
+/// These are the characters of a MIME token plus the at sign, so that
+/// addresses and domain names can go in unquoted.
+fn is_pvalue(ch: u8) -> bool {
+    ch > b' ' && ch < 0x7f && !b"()<>,;:\\\"/[]?=".contains(&ch)
+}
+
+fn push_quoted(target: &mut Vec<u8>, value: &[u8]) {
+    target.push(b'"');
+    for &ch in value {
+        if ch == b'"' || ch == b'\\' {
+            target.push(b'\\')
+        }
+        target.push(ch)
+    }
+    target.push(b'"');
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn to_field() {
+        let mut results = AuthResults::new(b"mx.test");
+        assert_eq!(results.to_field(),
+                   &b"Authentication-Results: mx.test; none\r\n"[..]);
+        results.add("dkim", "pass", None,
+                    &[("header.d", &b"example.com"[..]),
+                      ("header.b", &b"ab/c"[..])]);
+        results.add("dkim", "fail", Some("body hash mismatch"),
+                    &[("header.i", &b"@example.com"[..])]);
+        assert_eq!(results.to_field(),
+                   &b"Authentication-Results: mx.test;\r\n\
+                      \tdkim=pass header.d=example.com \
+                      header.b=\"ab/c\";\r\n\
+                      \tdkim=fail reason=\"body hash mismatch\" \
+                      header.i=@example.com\r\n"[..]);
+    }
+    #[test]
+    fn filter() {
+        let message = &b"Received: by mx.test\r\n\
+                         Authentication-Results: MX.test;\r\n\
+                         \tdkim=pass header.d=example.com\r\n\
+                         Authentication-Results: (forged) \"mx.test\";\r\n\
+                         \tdmarc=pass\r\n\
+                         Authentication-Results: mx.example.com;\r\n\
+                         \tdkim=pass header.d=example.com\r\n\
+                         Authentication-Results: mx.test.example.com; none\r\n\
+                         Subject: test\r\n\
+                         \r\n\
+                         Authentication-Results: mx.test; none\r\n"[..];
+        let expected = &b"Received: by mx.test\r\n\
+                          Authentication-Results: mx.example.com;\r\n\
+                          \tdkim=pass header.d=example.com\r\n\
+                          Authentication-Results: mx.test.example.com; \
+                          none\r\n\
+                          Subject: test\r\n\
+                          \r\n\
+                          Authentication-Results: mx.test; none\r\n"[..];
+
+        let mut filter = Filter::new(b"mx.test");
+        let mut res = filter.chunk(message);
+        res.extend_from_slice(&filter.finish());
+        assert_eq!(res, expected);
+
+        let mut filter = Filter::new(b"mx.test");
+        let mut res = Vec::new();
+        for chunk in message.chunks(7) {
+            res.extend_from_slice(&filter.chunk(chunk))
+        }
+        res.extend_from_slice(&filter.finish());
+        assert_eq!(res, expected);
+
+        let mut filter = Filter::new(b"mx.test");
+        let mut res = filter.chunk(b"Authentication-Results: mx.test;\r\n\
+                                     \tdkim=pass\r\n");
+        res.extend_from_slice(&filter.finish());
+        assert_eq!(res, b"");
+    }
+}
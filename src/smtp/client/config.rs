@@ -0,0 +1,64 @@
+//! Configuration for SMTP clients.
+
+use std::time::Duration;
+use openssl::ssl::SslContext;
+
+pub struct Config {
+    context: SslContext,
+    hostname: Vec<u8>,
+    tls_policy: TlsPolicy,
+    timeout: Duration,
+}
+
+impl Config {
+    pub fn new(context: SslContext, hostname: Vec<u8>) -> Self {
+        Config { context: context, hostname: hostname,
+                 tls_policy: TlsPolicy::Opportunistic,
+                 timeout: Duration::from_secs(5 * 60) }
+    }
+
+    pub fn ssl_context(&self) -> &SslContext {
+        &self.context
+    }
+
+    /// Returns the host name to use in EHLO and HELO.
+    pub fn hostname(&self) -> &[u8] {
+        &self.hostname
+    }
+
+    pub fn tls_policy(&self) -> TlsPolicy {
+        self.tls_policy
+    }
+
+    pub fn set_tls_policy(&mut self, policy: TlsPolicy) {
+        self.tls_policy = policy
+    }
+
+    /// Returns how long to wait for the server before giving up.
+    ///
+    /// The default is five minutes which is the shortest of the timeouts
+    /// in RFC 5321, section 4.5.3.2, except for DATA initiation.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+}
+
+
+//------------ TlsPolicy -----------------------------------------------------
+
+/// When to use STARTTLS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlsPolicy {
+    /// Never start TLS.
+    Never,
+
+    /// Start TLS if the server offers it.
+    Opportunistic,
+
+    /// Start TLS or give up.
+    Required,
+}
@@ -0,0 +1,169 @@
+//! The service extensions announced by a server.
+
+use std::ascii::AsciiExt;
+
+
+//------------ Extensions ----------------------------------------------------
+
+/// The extensions a server announced in its reply to EHLO.
+///
+/// Only the extensions the client cares about are kept. A server greeted
+/// with HELO has none of them.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Extensions {
+    pub pipelining: bool,
+    pub starttls: bool,
+
+    /// The SIZE extension with the maximum message size.
+    ///
+    /// The size is zero if the server didn’t give one.
+    pub size: Option<u64>,
+    pub eightbitmime: bool,
+    pub chunking: bool,
+    pub binarymime: bool,
+    pub smtputf8: bool,
+    pub dsn: bool,
+    pub enhancedstatuscodes: bool,
+    pub etrn: bool,
+
+    /// The SASL mechanisms offered by the AUTH extension.
+    pub auth: Vec<Vec<u8>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Extensions::default()
+    }
+
+    /// Parses the text of an EHLO reply.
+    ///
+    /// The first line contains the server’s domain and greeting and is
+    /// skipped. Each further line contains a keyword followed by optional
+    /// parameters separated by spaces. Unknown keywords are ignored.
+    ///
+    pub fn parse(text: &[u8]) -> Self {
+        let mut res = Extensions::new();
+        for line in Lines(text).skip(1) {
+            let mut words = line.split(|ch| *ch == b' ')
+                                .filter(|word| !word.is_empty());
+            let keyword = match words.next() {
+                Some(keyword) => keyword.to_ascii_uppercase(),
+                None => continue
+            };
+            match &keyword[..] {
+                b"PIPELINING" => res.pipelining = true,
+                b"STARTTLS" => res.starttls = true,
+                b"SIZE" => {
+                    res.size = Some(words.next().and_then(parse_u64)
+                                                .unwrap_or(0))
+                }
+                b"8BITMIME" => res.eightbitmime = true,
+                b"CHUNKING" => res.chunking = true,
+                b"BINARYMIME" => res.binarymime = true,
+                b"SMTPUTF8" => res.smtputf8 = true,
+                b"DSN" => res.dsn = true,
+                b"ENHANCEDSTATUSCODES" => res.enhancedstatuscodes = true,
+                b"ETRN" => res.etrn = true,
+                b"AUTH" => {
+                    res.auth = words.map(|word| word.to_ascii_uppercase())
+                                    .collect()
+                }
+                _ => { }
+            }
+        }
+        res
+    }
+
+    /// Returns whether a message of *size* octets is too big.
+    pub fn exceeds_size(&self, size: u64) -> bool {
+        match self.size {
+            Some(limit) => limit > 0 && size > limit,
+            None => false
+        }
+    }
+}
+
+
+//------------ Lines ---------------------------------------------------------
+
+/// An iterator over the CRLF separated lines of a reply text.
+struct Lines<'a>(&'a [u8]);
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.0.is_empty() {
+            return None
+        }
+        match self.0.windows(2).position(|w| w == b"\r\n") {
+            Some(idx) => {
+                let res = &self.0[..idx];
+                self.0 = &self.0[idx + 2..];
+                Some(res)
+            }
+            None => {
+                let res = self.0;
+                self.0 = b"";
+                Some(res)
+            }
+        }
+    }
+}
+
+
+fn parse_u64(digits: &[u8]) -> Option<u64> {
+    let mut res = 0u64;
+    if digits.is_empty() {
+        return None
+    }
+    for ch in digits {
+        match *ch {
+            b'0' ... b'9' => {
+                let digit = (ch - b'0') as u64;
+                res = match res.checked_mul(10)
+                               .and_then(|v| v.checked_add(digit)) {
+                    Some(v) => v,
+                    None => return None
+                }
+            }
+            _ => return None
+        }
+    }
+    Some(res)
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_ehlo() {
+        let ext = Extensions::parse(b"mx.example.com Hello\r\n\
+                                      PIPELINING\r\n\
+                                      size 10240000\r\n\
+                                      8BITMIME\r\n\
+                                      AUTH PLAIN login\r\n\
+                                      STARTTLS");
+        assert!(ext.pipelining);
+        assert!(ext.starttls);
+        assert!(ext.eightbitmime);
+        assert!(!ext.chunking);
+        assert_eq!(ext.size, Some(10240000));
+        assert_eq!(ext.auth, vec![b"PLAIN".to_vec(), b"LOGIN".to_vec()]);
+        assert!(ext.exceeds_size(10240001));
+        assert!(!ext.exceeds_size(10240000));
+
+        let ext = Extensions::parse(b"mx.example.com\r\nSIZE\r\nDSN");
+        assert_eq!(ext.size, Some(0));
+        assert!(ext.dsn);
+        assert!(!ext.exceeds_size(u64::max_value()));
+
+        assert_eq!(Extensions::parse(b"mx.example.com STARTTLS"),
+                   Extensions::new());
+    }
+}
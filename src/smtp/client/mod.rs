@@ -0,0 +1,22 @@
+//! An SMTP client.
+//!
+//! The client mirrors the design of the server: a `Session` is a state
+//! machine that only deals with buffers and a `Transport` plugs it into
+//! netmachines. What the client actually sends is decided by a `Handler`
+//! that hands out mails and learns about their fate.
+//!
+//! The client uses PIPELINING and STARTTLS if the server offers them and
+//! passes on the SIZE, BODY, and DSN parameters of a mail if the server
+//! supports the respective extensions.
+
+pub use self::config::{Config, TlsPolicy};
+pub use self::extensions::Extensions;
+pub use self::protocol::{Error, Handler, Mail, Outcome, Recipient, Report};
+pub use self::session::{Action, Session};
+pub use self::transport::Transport;
+
+pub mod config;
+pub mod extensions;
+pub mod protocol;
+pub mod session;
+pub mod transport;
@@ -0,0 +1,213 @@
+//! The interface between an SMTP client session and its user.
+
+use std::io;
+use std::io::Read;
+use ::smtp::syntax::{NotifyValue, Reply, RetValue};
+
+
+//------------ Handler -------------------------------------------------------
+
+/// A type that decides what a client session sends.
+///
+/// Once the session is ready for a mail transaction, it asks for the next
+/// mail via `next_mail()`. When the transaction is over, it reports the
+/// outcome via `done()` and asks for the next mail. If there are no more
+/// mails, the session ends.
+///
+pub trait Handler {
+    /// Returns the next mail to send or `None` to end the session.
+    fn next_mail(&mut self) -> Option<Mail>;
+
+    /// Reports what happened to a mail.
+    fn done(&mut self, mail: Mail, report: Report);
+
+    /// Reports that the session failed.
+    ///
+    /// This is called at most once. If a mail was in flight, it has been
+    /// reported via `done()` already.
+    fn failed(&mut self, error: Error);
+}
+
+
+//------------ Mail ----------------------------------------------------------
+
+/// A mail to be sent.
+///
+pub struct Mail {
+    /// The reverse path without the angle brackets.
+    ///
+    /// This is empty for the null reverse path.
+    pub reverse_path: Vec<u8>,
+
+    /// The recipients of the mail.
+    pub recipients: Vec<Recipient>,
+
+    /// Is the message 8BITMIME rather than 7BIT?
+    ///
+    /// Such a message is not downgraded. If the server doesn’t support
+    /// 8BITMIME, the mail fails with a 554 5.6.3 reply instead.
+    pub eightbitmime: bool,
+
+    /// The size of the message if known.
+    pub size: Option<u64>,
+
+    /// The DSN RET parameter.
+    pub ret: Option<RetValue>,
+
+    /// The DSN ENVID parameter already encoded as xtext.
+    pub envid: Option<Vec<u8>>,
+
+    /// The message itself.
+    ///
+    /// The message must use CRLF line endings. Dot-stuffing happens while
+    /// sending.
+    pub data: Box<Read>,
+}
+
+impl Mail {
+    pub fn new(reverse_path: Vec<u8>, recipients: Vec<Recipient>,
+               data: Box<Read>) -> Self {
+        Mail { reverse_path: reverse_path, recipients: recipients,
+               eightbitmime: false, size: None, ret: None, envid: None,
+               data: data }
+    }
+}
+
+
+//------------ Recipient -----------------------------------------------------
+
+/// A recipient of a mail.
+///
+pub struct Recipient {
+    /// The forward path without the angle brackets.
+    pub path: Vec<u8>,
+
+    /// The DSN NOTIFY parameter.
+    pub notify: Option<NotifyValue>,
+
+    /// The DSN ORCPT parameter already encoded as `addr-type ";" xtext`.
+    pub orcpt: Option<Vec<u8>>,
+}
+
+impl Recipient {
+    pub fn new(path: Vec<u8>) -> Self {
+        Recipient { path: path, notify: None, orcpt: None }
+    }
+}
+
+
+//------------ Report --------------------------------------------------------
+
+/// The replies received during a mail transaction.
+///
+/// A reply is `None` if the session never got that far.
+///
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub mail: Option<Reply>,
+    pub recipients: Vec<Option<Reply>>,
+    pub data: Option<Reply>,
+}
+
+impl Report {
+    pub fn new(recipients: usize) -> Self {
+        Report { mail: None, recipients: vec![None; recipients], data: None }
+    }
+
+    /// Returns whether any recipient has been accepted by the server.
+    pub fn any_accepted(&self) -> bool {
+        self.recipients.iter().any(|reply| match *reply {
+            Some(ref reply) => reply.is_positive(),
+            None => false
+        })
+    }
+
+    /// Returns what happened to the recipient with index *idx*.
+    pub fn outcome(&self, idx: usize) -> Outcome {
+        let rcpt = match self.recipients.get(idx) {
+            Some(rcpt) => rcpt,
+            None => return Outcome::Deferred(None)
+        };
+        for reply in &[&self.mail, rcpt] {
+            match **reply {
+                Some(ref reply) if reply.is_positive() => { }
+                Some(ref reply) => return Outcome::from_negative(reply),
+                None => return Outcome::Deferred(None)
+            }
+        }
+        match self.data {
+            Some(ref reply) if reply.is_positive() => {
+                Outcome::Delivered(reply)
+            }
+            Some(ref reply) => Outcome::from_negative(reply),
+            None => Outcome::Deferred(None)
+        }
+    }
+}
+
+
+//------------ Outcome -------------------------------------------------------
+
+/// What happened to a single recipient.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome<'a> {
+    /// The server took responsibility with the given reply.
+    Delivered(&'a Reply),
+
+    /// Try again later.
+    ///
+    /// There is no reply if the session failed before the server had a
+    /// say.
+    Deferred(Option<&'a Reply>),
+
+    /// The server rejected the recipient for good.
+    Failed(&'a Reply),
+}
+
+impl<'a> Outcome<'a> {
+    fn from_negative(reply: &'a Reply) -> Self {
+        if reply.is_permanent() {
+            Outcome::Failed(reply)
+        }
+        else {
+            Outcome::Deferred(Some(reply))
+        }
+    }
+}
+
+
+//------------ Error ---------------------------------------------------------
+
+/// The reasons why a session may fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The server didn’t like us from the start.
+    Greeting(Reply),
+
+    /// The server rejected both EHLO and HELO.
+    Hello(Reply),
+
+    /// The server rejected STARTTLS while we require TLS.
+    StartTls(Reply),
+
+    /// The server doesn’t offer STARTTLS while we require TLS.
+    TlsRequired,
+
+    /// The TLS handshake failed.
+    Tls,
+
+    /// The server sent something that isn’t a reply.
+    Syntax,
+
+    /// The server closed the connection.
+    Closed,
+
+    /// The server took too long to answer.
+    Timeout,
+
+    /// Reading the message failed.
+    Source(io::Error),
+
+    /// Reading from or writing to the connection failed.
+    Io(io::Error),
+}
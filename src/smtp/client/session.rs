@@ -0,0 +1,795 @@
+//! An SMTP client session.
+
+use std::collections::VecDeque;
+use std::io::Read;
+use std::mem;
+use std::rc::Rc;
+use ::smtp::dotstuff::Stuffer;
+use ::smtp::server::buf::{RecvBuf, SendBuf};
+use ::smtp::syntax::{Reply, RetValue};
+use ::util::scribe::Scribe;
+use super::config::{Config, TlsPolicy};
+use super::extensions::Extensions;
+use super::protocol::{Error, Handler, Mail, Report};
+
+
+/// The size of the blocks the message is read in.
+const BLOCK_SIZE: usize = 16384;
+
+
+//------------ Action --------------------------------------------------------
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// Write all data, then read.
+    ///
+    /// If there is no data to write, read right away.
+    Read,
+
+    /// Write all data, then call `feed()` for more.
+    Feed,
+
+    /// Write all data, then start a TLS handshake, then call `tls_ready()`.
+    StartTls,
+
+    /// Write all data, then close the connection.
+    Close
+}
+
+
+//------------ Session -------------------------------------------------------
+
+pub struct Session<H: Handler> {
+    handler: H,
+    config: Rc<Config>,
+    state: State,
+    extensions: Extensions,
+    secure: bool,
+
+    /// Has the handler been told about a failure?
+    failed: bool,
+}
+
+impl<H: Handler> Session<H> {
+    /// Creates a new session for a freshly opened connection.
+    ///
+    /// The server talks first, so there is nothing to send yet.
+    pub fn new(handler: H, config: Rc<Config>) -> (Self, Action) {
+        (Session { handler: handler, config: config, state: State::Greeting,
+                   extensions: Extensions::new(), secure: false,
+                   failed: false },
+         Action::Read)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Processes all complete replies in the receive buffer.
+    pub fn recv(mut self, recv: &mut RecvBuf, send: &mut SendBuf)
+                -> (Self, Action) {
+        loop {
+            let reply = match recv.parse_reply() {
+                Ok(Some(reply)) => reply,
+                Ok(None) => return (self, Action::Read),
+                Err(()) => {
+                    let action = self.fail(Error::Syntax, send, false);
+                    return (self, action)
+                }
+            };
+            let action = self.reply(reply, send);
+            if action != Action::Read {
+                return (self, action)
+            }
+        }
+    }
+
+    /// Sends more of the message.
+    pub fn feed(mut self, send: &mut SendBuf) -> (Self, Action) {
+        let action = match mem::replace(&mut self.state, State::Closed) {
+            State::Data(mut trans, mut stuffer) => {
+                let mut buf = [0u8; BLOCK_SIZE];
+                match trans.mail.data.read(&mut buf) {
+                    Ok(0) => {
+                        stuffer.finish(send);
+                        self.state = State::DataEnd(trans);
+                        Action::Read
+                    }
+                    Ok(len) => {
+                        stuffer.encode(&buf[..len], send);
+                        self.state = State::Data(trans, stuffer);
+                        Action::Feed
+                    }
+                    Err(err) => {
+                        // We can’t abort DATA, so all we can do is close
+                        // the connection.
+                        self.state = State::Data(trans, stuffer);
+                        self.fail(Error::Source(err), send, false)
+                    }
+                }
+            }
+            state => {
+                self.state = state;
+                Action::Read
+            }
+        };
+        (self, action)
+    }
+
+    /// Continues after the TLS handshake.
+    ///
+    /// As with the server, everything learned before is forgotten and we
+    /// start over with EHLO.
+    pub fn tls_ready(mut self, send: &mut SendBuf) -> (Self, Action) {
+        self.secure = true;
+        self.extensions = Extensions::new();
+        self.hello(b"EHLO", send);
+        self.state = State::Ehlo;
+        (self, Action::Read)
+    }
+
+    /// Gives up on the session because the connection is gone.
+    pub fn abort(mut self, error: Error) -> Self {
+        let mut send = SendBuf::new();
+        if let State::Quit = self.state {
+            self.state = State::Closed;
+        }
+        else {
+            self.fail(error, &mut send, false);
+        }
+        self
+    }
+}
+
+
+impl<H: Handler> Session<H> {
+    fn reply(&mut self, reply: Reply, send: &mut SendBuf) -> Action {
+        match mem::replace(&mut self.state, State::Closed) {
+            State::Greeting => {
+                if reply.is_positive() {
+                    self.hello(b"EHLO", send);
+                    self.state = State::Ehlo;
+                    Action::Read
+                }
+                else {
+                    self.fail(Error::Greeting(reply), send, true)
+                }
+            }
+            State::Ehlo => {
+                if reply.is_positive() {
+                    self.extensions = Extensions::parse(&reply.text);
+                    self.hello_done(send)
+                }
+                else if reply.code == 500 || reply.code == 502 {
+                    self.hello(b"HELO", send);
+                    self.state = State::Helo;
+                    Action::Read
+                }
+                else {
+                    self.fail(Error::Hello(reply), send, true)
+                }
+            }
+            State::Helo => {
+                if reply.is_positive() {
+                    self.extensions = Extensions::new();
+                    self.hello_done(send)
+                }
+                else {
+                    self.fail(Error::Hello(reply), send, true)
+                }
+            }
+            State::StartTls => {
+                if reply.code == 220 {
+                    self.state = State::StartTls;
+                    Action::StartTls
+                }
+                else if self.config.tls_policy() == TlsPolicy::Required {
+                    self.fail(Error::StartTls(reply), send, true)
+                }
+                else {
+                    self.next_mail(send)
+                }
+            }
+            State::Transaction(trans) => self.transaction(trans, reply, send),
+            State::Data(trans, stuffer) => {
+                // The server shouldn’t talk now. Take note and carry on.
+                debug!("SMTP client: unexpected reply during DATA: {:?}",
+                       reply);
+                self.state = State::Data(trans, stuffer);
+                Action::Feed
+            }
+            State::DataEnd(mut trans) => {
+                trans.report.data = Some(reply);
+                self.end_transaction(trans, false, send)
+            }
+            State::Rset => self.next_mail(send),
+            State::Quit | State::Closed => Action::Close
+        }
+    }
+
+    fn transaction(&mut self, mut trans: Transaction, reply: Reply,
+                   send: &mut SendBuf) -> Action {
+        let pipelining = self.extensions.pipelining;
+        match trans.pending.pop_front() {
+            Some(Pending::Mail) => {
+                let positive = reply.is_positive();
+                trans.report.mail = Some(reply);
+                if !pipelining {
+                    if positive {
+                        self.rcpt(&mut trans, send);
+                    }
+                    else {
+                        return self.end_transaction(trans, false, send)
+                    }
+                }
+            }
+            Some(Pending::Rcpt(idx)) => {
+                trans.report.recipients[idx] = Some(reply);
+                if !pipelining {
+                    if trans.next_rcpt < trans.mail.recipients.len() {
+                        self.rcpt(&mut trans, send);
+                    }
+                    else if trans.report.any_accepted() {
+                        send.scribble_bytes(b"DATA\r\n");
+                        trans.pending.push_back(Pending::Data);
+                    }
+                    else {
+                        return self.end_transaction(trans, true, send)
+                    }
+                }
+            }
+            Some(Pending::Data) => {
+                if reply.code == 354 {
+                    if trans.report.any_accepted() {
+                        self.state = State::Data(trans, Stuffer::new());
+                        return Action::Feed
+                    }
+                    // Pipelined DATA got through without any recipients.
+                    // Send an empty message, the server will reject it.
+                    Stuffer::new().finish(send);
+                    self.state = State::DataEnd(trans);
+                    return Action::Read
+                }
+                let open = match trans.report.mail {
+                    Some(ref mail) => mail.is_positive(),
+                    None => false
+                };
+                trans.report.data = Some(reply);
+                return self.end_transaction(trans, open, send)
+            }
+            None => {
+                debug!("SMTP client: unexpected reply: {:?}", reply);
+            }
+        }
+        self.state = State::Transaction(trans);
+        Action::Read
+    }
+
+    /// Sends EHLO or HELO.
+    fn hello(&mut self, verb: &[u8], send: &mut SendBuf) {
+        send.scribble_bytes(verb);
+        send.scribble_octet(b' ');
+        send.scribble_bytes(self.config.hostname());
+        send.scribble_bytes(b"\r\n");
+    }
+
+    /// Continues after a successful EHLO or HELO.
+    fn hello_done(&mut self, send: &mut SendBuf) -> Action {
+        if !self.secure {
+            let policy = self.config.tls_policy();
+            if self.extensions.starttls && policy != TlsPolicy::Never {
+                send.scribble_bytes(b"STARTTLS\r\n");
+                self.state = State::StartTls;
+                return Action::Read
+            }
+            if policy == TlsPolicy::Required {
+                return self.fail(Error::TlsRequired, send, true)
+            }
+        }
+        self.next_mail(send)
+    }
+
+    /// Starts the next mail transaction or ends the session.
+    fn next_mail(&mut self, send: &mut SendBuf) -> Action {
+        loop {
+            let mail = match self.handler.next_mail() {
+                Some(mail) => mail,
+                None => {
+                    send.scribble_bytes(b"QUIT\r\n");
+                    self.state = State::Quit;
+                    return Action::Read
+                }
+            };
+            let mut report = Report::new(mail.recipients.len());
+            if mail.recipients.is_empty() {
+                self.handler.done(mail, report);
+                continue
+            }
+            if let Some(size) = mail.size {
+                if self.extensions.exceeds_size(size) {
+                    report.mail = Some(Reply::new(552, Some((5,3,4)),
+                                                  b"Message too big for \
+                                                    server".to_vec()));
+                    self.handler.done(mail, report);
+                    continue
+                }
+            }
+            // We don’t downgrade 8bit messages to 7bit, so they can only
+            // go to servers that take 8BITMIME (RFC 6152, section 3).
+            if mail.eightbitmime && !self.extensions.eightbitmime {
+                report.mail = Some(Reply::new(554, Some((5,6,3)),
+                                              b"Server does not accept \
+                                                8bit messages".to_vec()));
+                self.handler.done(mail, report);
+                continue
+            }
+            let mut trans = Transaction { mail: mail, report: report,
+                                          pending: VecDeque::new(),
+                                          next_rcpt: 0 };
+            self.mail(&mut trans, send);
+            if self.extensions.pipelining {
+                while trans.next_rcpt < trans.mail.recipients.len() {
+                    self.rcpt(&mut trans, send);
+                }
+                send.scribble_bytes(b"DATA\r\n");
+                trans.pending.push_back(Pending::Data);
+            }
+            self.state = State::Transaction(trans);
+            return Action::Read
+        }
+    }
+
+    /// Sends the MAIL command for a transaction.
+    fn mail(&self, trans: &mut Transaction, send: &mut SendBuf) {
+        let mail = &trans.mail;
+        send.scribble_bytes(b"MAIL FROM:<");
+        send.scribble_bytes(&mail.reverse_path);
+        send.scribble_octet(b'>');
+        if let Some(size) = mail.size {
+            if self.extensions.size.is_some() {
+                send.scribble_bytes(b" SIZE=");
+                send.scribble_u64(size);
+            }
+        }
+        if mail.eightbitmime && self.extensions.eightbitmime {
+            send.scribble_bytes(b" BODY=8BITMIME");
+        }
+        if self.extensions.dsn {
+            match mail.ret {
+                Some(RetValue::Full) => send.scribble_bytes(b" RET=FULL"),
+                Some(RetValue::Hdrs) => send.scribble_bytes(b" RET=HDRS"),
+                None => { }
+            }
+            if let Some(ref envid) = mail.envid {
+                send.scribble_bytes(b" ENVID=");
+                send.scribble_bytes(envid);
+            }
+        }
+        send.scribble_bytes(b"\r\n");
+        trans.pending.push_back(Pending::Mail);
+    }
+
+    /// Sends the RCPT command for the next recipient of a transaction.
+    fn rcpt(&self, trans: &mut Transaction, send: &mut SendBuf) {
+        let idx = trans.next_rcpt;
+        {
+            let rcpt = &trans.mail.recipients[idx];
+            send.scribble_bytes(b"RCPT TO:<");
+            send.scribble_bytes(&rcpt.path);
+            send.scribble_octet(b'>');
+            if self.extensions.dsn {
+                if let Some(ref notify) = rcpt.notify {
+                    send.scribble_bytes(b" NOTIFY=");
+                    let values = [(notify.success, &b"SUCCESS"[..]),
+                                  (notify.failure, &b"FAILURE"[..]),
+                                  (notify.delay, &b"DELAY"[..])];
+                    let mut first = true;
+                    for &(set, value) in &values {
+                        if set {
+                            if !first { send.scribble_octet(b',') }
+                            send.scribble_bytes(value);
+                            first = false;
+                        }
+                    }
+                    if first {
+                        send.scribble_bytes(b"NEVER");
+                    }
+                }
+                if let Some(ref orcpt) = rcpt.orcpt {
+                    send.scribble_bytes(b" ORCPT=");
+                    send.scribble_bytes(orcpt);
+                }
+            }
+            send.scribble_bytes(b"\r\n");
+        }
+        trans.next_rcpt += 1;
+        trans.pending.push_back(Pending::Rcpt(idx));
+    }
+
+    /// Reports a finished transaction and moves on.
+    ///
+    /// If *rset* is true, the server still considers the transaction open
+    /// and we need to reset it first.
+    fn end_transaction(&mut self, trans: Transaction, rset: bool,
+                       send: &mut SendBuf) -> Action {
+        self.handler.done(trans.mail, trans.report);
+        if rset {
+            send.scribble_bytes(b"RSET\r\n");
+            self.state = State::Rset;
+            Action::Read
+        }
+        else {
+            self.next_mail(send)
+        }
+    }
+
+    /// Gives up.
+    ///
+    /// Any mail in flight is reported as it is. If *quit* is true, the
+    /// connection is still in a state where we can politely say goodbye
+    /// before closing it.
+    fn fail(&mut self, error: Error, send: &mut SendBuf, quit: bool)
+            -> Action {
+        match mem::replace(&mut self.state, State::Closed) {
+            State::Transaction(trans) | State::Data(trans, _)
+                    | State::DataEnd(trans) => {
+                self.handler.done(trans.mail, trans.report)
+            }
+            _ => { }
+        }
+        if !self.failed {
+            self.failed = true;
+            self.handler.failed(error);
+        }
+        if quit {
+            send.scribble_bytes(b"QUIT\r\n");
+        }
+        Action::Close
+    }
+}
+
+
+//------------ State ---------------------------------------------------------
+
+enum State {
+    /// Waiting for the greeting.
+    Greeting,
+
+    /// Waiting for the reply to EHLO.
+    Ehlo,
+
+    /// Waiting for the reply to HELO.
+    Helo,
+
+    /// Waiting for the reply to STARTTLS or for the handshake.
+    StartTls,
+
+    /// Waiting for replies to the commands of a transaction.
+    Transaction(Transaction),
+
+    /// Sending the message.
+    Data(Transaction, Stuffer),
+
+    /// Waiting for the reply to the message.
+    DataEnd(Transaction),
+
+    /// Waiting for the reply to RSET.
+    Rset,
+
+    /// Waiting for the reply to QUIT.
+    Quit,
+
+    /// All done.
+    Closed,
+}
+
+
+//------------ Transaction ---------------------------------------------------
+
+struct Transaction {
+    mail: Mail,
+    report: Report,
+
+    /// The commands we are still waiting for replies to.
+    pending: VecDeque<Pending>,
+
+    /// The index of the next recipient to send RCPT for.
+    next_rcpt: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Pending {
+    Mail,
+    Rcpt(usize),
+    Data,
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use openssl::ssl::{SslContext, SslMethod};
+    use ::smtp::client::config::{Config, TlsPolicy};
+    use ::smtp::client::protocol::{Error, Handler, Mail, Outcome, Recipient,
+                                   Report};
+    use ::smtp::server::buf::{RecvBuf, SendBuf};
+    use super::*;
+
+    /// A handler sending a list of mails and keeping what happened.
+    struct Mails {
+        mails: Vec<Mail>,
+        reports: Vec<Report>,
+        error: Option<Error>,
+    }
+
+    impl Handler for Mails {
+        fn next_mail(&mut self) -> Option<Mail> {
+            if self.mails.is_empty() { None }
+            else { Some(self.mails.remove(0)) }
+        }
+
+        fn done(&mut self, _mail: Mail, report: Report) {
+            self.reports.push(report)
+        }
+
+        fn failed(&mut self, error: Error) {
+            self.error = Some(error)
+        }
+    }
+
+    fn mail(recipients: &[&[u8]]) -> Mail {
+        Mail::new(b"a@client.test".to_vec(),
+                  recipients.iter().map(|path| {
+                      Recipient::new(path.to_vec())
+                  }).collect(),
+                  Box::new(Cursor::new(b"Hello\r\n.Dot\r\n".to_vec())))
+    }
+
+    /// A scripted server talking to a client session.
+    struct Script {
+        session: Option<Session<Mails>>,
+        action: Action,
+        recv: RecvBuf,
+        send: SendBuf,
+    }
+
+    impl Script {
+        fn new(mails: Vec<Mail>, policy: TlsPolicy) -> Self {
+            let context = SslContext::new(SslMethod::Tlsv1).unwrap();
+            let mut config = Config::new(context, b"client.test".to_vec());
+            config.set_tls_policy(policy);
+            let handler = Mails { mails: mails, reports: Vec::new(),
+                                  error: None };
+            let (session, action) = Session::new(handler, Rc::new(config));
+            Script { session: Some(session), action: action,
+                     recv: RecvBuf::new(), send: SendBuf::new() }
+        }
+
+        /// The server sends *reply*.
+        ///
+        /// If the client wants to feed message data, it gets to do so.
+        fn replies(&mut self, reply: &[u8]) -> &mut Self {
+            assert_eq!(self.action, Action::Read);
+            self.recv.push(reply);
+            let session = self.session.take().unwrap();
+            let (mut session, mut action) = session.recv(&mut self.recv,
+                                                         &mut self.send);
+            while action == Action::Feed {
+                let (next, next_action) = session.feed(&mut self.send);
+                session = next;
+                action = next_action;
+            }
+            self.session = Some(session);
+            self.action = action;
+            self
+        }
+
+        /// The client has sent exactly *data* since last time.
+        fn expects(&mut self, data: &[u8]) -> &mut Self {
+            assert_eq!(String::from_utf8_lossy(self.send.as_slice()),
+                       String::from_utf8_lossy(data));
+            let len = self.send.len();
+            self.send.advance(len);
+            self
+        }
+
+        /// The client wants to do *action* next.
+        fn then(&mut self, action: Action) -> &mut Self {
+            assert_eq!(self.action, action);
+            self
+        }
+
+        /// The TLS handshake has finished.
+        fn tls_ready(&mut self) -> &mut Self {
+            assert_eq!(self.action, Action::StartTls);
+            let session = self.session.take().unwrap();
+            let (session, action) = session.tls_ready(&mut self.send);
+            self.session = Some(session);
+            self.action = action;
+            self
+        }
+
+        fn handler(&self) -> &Mails {
+            self.session.as_ref().unwrap().handler()
+        }
+    }
+
+    #[test]
+    fn pipelining() {
+        let mut script = Script::new(vec![mail(&[b"b@mx.test",
+                                                 b"c@mx.test"])],
+                                     TlsPolicy::Opportunistic);
+        script.replies(b"220 mx.test ESMTP\r\n")
+              .expects(b"EHLO client.test\r\n")
+              .replies(b"250-mx.test\r\n250-PIPELINING\r\n250 SIZE\r\n")
+              .expects(b"MAIL FROM:<a@client.test>\r\n\
+                         RCPT TO:<b@mx.test>\r\n\
+                         RCPT TO:<c@mx.test>\r\n\
+                         DATA\r\n")
+              .replies(b"250 Ok\r\n550 No such user\r\n")
+              .expects(b"")
+              .replies(b"250 Ok\r\n354 Go ahead\r\n")
+              .expects(b"Hello\r\n..Dot\r\n.\r\n")
+              .replies(b"250 Queued\r\n")
+              .expects(b"QUIT\r\n")
+              .replies(b"221 Bye\r\n")
+              .then(Action::Close);
+        let handler = script.handler();
+        assert!(handler.error.is_none());
+        assert_eq!(handler.reports.len(), 1);
+        let report = &handler.reports[0];
+        match report.outcome(0) {
+            Outcome::Failed(reply) => assert_eq!(reply.code, 550),
+            outcome => panic!("unexpected outcome {:?}", outcome)
+        }
+        match report.outcome(1) {
+            Outcome::Delivered(reply) => assert_eq!(reply.code, 250),
+            outcome => panic!("unexpected outcome {:?}", outcome)
+        }
+    }
+
+    #[test]
+    fn helo_fallback() {
+        let mut script = Script::new(vec![mail(&[b"b@mx.test"])],
+                                     TlsPolicy::Opportunistic);
+        script.replies(b"220 mx.test\r\n")
+              .expects(b"EHLO client.test\r\n")
+              .replies(b"502 Command not implemented\r\n")
+              .expects(b"HELO client.test\r\n")
+              .replies(b"250 mx.test\r\n")
+              .expects(b"MAIL FROM:<a@client.test>\r\n")
+              .replies(b"250 Ok\r\n")
+              .expects(b"RCPT TO:<b@mx.test>\r\n")
+              .replies(b"250 Ok\r\n")
+              .expects(b"DATA\r\n")
+              .replies(b"354 Go ahead\r\n")
+              .expects(b"Hello\r\n..Dot\r\n.\r\n")
+              .replies(b"250 Queued\r\n")
+              .expects(b"QUIT\r\n");
+        assert!(!script.session.as_ref().unwrap().extensions().pipelining);
+        match script.handler().reports[0].outcome(0) {
+            Outcome::Delivered(reply) => assert_eq!(reply.code, 250),
+            outcome => panic!("unexpected outcome {:?}", outcome)
+        }
+    }
+
+    #[test]
+    fn starttls() {
+        let mut script = Script::new(Vec::new(), TlsPolicy::Opportunistic);
+        script.replies(b"220 mx.test\r\n")
+              .expects(b"EHLO client.test\r\n")
+              .replies(b"250-mx.test\r\n250 STARTTLS\r\n")
+              .expects(b"STARTTLS\r\n")
+              .replies(b"220 Go ahead\r\n")
+              .then(Action::StartTls)
+              .tls_ready()
+              .expects(b"EHLO client.test\r\n")
+              // The server still says STARTTLS, but we are done with it.
+              .replies(b"250-mx.test\r\n250 STARTTLS\r\n")
+              .expects(b"QUIT\r\n");
+        assert!(script.session.as_ref().unwrap().is_secure());
+
+        // Without TLS, going on is fine unless it is required.
+        let mut script = Script::new(Vec::new(), TlsPolicy::Opportunistic);
+        script.replies(b"220 mx.test\r\n")
+              .expects(b"EHLO client.test\r\n")
+              .replies(b"250-mx.test\r\n250 STARTTLS\r\n")
+              .expects(b"STARTTLS\r\n")
+              .replies(b"454 TLS not available\r\n")
+              .expects(b"QUIT\r\n");
+        let mut script = Script::new(Vec::new(), TlsPolicy::Required);
+        script.replies(b"220 mx.test\r\n")
+              .expects(b"EHLO client.test\r\n")
+              .replies(b"250 mx.test\r\n")
+              .expects(b"QUIT\r\n")
+              .then(Action::Close);
+        match script.handler().error {
+            Some(Error::TlsRequired) => { }
+            ref error => panic!("unexpected error {:?}", error)
+        }
+    }
+
+    #[test]
+    fn eightbitmime() {
+        let mut eightbit = mail(&[b"b@mx.test"]);
+        eightbit.eightbitmime = true;
+        let mut script = Script::new(vec![eightbit], TlsPolicy::Never);
+        script.replies(b"220 mx.test\r\n")
+              .expects(b"EHLO client.test\r\n")
+              .replies(b"250-mx.test\r\n250 8BITMIME\r\n")
+              .expects(b"MAIL FROM:<a@client.test> BODY=8BITMIME\r\n");
+
+        let mut eightbit = mail(&[b"b@mx.test", b"c@mx.test"]);
+        eightbit.eightbitmime = true;
+        let mut script = Script::new(vec![eightbit, mail(&[b"d@mx.test"])],
+                                     TlsPolicy::Never);
+        script.replies(b"220 mx.test\r\n")
+              .expects(b"EHLO client.test\r\n")
+              .replies(b"250-mx.test\r\n250 PIPELINING\r\n")
+              // The 8bit mail is skipped.
+              .expects(b"MAIL FROM:<a@client.test>\r\n\
+                         RCPT TO:<d@mx.test>\r\n\
+                         DATA\r\n");
+        let handler = script.handler();
+        assert_eq!(handler.reports.len(), 1);
+        for idx in 0..2 {
+            match handler.reports[0].outcome(idx) {
+                Outcome::Failed(reply) => {
+                    assert_eq!(reply.code, 554);
+                    assert_eq!(reply.status, Some((5,6,3)));
+                }
+                outcome => panic!("unexpected outcome {:?}", outcome)
+            }
+        }
+    }
+
+    #[test]
+    fn rset() {
+        let mut script = Script::new(vec![mail(&[b"x@mx.test"]),
+                                          mail(&[b"b@mx.test"])],
+                                     TlsPolicy::Never);
+        script.replies(b"220 mx.test\r\n")
+              .expects(b"EHLO client.test\r\n")
+              .replies(b"250-mx.test\r\n250 STARTTLS\r\n")
+              .expects(b"MAIL FROM:<a@client.test>\r\n")
+              .replies(b"250 Ok\r\n")
+              .expects(b"RCPT TO:<x@mx.test>\r\n")
+              .replies(b"550 No such user\r\n")
+              // The transaction is still open.
+              .expects(b"RSET\r\n")
+              .replies(b"250 Ok\r\n")
+              .expects(b"MAIL FROM:<a@client.test>\r\n")
+              .replies(b"451 Try again later\r\n")
+              // The transaction never started, so no RSET.
+              .expects(b"QUIT\r\n");
+        let handler = script.handler();
+        assert_eq!(handler.reports.len(), 2);
+        match handler.reports[0].outcome(0) {
+            Outcome::Failed(reply) => assert_eq!(reply.code, 550),
+            outcome => panic!("unexpected outcome {:?}", outcome)
+        }
+        match handler.reports[1].outcome(0) {
+            Outcome::Deferred(Some(reply)) => assert_eq!(reply.code, 451),
+            outcome => panic!("unexpected outcome {:?}", outcome)
+        }
+    }
+}
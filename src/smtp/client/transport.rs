@@ -0,0 +1,178 @@
+//! Netmachines handlers.
+
+use std::rc::Rc;
+use netmachines::{Next, TransportHandler};
+use netmachines::sockets::HybridStream;
+use rotor::Notifier;
+use ::smtp::server::buf::{RecvBuf, SendBuf};
+use super::config::Config;
+use super::protocol::{Error, Handler};
+use super::session::{Action, Session};
+
+
+//------------ Transport -----------------------------------------------------
+
+/// The transport handler for an outgoing SMTP connection.
+///
+/// Its seed is the handler for the session and the configuration.
+///
+pub struct Transport<H: Handler> {
+    session: Session<H>,
+    plot: Plot,
+    recv: RecvBuf,
+    send: SendBuf,
+}
+
+impl<H: Handler> Transport<H> {
+    fn new(session: Session<H>, plot: Plot) -> Self {
+        Transport { session: session, plot: plot, recv: RecvBuf::new(),
+                    send: SendBuf::new() }
+    }
+
+    fn next(self) -> Next<Self> {
+        let timeout = self.session.config().timeout();
+        match self.plot {
+            Plot::Read => Next::read(self).timeout(timeout),
+            Plot::Write(_) => Next::write(self).timeout(timeout),
+        }
+    }
+
+    fn next_action<T: HybridStream>(mut self, action: Action, sock: &mut T)
+                                    -> Next<Self> {
+        self.plot = Plot::from(action);
+        match self.plot {
+            Plot::Read => {
+                if self.send.is_empty() { self.next() }
+                else { self.next_plot(Plot::Write(AndThen::Read)) }
+            }
+            Plot::Write(then) => {
+                if self.send.is_empty() { self.and_then(then, sock) }
+                else { self.next() }
+            }
+        }
+    }
+
+    fn next_plot(mut self, plot: Plot) -> Next<Self> {
+        self.plot = plot;
+        self.next()
+    }
+
+    /// Determines what happens after all data has been written.
+    fn and_then<T: HybridStream>(mut self, then: AndThen, sock: &mut T)
+                                 -> Next<Self> {
+        match then {
+            AndThen::Read => {
+                if self.recv.is_empty() { self.next_plot(Plot::Read) }
+                else { self.recv(sock) }
+            }
+            AndThen::Feed => {
+                let (session, action) = self.session.feed(&mut self.send);
+                self.session = session;
+                self.next_action(action, sock)
+            }
+            AndThen::StartTls => {
+                self.recv = RecvBuf::new();
+                self.send = SendBuf::new();
+                if let Err(err) = sock.connect_secure() {
+                    error!("SMTP client: TLS handshake failed: {:?}", err);
+                    self.session.abort(Error::Tls);
+                    Next::remove()
+                }
+                else {
+                    let (session, action) = self.session
+                                                .tls_ready(&mut self.send);
+                    self.session = session;
+                    self.next_action(action, sock)
+                }
+            }
+            AndThen::Close => Next::remove()
+        }
+    }
+
+    fn recv<T: HybridStream>(mut self, sock: &mut T) -> Next<Self> {
+        let (session, action) = self.session.recv(&mut self.recv,
+                                                  &mut self.send);
+        self.session = session;
+        self.next_action(action, sock)
+    }
+}
+
+
+impl<T: HybridStream, H: Handler> TransportHandler<T> for Transport<H> {
+    type Seed = (H, Rc<Config>);
+
+    fn create(seed: Self::Seed, _sock: &mut T, _notifier: Notifier)
+              -> Next<Self> {
+        let (handler, config) = seed;
+        let (session, action) = Session::new(handler, config);
+        Transport::new(session, Plot::from(action)).next()
+    }
+
+    fn readable(mut self, sock: &mut T) -> Next<Self> {
+        match self.recv.try_read(sock) {
+            Ok(Some(0)) => {
+                self.session.abort(Error::Closed);
+                Next::remove()
+            }
+            Err(err) => {
+                self.session.abort(Error::Io(err));
+                Next::remove()
+            }
+            Ok(None) => self.next(),
+            Ok(Some(_)) => self.recv(sock)
+        }
+    }
+
+    fn writable(mut self, sock: &mut T) -> Next<Self> {
+        match self.send.try_write(sock) {
+            Err(err) => {
+                self.session.abort(Error::Io(err));
+                Next::remove()
+            }
+            Ok(false) => self.next(),
+            Ok(true) => {
+                match self.plot {
+                    Plot::Read => self.next(),
+                    Plot::Write(then) => self.and_then(then, sock)
+                }
+            }
+        }
+    }
+
+    fn wakeup(self, _sock: &mut T) -> Next<Self> {
+        self.next()
+    }
+
+    fn timeout(self, _sock: &mut T) -> Next<Self> {
+        self.session.abort(Error::Timeout);
+        Next::remove()
+    }
+}
+
+
+//------------ Plot ----------------------------------------------------------
+
+#[derive(Clone, Copy, Debug)]
+enum Plot {
+    Read,
+    Write(AndThen)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum AndThen {
+    Read,
+    Feed,
+    StartTls,
+    Close
+}
+
+impl Plot {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Read => Plot::Read,
+            Action::Feed => Plot::Write(AndThen::Feed),
+            Action::StartTls => Plot::Write(AndThen::StartTls),
+            Action::Close => Plot::Write(AndThen::Close)
+        }
+    }
+}
@@ -0,0 +1,742 @@
+//! DomainKeys Identified Mail.
+//!
+//! DKIM, defined in RFC 6376, lets a domain take responsibility for a
+//! message by signing some of its header fields and its body. The
+//! signature is placed into a DKIM-Signature header field while the key
+//! needed to check it is published in the DNS.
+//!
+//! The `verify` module checks the signatures of a message. It works on
+//! the message as it streams past, so only the header is ever kept in
+//! memory. The body is canonicalized and hashed on the fly. The `sign`
+//! module works the same way when adding a signature to a message using
+//! the key configured for the domain of its author.
+//!
+//! The only algorithm that can actually be checked is rsa-sha256.
+//! Signatures using ed25519-sha256 from RFC 8463 are recognized but the
+//! OpenSSL bindings we use know nothing of Ed25519, so they end up as a
+//! permanent error. The rsa-sha1 algorithm is not accepted anymore as
+//! required by RFC 8301.
+
+use std::ascii::AsciiExt;
+use std::mem;
+use openssl::crypto::hash::{Hasher, Type};
+use ::smtp::syntax::test_atext;
+use ::util::text::trim_fws;
+
+pub mod sign;
+pub mod verify;
+
+
+//------------ Algorithm -----------------------------------------------------
+
+/// A signing algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+impl Algorithm {
+    pub fn from_bytes(value: &[u8]) -> Option<Self> {
+        if value.eq_ignore_ascii_case(b"rsa-sha256") {
+            Some(Algorithm::RsaSha256)
+        }
+        else if value.eq_ignore_ascii_case(b"ed25519-sha256") {
+            Some(Algorithm::Ed25519Sha256)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Algorithm::RsaSha256 => "rsa-sha256",
+            Algorithm::Ed25519Sha256 => "ed25519-sha256",
+        }
+    }
+
+    /// Returns the key type for the k= tag of a key record.
+    pub fn key_type(self) -> &'static [u8] {
+        match self {
+            Algorithm::RsaSha256 => b"rsa",
+            Algorithm::Ed25519Sha256 => b"ed25519",
+        }
+    }
+}
+
+
+//------------ Canon ---------------------------------------------------------
+
+/// A canonicalization algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Canon {
+    Simple,
+    Relaxed,
+}
+
+impl Canon {
+    pub fn from_bytes(value: &[u8]) -> Option<Self> {
+        if value.eq_ignore_ascii_case(b"simple") {
+            Some(Canon::Simple)
+        }
+        else if value.eq_ignore_ascii_case(b"relaxed") {
+            Some(Canon::Relaxed)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Parses the value of the c= tag into header and body canonicalization.
+    pub fn parse_pair(value: &[u8]) -> Option<(Self, Self)> {
+        let mut parts = value.splitn(2, |ch| *ch == b'/');
+        let header = match parts.next().and_then(Canon::from_bytes) {
+            Some(header) => header,
+            None => return None
+        };
+        match parts.next() {
+            Some(body) => Canon::from_bytes(body).map(|body| (header, body)),
+            None => Some((header, Canon::Simple))
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Canon::Simple => "simple",
+            Canon::Relaxed => "relaxed",
+        }
+    }
+}
+
+
+//------------ HeaderBuf -----------------------------------------------------
+
+/// Collects the header of a message streaming past.
+#[derive(Clone, Debug, Default)]
+struct HeaderBuf {
+    /// The header including the empty line ending it.
+    header: Vec<u8>,
+
+    /// Has the empty line been seen?
+    complete: bool,
+}
+
+impl HeaderBuf {
+    /// Takes whatever belongs to the header from *data*.
+    ///
+    /// Returns the part of *data* that belongs to the body.
+    fn push<'a>(&mut self, data: &'a [u8]) -> &'a [u8] {
+        if self.complete {
+            return data
+        }
+        for (i, &ch) in data.iter().enumerate() {
+            self.header.push(ch);
+            if ch == b'\n' && (self.header.ends_with(b"\r\n\r\n")
+                               || self.header == b"\r\n") {
+                self.complete = true;
+                return &data[i + 1..]
+            }
+        }
+        b""
+    }
+
+    /// Returns the header fields.
+    fn fields(&self) -> Vec<&[u8]> {
+        header_fields(&self.header)
+    }
+}
+
+
+//------------ BodyHasher ----------------------------------------------------
+
+/// Canonicalizes and hashes a body streaming past.
+struct BodyHasher {
+    canon: Canon,
+    hasher: Hasher,
+
+    /// The number of octets that may still be hashed if there is a limit.
+    remaining: Option<u64>,
+
+    /// The number of canonicalized octets so far.
+    len: u64,
+
+    /// The current line without its line break.
+    line: Vec<u8>,
+
+    /// The number of empty lines not yet hashed.
+    ///
+    /// Empty lines at the end of the body are ignored, so they are only
+    /// hashed once a line with content follows.
+    empty: u64,
+}
+
+impl BodyHasher {
+    /// Creates a new hasher hashing at most *limit* octets.
+    fn new(canon: Canon, limit: Option<u64>) -> Self {
+        BodyHasher { canon: canon, hasher: Hasher::new(Type::SHA256),
+                     remaining: limit, len: 0, line: Vec::new(), empty: 0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        let mut lines = data.split(|ch| *ch == b'\n');
+        let mut part = lines.next().unwrap_or(b"");
+        for next in lines {
+            self.line.extend_from_slice(part);
+            self.end_line();
+            part = next;
+        }
+        self.line.extend_from_slice(part);
+    }
+
+    /// Finishes hashing.
+    ///
+    /// Returns the hash and the length of the complete canonicalized
+    /// body, which may be more than what was hashed.
+    fn finish(mut self) -> (Vec<u8>, u64) {
+        if !self.line.is_empty() {
+            self.end_line()
+        }
+        if self.len == 0 && self.canon == Canon::Simple {
+            self.write(b"\r\n")
+        }
+        (self.hasher.finish(), self.len)
+    }
+
+    fn end_line(&mut self) {
+        if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+        }
+        let mut line = mem::replace(&mut self.line, Vec::new());
+        if self.canon == Canon::Relaxed {
+            line = relax(&line);
+        }
+        if line.is_empty() {
+            self.empty += 1;
+        }
+        else {
+            while self.empty > 0 {
+                self.write(b"\r\n");
+                self.empty -= 1;
+            }
+            self.write(&line);
+            self.write(b"\r\n");
+        }
+        line.clear();
+        self.line = line;
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.len += data.len() as u64;
+        let data = match self.remaining {
+            Some(ref mut remaining) => {
+                let len = ::std::cmp::min(*remaining, data.len() as u64);
+                *remaining -= len;
+                &data[..len as usize]
+            }
+            None => data
+        };
+        self.hasher.update(data);
+    }
+}
+
+
+//------------ Tag -----------------------------------------------------------
+
+/// A tag of a tag list as used by signatures and key records.
+#[derive(Clone, Debug)]
+struct Tag<'a> {
+    name: &'a [u8],
+
+    /// The value without surrounding white space.
+    value: &'a [u8],
+
+    /// The start and end of the value including surrounding white space.
+    ///
+    /// The positions are relative to the start of the tag list.
+    span: (usize, usize),
+}
+
+/// Parses a tag list.
+///
+/// Returns `None` if the list is malformed or a tag appears twice.
+fn tag_list(list: &[u8]) -> Option<Vec<Tag>> {
+    let mut res: Vec<Tag> = Vec::new();
+    let mut start = 0;
+    for spec in list.split(|ch| *ch == b';') {
+        let end = start + spec.len();
+        if trim_fws(spec).is_empty() && end == list.len() {
+            break
+        }
+        let eq = match spec.iter().position(|ch| *ch == b'=') {
+            Some(eq) => eq,
+            None => return None
+        };
+        let name = trim_fws(&spec[..eq]);
+        if !is_tag_name(name) || res.iter().any(|tag| tag.name == name) {
+            return None
+        }
+        res.push(Tag { name: name, value: trim_fws(&spec[eq + 1..]),
+                       span: (start + eq + 1, end) });
+        start = end + 1;
+    }
+    Some(res)
+}
+
+/// Returns the value of the tag *name* if it is present.
+fn tag_value<'a>(tags: &[Tag<'a>], name: &[u8]) -> Option<&'a [u8]> {
+    tags.iter().find(|tag| tag.name == name).map(|tag| tag.value)
+}
+
+fn is_tag_name(name: &[u8]) -> bool {
+    match name.split_first() {
+        Some((&first, rest)) => {
+            is_alpha(first)
+                && rest.iter().all(|&ch| is_alpha(ch) || is_digit(ch)
+                                         || ch == b'_')
+        }
+        None => false
+    }
+}
+
+fn is_alpha(ch: u8) -> bool {
+    (ch >= b'A' && ch <= b'Z') || (ch >= b'a' && ch <= b'z')
+}
+
+fn is_digit(ch: u8) -> bool {
+    ch >= b'0' && ch <= b'9'
+}
+
+
+//------------ Header Fields -------------------------------------------------
+
+/// Splits a header into its fields.
+///
+/// Each field includes its final line break. Parsing stops at the empty
+/// line ending the header.
+fn header_fields(header: &[u8]) -> Vec<&[u8]> {
+    let mut res = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while pos < header.len() {
+        if header[pos] != b'\n' {
+            pos += 1;
+            continue
+        }
+        pos += 1;
+        match header.get(pos) {
+            Some(&b' ') | Some(&b'\t') => continue,
+            _ => { }
+        }
+        let field = &header[start..pos];
+        if field == b"\r\n" {
+            break
+        }
+        res.push(field);
+        start = pos;
+    }
+    res
+}
+
+/// Returns the name of a header field.
+fn field_name(field: &[u8]) -> &[u8] {
+    match field.iter().position(|ch| *ch == b':') {
+        Some(colon) => trim_fws(&field[..colon]),
+        None => b""
+    }
+}
+
+/// Returns the domain of the author’s address in the From field.
+///
+/// The header must have exactly one From field with exactly one mailbox.
+/// Otherwise, it isn’t clear who the author is and there is no domain as
+/// RFC 7489, section 6.6.1 suggests. This includes group syntax and
+/// addresses with a domain literal.
+fn author_domain(fields: &[&[u8]]) -> Option<Vec<u8>> {
+    let mut from = fields.iter().filter(|field| {
+        field_name(field).eq_ignore_ascii_case(b"From")
+    });
+    let field = match (from.next(), from.next()) {
+        (Some(field), None) => *field,
+        _ => return None
+    };
+    match field.iter().position(|ch| *ch == b':') {
+        Some(colon) => Mailbox::new(&field[colon + 1..]).domain(),
+        None => None
+    }
+}
+
+/// A parser for the mailbox in a From field.
+///
+/// This follows RFC 5322, section 3.4, including the obsolete syntax
+/// that allows comments and white space between the parts of an
+/// address.
+struct Mailbox<'a> {
+    value: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Mailbox<'a> {
+    fn new(value: &'a [u8]) -> Self {
+        Mailbox { value: value, pos: 0 }
+    }
+
+    /// Returns the domain of the only mailbox in the value.
+    fn domain(mut self) -> Option<Vec<u8>> {
+        let domain = if self.local_part() && self.peek() == Some(b'@') {
+            self.pos += 1;
+            self.domain_part()
+        }
+        else {
+            // Not a bare address, so a display name may come first.
+            self.pos = 0;
+            if !self.phrase() {
+                self.pos = 0
+            }
+            if !self.cfws() || self.next() != Some(b'<')
+                    || !self.local_part() || self.next() != Some(b'@') {
+                return None
+            }
+            let domain = self.domain_part();
+            if self.next() != Some(b'>') {
+                return None
+            }
+            domain
+        };
+        if !self.cfws() || self.pos < self.value.len() {
+            return None
+        }
+        domain.map(|domain| domain.to_ascii_lowercase())
+    }
+
+    /// Skips a display name.
+    ///
+    /// This is a sequence of words. The obsolete syntax allows dots
+    /// between them, too.
+    fn phrase(&mut self) -> bool {
+        if !self.word() {
+            return false
+        }
+        loop {
+            if self.peek() == Some(b'.') {
+                self.pos += 1;
+                continue
+            }
+            let start = self.pos;
+            if !self.word() {
+                self.pos = start;
+                return true
+            }
+        }
+    }
+
+    /// Skips a local part, words separated by dots.
+    fn local_part(&mut self) -> bool {
+        loop {
+            if !self.word() {
+                return false
+            }
+            if self.peek() != Some(b'.') {
+                return true
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// Skips an atom or a quoted string with the white space around it.
+    fn word(&mut self) -> bool {
+        if !self.cfws() {
+            return false
+        }
+        let res = match self.peek() {
+            Some(b'"') => self.quoted(b'"'),
+            _ => self.skip_atoms()
+        };
+        res && self.cfws()
+    }
+
+    /// Returns the domain after the at sign.
+    ///
+    /// Domain literals have no use for DMARC and result in `None`.
+    fn domain_part(&mut self) -> Option<Vec<u8>> {
+        let mut res = Vec::new();
+        loop {
+            if !self.cfws() {
+                return None
+            }
+            let start = self.pos;
+            if !self.skip_atoms() {
+                return None
+            }
+            res.extend_from_slice(&self.value[start..self.pos]);
+            if !self.cfws() {
+                return None
+            }
+            if self.peek() != Some(b'.') {
+                return Some(res)
+            }
+            self.pos += 1;
+            res.push(b'.');
+        }
+    }
+
+    /// Skips atom characters.
+    ///
+    /// Returns whether there were any.
+    fn skip_atoms(&mut self) -> bool {
+        let start = self.pos;
+        while let Some(ch) = self.peek() {
+            if test_atext(ch).is_err() {
+                break
+            }
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    /// Skips comments and folding white space.
+    ///
+    /// Returns `false` if a comment isn’t closed.
+    fn cfws(&mut self) -> bool {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') => {
+                    self.pos += 1
+                }
+                Some(b'(') => {
+                    if !self.quoted(b')') {
+                        return false
+                    }
+                }
+                _ => return true
+            }
+        }
+    }
+
+    /// Skips a quoted string or a comment ending in *close*.
+    ///
+    /// Comments may be nested. Returns `false` if the end is missing.
+    fn quoted(&mut self, close: u8) -> bool {
+        let mut depth = 0;
+        self.pos += 1;
+        while let Some(ch) = self.next() {
+            match ch {
+                b'\\' => { self.next(); }
+                b'(' if close == b')' => depth += 1,
+                _ if ch == close => {
+                    if depth == 0 {
+                        return true
+                    }
+                    depth -= 1;
+                }
+                _ => { }
+            }
+        }
+        false
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.value.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let res = self.peek();
+        if res.is_some() {
+            self.pos += 1
+        }
+        res
+    }
+}
+
+/// Selects the header fields to sign for *names*.
+///
+/// If a name appears more than once, the instances of the field are used
+/// from the bottom up. Names without a matching field are skipped.
+fn select_fields<'a, N>(fields: &[&'a [u8]], names: &[N]) -> Vec<&'a [u8]>
+                 where N: AsRef<[u8]> {
+    let mut used = vec![false; fields.len()];
+    let mut res = Vec::new();
+    for name in names {
+        let name = name.as_ref();
+        let found = fields.iter().enumerate().rev().find(|&(i, field)| {
+            !used[i] && field_name(field).eq_ignore_ascii_case(name)
+        }).map(|(i, _)| i);
+        if let Some(i) = found {
+            used[i] = true;
+            res.push(fields[i]);
+        }
+    }
+    res
+}
+
+/// Appends the canonicalized form of *field* to *target*.
+fn canon_field(canon: Canon, field: &[u8], target: &mut Vec<u8>) {
+    let colon = match (canon, field.iter().position(|ch| *ch == b':')) {
+        (Canon::Relaxed, Some(colon)) => colon,
+        _ => return target.extend_from_slice(field)
+    };
+    for ch in trim_fws(&field[..colon]) {
+        target.push(ch.to_ascii_lowercase())
+    }
+    target.push(b':');
+    let value: Vec<u8> = field[colon + 1..].iter().cloned()
+                                           .filter(|ch| *ch != b'\r' &&
+                                                        *ch != b'\n')
+                                           .collect();
+    target.extend_from_slice(&relax(trim_fws(&value)));
+    target.extend_from_slice(b"\r\n");
+}
+
+/// Collapses runs of white space and removes trailing white space.
+fn relax(line: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(line.len());
+    let mut space = false;
+    for &ch in line {
+        if ch == b' ' || ch == b'\t' {
+            space = true;
+        }
+        else {
+            if space {
+                res.push(b' ');
+                space = false;
+            }
+            res.push(ch)
+        }
+    }
+    res
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::{BodyHasher, Canon, HeaderBuf, author_domain, canon_field,
+                header_fields, select_fields, tag_list};
+    use openssl::crypto::hash::{hash, Type};
+
+    fn body_hash(canon: Canon, limit: Option<u64>, chunks: &[&[u8]])
+                 -> (Vec<u8>, u64) {
+        let mut hasher = BodyHasher::new(canon, limit);
+        for chunk in chunks {
+            hasher.update(chunk)
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn body() {
+        // The example from RFC 6376, section 3.4.5, split awkwardly.
+        let chunks: &[&[u8]] = &[b" C \r", b"\nD \t E\r\n\r", b"\n\r\n"];
+        assert_eq!(body_hash(Canon::Simple, None, chunks),
+                   (hash(Type::SHA256, b" C \r\nD \t E\r\n"), 12));
+        assert_eq!(body_hash(Canon::Relaxed, None, chunks),
+                   (hash(Type::SHA256, b" C\r\nD E\r\n"), 9));
+
+        // Empty bodies.
+        assert_eq!(body_hash(Canon::Simple, None, &[b"\r\n\r\n"]).0,
+                   hash(Type::SHA256, b"\r\n"));
+        assert_eq!(body_hash(Canon::Relaxed, None, &[b"\r\n"]).0,
+                   hash(Type::SHA256, b""));
+
+        // A missing final line break is added.
+        assert_eq!(body_hash(Canon::Simple, None, &[b"A\r\n\r\nB"]).0,
+                   hash(Type::SHA256, b"A\r\n\r\nB\r\n"));
+
+        // The l= tag limits what is hashed but not the length.
+        assert_eq!(body_hash(Canon::Simple, Some(3), &[b"AB\r\nC\r\n"]),
+                   (hash(Type::SHA256, b"AB\r"), 7));
+    }
+
+    #[test]
+    fn header() {
+        let mut buf = HeaderBuf::default();
+        assert_eq!(buf.push(b"A: 1\r\nSubject: a\r\n\tb\r"), &b""[..]);
+        assert_eq!(buf.push(b"\nA: 2\r\n\r\nBody\r\n"), &b"Body\r\n"[..]);
+        assert_eq!(buf.push(b"\r\n\r\n"), &b"\r\n\r\n"[..]);
+        let fields = buf.fields();
+        assert_eq!(fields, [&b"A: 1\r\n"[..], &b"Subject: a\r\n\tb\r\n"[..],
+                            &b"A: 2\r\n"[..]]);
+        assert_eq!(header_fields(b"\r\nA: 1\r\n"), Vec::<&[u8]>::new());
+
+        assert_eq!(select_fields(&fields, &["a", "subject", "A", "a", "b"]),
+                   [&b"A: 2\r\n"[..], &b"Subject: a\r\n\tb\r\n"[..],
+                    &b"A: 1\r\n"[..]]);
+
+        // The example from RFC 6376, section 3.4.5.
+        let mut canon = Vec::new();
+        canon_field(Canon::Relaxed, b"A: X\r\n", &mut canon);
+        canon_field(Canon::Relaxed, b"B : Y\t\r\n\tZ  \r\n", &mut canon);
+        assert_eq!(canon, b"a:X\r\nb:Y Z\r\n");
+        canon.clear();
+        canon_field(Canon::Simple, b"B : Y\t\r\n\tZ  \r\n", &mut canon);
+        assert_eq!(canon, b"B : Y\t\r\n\tZ  \r\n");
+    }
+
+    #[test]
+    fn tags() {
+        let list = b" v=1; a = rsa-sha256 ;\r\n\tb=ab\r\n\t cd;";
+        let tags = tag_list(list).unwrap();
+        let names: Vec<_> = tags.iter().map(|tag| tag.name).collect();
+        assert_eq!(names, [&b"v"[..], &b"a"[..], &b"b"[..]]);
+        assert_eq!(tags[1].value, b"rsa-sha256");
+        assert_eq!(&list[tags[1].span.0..tags[1].span.1], b" rsa-sha256 ");
+        assert_eq!(tags[2].value, b"ab\r\n\t cd");
+        assert_eq!(tags[2].span, (27, 35));
+        assert!(tag_list(b"v=1; v=1").is_none());
+        assert!(tag_list(b"v=1;; a=b").is_none());
+        assert!(tag_list(b"v").is_none());
+        assert!(tag_list(b"1v=1").is_none());
+        assert_eq!(tag_list(b"").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn author() {
+        let author = |fields: &[&[u8]]| {
+            author_domain(fields).map(|domain| {
+                String::from_utf8(domain).unwrap()
+            })
+        };
+        let domain = |field: &[u8]| author(&[field]);
+        let some = |domain: &str| Some(domain.to_string());
+
+        assert_eq!(domain(b"From: a@Example.COM\r\n"), some("example.com"));
+        assert_eq!(domain(b"From: Alice <alice@example.com>\r\n"),
+                   some("example.com"));
+        assert_eq!(domain(b"From:<a@example.com>\r\n"),
+                   some("example.com"));
+        assert_eq!(domain(b"From: \"Bob, <x@attacker.example>\" \
+                            <bob@bank.example>\r\n"),
+                   some("bank.example"));
+        assert_eq!(domain(b"From: Dr. J. \"Bob\" Smith\r\n \
+                            <\"bob smith\"@bank.example>\r\n"),
+                   some("bank.example"));
+        assert_eq!(domain(b"From: bob (Bob @ <x@attacker.example>)@ \
+                            bank . example (Bank)\r\n"),
+                   some("bank.example"));
+        assert_eq!(domain(b"From: =?utf-8?q?B=C3=B6b?= \
+                            <bob@bank.example>\r\n"),
+                   some("bank.example"));
+
+        // More than one author or none at all.
+        assert_eq!(domain(b"From: a@bank.example, \
+                            <x@attacker.example>\r\n"),
+                   None);
+        assert_eq!(domain(b"From: Bank: a@bank.example;\r\n"), None);
+        assert_eq!(domain(b"From: Bank:;\r\n"), None);
+        assert_eq!(author(&[b"From: a@bank.example\r\n",
+                            b"From: x@attacker.example\r\n"]),
+                   None);
+        assert_eq!(author(&[b"Sender: a@bank.example\r\n"]), None);
+
+        // Broken syntax.
+        assert_eq!(domain(b"From: alice\r\n"), None);
+        assert_eq!(domain(b"From: a b@bank.example\r\n"), None);
+        assert_eq!(domain(b"From: a@[192.0.2.1]\r\n"), None);
+        assert_eq!(domain(b"From: <a@bank.example\r\n"), None);
+        assert_eq!(domain(b"From: a@bank.example (x\r\n"), None);
+        assert_eq!(domain(b"From: \"a@bank.example\r\n"), None);
+        assert_eq!(domain(b"From: a@bank..example\r\n"), None);
+        assert_eq!(domain(b"From:\r\n"), None);
+    }
+}
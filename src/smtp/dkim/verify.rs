@@ -0,0 +1,699 @@
+//! Verifying DKIM signatures.
+//!
+//! A `Verifier` is fed the message chunk by chunk. It collects the
+//! header and, once it is complete, picks up all DKIM-Signature fields
+//! and starts hashing the body for each of them. When the message is
+//! complete, `Verifier::finish()` returns the `Signatures` with their
+//! body hashes. Its `verify()` method fetches the keys and checks the
+//! signatures. Since this involves DNS lookups, it blocks and should be
+//! run on a worker pool.
+//!
+//! The outcome for each signature is a `Verification`. These can be
+//! added to an Authentication-Results header field with `add_results()`.
+
+use std::ascii::AsciiExt;
+use std::fmt;
+use openssl::crypto::hash::{Hasher, Type};
+use openssl::crypto::pkey::PKey;
+use ::net::dns::{self, Resolver};
+use ::smtp::authres::AuthResults;
+use ::util::{base64, date};
+use ::util::text::{is_within, trim_fws};
+use super::{Algorithm, BodyHasher, Canon, HeaderBuf, Tag, author_domain,
+            canon_field, select_fields, tag_list, tag_value};
+
+
+/// The maximum number of signatures checked for a message.
+const MAX_SIGNATURES: usize = 10;
+
+/// The smallest acceptable RSA key size in octets.
+///
+/// RFC 8301 requires keys of at least 1024 bits.
+const MIN_KEY_SIZE: usize = 128;
+
+/// The DER encoding of the object identifier for RSA keys.
+const RSA_OID: &'static [u8] = b"\x06\x09\x2a\x86\x48\x86\xf7\x0d\x01\x01\x01";
+
+
+//------------ Verifier ------------------------------------------------------
+
+/// Collects what is needed to verify the signatures of a message.
+pub struct Verifier {
+    header: HeaderBuf,
+
+    /// The signatures and their body hashers.
+    ///
+    /// Signatures that are broken don’t need a hasher.
+    checks: Vec<(Result<Signature, Verification>, Option<BodyHasher>)>,
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Verifier { header: HeaderBuf::default(), checks: Vec::new() }
+    }
+
+    /// Processes the next chunk of the message.
+    pub fn chunk(&mut self, data: &[u8]) {
+        let complete = self.header.complete;
+        let body = self.header.push(data);
+        if !complete && self.header.complete {
+            self.start_body()
+        }
+        if !body.is_empty() {
+            for &mut (_, ref mut hasher) in &mut self.checks {
+                if let Some(ref mut hasher) = *hasher {
+                    hasher.update(body)
+                }
+            }
+        }
+    }
+
+    /// Finishes the message, returning its signatures.
+    pub fn finish(mut self) -> Signatures {
+        if !self.header.complete {
+            // A message without a body. Pretend there is an empty one.
+            self.header.complete = true;
+            self.start_body();
+        }
+        let fields = self.header.fields().into_iter()
+                                 .map(Vec::from).collect();
+        let signatures = self.checks.into_iter().map(|(sig, hasher)| {
+            sig.map(|sig| {
+                let (hash, len) = hasher.unwrap().finish();
+                (sig, hash, len)
+            })
+        }).collect();
+        Signatures { fields: fields, signatures: signatures }
+    }
+
+    fn start_body(&mut self) {
+        let sigs: Vec<_> = self.header.fields().into_iter().filter(|field| {
+            super::field_name(field).eq_ignore_ascii_case(b"DKIM-Signature")
+        }).take(MAX_SIGNATURES).map(Signature::parse).collect();
+        for sig in sigs {
+            let hasher = match sig {
+                Ok(ref sig) => Some(BodyHasher::new(sig.body_canon,
+                                                    sig.length)),
+                Err(_) => None
+            };
+            self.checks.push((sig, hasher))
+        }
+    }
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Verifier::new()
+    }
+}
+
+
+//------------ Signatures ----------------------------------------------------
+
+/// The signatures of a message ready to be checked.
+#[derive(Clone, Debug)]
+pub struct Signatures {
+    /// The header fields of the message.
+    fields: Vec<Vec<u8>>,
+
+    /// The signatures with their body hash and canonical body length.
+    signatures: Vec<Result<(Signature, Vec<u8>, u64), Verification>>,
+}
+
+impl Signatures {
+    /// Returns the domain of the author’s address in the From field.
+    pub fn author_domain(&self) -> Option<Vec<u8>> {
+        let fields: Vec<&[u8]> = self.fields.iter().map(|field| &field[..])
+                                                  .collect();
+        author_domain(&fields)
+    }
+
+    /// Returns whether the message has no signatures at all.
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+
+    /// Checks all signatures, looking up keys via *resolver*.
+    ///
+    /// This blocks until all lookups are done.
+    pub fn verify(self, resolver: &Resolver) -> Vec<Verification> {
+        let fields: Vec<&[u8]> = self.fields.iter().map(|field| &field[..])
+                                                  .collect();
+        self.signatures.into_iter().map(|sig| {
+            match sig {
+                Ok((sig, hash, len)) => {
+                    let result = sig.check(&fields, &hash, len, resolver);
+                    sig.verification(result)
+                }
+                Err(verification) => verification
+            }
+        }).collect()
+    }
+}
+
+
+//------------ Signature -----------------------------------------------------
+
+/// A parsed DKIM-Signature header field.
+#[derive(Clone, Debug)]
+struct Signature {
+    /// The complete field with the value of the b= tag removed.
+    field: Vec<u8>,
+
+    algorithm: Algorithm,
+    signature: Vec<u8>,
+    body_hash: Vec<u8>,
+    header_canon: Canon,
+    body_canon: Canon,
+    domain: Vec<u8>,
+    selector: Vec<u8>,
+
+    /// The domain part of the i= tag.
+    identity_domain: Vec<u8>,
+
+    /// The names of the signed header fields.
+    headers: Vec<Vec<u8>>,
+
+    /// The number of body octets signed if limited.
+    length: Option<u64>,
+
+    /// The b= tag as given, for identifying the signature.
+    b: Vec<u8>,
+}
+
+impl Signature {
+    fn parse(field: &[u8]) -> Result<Self, Verification> {
+        let mut res = Verification::new(Outcome::PermError);
+        let colon = match field.iter().position(|ch| *ch == b':') {
+            Some(colon) => colon + 1,
+            None => return Err(res.because("malformed signature"))
+        };
+        // The tags end before the final line break.
+        let end = if field.ends_with(b"\r\n") { field.len() - 2 }
+                  else { field.len() };
+        let tags = match tag_list(&field[colon..end]) {
+            Some(tags) => tags,
+            None => return Err(res.because("malformed signature"))
+        };
+        if let Some(domain) = tag_value(&tags, b"d") {
+            res.domain = domain.to_ascii_lowercase()
+        }
+        if let Some(selector) = tag_value(&tags, b"s") {
+            res.selector = selector.into()
+        }
+        if let Some(b) = tag_value(&tags, b"b") {
+            res.b = b.into()
+        }
+        match Signature::from_tags(field, colon, &tags) {
+            Ok(sig) => Ok(sig),
+            Err(reason) => Err(res.because(reason))
+        }
+    }
+
+    fn from_tags(field: &[u8], colon: usize, tags: &[Tag])
+                 -> Result<Self, &'static str> {
+        if try!(required(tags, b"v")) != b"1" {
+            return Err("incompatible version")
+        }
+        let algorithm = try!(required(tags, b"a"));
+        let algorithm = try!(Algorithm::from_bytes(algorithm)
+                                       .ok_or("unsupported algorithm"));
+        let b = try!(required(tags, b"b"));
+        let signature = try!(base64::decode_lenient(b)
+                                    .map_err(|_| "malformed signature"));
+        let body_hash = try!(required(tags, b"bh"));
+        let body_hash = try!(base64::decode_lenient(body_hash)
+                                    .map_err(|_| "malformed body hash"));
+        let (header_canon, body_canon) = match tag_value(tags, b"c") {
+            Some(value) => {
+                try!(Canon::parse_pair(value)
+                           .ok_or("unsupported canonicalization"))
+            }
+            None => (Canon::Simple, Canon::Simple)
+        };
+        let domain = try!(required(tags, b"d")).to_ascii_lowercase();
+        let selector = try!(required(tags, b"s"));
+        if domain.is_empty() || selector.is_empty() {
+            return Err("missing required tag")
+        }
+        let headers: Vec<Vec<u8>> = try!(required(tags, b"h"))
+                                        .split(|ch| *ch == b':')
+                                        .map(|name| trim_fws(name).into())
+                                        .collect();
+        if !headers.iter().any(|name| name.eq_ignore_ascii_case(b"From")) {
+            return Err("From field not signed")
+        }
+        let identity_domain = match tag_value(tags, b"i") {
+            Some(identity) => {
+                let at = try!(identity.iter().rposition(|ch| *ch == b'@')
+                                      .ok_or("malformed identity"));
+                let identity = identity[at + 1..].to_ascii_lowercase();
+                if !is_within(&identity, &domain) {
+                    return Err("identity outside of signing domain")
+                }
+                identity
+            }
+            None => domain.clone()
+        };
+        let length = match tag_value(tags, b"l") {
+            Some(length) => Some(try!(parse_number(length)
+                                          .ok_or("malformed body length"))),
+            None => None
+        };
+        if let Some(query) = tag_value(tags, b"q") {
+            if !query.split(|ch| *ch == b':')
+                     .any(|method| trim_fws(method) == b"dns/txt") {
+                return Err("unsupported query method")
+            }
+        }
+        let timestamp = match tag_value(tags, b"t") {
+            Some(value) => Some(try!(parse_number(value)
+                                         .ok_or("malformed timestamp"))),
+            None => None
+        };
+        if let Some(value) = tag_value(tags, b"x") {
+            let expires = try!(parse_number(value)
+                                   .ok_or("malformed expiration"));
+            if timestamp.map_or(false, |timestamp| expires < timestamp) {
+                return Err("expiration before timestamp")
+            }
+            if expires < date::now() {
+                return Err("signature expired")
+            }
+        }
+
+        // The b= tag is always there, so this can’t fail.
+        let span = tags.iter().find(|tag| tag.name == b"b").unwrap().span;
+        let mut stripped = Vec::with_capacity(field.len());
+        stripped.extend_from_slice(&field[..colon + span.0]);
+        stripped.extend_from_slice(&field[colon + span.1..]);
+
+        Ok(Signature {
+            field: stripped, algorithm: algorithm, signature: signature,
+            body_hash: body_hash, header_canon: header_canon,
+            body_canon: body_canon, domain: domain, selector: selector.into(),
+            identity_domain: identity_domain, headers: headers,
+            length: length, b: b.into()
+        })
+    }
+
+    /// Checks the signature.
+    ///
+    /// The *hash* and *len* are the body hash and canonicalized length
+    /// of the body.
+    fn check(&self, fields: &[&[u8]], hash: &[u8], len: u64,
+             resolver: &Resolver) -> Result<(), (Outcome, &'static str)> {
+        if self.length.map_or(false, |length| len < length) {
+            return Err((Outcome::Fail, "body shorter than signed length"))
+        }
+        if hash != &self.body_hash[..] {
+            return Err((Outcome::Fail, "body hash mismatch"))
+        }
+        if self.algorithm != Algorithm::RsaSha256 {
+            return Err((Outcome::PermError, "unsupported algorithm"))
+        }
+        let key = try!(self.key(resolver));
+        let mut hasher = Hasher::new(Type::SHA256);
+        let mut data = Vec::new();
+        for field in select_fields(fields, &self.headers) {
+            canon_field(self.header_canon, field, &mut data);
+        }
+        canon_field(self.header_canon, &self.field, &mut data);
+        // The signature field itself goes in without the line break.
+        let end = data.len() - 2;
+        hasher.update(&data[..end]);
+        if key.verify_with_hash(&hasher.finish(), &self.signature,
+                                Type::SHA256) {
+            Ok(())
+        }
+        else {
+            Err((Outcome::Fail, "signature verification failed"))
+        }
+    }
+
+    /// Looks up and parses the key for the signature.
+    fn key(&self, resolver: &Resolver)
+           -> Result<PKey, (Outcome, &'static str)> {
+        let perm = |reason| (Outcome::PermError, reason);
+        let mut name = self.selector.clone();
+        name.extend_from_slice(b"._domainkey.");
+        name.extend_from_slice(&self.domain);
+        let record = match resolver.lookup_txt(&name) {
+            Ok(mut records) => {
+                if records.is_empty() {
+                    return Err(perm("no key for signature"))
+                }
+                records.swap_remove(0)
+            }
+            Err(dns::Error::NotFound) => {
+                return Err(perm("no key for signature"))
+            }
+            Err(_) => return Err((Outcome::TempError, "key unavailable"))
+        };
+        let tags = try!(tag_list(&record).ok_or(perm("malformed key")));
+        if let Some(version) = tag_value(&tags, b"v") {
+            if version != b"DKIM1" {
+                return Err(perm("malformed key"))
+            }
+        }
+        if let Some(hashes) = tag_value(&tags, b"h") {
+            if !hashes.split(|ch| *ch == b':').any(|hash| {
+                trim_fws(hash).eq_ignore_ascii_case(b"sha256")
+            }) {
+                return Err(perm("inappropriate hash algorithm"))
+            }
+        }
+        let key_type = tag_value(&tags, b"k").unwrap_or(b"rsa");
+        if !key_type.eq_ignore_ascii_case(self.algorithm.key_type()) {
+            return Err(perm("inappropriate key algorithm"))
+        }
+        if let Some(services) = tag_value(&tags, b"s") {
+            if !services.split(|ch| *ch == b':').any(|service| {
+                let service = trim_fws(service);
+                service == b"*" || service.eq_ignore_ascii_case(b"email")
+            }) {
+                return Err(perm("inappropriate service type"))
+            }
+        }
+        if let Some(flags) = tag_value(&tags, b"t") {
+            if flags.split(|ch| *ch == b':').any(|flag| trim_fws(flag) == b"s")
+                    && self.identity_domain != self.domain {
+                return Err(perm("identity must match signing domain"))
+            }
+        }
+        let key = try!(tag_value(&tags, b"p").ok_or(perm("malformed key")));
+        if key.is_empty() {
+            return Err((Outcome::Fail, "key revoked"))
+        }
+        let der = try!(base64::decode_lenient(key)
+                              .map_err(|_| perm("malformed key")));
+        load_rsa_key(&der).ok_or(perm("malformed key")).and_then(|key| {
+            if key.size() < MIN_KEY_SIZE { Err(perm("key too short")) }
+            else { Ok(key) }
+        })
+    }
+
+    /// Turns the outcome of `check()` into a verification.
+    fn verification(&self, result: Result<(), (Outcome, &'static str)>)
+                    -> Verification {
+        let (outcome, reason) = match result {
+            Ok(()) => (Outcome::Pass, None),
+            Err((outcome, reason)) => (outcome, Some(reason))
+        };
+        Verification { result: outcome, domain: self.domain.clone(),
+                       selector: self.selector.clone(), b: self.b.clone(),
+                       reason: reason }
+    }
+}
+
+
+//------------ Verification --------------------------------------------------
+
+/// The outcome of checking a signature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verification {
+    pub result: Outcome,
+
+    /// The signing domain from the d= tag.
+    ///
+    /// This is empty if the signature was too broken to tell.
+    pub domain: Vec<u8>,
+
+    /// The selector from the s= tag.
+    pub selector: Vec<u8>,
+
+    /// The signature data as given in the b= tag.
+    pub b: Vec<u8>,
+
+    /// Why the signature didn’t pass.
+    pub reason: Option<&'static str>,
+}
+
+impl Verification {
+    fn new(result: Outcome) -> Self {
+        Verification { result: result, domain: Vec::new(),
+                       selector: Vec::new(), b: Vec::new(), reason: None }
+    }
+
+    fn because(mut self, reason: &'static str) -> Self {
+        self.reason = Some(reason);
+        self
+    }
+
+    /// Adds the verification as a dkim result to *results*.
+    pub fn add_to(&self, results: &mut AuthResults) {
+        // RFC 6008 says eight characters of the signature are enough to
+        // tell signatures apart.
+        let b: Vec<u8> = self.b.iter().cloned()
+                               .filter(|&ch| ch != b' ' && ch != b'\t' &&
+                                             ch != b'\r' && ch != b'\n')
+                               .take(8).collect();
+        let mut props: Vec<(&str, &[u8])> = Vec::new();
+        if !self.domain.is_empty() {
+            props.push(("header.d", &self.domain[..]));
+        }
+        if !self.selector.is_empty() {
+            props.push(("header.s", &self.selector[..]));
+        }
+        if !b.is_empty() {
+            props.push(("header.b", &b[..]));
+        }
+        results.add("dkim", self.result.as_str(), self.reason, &props);
+    }
+}
+
+/// Adds the result of verifying a message to *results*.
+///
+/// Each verification becomes one dkim result. If there weren’t any
+/// signatures, a dkim result of none is added.
+pub fn add_results(verifications: &[Verification],
+                   results: &mut AuthResults) {
+    if verifications.is_empty() {
+        results.add("dkim", "none", None, &[]);
+    }
+    for verification in verifications {
+        verification.add_to(results)
+    }
+}
+
+
+//------------ Outcome -------------------------------------------------------
+
+/// The result of checking a signature as named by RFC 8601.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The signature is valid.
+    Pass,
+
+    /// The signature doesn’t match the message.
+    Fail,
+
+    /// The key couldn’t be retrieved because of a temporary problem.
+    TempError,
+
+    /// The signature couldn’t be checked for good.
+    PermError,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+            Outcome::TempError => "temperror",
+            Outcome::PermError => "permerror",
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Loads an RSA public key in DER encoded SubjectPublicKeyInfo format.
+///
+/// The OpenSSL bindings only know how to deal with RSA keys, so anything
+/// else is rejected before it gets to them.
+fn load_rsa_key(der: &[u8]) -> Option<PKey> {
+    if !der.windows(RSA_OID.len()).take(16).any(|part| part == RSA_OID) {
+        return None
+    }
+    let mut pem = Vec::new();
+    pem.extend_from_slice(b"-----BEGIN PUBLIC KEY-----\n");
+    for line in base64::encode(der).chunks(64) {
+        pem.extend_from_slice(line);
+        pem.push(b'\n');
+    }
+    pem.extend_from_slice(b"-----END PUBLIC KEY-----\n");
+    PKey::public_key_from_pem(&mut &pem[..]).ok()
+}
+
+/// Returns the value of a tag that must be present.
+fn required<'a>(tags: &[Tag<'a>], name: &[u8])
+                -> Result<&'a [u8], &'static str> {
+    tag_value(tags, name).ok_or("missing required tag")
+}
+
+fn parse_number(value: &[u8]) -> Option<u64> {
+    if value.is_empty() || !value.iter().all(|ch| super::is_digit(*ch)) {
+        return None
+    }
+    ::std::str::from_utf8(value).ok().and_then(|s| s.parse().ok())
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use ::net::dns::{Record, Zone};
+    use ::smtp::authres::AuthResults;
+    use super::*;
+
+    /// A message with two signatures.
+    ///
+    /// The first one is relaxed/relaxed with a folded b= tag, the second
+    /// one simple/simple covering only the first twenty octets of the
+    /// body.
+    const MESSAGE: &'static [u8] =
+        b"DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed;\r\n\
+          \td=Example.com; s=sel; h=From : Subject:subject; \
+          bh=uu1DYZYUVUSqoJPsWTbOnl5HBCp2VWP6LuU72L/1Kaw=;\r\n\
+          \tb=We3f52VbJ0QAVNbM+KW8zr06E9yOHr3mbIGcDxuP\r\n\
+          \t u4X+XwOxhXo21MzufqBN1RqX0YTh4X3MTfeEeUwy/VIE8DrWMjHJCJS\
+          4KEzjW5buaYmnfyScVQeZXX3PEliEB4ijayCs7j/r3yfiK4X6gbzQDagew\
+          h1jv0HX5EK0a6VJ6pU=\r\n\
+          DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=sel;\r\n\
+          \ti=@mail.example.com; h=from:subject:subject; l=20; \
+          bh=OlJAWc0qrJrBdvrWfrV5xFwHYdEFziYUkyeRyYI70dM=;\r\n\
+          \tb=kSpQ4eR5eK971s5aWNm8uc9bc9+hV8y54f0qX+shaWCUB6OcxM8cAO\
+          5RUV1B0ZxIOjYTWEqIziEHHbU1SOl2HAmu7zm8kMD+0Jd7WkSmFaSW78KH\
+          /ScEYC/wzXnDRu2NWqJA5hJF/2/Ec43zAYRhihOY3KfTbjOuYvUKVRJB/l\
+          I=\r\n\
+          From: Alice <alice@example.com>\r\n\
+          Subject:  Hello \r\n\
+          \t  there\r\n\
+          To: bob@mx.test\r\n\
+          \r\n\
+          Hi Bob,  \r\n\
+          \x20\t\r\n\
+          \x20See   you.\r\n\
+          \r\n\
+          \r\n";
+
+    const KEY: &'static [u8] =
+        b"v=DKIM1; k=rsa; \
+          p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQCdV4MRj5267V1xiEjx\
+          0DsH1++gDaKn2Z2uoxnrgG3YCAmUNUCKt8f10pzEDBtbba+GZovOdLgz\
+          /Uk86Ym6Y7uCpLp3Xe37Uy9V87nMDGucoTi0T2DxuxRA4IqDNYfAKh7F\
+          qANBlxJfBRq38xPf9/vyVqptObHenSsdF0yBPrvZEwIDAQAB";
+
+    fn zone(key: &[u8]) -> Zone {
+        let mut zone = Zone::new();
+        zone.insert(b"sel._domainkey.example.com", Record::Txt(key.into()));
+        zone
+    }
+
+    /// Verifies *message* fed in small chunks.
+    fn verify(message: &[u8], zone: &Zone) -> Vec<Verification> {
+        let mut verifier = Verifier::new();
+        for chunk in message.chunks(7) {
+            verifier.chunk(chunk)
+        }
+        verifier.finish().verify(zone)
+    }
+
+    fn outcomes(verifications: &[Verification])
+                -> Vec<(Outcome, Option<&'static str>)> {
+        verifications.iter().map(|item| (item.result, item.reason))
+                     .collect()
+    }
+
+    #[test]
+    fn pass() {
+        let res = verify(MESSAGE, &zone(KEY));
+        assert_eq!(outcomes(&res), [(Outcome::Pass, None),
+                                    (Outcome::Pass, None)]);
+        assert_eq!(res[0].domain, b"example.com");
+        assert_eq!(res[0].selector, b"sel");
+
+        // Whatever comes after the signed length doesn’t matter for the
+        // second signature.
+        let mut message = MESSAGE.to_vec();
+        message.extend_from_slice(b"Unsigned\r\n");
+        assert_eq!(outcomes(&verify(&message, &zone(KEY))),
+                   [(Outcome::Fail, Some("body hash mismatch")),
+                    (Outcome::Pass, None)]);
+    }
+
+    #[test]
+    fn fail() {
+        let message: Vec<u8> = String::from_utf8(MESSAGE.into()).unwrap()
+                                      .replace("Hello", "Hallo").into();
+        assert_eq!(outcomes(&verify(&message, &zone(KEY))),
+                   [(Outcome::Fail, Some("signature verification failed")),
+                    (Outcome::Fail, Some("signature verification failed"))]);
+        assert_eq!(outcomes(&verify(MESSAGE, &Zone::new()))[0],
+                   (Outcome::PermError, Some("no key for signature")));
+        assert_eq!(outcomes(&verify(MESSAGE, &zone(b"v=DKIM1; p=")))[0],
+                   (Outcome::Fail, Some("key revoked")));
+        assert_eq!(outcomes(&verify(MESSAGE, &zone(b"v=DKIM1; k=ed25519; \
+                                                     p=AAAA")))[0],
+                   (Outcome::PermError, Some("inappropriate key algorithm")));
+    }
+
+    #[test]
+    fn broken() {
+        let check = |tags: &str| {
+            let mut message = Vec::new();
+            message.extend_from_slice(b"DKIM-Signature: v=1; d=example.com; \
+                                        s=sel; ");
+            message.extend_from_slice(tags.as_bytes());
+            message.extend_from_slice(b"\r\nFrom: a@example.com\r\n\r\n");
+            let res = verify(&message, &zone(KEY));
+            assert_eq!(res.len(), 1);
+            (res[0].result, res[0].reason.unwrap())
+        };
+        // The body hash of an empty body in relaxed canonicalization.
+        let bh = "bh=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        assert_eq!(check(&format!("a=ed25519-sha256; c=relaxed/relaxed; \
+                                   h=from; {}; b=AAAA", bh)),
+                   (Outcome::PermError, "unsupported algorithm"));
+        assert_eq!(check("a=rsa-sha1; h=from; bh=AAAA; b=AAAA"),
+                   (Outcome::PermError, "unsupported algorithm"));
+        assert_eq!(check("a=rsa-sha256; h=subject; bh=AAAA; b=AAAA"),
+                   (Outcome::PermError, "From field not signed"));
+        assert_eq!(check("a=rsa-sha256; h=from; b=AAAA"),
+                   (Outcome::PermError, "missing required tag"));
+        assert_eq!(check("a=rsa-sha256; h=from; bh=AAAA; b=AAAA; \
+                          i=a@example.org"),
+                   (Outcome::PermError, "identity outside of signing domain"));
+        assert_eq!(check("a=rsa-sha256; h=from; bh=AAAA; b=AAAA; x=1"),
+                   (Outcome::PermError, "signature expired"));
+        assert_eq!(check("a=rsa-sha256; h=from; bh=AAAA; b=AAAA; b=AAAA"),
+                   (Outcome::PermError, "malformed signature"));
+    }
+
+    #[test]
+    fn results() {
+        let mut results = AuthResults::new(b"mx.test");
+        add_results(&verify(b"From: a@example.com\r\n\r\n", &zone(KEY)),
+                    &mut results);
+        assert_eq!(results.to_field(),
+                   &b"Authentication-Results: mx.test;\r\n\
+                      \tdkim=none\r\n"[..]);
+
+        let mut results = AuthResults::new(b"mx.test");
+        add_results(&verify(MESSAGE, &Zone::new())[..1], &mut results);
+        assert_eq!(results.to_field(),
+                   &b"Authentication-Results: mx.test;\r\n\
+                      \tdkim=permerror reason=\"no key for signature\" \
+                      header.d=example.com header.s=sel \
+                      header.b=We3f52Vb\r\n"[..]);
+    }
+}
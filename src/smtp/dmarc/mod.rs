@@ -0,0 +1,755 @@
+//! Domain-based Message Authentication, Reporting, and Conformance.
+//!
+//! DMARC, defined in RFC 7489, lets the domain of a message’s author
+//! publish a policy on what to do with mail that neither SPF nor DKIM
+//! can tie to it. A message passes if either an SPF pass for the MAIL
+//! FROM domain or a DKIM pass for a signing domain is aligned with the
+//! domain of the address in the From field. In relaxed mode, alignment
+//! only needs both domains to share their organizational domain; in
+//! strict mode, they have to be identical.
+//!
+//! A `Query` collects what is known about a message and its `check()`
+//! method looks up the policy and evaluates it against the SPF and DKIM
+//! results, giving an `Evaluation`. Looking up the policy blocks, so this
+//! should happen on a worker pool.
+//!
+//! Organizational domains are determined using the Public Suffix List
+//! loaded into a `Suffixes` value. Without the list, the implicit rule
+//! of the algorithm applies and the organizational domain is simply made
+//! of the last two labels of a domain.
+//!
+//! The `report` module collects evaluations and turns them into the
+//! aggregate reports of RFC 7489, section 7.2. Failure reports are not
+//! supported.
+
+use std::ascii::AsciiExt;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+use openssl::crypto::rand::rand_bytes;
+use ::net::dns::{self, Resolver};
+use ::smtp::authres::AuthResults;
+use ::smtp::dkim::verify::{self, Verification};
+use ::smtp::spf::{self, Spf};
+use ::util::text::trim_fws;
+
+pub mod report;
+
+
+//------------ Query ---------------------------------------------------------
+
+/// What is known about a message for evaluating the DMARC policy.
+#[derive(Clone, Debug)]
+pub struct Query {
+    /// The address of the client that sent the message.
+    ip: IpAddr,
+
+    /// The domain of the author’s address in the From field.
+    author: Option<Vec<u8>>,
+
+    /// The result of the SPF check if there was one.
+    spf: Option<Spf>,
+}
+
+impl Query {
+    /// Creates a query for a message from *author* sent by *ip*.
+    ///
+    /// If the domain of the author is unknown because the From field is
+    /// missing or broken or names more than one author, *author* is
+    /// `None`. Such messages are to be rejected since whoever reads them
+    /// can’t tell where they are from.
+    pub fn new(ip: IpAddr, author: Option<Vec<u8>>, spf: Option<Spf>)
+               -> Self {
+        Query { ip: ip, author: author, spf: spf }
+    }
+
+    /// Evaluates the policy for the message given its DKIM results.
+    ///
+    /// This blocks until the policy has been looked up via *resolver*.
+    pub fn check(self, dkim: &[Verification], suffixes: &Suffixes,
+                 resolver: &Resolver) -> Evaluation {
+        let mut res = Evaluation {
+            result: Outcome::None, ip: self.ip, domain: Vec::new(),
+            policy: None, disposition: Disposition::None, reason: None,
+            dkim_aligned: false, spf_aligned: false, spf: self.spf,
+            dkim: dkim.to_vec()
+        };
+        res.domain = match self.author {
+            Some(author) => author.to_ascii_lowercase(),
+            None => {
+                res.result = Outcome::PermError;
+                res.disposition = Disposition::Reject;
+                return res
+            }
+        };
+        let policy = match Policy::lookup(&res.domain, suffixes, resolver) {
+            Ok(Some(policy)) => policy,
+            Ok(None) => return res,
+            Err(_) => {
+                res.result = Outcome::TempError;
+                return res
+            }
+        };
+        let spf_aligned = match res.spf {
+            Some(ref spf) => {
+                spf.result == spf::Outcome::Pass
+                    && suffixes.is_aligned(policy.spf_strict, &res.domain,
+                                           &spf.domain)
+            }
+            None => false
+        };
+        let dkim_aligned = res.dkim.iter().any(|item| {
+            item.result == verify::Outcome::Pass
+                && suffixes.is_aligned(policy.dkim_strict, &res.domain,
+                                       &item.domain)
+        });
+        res.spf_aligned = spf_aligned;
+        res.dkim_aligned = dkim_aligned;
+        if spf_aligned || dkim_aligned {
+            res.result = Outcome::Pass;
+        }
+        else {
+            res.result = Outcome::Fail;
+            res.disposition = policy.requested(&res.domain);
+        }
+        res.policy = Some(policy);
+        res.sample(roll());
+        res
+    }
+}
+
+
+//------------ Evaluation ----------------------------------------------------
+
+/// The outcome of evaluating the DMARC policy for a message.
+#[derive(Clone, Debug)]
+pub struct Evaluation {
+    pub result: Outcome,
+
+    /// The address of the client that sent the message.
+    pub ip: IpAddr,
+
+    /// The domain of the author’s address in the From field.
+    ///
+    /// This is empty if the domain couldn’t be determined.
+    pub domain: Vec<u8>,
+
+    /// The policy that applied if one was found.
+    pub policy: Option<Policy>,
+
+    /// What should happen to the message.
+    pub disposition: Disposition,
+
+    /// Why the disposition differs from what the policy asked for.
+    pub reason: Option<Override>,
+
+    /// Was there a DKIM pass aligned with the author’s domain?
+    pub dkim_aligned: bool,
+
+    /// Was there an SPF pass aligned with the author’s domain?
+    pub spf_aligned: bool,
+
+    /// The SPF result the evaluation was based on.
+    pub spf: Option<Spf>,
+
+    /// The DKIM results the evaluation was based on.
+    pub dkim: Vec<Verification>,
+}
+
+impl Evaluation {
+    /// Overrides the disposition of the policy for *reason*.
+    pub fn set_override(&mut self, disposition: Disposition,
+                        reason: Override) {
+        if disposition != self.disposition {
+            self.disposition = disposition;
+            self.reason = Some(reason);
+        }
+    }
+
+    /// Adds the evaluation as a dmarc result to *results*.
+    ///
+    /// If the message failed, the disposition is included as the
+    /// policy.dmarc property.
+    pub fn add_to(&self, results: &mut AuthResults) {
+        let mut props: Vec<(&str, &[u8])> = Vec::new();
+        if !self.domain.is_empty() {
+            props.push(("header.from", &self.domain[..]));
+        }
+        if self.result == Outcome::Fail {
+            props.push(("policy.dmarc",
+                        self.disposition.as_str().as_bytes()));
+        }
+        results.add("dmarc", self.result.as_str(), None, &props);
+    }
+
+    /// Applies the percentage of the policy given a random *roll*.
+    ///
+    /// Messages that are not sampled get the next less strict
+    /// disposition as described in RFC 7489, section 6.6.4.
+    fn sample(&mut self, roll: u32) {
+        let percent = match self.policy {
+            Some(ref policy) => policy.percent as u32,
+            None => return
+        };
+        if self.disposition == Disposition::None || roll % 100 < percent {
+            return
+        }
+        let disposition = match self.disposition {
+            Disposition::Reject => Disposition::Quarantine,
+            _ => Disposition::None
+        };
+        self.set_override(disposition, Override::SampledOut)
+    }
+}
+
+
+//------------ Policy --------------------------------------------------------
+
+/// A DMARC policy as published by a domain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    /// The domain the policy was found for.
+    pub domain: Vec<u8>,
+
+    /// What should happen to failing mail from the domain itself.
+    pub request: Disposition,
+
+    /// What should happen to failing mail from subdomains.
+    ///
+    /// If this is `None`, `request` applies to subdomains, too.
+    pub subdomain: Option<Disposition>,
+
+    /// Is strict alignment required for DKIM?
+    pub dkim_strict: bool,
+
+    /// Is strict alignment required for SPF?
+    pub spf_strict: bool,
+
+    /// The percentage of failing mail the policy should be applied to.
+    pub percent: u8,
+
+    /// Where to send aggregate reports to.
+    pub rua: Vec<Vec<u8>>,
+}
+
+impl Policy {
+    /// Parses the policy *record* found for *domain*.
+    ///
+    /// Returns `None` if the record isn’t a DMARC record or is invalid.
+    /// Invalid values of optional tags are replaced by their defaults.
+    pub fn parse(domain: &[u8], record: &[u8]) -> Option<Self> {
+        let mut tags = record.split(|ch| *ch == b';').map(trim_fws)
+                             .filter(|tag| !tag.is_empty())
+                             .map(|tag| {
+            match tag.iter().position(|ch| *ch == b'=') {
+                Some(eq) => (trim_fws(&tag[..eq]), trim_fws(&tag[eq + 1..])),
+                None => (tag, &b""[..])
+            }
+        });
+        match tags.next() {
+            Some((name, value)) if name == b"v" && value == b"DMARC1" => { }
+            _ => return None
+        }
+        let mut request = None;
+        let mut res = Policy {
+            domain: domain.to_ascii_lowercase(), request: Disposition::None,
+            subdomain: None, dkim_strict: false, spf_strict: false,
+            percent: 100, rua: Vec::new()
+        };
+        for (name, value) in tags {
+            if name.eq_ignore_ascii_case(b"p") {
+                request = Disposition::from_bytes(value)
+            }
+            else if name.eq_ignore_ascii_case(b"sp") {
+                res.subdomain = Disposition::from_bytes(value)
+            }
+            else if name.eq_ignore_ascii_case(b"adkim") {
+                res.dkim_strict = value.eq_ignore_ascii_case(b"s")
+            }
+            else if name.eq_ignore_ascii_case(b"aspf") {
+                res.spf_strict = value.eq_ignore_ascii_case(b"s")
+            }
+            else if name.eq_ignore_ascii_case(b"pct") {
+                res.percent = parse_percent(value).unwrap_or(100)
+            }
+            else if name.eq_ignore_ascii_case(b"rua") {
+                res.rua = value.split(|ch| *ch == b',').map(trim_fws)
+                               .filter(|uri| is_mailto(uri))
+                               .map(Vec::from).collect()
+            }
+        }
+        // A record without a valid policy still counts if it asks for
+        // reports. See RFC 7489, section 6.6.3.
+        match request {
+            Some(request) => res.request = request,
+            None if !res.rua.is_empty() => { }
+            None => return None
+        }
+        Some(res)
+    }
+
+    /// Looks up the policy for the author domain *author*.
+    ///
+    /// If the domain itself doesn’t publish a policy, the policy of its
+    /// organizational domain applies. Returns `Ok(None)` if there is no
+    /// policy at all and an error if the lookup failed.
+    pub fn lookup(author: &[u8], suffixes: &Suffixes, resolver: &Resolver)
+                  -> Result<Option<Self>, dns::Error> {
+        if let Some(policy) = try!(Policy::lookup_at(author, resolver)) {
+            return Ok(Some(policy))
+        }
+        let org = suffixes.organizational_domain(author);
+        if org.eq_ignore_ascii_case(author) {
+            return Ok(None)
+        }
+        Policy::lookup_at(&org, resolver)
+    }
+
+    fn lookup_at(domain: &[u8], resolver: &Resolver)
+                 -> Result<Option<Self>, dns::Error> {
+        let mut name = b"_dmarc.".to_vec();
+        name.extend_from_slice(domain);
+        let records = match resolver.lookup_txt(&name) {
+            Ok(records) => records,
+            Err(dns::Error::NotFound) | Err(dns::Error::InvalidName) => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err)
+        };
+        let mut policies = records.iter().filter_map(|record| {
+            Policy::parse(domain, record)
+        });
+        // More than one policy is as good as none.
+        match (policies.next(), policies.next()) {
+            (Some(policy), None) => Ok(Some(policy)),
+            _ => Ok(None)
+        }
+    }
+
+    /// Returns the disposition requested for failing mail from *author*.
+    pub fn requested(&self, author: &[u8]) -> Disposition {
+        if author.eq_ignore_ascii_case(&self.domain) {
+            self.request
+        }
+        else {
+            self.subdomain.unwrap_or(self.request)
+        }
+    }
+}
+
+
+//------------ Suffixes ------------------------------------------------------
+
+/// The Public Suffix List for finding organizational domains.
+///
+/// The list can be read from a file in the format of the list published
+/// at <https://publicsuffix.org/>. Only rules in ASCII are useful since
+/// domains are compared in their ASCII form.
+#[derive(Clone, Debug, Default)]
+pub struct Suffixes {
+    /// The normal rules.
+    rules: HashSet<Vec<u8>>,
+
+    /// The wildcard rules without their leading `*.`.
+    wildcards: HashSet<Vec<u8>>,
+
+    /// The exception rules without their leading `!`.
+    exceptions: HashSet<Vec<u8>>,
+}
+
+impl Suffixes {
+    /// Creates an empty list.
+    pub fn new() -> Self {
+        Suffixes::default()
+    }
+
+    /// Creates a list with the rules of the file at *path*.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut res = Suffixes::new();
+        try!(res.read(BufReader::new(try!(File::open(path)))));
+        Ok(res)
+    }
+
+    /// Adds the rules read from *source*.
+    pub fn read<R: BufRead>(&mut self, source: R) -> io::Result<()> {
+        for line in source.split(b'\n') {
+            let line = try!(line);
+            let rule = match line.split(|ch| {
+                *ch == b' ' || *ch == b'\t' || *ch == b'\r'
+            }).next() {
+                Some(rule) => rule.to_ascii_lowercase(),
+                None => continue
+            };
+            if rule.is_empty() || rule.starts_with(b"//") {
+                continue
+            }
+            if rule.starts_with(b"!") {
+                self.exceptions.insert(rule[1..].into());
+            }
+            else if rule.starts_with(b"*.") {
+                self.wildcards.insert(rule[2..].into());
+            }
+            else {
+                self.rules.insert(rule);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the organizational domain of *domain*.
+    ///
+    /// This is the public suffix of the domain plus one more label. The
+    /// domain is returned in lowercase without a trailing dot. If the
+    /// domain is a public suffix itself, it is its own organizational
+    /// domain.
+    pub fn organizational_domain(&self, domain: &[u8]) -> Vec<u8> {
+        let mut domain = domain.to_ascii_lowercase();
+        if domain.ends_with(b".") {
+            domain.pop();
+        }
+        let mut starts = vec![0];
+        starts.extend(domain.iter().enumerate().filter(|&(_, ch)| {
+            *ch == b'.'
+        }).map(|(i, _)| i + 1));
+
+        // Without a matching rule, the last label is the public suffix.
+        let mut suffix = starts.len() - 1;
+        for i in 0..starts.len() {
+            let name = &domain[starts[i]..];
+            if self.exceptions.contains(name) {
+                suffix = i + 1;
+                break
+            }
+            if self.rules.contains(name) ||
+                    (i + 1 < starts.len() &&
+                     self.wildcards.contains(&domain[starts[i + 1]..])) {
+                suffix = i;
+                break
+            }
+        }
+        if suffix == 0 {
+            domain
+        }
+        else {
+            domain[starts[suffix - 1]..].into()
+        }
+    }
+
+    /// Returns whether *domain* is aligned with *author*.
+    pub fn is_aligned(&self, strict: bool, author: &[u8], domain: &[u8])
+                      -> bool {
+        if strict {
+            author.eq_ignore_ascii_case(domain)
+        }
+        else {
+            self.organizational_domain(author)
+                == self.organizational_domain(domain)
+        }
+    }
+}
+
+
+//------------ Outcome -------------------------------------------------------
+
+/// The result of a DMARC evaluation as named by RFC 8601.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The author domain doesn’t publish a policy.
+    None,
+
+    /// The message is aligned with the author domain.
+    Pass,
+
+    /// The message isn’t aligned with the author domain.
+    Fail,
+
+    /// The policy couldn’t be looked up.
+    TempError,
+
+    /// The author domain couldn’t be determined.
+    PermError,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::None => "none",
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+            Outcome::TempError => "temperror",
+            Outcome::PermError => "permerror",
+        }
+    }
+}
+
+
+//------------ Disposition ---------------------------------------------------
+
+/// What should happen to a message failing DMARC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disposition {
+    /// Nothing special.
+    None,
+
+    /// The message should be treated as suspicious.
+    Quarantine,
+
+    /// The message should be rejected.
+    Reject,
+}
+
+impl Disposition {
+    pub fn from_bytes(value: &[u8]) -> Option<Self> {
+        if value.eq_ignore_ascii_case(b"none") {
+            Some(Disposition::None)
+        }
+        else if value.eq_ignore_ascii_case(b"quarantine") {
+            Some(Disposition::Quarantine)
+        }
+        else if value.eq_ignore_ascii_case(b"reject") {
+            Some(Disposition::Reject)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Disposition::None => "none",
+            Disposition::Quarantine => "quarantine",
+            Disposition::Reject => "reject",
+        }
+    }
+}
+
+
+//------------ Override ------------------------------------------------------
+
+/// Why a disposition differs from what the policy requested.
+///
+/// These are the policy override types of RFC 7489, appendix C, that
+/// we can actually have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Override {
+    /// The message was not selected by the policy’s percentage.
+    SampledOut,
+
+    /// Local configuration overrode the policy.
+    LocalPolicy,
+}
+
+impl Override {
+    pub fn from_bytes(value: &[u8]) -> Option<Self> {
+        if value == b"sampled_out" {
+            Some(Override::SampledOut)
+        }
+        else if value == b"local_policy" {
+            Some(Override::LocalPolicy)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Override::SampledOut => "sampled_out",
+            Override::LocalPolicy => "local_policy",
+        }
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns a random number for sampling.
+fn roll() -> u32 {
+    rand_bytes(4).iter().fold(0, |res, ch| res << 8 | *ch as u32)
+}
+
+fn parse_percent(value: &[u8]) -> Option<u8> {
+    if value.is_empty() || value.len() > 3
+            || !value.iter().all(|ch| *ch >= b'0' && *ch <= b'9') {
+        return None
+    }
+    let res = value.iter().fold(0u32, |res, ch| res * 10 +
+                                                (ch - b'0') as u32);
+    if res > 100 { None } else { Some(res as u8) }
+}
+
+/// Returns whether *uri* is a mailto URI.
+///
+/// We can only send reports via mail, so other URIs are dropped.
+fn is_mailto(uri: &[u8]) -> bool {
+    uri.len() > 7 && uri[..7].eq_ignore_ascii_case(b"mailto:")
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use ::net::dns::{Record, Zone};
+    use ::smtp::dkim::verify::{self, Verification};
+    use ::smtp::spf::{self, Identity, Spf};
+    use super::*;
+
+    fn zone() -> Zone {
+        let mut zone = Zone::new();
+        zone.insert(b"_dmarc.example.com",
+                    Record::Txt(b"v=DMARC1; p=reject; sp=quarantine; \
+                                  rua=mailto:dmarc@example.com".to_vec()));
+        zone.insert(b"_dmarc.strict.test",
+                    Record::Txt(b"v=DMARC1; p=quarantine; adkim=s; \
+                                  aspf=s".to_vec()));
+        zone.insert(b"_dmarc.twice.test",
+                    Record::Txt(b"v=DMARC1; p=reject".to_vec()));
+        zone.insert(b"_dmarc.twice.test",
+                    Record::Txt(b"v=DMARC1; p=none".to_vec()));
+        zone
+    }
+
+    fn spf(result: spf::Outcome, domain: &[u8]) -> Spf {
+        Spf { result: result, identity: Identity::MailFrom,
+              ip: "192.0.2.1".parse().unwrap(), sender: Vec::new(),
+              envelope_from: Vec::new(),
+              helo: b"client.test".to_vec(), domain: domain.into(),
+              explanation: None }
+    }
+
+    fn dkim(result: verify::Outcome, domain: &[u8]) -> Verification {
+        Verification { result: result, domain: domain.into(),
+                       selector: b"sel".to_vec(), b: Vec::new(),
+                       reason: None }
+    }
+
+    fn check(author: &[u8], spf: Option<Spf>, dkim: &[Verification])
+             -> Evaluation {
+        let mut suffixes = Suffixes::new();
+        suffixes.read(&b"com\nco.uk\n"[..]).unwrap();
+        Query::new("192.0.2.1".parse().unwrap(), Some(author.into()), spf)
+              .check(dkim, &suffixes, &zone())
+    }
+
+    #[test]
+    fn parse() {
+        let policy = Policy::parse(b"Example.com",
+                                   b"v=DMARC1 ; p = Quarantine; pct=20;\
+                                     adkim=s; aspf=x; \
+                                     rua=mailto:a@example.com, \
+                                     https://example.com/,\
+                                     mailto:b@example.net!10m").unwrap();
+        assert_eq!(policy,
+                   Policy { domain: b"example.com".to_vec(),
+                            request: Disposition::Quarantine,
+                            subdomain: None, dkim_strict: true,
+                            spf_strict: false, percent: 20,
+                            rua: vec![b"mailto:a@example.com".to_vec(),
+                                      b"mailto:b@example.net!10m".to_vec()]
+                   });
+        assert_eq!(Policy::parse(b"example.com", b"v=DMARC1; pct=200; \
+                                                   rua=mailto:a@example.com")
+                          .map(|policy| (policy.request, policy.percent)),
+                   Some((Disposition::None, 100)));
+        assert_eq!(Policy::parse(b"example.com", b"v=DMARC1; p=bad"), None);
+        assert_eq!(Policy::parse(b"example.com", b"p=none; v=DMARC1"), None);
+        assert_eq!(Policy::parse(b"example.com", b"v=spf1 -all"), None);
+    }
+
+    #[test]
+    fn organizational_domain() {
+        let mut suffixes = Suffixes::new();
+        assert_eq!(suffixes.organizational_domain(b"a.b.Example.co.uk."),
+                   b"co.uk");
+        suffixes.read(&b"// comment\n\
+                         uk\nco.uk\n\
+                         *.ck\n!www.ck\n"[..]).unwrap();
+        assert_eq!(suffixes.organizational_domain(b"a.b.Example.co.uk."),
+                   b"example.co.uk");
+        assert_eq!(suffixes.organizational_domain(b"co.uk"), b"co.uk");
+        assert_eq!(suffixes.organizational_domain(b"a.b.c.ck"), b"b.c.ck");
+        assert_eq!(suffixes.organizational_domain(b"a.www.ck"), b"www.ck");
+        assert_eq!(suffixes.organizational_domain(b"example.org"),
+                   b"example.org");
+        assert!(suffixes.is_aligned(false, b"example.co.uk",
+                                    b"mail.EXAMPLE.co.uk"));
+        assert!(!suffixes.is_aligned(true, b"example.co.uk",
+                                     b"mail.example.co.uk"));
+        assert!(!suffixes.is_aligned(false, b"a.co.uk", b"b.co.uk"));
+    }
+
+    #[test]
+    fn evaluate() {
+        // Relaxed alignment through either SPF or DKIM.
+        let res = check(b"example.com",
+                        Some(spf(spf::Outcome::Pass, b"bounce.example.com")),
+                        &[]);
+        assert_eq!((res.result, res.spf_aligned, res.dkim_aligned),
+                   (Outcome::Pass, true, false));
+        let res = check(b"example.com",
+                        Some(spf(spf::Outcome::Pass, b"example.net")),
+                        &[dkim(verify::Outcome::Fail, b"example.com"),
+                          dkim(verify::Outcome::Pass, b"mail.example.com")]);
+        assert_eq!((res.result, res.spf_aligned, res.dkim_aligned),
+                   (Outcome::Pass, false, true));
+        assert_eq!(res.disposition, Disposition::None);
+
+        // Failing mail from the domain and a subdomain.
+        let res = check(b"example.com",
+                        Some(spf(spf::Outcome::Pass, b"example.net")),
+                        &[dkim(verify::Outcome::Pass, b"example.org")]);
+        assert_eq!((res.result, res.disposition),
+                   (Outcome::Fail, Disposition::Reject));
+        let res = check(b"sub.example.com", None, &[]);
+        assert_eq!((res.result, res.disposition),
+                   (Outcome::Fail, Disposition::Quarantine));
+        assert_eq!(res.policy.unwrap().domain, b"example.com");
+
+        // Strict alignment.
+        let res = check(b"strict.test", None,
+                        &[dkim(verify::Outcome::Pass, b"mail.strict.test")]);
+        assert_eq!(res.result, Outcome::Fail);
+        let res = check(b"strict.test", None,
+                        &[dkim(verify::Outcome::Pass, b"Strict.test")]);
+        assert_eq!(res.result, Outcome::Pass);
+
+        // No usable policy.
+        assert_eq!(check(b"example.org", None, &[]).result, Outcome::None);
+        assert_eq!(check(b"twice.test", None, &[]).result, Outcome::None);
+        let res = Query::new("192.0.2.1".parse().unwrap(), None, None)
+                        .check(&[], &Suffixes::new(), &zone());
+        assert_eq!((res.result, res.disposition),
+                   (Outcome::PermError, Disposition::Reject));
+    }
+
+    #[test]
+    fn sample() {
+        let mut res = check(b"example.com", None, &[]);
+        res.policy.as_mut().unwrap().percent = 10;
+        res.disposition = Disposition::Reject;
+        res.reason = None;
+        res.sample(109);
+        assert_eq!((res.disposition, res.reason),
+                   (Disposition::Reject, None));
+        res.sample(110);
+        assert_eq!((res.disposition, res.reason),
+                   (Disposition::Quarantine, Some(Override::SampledOut)));
+        res.sample(110);
+        assert_eq!(res.disposition, Disposition::None);
+    }
+
+    #[test]
+    fn add_to() {
+        let mut results = AuthResults::new(b"mx.test");
+        check(b"example.com", None, &[]).add_to(&mut results);
+        check(b"example.org", None, &[]).add_to(&mut results);
+        assert_eq!(results.to_field(),
+                   &b"Authentication-Results: mx.test;\r\n\
+                      \tdmarc=fail header.from=example.com \
+                      policy.dmarc=reject;\r\n\
+                      \tdmarc=none header.from=example.org\r\n"[..]);
+    }
+}
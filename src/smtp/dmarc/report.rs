@@ -0,0 +1,907 @@
+//! DMARC aggregate reports.
+//!
+//! Evaluations are recorded in a `Store`, a directory with one file per
+//! policy domain to which a line is appended for each evaluation. Only
+//! evaluations of policies that ask for aggregate reports via the rua
+//! tag are kept.
+//!
+//! Once a reporting interval is over, `Store::collect()` takes all the
+//! recorded evaluations out of the store and turns them into one `Report`
+//! per policy domain. Evaluations with identical results for the same
+//! client end up as a single row with a count. A report can be rendered
+//! as the XML document described in RFC 7489, appendix C, or as a mail
+//! message with that document attached. The `Reporter` takes care of the
+//! whole process, queueing the report messages for delivery.
+//!
+//! The attached XML document is not compressed.
+
+use std::ascii::AsciiExt;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use ::net::dns::Resolver;
+use ::smtp::fs::queue::{Envelope, Queue, Recipient};
+use ::smtp::spf::Identity;
+use ::util::{base64, date};
+use ::util::text::Words;
+use super::{Disposition, Evaluation, Override, Policy, Suffixes,
+            is_mailto};
+
+
+//------------ Store ---------------------------------------------------------
+
+/// The evaluations recorded for reporting.
+#[derive(Clone, Debug)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    /// Opens the store in directory *dir*, creating it if necessary.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        try!(fs::create_dir_all(&dir));
+        Ok(Store { dir: dir.as_ref().into() })
+    }
+
+    /// Records *evaluation* made at *time*.
+    ///
+    /// Evaluations without a policy or for policies that don’t want
+    /// aggregate reports are quietly dropped.
+    pub fn record(&self, evaluation: &Evaluation, time: u64)
+                  -> io::Result<()> {
+        let entry = match Entry::new(evaluation, time) {
+            Some(entry) => entry,
+            None => return Ok(())
+        };
+        let name = match file_name(&entry.policy.domain) {
+            Some(name) => name,
+            None => return Ok(())
+        };
+        let mut file = try!(OpenOptions::new().create(true).append(true)
+                                              .open(self.dir.join(name)));
+        file.write_all(&entry.to_line())
+    }
+
+    /// Takes all recorded evaluations out of the store.
+    ///
+    /// Returns one report for each policy domain covering the time from
+    /// the first evaluation recorded for it until *end*.
+    pub fn collect(&self, end: u64) -> io::Result<Vec<Report>> {
+        let mut res = Vec::new();
+        for item in try!(fs::read_dir(&self.dir)) {
+            let path = try!(item).path();
+            // Files are moved aside first so that evaluations recorded
+            // while we are reading go into a fresh file.
+            let path = if path.extension() == Some(OsStr::new("taken")) {
+                path
+            }
+            else {
+                let mut name = match path.file_name() {
+                    Some(name) => OsString::from(name),
+                    None => continue
+                };
+                name.push(".taken");
+                let taken = path.with_file_name(name);
+                try!(fs::rename(&path, &taken));
+                taken
+            };
+            let mut entries = Vec::new();
+            for line in BufReader::new(try!(File::open(&path)))
+                                  .split(b'\n') {
+                let line = try!(line);
+                match Entry::parse(&line) {
+                    Some(entry) => entries.push(entry),
+                    None => {
+                        warn!("DMARC: skipping broken line in {}",
+                              path.display())
+                    }
+                }
+            }
+            if let Some(report) = Report::new(entries, end) {
+                res.push(report)
+            }
+            try!(fs::remove_file(&path));
+        }
+        Ok(res)
+    }
+}
+
+
+//------------ Reporter ------------------------------------------------------
+
+/// The sender of aggregate reports.
+#[derive(Clone, Debug)]
+pub struct Reporter {
+    /// The name of our organization.
+    org_name: Vec<u8>,
+
+    /// The address reports are sent from.
+    email: Vec<u8>,
+
+    /// The suffixes for checking report destinations.
+    suffixes: Suffixes,
+}
+
+impl Reporter {
+    pub fn new(org_name: &[u8], email: &[u8], suffixes: Suffixes) -> Self {
+        Reporter { org_name: org_name.into(), email: email.into(),
+                   suffixes: suffixes }
+    }
+
+    /// Returns the domain of the address reports are sent from.
+    fn domain(&self) -> &[u8] {
+        match self.email.iter().rposition(|ch| *ch == b'@') {
+            Some(pos) => &self.email[pos + 1..],
+            None => &self.email[..]
+        }
+    }
+
+    /// Collects the reports from *store* and queues them to *queue*.
+    ///
+    /// Destinations outside of a policy’s domain are checked via
+    /// *resolver*. Failing to queue a report is logged but otherwise
+    /// ignored.
+    pub fn send(&self, store: &Store, queue: &Queue, resolver: &Resolver)
+                -> io::Result<()> {
+        let now = date::now();
+        for report in try!(store.collect(now)) {
+            let envelope = match report.envelope(self, resolver, now) {
+                Some(envelope) => envelope,
+                None => {
+                    info!("DMARC: no destination for report on {}",
+                          String::from_utf8_lossy(report.domain()));
+                    continue
+                }
+            };
+            let res = queue.create().and_then(|mut spool| {
+                let res = report.write(self, &envelope, &mut spool, now)
+                                .and_then(|_| spool.flush());
+                match res {
+                    Ok(()) => spool.commit(&envelope),
+                    Err(err) => {
+                        let _ = spool.abort();
+                        Err(err)
+                    }
+                }
+            });
+            match res {
+                Ok(id) => {
+                    info!("DMARC: report on {} queued as {}",
+                          String::from_utf8_lossy(report.domain()), id)
+                }
+                Err(err) => {
+                    error!("DMARC: report on {} failed: {}",
+                           String::from_utf8_lossy(report.domain()), err)
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+//------------ Report --------------------------------------------------------
+
+/// An aggregate report for one policy domain.
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// The policy as last seen.
+    pub policy: Policy,
+
+    /// The start of the reporting interval in seconds since the epoch.
+    pub begin: u64,
+
+    /// The end of the reporting interval in seconds since the epoch.
+    pub end: u64,
+
+    /// The rows of the report with their count.
+    rows: Vec<(Row, u64)>,
+}
+
+impl Report {
+    /// Creates a report from *entries* for the same policy domain.
+    fn new(entries: Vec<Entry>, end: u64) -> Option<Self> {
+        let mut res = match entries.last() {
+            Some(entry) => {
+                Report { policy: entry.policy.clone(), begin: entry.time,
+                         end: end, rows: Vec::new() }
+            }
+            None => return None
+        };
+        for entry in entries {
+            res.begin = ::std::cmp::min(res.begin, entry.time);
+            let pos = res.rows.iter().position(|&(ref row, _)| {
+                *row == entry.row
+            });
+            match pos {
+                Some(pos) => res.rows[pos].1 += 1,
+                None => res.rows.push((entry.row, 1))
+            }
+        }
+        Some(res)
+    }
+
+    /// Returns the domain the report is about.
+    pub fn domain(&self) -> &[u8] {
+        &self.policy.domain
+    }
+
+    /// Returns the number of messages covered by the report.
+    pub fn count(&self) -> u64 {
+        self.rows.iter().fold(0, |res, &(_, count)| res + count)
+    }
+
+    /// Returns the report ID.
+    pub fn id(&self) -> String {
+        format!("{}.{}.{}", String::from_utf8_lossy(self.domain()),
+                self.begin, self.end)
+    }
+
+    /// Returns the envelope for sending the report.
+    ///
+    /// The recipients are the mailto addresses of the policy’s rua tag.
+    /// Addresses outside the policy’s organizational domain are only used
+    /// if their domain agrees to receive reports as described in RFC 7489,
+    /// section 7.1. Addresses with a size limit smaller than the report
+    /// message written at *now* are dropped, too. Returns `None` if there
+    /// are no recipients left.
+    pub fn envelope(&self, reporter: &Reporter, resolver: &Resolver,
+                    now: u64) -> Option<Envelope> {
+        let mut res = Envelope::new(reporter.email.clone());
+        let mut limits = Vec::new();
+        for (addr, limit) in self.addrs() {
+            let rcpt = Recipient::new(addr.into());
+            if rcpt.domain().is_empty()
+                    || !self.is_destination(rcpt.domain(), reporter,
+                                            resolver) {
+                continue
+            }
+            if !res.recipients.iter().any(|item| item.path == rcpt.path) {
+                res.recipients.push(rcpt);
+                limits.push(limit);
+            }
+        }
+
+        // Dropping recipients only makes the message smaller, so the
+        // size with all of them in the To field is good enough.
+        let mut message = Vec::new();
+        if self.write(reporter, &res, &mut message, now).is_err() {
+            return None
+        }
+        let size = message.len() as u64;
+        res.recipients = res.recipients.into_iter().zip(limits)
+                            .filter(|&(_, limit)| {
+                                limit.map_or(true, |limit| size <= limit)
+                            })
+                            .map(|(rcpt, _)| rcpt).collect();
+        if res.recipients.is_empty() { None } else { Some(res) }
+    }
+
+    /// Returns the addresses of the policy’s mailto URIs.
+    ///
+    /// Each address comes with its size limit if there is one. A limit
+    /// that can’t be parsed is ignored.
+    fn addrs(&self) -> Vec<(&[u8], Option<u64>)> {
+        self.policy.rua.iter().filter(|uri| is_mailto(uri)).map(|uri| {
+            // Drop the scheme and header fields.
+            let addr = &uri[7..];
+            let addr = match addr.iter().position(|ch| *ch == b'?') {
+                Some(end) => &addr[..end],
+                None => addr
+            };
+            match addr.iter().position(|ch| *ch == b'!') {
+                Some(end) => (&addr[..end], size_limit(&addr[end + 1..])),
+                None => (addr, None)
+            }
+        }).collect()
+    }
+
+    /// Returns whether reports for our domain may be sent to *domain*.
+    fn is_destination(&self, domain: &[u8], reporter: &Reporter,
+                      resolver: &Resolver) -> bool {
+        if reporter.suffixes.is_aligned(false, self.domain(), domain) {
+            return true
+        }
+        let mut name = self.domain().to_vec();
+        name.extend_from_slice(b"._report._dmarc.");
+        name.extend_from_slice(domain);
+        match resolver.lookup_txt(&name) {
+            Ok(records) => {
+                records.iter().any(|record| record.starts_with(b"v=DMARC1"))
+            }
+            Err(_) => false
+        }
+    }
+
+    /// Writes the report message.
+    ///
+    /// The message is addressed to the recipients of *envelope*.
+    pub fn write<W: Write>(&self, reporter: &Reporter, envelope: &Envelope,
+                           target: &mut W, now: u64) -> io::Result<()> {
+        let boundary = format!("={}", self.id());
+        let domain = String::from_utf8_lossy(self.domain());
+        let org_name = String::from_utf8_lossy(&reporter.org_name);
+        try!(target.write_all(b"From: "));
+        try!(target.write_all(&reporter.email));
+        try!(target.write_all(b"\r\nTo: "));
+        for (i, rcpt) in envelope.recipients.iter().enumerate() {
+            if i > 0 {
+                try!(target.write_all(b",\r\n\t"));
+            }
+            try!(target.write_all(&rcpt.path));
+        }
+        try!(write!(target, "\r\nSubject: Report Domain: {} \
+                             Submitter: {}\r\n\tReport-ID: <{}>\r\n",
+                    domain, org_name, self.id()));
+        try!(write!(target, "Date: {}\r\n", date::rfc5322(now)));
+        try!(write!(target, "Message-ID: <{}@", self.id()));
+        try!(target.write_all(reporter.domain()));
+        try!(target.write_all(b">\r\n\
+                                Auto-Submitted: auto-generated\r\n\
+                                MIME-Version: 1.0\r\n"));
+        try!(write!(target, "Content-Type: multipart/mixed;\r\n\
+                             \tboundary=\"{}\"\r\n\r\n\
+                             This is a MIME-encapsulated message.\r\n\r\n",
+                    boundary));
+
+        try!(write!(target, "--{}\r\n", boundary));
+        try!(target.write_all(b"Content-Type: text/plain; \
+                                charset=us-ascii\r\n\r\n"));
+        try!(write!(target, "This is an aggregate report from {} for {} \
+                             covering {} messages.\r\n",
+                    org_name, domain, self.count()));
+
+        try!(write!(target, "\r\n--{}\r\n", boundary));
+        try!(target.write_all(b"Content-Type: text/xml\r\n\
+                                Content-Disposition: attachment;\r\n\
+                                \tfilename=\""));
+        try!(target.write_all(reporter.domain()));
+        try!(write!(target, "!{}!{}!{}.xml\"\r\n\
+                             Content-Transfer-Encoding: base64\r\n\r\n",
+                    domain, self.begin, self.end));
+        for line in base64::encode(&self.to_xml(reporter)).chunks(76) {
+            try!(target.write_all(line));
+            try!(target.write_all(b"\r\n"));
+        }
+        write!(target, "\r\n--{}--\r\n", boundary)
+    }
+
+    /// Returns the report as an XML document.
+    pub fn to_xml(&self, reporter: &Reporter) -> Vec<u8> {
+        let mut res = String::new();
+        res.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                      <feedback>\n  <version>1.0</version>\n");
+        res.push_str("  <report_metadata>\n");
+        element(&mut res, 4, "org_name", &reporter.org_name);
+        element(&mut res, 4, "email", &reporter.email);
+        element(&mut res, 4, "report_id", self.id().as_bytes());
+        res.push_str("    <date_range>\n");
+        element(&mut res, 6, "begin", self.begin.to_string().as_bytes());
+        element(&mut res, 6, "end", self.end.to_string().as_bytes());
+        res.push_str("    </date_range>\n  </report_metadata>\n");
+
+        let policy = &self.policy;
+        res.push_str("  <policy_published>\n");
+        element(&mut res, 4, "domain", &policy.domain);
+        element(&mut res, 4, "adkim", alignment(policy.dkim_strict));
+        element(&mut res, 4, "aspf", alignment(policy.spf_strict));
+        element(&mut res, 4, "p", policy.request.as_str().as_bytes());
+        element(&mut res, 4, "sp", policy.subdomain.unwrap_or(policy.request)
+                                         .as_str().as_bytes());
+        element(&mut res, 4, "pct", policy.percent.to_string().as_bytes());
+        res.push_str("  </policy_published>\n");
+
+        for &(ref row, count) in &self.rows {
+            row.to_xml(&mut res, count)
+        }
+        res.push_str("</feedback>\n");
+        res.into_bytes()
+    }
+}
+
+
+//------------ Row -----------------------------------------------------------
+
+/// The results for some messages from one client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Row {
+    ip: IpAddr,
+
+    /// The domain of the From field.
+    header_from: Vec<u8>,
+
+    disposition: Disposition,
+    reason: Option<Override>,
+
+    /// Did DKIM and SPF give an aligned pass?
+    dkim: bool,
+    spf: bool,
+
+    /// The scope, result, and domain of the SPF check.
+    spf_result: Option<(Vec<u8>, Vec<u8>, Vec<u8>)>,
+
+    /// The result, selector, and domain of each DKIM signature.
+    dkim_results: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)>,
+}
+
+impl Row {
+    fn to_xml(&self, target: &mut String, count: u64) {
+        target.push_str("  <record>\n    <row>\n");
+        element(target, 6, "source_ip", self.ip.to_string().as_bytes());
+        element(target, 6, "count", count.to_string().as_bytes());
+        target.push_str("      <policy_evaluated>\n");
+        element(target, 8, "disposition",
+                self.disposition.as_str().as_bytes());
+        element(target, 8, "dkim", pass_fail(self.dkim));
+        element(target, 8, "spf", pass_fail(self.spf));
+        if let Some(reason) = self.reason {
+            target.push_str("        <reason>\n");
+            element(target, 10, "type", reason.as_str().as_bytes());
+            target.push_str("        </reason>\n");
+        }
+        target.push_str("      </policy_evaluated>\n    </row>\n");
+        target.push_str("    <identifiers>\n");
+        element(target, 6, "header_from", &self.header_from);
+        target.push_str("    </identifiers>\n    <auth_results>\n");
+        for &(ref result, ref selector, ref domain) in &self.dkim_results {
+            target.push_str("      <dkim>\n");
+            element(target, 8, "domain", domain);
+            element(target, 8, "selector", selector);
+            element(target, 8, "result", result);
+            target.push_str("      </dkim>\n");
+        }
+        // The schema insists on an SPF result.
+        target.push_str("      <spf>\n");
+        match self.spf_result {
+            Some((ref scope, ref result, ref domain)) => {
+                element(target, 8, "domain", domain);
+                element(target, 8, "scope", scope);
+                element(target, 8, "result", result);
+            }
+            None => {
+                element(target, 8, "domain", b"");
+                element(target, 8, "result", b"none");
+            }
+        }
+        target.push_str("      </spf>\n    </auth_results>\n  </record>\n");
+    }
+}
+
+
+//------------ Entry ---------------------------------------------------------
+
+/// An evaluation as kept in the store.
+///
+/// Each entry is stored as a line of white space separated words: the
+/// time, client address, From domain, disposition, override reason,
+/// aligned DKIM and SPF results, the policy’s domain, p, sp, adkim, aspf,
+/// pct, and rua, followed by an `spf=scope,result,domain` word if there
+/// was an SPF check and a `dkim=result,selector,domain` word for each
+/// signature. Missing values are given as `-`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    time: u64,
+    policy: Policy,
+    row: Row,
+}
+
+impl Entry {
+    fn new(evaluation: &Evaluation, time: u64) -> Option<Self> {
+        let policy = match evaluation.policy {
+            Some(ref policy) if !policy.rua.is_empty() => policy.clone(),
+            _ => return None
+        };
+        let spf_result = evaluation.spf.as_ref().map(|spf| {
+            let scope = match spf.identity {
+                Identity::Helo => "helo",
+                Identity::MailFrom => "mfrom",
+            };
+            (scope.as_bytes().to_vec(),
+             spf.result.as_str().as_bytes().to_vec(),
+             spf.domain.to_ascii_lowercase())
+        });
+        let dkim_results = evaluation.dkim.iter().map(|item| {
+            (item.result.as_str().as_bytes().to_vec(),
+             item.selector.clone(), item.domain.to_ascii_lowercase())
+        }).collect();
+        Some(Entry {
+            time: time, policy: policy,
+            row: Row {
+                ip: evaluation.ip, header_from: evaluation.domain.clone(),
+                disposition: evaluation.disposition,
+                reason: evaluation.reason, dkim: evaluation.dkim_aligned,
+                spf: evaluation.spf_aligned, spf_result: spf_result,
+                dkim_results: dkim_results
+            }
+        })
+    }
+
+    fn to_line(&self) -> Vec<u8> {
+        let row = &self.row;
+        let policy = &self.policy;
+        let mut res = format!("{} {} ", self.time, row.ip).into_bytes();
+        push_word(&mut res, &row.header_from);
+        res.push(b' ');
+        res.extend_from_slice(row.disposition.as_str().as_bytes());
+        res.push(b' ');
+        res.extend_from_slice(row.reason.map_or("-", Override::as_str)
+                                 .as_bytes());
+        res.push(b' ');
+        res.extend_from_slice(pass_fail(row.dkim));
+        res.push(b' ');
+        res.extend_from_slice(pass_fail(row.spf));
+        res.push(b' ');
+        push_word(&mut res, &policy.domain);
+        res.push(b' ');
+        res.extend_from_slice(policy.request.as_str().as_bytes());
+        res.push(b' ');
+        res.extend_from_slice(policy.subdomain
+                                    .map_or("-", Disposition::as_str)
+                                    .as_bytes());
+        res.push(b' ');
+        res.extend_from_slice(alignment(policy.dkim_strict));
+        res.push(b' ');
+        res.extend_from_slice(alignment(policy.spf_strict));
+        res.extend_from_slice(format!(" {} ", policy.percent).as_bytes());
+        for (i, uri) in policy.rua.iter().enumerate() {
+            if i > 0 {
+                res.push(b',');
+            }
+            push_word(&mut res, uri);
+        }
+        if let Some((ref scope, ref result, ref domain)) = row.spf_result {
+            res.extend_from_slice(b" spf=");
+            push_word(&mut res, scope);
+            res.push(b',');
+            push_word(&mut res, result);
+            res.push(b',');
+            push_word(&mut res, domain);
+        }
+        for &(ref result, ref selector, ref domain) in &row.dkim_results {
+            res.extend_from_slice(b" dkim=");
+            push_word(&mut res, result);
+            res.push(b',');
+            push_word(&mut res, selector);
+            res.push(b',');
+            push_word(&mut res, domain);
+        }
+        res.push(b'\n');
+        res
+    }
+
+    fn parse(line: &[u8]) -> Option<Self> {
+        let words: Vec<&[u8]> = Words::new(line).collect();
+        if words.len() < 14 {
+            return None
+        }
+        let mut row = Row {
+            ip: match parse_str(words[1]) {
+                Some(ip) => ip,
+                None => return None
+            },
+            header_from: word(words[2]),
+            disposition: match Disposition::from_bytes(words[3]) {
+                Some(disposition) => disposition,
+                None => return None
+            },
+            reason: Override::from_bytes(words[4]),
+            dkim: words[5] == b"pass",
+            spf: words[6] == b"pass",
+            spf_result: None,
+            dkim_results: Vec::new()
+        };
+        let policy = Policy {
+            domain: word(words[7]),
+            request: match Disposition::from_bytes(words[8]) {
+                Some(request) => request,
+                None => return None
+            },
+            subdomain: Disposition::from_bytes(words[9]),
+            dkim_strict: words[10] == b"s",
+            spf_strict: words[11] == b"s",
+            percent: match parse_str(words[12]) {
+                Some(percent) => percent,
+                None => return None
+            },
+            rua: words[13].split(|ch| *ch == b',').map(word).collect()
+        };
+        for item in &words[14..] {
+            let (name, value) = match item.iter().position(|ch| *ch == b'=') {
+                Some(pos) => (&item[..pos], &item[pos + 1..]),
+                None => return None
+            };
+            let mut parts = value.splitn(3, |ch| *ch == b',').map(word);
+            let part = (parts.next(), parts.next(), parts.next());
+            let part = match part {
+                (Some(a), Some(b), Some(c)) => (a, b, c),
+                _ => return None
+            };
+            if name == b"spf" {
+                row.spf_result = Some(part)
+            }
+            else if name == b"dkim" {
+                row.dkim_results.push(part)
+            }
+            else {
+                return None
+            }
+        }
+        Some(Entry {
+            time: match parse_str(words[0]) {
+                Some(time) => time,
+                None => return None
+            },
+            policy: policy, row: row
+        })
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the name of the store file for *domain*.
+///
+/// Returns `None` if the domain would make a bad file name.
+fn file_name(domain: &[u8]) -> Option<String> {
+    if domain.is_empty() || domain[0] == b'.' || !domain.iter().all(|&ch| {
+        ch == b'-' || ch == b'.' || ch == b'_' || (ch >= b'0' && ch <= b'9')
+            || (ch >= b'a' && ch <= b'z')
+    }) {
+        return None
+    }
+    String::from_utf8(domain.into()).ok()
+}
+
+/// Parses the size limit of a report URI.
+///
+/// The limit is a number of octets, optionally followed by a unit of
+/// kibi-, mebi-, gibi-, or tebioctets as described in RFC 7489, section
+/// 6.2.
+fn size_limit(limit: &[u8]) -> Option<u64> {
+    let (digits, shift) = match limit.last() {
+        Some(&b'k') | Some(&b'K') => (&limit[..limit.len() - 1], 10),
+        Some(&b'm') | Some(&b'M') => (&limit[..limit.len() - 1], 20),
+        Some(&b'g') | Some(&b'G') => (&limit[..limit.len() - 1], 30),
+        Some(&b't') | Some(&b'T') => (&limit[..limit.len() - 1], 40),
+        _ => (limit, 0)
+    };
+    if digits.is_empty()
+            || !digits.iter().all(|&ch| ch >= b'0' && ch <= b'9') {
+        return None
+    }
+    parse_str::<u64>(digits).and_then(|value| {
+        value.checked_mul(1 << shift)
+    })
+}
+
+/// Appends *value* as a word of a store line.
+///
+/// Characters that would break up the word are replaced.
+fn push_word(target: &mut Vec<u8>, value: &[u8]) {
+    if value.is_empty() {
+        return target.push(b'-')
+    }
+    for &ch in value {
+        if ch <= b' ' || ch == b',' || ch == b'#' || ch == 0x7f {
+            target.push(b'?')
+        }
+        else {
+            target.push(ch)
+        }
+    }
+}
+
+/// Returns the value of a word of a store line.
+fn word(value: &[u8]) -> Vec<u8> {
+    if value == b"-" { Vec::new() } else { value.into() }
+}
+
+fn parse_str<T: ::std::str::FromStr>(value: &[u8]) -> Option<T> {
+    match ::std::str::from_utf8(value) {
+        Ok(value) => value.parse().ok(),
+        Err(_) => None
+    }
+}
+
+fn pass_fail(pass: bool) -> &'static [u8] {
+    if pass { b"pass" } else { b"fail" }
+}
+
+fn alignment(strict: bool) -> &'static [u8] {
+    if strict { b"s" } else { b"r" }
+}
+
+/// Appends an XML element *name* with content *value*.
+fn element(target: &mut String, indent: usize, name: &str, value: &[u8]) {
+    for _ in 0..indent {
+        target.push(' ');
+    }
+    target.push('<');
+    target.push_str(name);
+    target.push('>');
+    for ch in String::from_utf8_lossy(value).chars() {
+        match ch {
+            '&' => target.push_str("&amp;"),
+            '<' => target.push_str("&lt;"),
+            '>' => target.push_str("&gt;"),
+            _ => target.push(ch)
+        }
+    }
+    target.push_str("</");
+    target.push_str(name);
+    target.push_str(">\n");
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use ::net::dns::{Record, Zone};
+    use ::smtp::dkim::verify::{self, Verification};
+    use ::smtp::dmarc::{Disposition, Evaluation, Outcome, Override, Policy,
+                        Suffixes};
+    use ::smtp::spf::{self, Identity, Spf};
+    use ::util::test::TempDir;
+    use super::*;
+
+    fn evaluation(ip: &str, dkim: verify::Outcome) -> Evaluation {
+        let ip: IpAddr = ip.parse().unwrap();
+        let policy = Policy::parse(b"example.com",
+                                   b"v=DMARC1; p=reject; \
+                                     rua=mailto:dmarc@example.com,\
+                                     mailto:dmarc@example.net!10m,\
+                                     mailto:dmarc@example.org").unwrap();
+        let pass = dkim == verify::Outcome::Pass;
+        Evaluation {
+            result: if pass { Outcome::Pass } else { Outcome::Fail },
+            ip: ip, domain: b"example.com".to_vec(), policy: Some(policy),
+            disposition: if pass { Disposition::None }
+                         else { Disposition::Quarantine },
+            reason: if pass { None } else { Some(Override::SampledOut) },
+            dkim_aligned: pass, spf_aligned: false,
+            spf: Some(Spf { result: spf::Outcome::SoftFail,
+                            identity: Identity::MailFrom, ip: ip,
+                            sender: b"a@example.net".to_vec(),
+                            envelope_from: b"a@example.net".to_vec(),
+                            helo: b"client.test".to_vec(),
+                            domain: b"example.net".to_vec(),
+                            explanation: None }),
+            dkim: vec![Verification { result: dkim,
+                                      domain: b"example.com".to_vec(),
+                                      selector: b"sel".to_vec(),
+                                      b: Vec::new(), reason: None }]
+        }
+    }
+
+    fn reporter() -> Reporter {
+        Reporter::new(b"Test & Co", b"dmarc@mx.test", Suffixes::new())
+    }
+
+    #[test]
+    fn entry() {
+        let entry = Entry::new(&evaluation("192.0.2.1",
+                                           verify::Outcome::Fail), 1234)
+                          .unwrap();
+        let line = entry.to_line();
+        assert_eq!(line,
+                   &b"1234 192.0.2.1 example.com quarantine sampled_out \
+                      fail fail example.com reject - r r 100 \
+                      mailto:dmarc@example.com,mailto:dmarc@example.net!10m,\
+                      mailto:dmarc@example.org \
+                      spf=mfrom,softfail,example.net \
+                      dkim=fail,sel,example.com\n"[..]);
+        assert_eq!(Entry::parse(&line[..line.len() - 1]), Some(entry));
+        assert_eq!(Entry::parse(b"1234 192.0.2.1 example.com"), None);
+
+        let mut evaluation = evaluation("192.0.2.1", verify::Outcome::Pass);
+        evaluation.policy.as_mut().unwrap().rua.clear();
+        assert_eq!(Entry::new(&evaluation, 1234), None);
+    }
+
+    #[test]
+    fn collect() {
+        let dir = TempDir::new("dmarc-store");
+        let store = Store::open(&dir).unwrap();
+        store.record(&evaluation("192.0.2.1", verify::Outcome::Pass), 200)
+             .unwrap();
+        store.record(&evaluation("192.0.2.1", verify::Outcome::Pass), 100)
+             .unwrap();
+        store.record(&evaluation("2001:db8::1", verify::Outcome::Fail), 300)
+             .unwrap();
+        let reports = store.collect(400).unwrap();
+        assert!(store.collect(500).unwrap().is_empty());
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert_eq!((report.begin, report.end, report.count()),
+                   (100, 400, 3));
+        assert_eq!(report.rows.len(), 2);
+        assert_eq!(report.rows[0].1, 2);
+
+        let xml = String::from_utf8(report.to_xml(&reporter())).unwrap();
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                                 <feedback>\n  <version>1.0</version>\n  \
+                                 <report_metadata>\n    \
+                                 <org_name>Test &amp; Co</org_name>\n"));
+        assert!(xml.contains("    <report_id>example.com.100.400\
+                              </report_id>\n"));
+        assert!(xml.contains("    <p>reject</p>\n    <sp>reject</sp>\n"));
+        assert!(xml.contains("      <source_ip>192.0.2.1</source_ip>\n      \
+                              <count>2</count>\n"));
+        assert!(xml.contains("        <disposition>quarantine</disposition>\
+                              \n        <dkim>fail</dkim>\n        \
+                              <spf>fail</spf>\n        <reason>\n          \
+                              <type>sampled_out</type>\n"));
+        assert!(xml.contains("      <spf>\n        \
+                              <domain>example.net</domain>\n        \
+                              <scope>mfrom</scope>\n        \
+                              <result>softfail</result>\n"));
+        assert!(xml.ends_with("</record>\n</feedback>\n"));
+    }
+
+    #[test]
+    fn message() {
+        let report = Report::new(vec![
+            Entry::new(&evaluation("192.0.2.1", verify::Outcome::Pass), 100)
+                  .unwrap()
+        ], 200).unwrap();
+
+        // Only example.net agrees to receive reports for example.com.
+        let mut zone = Zone::new();
+        zone.insert(b"example.com._report._dmarc.example.net",
+                    Record::Txt(b"v=DMARC1".to_vec()));
+        let envelope = report.envelope(&reporter(), &zone, 1234567890)
+                             .unwrap();
+        assert_eq!(envelope.reverse_path, b"dmarc@mx.test");
+        let rcpts: Vec<_> = envelope.recipients.iter()
+                                    .map(|rcpt| &rcpt.path[..]).collect();
+        assert_eq!(rcpts, [&b"dmarc@example.com"[..],
+                           &b"dmarc@example.net"[..]]);
+
+        let mut message = Vec::new();
+        report.write(&reporter(), &envelope, &mut message, 1234567890)
+              .unwrap();
+        let message = String::from_utf8(message).unwrap();
+        assert!(message.starts_with("From: dmarc@mx.test\r\n\
+                                     To: dmarc@example.com,\r\n\
+                                     \tdmarc@example.net\r\n\
+                                     Subject: Report Domain: example.com \
+                                     Submitter: Test & Co\r\n\
+                                     \tReport-ID: <example.com.100.200>\r\n\
+                                     Date: Fri, 13 Feb 2009 23:31:30 \
+                                     +0000\r\n"));
+        assert!(message.contains("\tfilename=\"mx.test!example.com!100!200\
+                                  .xml\"\r\n"));
+        assert!(message.ends_with("\r\n--=example.com.100.200--\r\n"));
+    }
+
+    #[test]
+    fn size_limit() {
+        let mut report = Report::new(vec![
+            Entry::new(&evaluation("192.0.2.1", verify::Outcome::Pass), 100)
+                  .unwrap()
+        ], 200).unwrap();
+        report.policy.rua = vec![b"mailto:small@example.com!1k".to_vec(),
+                                 b"mailto:large@example.com!1M".to_vec(),
+                                 b"mailto:any@example.com!x".to_vec()];
+        let envelope = report.envelope(&reporter(), &Zone::new(), 100)
+                             .unwrap();
+        let rcpts: Vec<_> = envelope.recipients.iter()
+                                    .map(|rcpt| &rcpt.path[..]).collect();
+        assert_eq!(rcpts, [&b"large@example.com"[..],
+                           &b"any@example.com"[..]]);
+
+        report.policy.rua.truncate(1);
+        assert!(report.envelope(&reporter(), &Zone::new(), 100).is_none());
+
+        assert_eq!(super::size_limit(b"100"), Some(100));
+        assert_eq!(super::size_limit(b"2k"), Some(2048));
+        assert_eq!(super::size_limit(b"10m"), Some(10 << 20));
+        assert_eq!(super::size_limit(b"k"), None);
+        assert_eq!(super::size_limit(b"-1"), None);
+        assert_eq!(super::size_limit(b"99999999999999t"), None);
+    }
+}
@@ -0,0 +1,306 @@
+//! Dot-stuffing of message data.
+//!
+//! When transferring a message with DATA, a line consisting of a single
+//! period marks the end of the message. Lines of the message that start
+//! with a period get an extra period prepended. See RFC 5321, section
+//! 4.5.2 for the details.
+
+use ::util::scribe::Scribe;
+
+//------------ Unstuffer -----------------------------------------------------
+
+/// A streaming decoder for dot-stuffed message data.
+///
+/// The decoder is fed whatever data arrives and writes the unstuffed
+/// message into an output buffer until it encounters the terminating
+/// line. It starts out at the beginning of a line, so a terminator
+/// right after the DATA command is recognized, too.
+///
+/// Only CRLF counts as a line ending. Bare LF and bare CR characters are
+/// passed on as they are, but are remembered so the receiver can decide
+/// what to do about them.
+///
+#[derive(Debug)]
+pub struct Unstuffer {
+    state: State,
+    bare_lf: bool,
+    bare_cr: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// At the start of a line.
+    LineStart,
+
+    /// Seen a period at the start of a line.
+    Dot,
+
+    /// Seen a period and a CR at the start of a line.
+    DotCr,
+
+    /// Somewhere within a line.
+    Text,
+
+    /// Seen a CR within a line.
+    Cr,
+}
+
+impl Unstuffer {
+    pub fn new() -> Self {
+        Unstuffer { state: State::LineStart, bare_lf: false, bare_cr: false }
+    }
+
+    /// Has the data contained a bare LF so far?
+    pub fn bare_lf(&self) -> bool {
+        self.bare_lf
+    }
+
+    /// Has the data contained a bare CR so far?
+    pub fn bare_cr(&self) -> bool {
+        self.bare_cr
+    }
+
+    /// Decodes *input* appending the unstuffed data to *output*.
+    ///
+    /// If the terminating line was found, returns the number of octets
+    /// of *input* up to and including the terminator. Anything after it
+    /// is not part of the message. Otherwise returns `None` and all of
+    /// *input* has been consumed.
+    ///
+    pub fn decode(&mut self, input: &[u8], output: &mut Vec<u8>)
+                  -> Option<usize> {
+        let mut pos = 0;
+        while pos < input.len() {
+            // Fast path: copy everything up to the next CR or LF.
+            if self.state == State::Text {
+                let len = input[pos..].iter()
+                                      .position(|ch| *ch == b'\r' ||
+                                                     *ch == b'\n')
+                                      .unwrap_or(input.len() - pos);
+                output.extend_from_slice(&input[pos..pos + len]);
+                pos += len;
+                if pos == input.len() {
+                    break
+                }
+            }
+            let ch = input[pos];
+            pos += 1;
+            match self.state {
+                State::LineStart => {
+                    if ch == b'.' { self.state = State::Dot }
+                    else { self.text(ch, output) }
+                }
+                State::Dot => {
+                    // Drop the period. If this was ".." the second period
+                    // is data.
+                    if ch == b'\r' { self.state = State::DotCr }
+                    else { self.text(ch, output) }
+                }
+                State::DotCr => {
+                    if ch == b'\n' {
+                        self.state = State::LineStart;
+                        return Some(pos)
+                    }
+                    self.bare_cr = true;
+                    output.push(b'\r');
+                    self.text(ch, output)
+                }
+                State::Text => self.text(ch, output),
+                State::Cr => {
+                    if ch == b'\n' {
+                        output.extend_from_slice(b"\r\n");
+                        self.state = State::LineStart;
+                    }
+                    else {
+                        self.bare_cr = true;
+                        output.push(b'\r');
+                        self.text(ch, output)
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Processes a character within a line.
+    fn text(&mut self, ch: u8, output: &mut Vec<u8>) {
+        match ch {
+            b'\r' => self.state = State::Cr,
+            b'\n' => {
+                self.bare_lf = true;
+                output.push(ch);
+                self.state = State::Text;
+            }
+            _ => {
+                output.push(ch);
+                self.state = State::Text;
+            }
+        }
+    }
+}
+
+impl Default for Unstuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+//------------ Stuffer -------------------------------------------------------
+
+/// A streaming encoder that dot-stuffs message data.
+///
+/// The encoder is fed the message in whatever pieces are convenient and
+/// writes the stuffed data to a scribe. Once the message is complete,
+/// `finish()` adds the terminating line.
+///
+/// Bare LF and bare CR characters are turned into CRLF. Otherwise, a
+/// server that takes them for line endings could find the end of data
+/// in the middle of the message.
+///
+#[derive(Debug)]
+pub struct Stuffer {
+    /// Are we at the start of a line?
+    line_start: bool,
+
+    /// Was the last octet a CR?
+    cr: bool,
+}
+
+impl Stuffer {
+    pub fn new() -> Self {
+        Stuffer { line_start: true, cr: false }
+    }
+
+    /// Encodes *input* appending the stuffed data to *output*.
+    pub fn encode<S: Scribe>(&mut self, input: &[u8], output: &mut S) {
+        let mut start = 0;
+        for (pos, &ch) in input.iter().enumerate() {
+            if self.cr && ch != b'\n' {
+                // Bare CR: add the missing LF.
+                output.scribble_bytes(&input[start..pos]);
+                output.scribble_octet(b'\n');
+                start = pos;
+                self.line_start = true;
+            }
+            else if !self.cr && ch == b'\n' {
+                // Bare LF: add the missing CR.
+                output.scribble_bytes(&input[start..pos]);
+                output.scribble_octet(b'\r');
+                start = pos;
+            }
+            if self.line_start && ch == b'.' {
+                output.scribble_bytes(&input[start..pos]);
+                output.scribble_octet(b'.');
+                start = pos;
+            }
+            self.line_start = ch == b'\n';
+            self.cr = ch == b'\r';
+        }
+        output.scribble_bytes(&input[start..]);
+    }
+
+    /// Ends the message.
+    ///
+    /// If the message didn’t end in a line break, one is added before the
+    /// terminating line.
+    pub fn finish<S: Scribe>(self, output: &mut S) {
+        if self.cr {
+            output.scribble_octet(b'\n');
+        }
+        else if !self.line_start {
+            output.scribble_bytes(b"\r\n");
+        }
+        output.scribble_bytes(b".\r\n");
+    }
+}
+
+impl Default for Stuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unstuff(parts: &[&[u8]]) -> (Vec<u8>, Option<usize>) {
+        let mut unstuffer = Unstuffer::new();
+        let mut output = Vec::new();
+        for part in parts {
+            if let Some(len) = unstuffer.decode(part, &mut output) {
+                return (output, Some(len))
+            }
+        }
+        (output, None)
+    }
+
+    #[test]
+    fn unstuff_good() {
+        assert_eq!(unstuff(&[b"foo\r\nbar\r\n.\r\nQUIT\r\n"]),
+                   (b"foo\r\nbar\r\n".to_vec(), Some(13)));
+        assert_eq!(unstuff(&[b".\r\n"]), (b"".to_vec(), Some(3)));
+        assert_eq!(unstuff(&[b"..foo\r\n..\r\n.\r\n"]),
+                   (b".foo\r\n.\r\n".to_vec(), Some(14)));
+        assert_eq!(unstuff(&[b"foo\r", b"\n.", b"\r", b"\n"]),
+                   (b"foo\r\n".to_vec(), Some(1)));
+        assert_eq!(unstuff(&[b"foo\r\n.bar"]),
+                   (b"foo\r\nbar".to_vec(), None));
+    }
+
+    fn stuff(parts: &[&[u8]]) -> Vec<u8> {
+        let mut stuffer = Stuffer::new();
+        let mut output = Vec::new();
+        for part in parts {
+            stuffer.encode(part, &mut output);
+        }
+        stuffer.finish(&mut output);
+        output
+    }
+
+    #[test]
+    fn stuff_good() {
+        assert_eq!(stuff(&[b"foo\r\nbar\r\n"]),
+                   b"foo\r\nbar\r\n.\r\n".to_vec());
+        assert_eq!(stuff(&[b".foo\r\n.\r\n"]),
+                   b"..foo\r\n..\r\n.\r\n".to_vec());
+        assert_eq!(stuff(&[b"foo\r", b"\n", b".bar"]),
+                   b"foo\r\n..bar\r\n.\r\n".to_vec());
+        assert_eq!(stuff(&[b""]), b".\r\n".to_vec());
+    }
+
+    #[test]
+    fn stuff_bare() {
+        assert_eq!(stuff(&[b"a.b\n.c\r\n"]),
+                   b"a.b\r\n..c\r\n.\r\n".to_vec());
+        assert_eq!(stuff(&[b"foo\n.\r\nMAIL FROM:<a@b.test>\r\n"]),
+                   b"foo\r\n..\r\nMAIL FROM:<a@b.test>\r\n.\r\n".to_vec());
+        assert_eq!(stuff(&[b"foo\r.\r", b"\n.\r"]),
+                   b"foo\r\n..\r\n..\r\n.\r\n".to_vec());
+        assert_eq!(stuff(&[b"\r\r\n\n"]),
+                   b"\r\n\r\n\r\n.\r\n".to_vec());
+    }
+
+    #[test]
+    fn unstuff_bare() {
+        let mut unstuffer = Unstuffer::new();
+        let mut output = Vec::new();
+        assert_eq!(unstuffer.decode(b"foo\n.\nbar\r\n.\r\n", &mut output),
+                   Some(14));
+        assert_eq!(output, b"foo\n.\nbar\r\n".to_vec());
+        assert!(unstuffer.bare_lf());
+        assert!(!unstuffer.bare_cr());
+
+        let mut unstuffer = Unstuffer::new();
+        let mut output = Vec::new();
+        assert_eq!(unstuffer.decode(b"foo\rbar\r\n.\rx\r\n.\r\n",
+                                    &mut output),
+                   Some(17));
+        assert_eq!(output, b"foo\rbar\r\n\rx\r\n".to_vec());
+        assert!(unstuffer.bare_cr());
+    }
+}
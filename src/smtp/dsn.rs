@@ -0,0 +1,500 @@
+//! Delivery Status Notifications.
+//!
+//! This module creates the notification messages of RFC 3464 for the
+//! recipients of a queued mail. Whether a notification is wanted at all
+//! is decided by the NOTIFY parameter of each recipient as described in
+//! RFC 3461. If a recipient didn’t give one, we notify of failures and
+//! delays but not of success. How much of the original message is
+//! included is decided by the RET parameter: the full message is only
+//! returned in failure notifications and only if asked for. Otherwise, it
+//! is only the header.
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use nom::IResult;
+use openssl::crypto::rand::rand_bytes;
+use ::smtp::fs::queue::{Envelope, Recipient};
+use ::smtp::syntax::{BodyValue, NotifyValue, Reply, RetValue, Xtext};
+use ::util::date;
+
+
+//------------ Action --------------------------------------------------------
+
+/// What happened to a recipient.
+///
+/// These are the values of the Action field defined in RFC 3464, section
+/// 2.3.3.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Delivery failed for good.
+    Failed,
+
+    /// Delivery has been delayed but will be tried again.
+    Delayed,
+
+    /// The message has been delivered to the recipient’s mailbox.
+    Delivered,
+
+    /// The message has been passed on to a place that doesn’t issue
+    /// notifications.
+    Relayed,
+
+    /// The message has been delivered to the recipient and passed on to
+    /// further recipients.
+    Expanded,
+}
+
+impl Action {
+    /// Returns whether *notify* asks for a notification of this action.
+    pub fn is_requested(&self, notify: Option<NotifyValue>) -> bool {
+        match (*self, notify) {
+            (Action::Failed, None) | (Action::Delayed, None) => true,
+            (_, None) => false,
+            (Action::Failed, Some(notify)) => notify.failure,
+            (Action::Delayed, Some(notify)) => notify.delay,
+            (_, Some(notify)) => notify.success
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Action::Failed => "failed",
+            Action::Delayed => "delayed",
+            Action::Delivered => "delivered",
+            Action::Relayed => "relayed",
+            Action::Expanded => "expanded",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+//------------ Dsn -----------------------------------------------------------
+
+/// A delivery status notification for some recipients of a mail.
+///
+pub struct Dsn<'a> {
+    /// The host name of the reporting MTA.
+    hostname: &'a [u8],
+
+    /// The envelope of the original mail.
+    envelope: &'a Envelope,
+
+    /// The recipients to report on by index into the envelope.
+    recipients: Vec<(usize, Action)>,
+
+    /// The time of the report in seconds since the epoch.
+    now: u64,
+
+    /// Until when delivery to delayed recipients will be tried.
+    expires: Option<u64>,
+}
+
+impl<'a> Dsn<'a> {
+    pub fn new(hostname: &'a [u8], envelope: &'a Envelope, now: u64)
+               -> Self {
+        Dsn { hostname: hostname, envelope: envelope,
+              recipients: Vec::new(), now: now, expires: None }
+    }
+
+    /// Adds the recipient with index *idx* if it wants to know about
+    /// *action*.
+    pub fn add(&mut self, idx: usize, action: Action) {
+        if action.is_requested(self.envelope.recipients[idx].notify) {
+            self.recipients.push((idx, action))
+        }
+    }
+
+    pub fn set_expires(&mut self, expires: u64) {
+        self.expires = Some(expires)
+    }
+
+    /// Returns whether there is nothing to report.
+    pub fn is_empty(&self) -> bool {
+        self.recipients.is_empty()
+    }
+
+    /// Returns the envelope for sending the notification.
+    ///
+    /// Returns `None` if the original mail had a null reverse path, in
+    /// which case no notifications must be sent at all.
+    pub fn envelope(&self) -> Option<Envelope> {
+        if self.envelope.reverse_path.is_empty() {
+            return None
+        }
+        let mut res = Envelope::new(Vec::new());
+        res.recipients.push(Recipient::new(self.envelope
+                                               .reverse_path.clone()));
+        if self.envelope.body == Some(BodyValue::EightBitMime) {
+            res.body = Some(BodyValue::EightBitMime)
+        }
+        Some(res)
+    }
+
+    /// Writes the notification message.
+    ///
+    /// The original message is read from *message*.
+    pub fn write<W: Write, R: BufRead>(&self, target: &mut W, message: R)
+                                       -> io::Result<()> {
+        let boundary = format!("={}", hex(&rand_bytes(12)));
+        try!(self.write_header(target, &boundary));
+
+        try!(write!(target, "--{}\r\n", boundary));
+        try!(target.write_all(b"Content-Type: text/plain; \
+                                charset=us-ascii\r\n\r\n"));
+        try!(self.write_text(target));
+
+        try!(write!(target, "\r\n--{}\r\n", boundary));
+        try!(target.write_all(b"Content-Type: message/delivery-status\
+                                \r\n\r\n"));
+        try!(self.write_status(target));
+
+        try!(write!(target, "\r\n--{}\r\n", boundary));
+        if self.full_message() {
+            try!(target.write_all(b"Content-Type: message/rfc822\r\n\r\n"));
+            try!(copy_message(message, target, false));
+        }
+        else {
+            try!(target.write_all(b"Content-Type: text/rfc822-headers\
+                                    \r\n\r\n"));
+            try!(copy_message(message, target, true));
+        }
+        write!(target, "\r\n--{}--\r\n", boundary)
+    }
+}
+
+impl<'a> Dsn<'a> {
+    fn has(&self, action: Action) -> bool {
+        self.recipients.iter().any(|&(_, item)| item == action)
+    }
+
+    /// Returns whether to return the full message.
+    ///
+    /// A binary message can’t go into a multipart, so we only return its
+    /// header.
+    fn full_message(&self) -> bool {
+        self.envelope.ret == Some(RetValue::Full)
+            && self.has(Action::Failed)
+            && self.envelope.body != Some(BodyValue::BinaryMime)
+    }
+
+    fn write_header<W: Write>(&self, target: &mut W, boundary: &str)
+                              -> io::Result<()> {
+        let subject = if self.has(Action::Failed) { "Failure" }
+                      else if self.has(Action::Delayed) { "Delay" }
+                      else { "Success" };
+        try!(target.write_all(b"From: Mail Delivery System \
+                                <MAILER-DAEMON@"));
+        try!(target.write_all(self.hostname));
+        try!(target.write_all(b">\r\nTo: <"));
+        try!(target.write_all(&self.envelope.reverse_path));
+        try!(write!(target, ">\r\nSubject: Delivery Status Notification \
+                             ({})\r\n", subject));
+        try!(write!(target, "Date: {}\r\n", date::rfc5322(self.now)));
+        try!(write!(target, "Message-ID: <{}@", hex(&rand_bytes(16))));
+        try!(target.write_all(self.hostname));
+        try!(target.write_all(b">\r\n\
+                                Auto-Submitted: auto-replied\r\n\
+                                MIME-Version: 1.0\r\n"));
+        write!(target, "Content-Type: multipart/report; \
+                        report-type=delivery-status;\r\n\
+                        \tboundary=\"{}\"\r\n\r\n\
+                        This is a MIME-encapsulated message.\r\n\r\n",
+               boundary)
+    }
+
+    fn write_text<W: Write>(&self, target: &mut W) -> io::Result<()> {
+        try!(target.write_all(b"This is the mail system at host "));
+        try!(target.write_all(self.hostname));
+        try!(target.write_all(b".\r\n"));
+        let actions = [(Action::Failed,
+                        &b"Your message could not be delivered to the \
+                           following recipients:"[..]),
+                       (Action::Delayed,
+                        &b"Delivery of your message to the following \
+                           recipients has been delayed. Delivery will be \
+                           tried again:"[..]),
+                       (Action::Delivered,
+                        &b"Your message has been delivered to the \
+                           following recipients:"[..]),
+                       (Action::Relayed,
+                        &b"Your message has been passed on to the \
+                           following recipients, but no further \
+                           notifications can be given:"[..]),
+                       (Action::Expanded,
+                        &b"Your message has been delivered to the \
+                           following recipients and passed on \
+                           further:"[..])];
+        for &(action, text) in &actions {
+            if !self.has(action) {
+                continue
+            }
+            try!(target.write_all(b"\r\n"));
+            try!(target.write_all(text));
+            try!(target.write_all(b"\r\n\r\n"));
+            for &(idx, item) in &self.recipients {
+                if item != action {
+                    continue
+                }
+                let rcpt = &self.envelope.recipients[idx];
+                try!(target.write_all(b"  <"));
+                try!(write_ascii(target, &rcpt.path));
+                try!(target.write_all(b">"));
+                if let Some(ref reply) = rcpt.reply {
+                    try!(target.write_all(b": "));
+                    try!(write_reply(target, reply));
+                }
+                try!(target.write_all(b"\r\n"));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_status<W: Write>(&self, target: &mut W) -> io::Result<()> {
+        try!(target.write_all(b"Reporting-MTA: dns; "));
+        try!(target.write_all(self.hostname));
+        try!(target.write_all(b"\r\n"));
+        if let Some(ref envid) = self.envelope.envid {
+            try!(target.write_all(b"Original-Envelope-Id: "));
+            try!(write_ascii(target, &decode_xtext(envid)));
+            try!(target.write_all(b"\r\n"));
+        }
+        try!(write!(target, "Arrival-Date: {}\r\n",
+                    date::rfc5322(self.envelope.arrival)));
+        for &(idx, action) in &self.recipients {
+            let rcpt = &self.envelope.recipients[idx];
+            try!(target.write_all(b"\r\n"));
+            if let Some(ref orcpt) = rcpt.orcpt {
+                if let Some(pos) = orcpt.iter().position(|ch| *ch == b';') {
+                    try!(target.write_all(b"Original-Recipient: "));
+                    try!(write_ascii(target, &orcpt[..pos]));
+                    try!(target.write_all(b"; "));
+                    try!(write_ascii(target,
+                                     &decode_xtext(&orcpt[pos + 1..])));
+                    try!(target.write_all(b"\r\n"));
+                }
+            }
+            try!(target.write_all(b"Final-Recipient: rfc822; "));
+            try!(write_ascii(target, &rcpt.path));
+            let (a, b, c) = status(rcpt, action);
+            try!(write!(target, "\r\nAction: {}\r\nStatus: {}.{}.{}\r\n",
+                        action, a, b, c));
+            if let Some(ref reply) = rcpt.reply {
+                try!(target.write_all(b"Diagnostic-Code: smtp; "));
+                try!(write_reply(target, reply));
+                try!(target.write_all(b"\r\n"));
+            }
+            if action == Action::Failed || action == Action::Delayed {
+                try!(write!(target, "Last-Attempt-Date: {}\r\n",
+                            date::rfc5322(self.now)));
+            }
+            if let (Action::Delayed, Some(expires)) = (action, self.expires) {
+                try!(write!(target, "Will-Retry-Until: {}\r\n",
+                            date::rfc5322(expires)));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Determines the status code for a recipient.
+///
+/// This is the enhanced status code of the last reply if it fits the
+/// action, or a generic code of the right class otherwise. A failed
+/// recipient whose last reply was transient has expired.
+fn status(rcpt: &Recipient, action: Action) -> (u16, u16, u16) {
+    let class = match action {
+        Action::Failed => 5,
+        Action::Delayed => 4,
+        _ => 2
+    };
+    let reply = match rcpt.reply {
+        Some(ref reply) => reply,
+        None => return (class, 0, 0)
+    };
+    if action == Action::Failed && reply.is_transient() {
+        return (4, 4, 7)
+    }
+    if reply.code / 100 != class {
+        return (class, 0, 0)
+    }
+    match reply.status {
+        Some(status) if status.0 == class => status,
+        _ => (class, 0, 0)
+    }
+}
+
+/// Writes a reply for a diagnostic code or the human readable part.
+fn write_reply<W: Write>(target: &mut W, reply: &Reply) -> io::Result<()> {
+    try!(write!(target, "{}", reply.code));
+    if let Some((a, b, c)) = reply.status {
+        try!(write!(target, " {}.{}.{}", a, b, c));
+    }
+    try!(target.write_all(b" "));
+    write_ascii(target, &reply.text)
+}
+
+/// Writes *data* replacing everything that isn’t printable ASCII.
+///
+/// Line breaks become spaces, everything else a question mark.
+fn write_ascii<W: Write>(target: &mut W, data: &[u8]) -> io::Result<()> {
+    let data: Vec<u8> = data.iter().map(|&ch| match ch {
+        b'\r' | b'\n' => b' ',
+        0x20 ... 0x7E => ch,
+        _ => b'?'
+    }).collect();
+    target.write_all(&data)
+}
+
+/// Copies the original message or only its header if *header* is true.
+///
+/// All lines are made to end in CRLF.
+fn copy_message<R: BufRead, W: Write>(mut source: R, target: &mut W,
+                                      header: bool) -> io::Result<()> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if try!(source.read_until(b'\n', &mut line)) == 0 {
+            return Ok(())
+        }
+        while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if header && line.is_empty() {
+            return Ok(())
+        }
+        try!(target.write_all(&line));
+        try!(target.write_all(b"\r\n"));
+    }
+}
+
+fn decode_xtext(data: &[u8]) -> Vec<u8> {
+    match Xtext::parse(data) {
+        IResult::Done(_, xtext) => xtext.decode(),
+        _ => data.into()
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    let mut res = String::with_capacity(data.len() * 2);
+    for ch in data {
+        res.push_str(&format!("{:02x}", ch));
+    }
+    res
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use ::smtp::fs::queue::{DeliveryState, Envelope, Recipient};
+    use ::smtp::syntax::{NotifyValue, Reply, RetValue};
+    use super::*;
+
+    fn envelope() -> Envelope {
+        let mut res = Envelope::new(b"me@example.com".to_vec());
+        res.arrival = 1234567890;
+        res.envid = Some(b"QQ+2B314".to_vec());
+        let mut rcpt = Recipient::new(b"a@example.org".to_vec());
+        rcpt.state = DeliveryState::Failed;
+        rcpt.reply = Some(Reply::new(550, Some((5, 1, 1)),
+                                     b"No such user".to_vec()));
+        rcpt.orcpt = Some(b"rfc822;A+40example.org".to_vec());
+        res.recipients.push(rcpt);
+        let mut rcpt = Recipient::new(b"b@example.org".to_vec());
+        rcpt.state = DeliveryState::Failed;
+        rcpt.reply = Some(Reply::new(451, Some((4, 3, 0)),
+                                     b"Try again".to_vec()));
+        res.recipients.push(rcpt);
+        let mut rcpt = Recipient::new(b"c@example.org".to_vec());
+        rcpt.notify = Some(NotifyValue::new());
+        rcpt.state = DeliveryState::Failed;
+        res.recipients.push(rcpt);
+        res
+    }
+
+    #[test]
+    fn requested() {
+        let mut notify = NotifyValue::new();
+        assert!(Action::Failed.is_requested(None));
+        assert!(Action::Delayed.is_requested(None));
+        assert!(!Action::Relayed.is_requested(None));
+        assert!(!Action::Failed.is_requested(Some(notify)));
+        notify.success = true;
+        assert!(Action::Delivered.is_requested(Some(notify)));
+        assert!(!Action::Delayed.is_requested(Some(notify)));
+    }
+
+    #[test]
+    fn failure() {
+        let mut envelope = envelope();
+        envelope.ret = Some(RetValue::Full);
+        let mut dsn = Dsn::new(b"mx.example.com", &envelope, 1234567900);
+        for idx in 0..3 {
+            dsn.add(idx, Action::Failed);
+        }
+        assert!(!dsn.is_empty());
+        let dsn_envelope = dsn.envelope().unwrap();
+        assert!(dsn_envelope.reverse_path.is_empty());
+        assert_eq!(dsn_envelope.recipients[0].path,
+                   b"me@example.com".to_vec());
+
+        let mut buf = Vec::new();
+        dsn.write(&mut buf, Cursor::new(&b"Subject: Hi\r\n\r\nBody\r\n"[..]))
+           .unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains("Subject: Delivery Status Notification \
+                               (Failure)\r\n"));
+        assert!(text.contains("Original-Envelope-Id: QQ+314\r\n"));
+        assert!(text.contains("Arrival-Date: Fri, 13 Feb 2009 23:31:30 \
+                               +0000\r\n"));
+        assert!(text.contains("Original-Recipient: rfc822; A@example.org\r\n\
+                               Final-Recipient: rfc822; a@example.org\r\n\
+                               Action: failed\r\n\
+                               Status: 5.1.1\r\n\
+                               Diagnostic-Code: smtp; 550 5.1.1 No such \
+                               user\r\n"));
+        assert!(text.contains("Final-Recipient: rfc822; b@example.org\r\n\
+                               Action: failed\r\n\
+                               Status: 4.4.7\r\n"));
+        assert!(!text.contains("c@example.org"));
+        assert!(text.contains("Content-Type: message/rfc822\r\n\r\n\
+                               Subject: Hi\r\n\r\nBody\r\n"));
+    }
+
+    #[test]
+    fn headers_only() {
+        let envelope = envelope();
+        let mut dsn = Dsn::new(b"mx.example.com", &envelope, 1234567900);
+        dsn.add(1, Action::Delayed);
+        dsn.set_expires(1234999999);
+        let mut buf = Vec::new();
+        dsn.write(&mut buf, Cursor::new(&b"Subject: Hi\r\n\r\nBody\r\n"[..]))
+           .unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains("(Delay)"));
+        assert!(text.contains("Action: delayed\r\nStatus: 4.3.0\r\n"));
+        assert!(text.contains("Will-Retry-Until: "));
+        assert!(text.contains("Content-Type: text/rfc822-headers\r\n\r\n\
+                               Subject: Hi\r\n\r\n--"));
+        assert!(!text.contains("Body"));
+    }
+
+    #[test]
+    fn null_reverse_path() {
+        let mut envelope = envelope();
+        envelope.reverse_path = Vec::new();
+        let dsn = Dsn::new(b"mx.example.com", &envelope, 1234567900);
+        assert!(dsn.envelope().is_none());
+    }
+}
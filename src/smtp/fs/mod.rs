@@ -2,3 +2,4 @@
 //!
 
 pub mod mta;
+pub mod queue;
@@ -0,0 +1,142 @@
+//! Authenticating clients.
+//!
+//! Clients can authenticate against the credential lookup set with
+//! `set_credentials()`. Without one, all attempts fail. SCRAM-SHA-256
+//! credentials for users that only have a plain text password are
+//! derived once and then kept. The derivation runs on the worker pool
+//! if there is one.
+
+use std::rc::Rc;
+use rotor::Notifier;
+use ::smtp::server::protocol::Hesitant;
+use ::smtp::server::sasl::{self, CredentialLookup, Derivation, ScramCache,
+                           ScramCredentials};
+use ::smtp::server::worker::{Deferred, Pool};
+
+
+//------------ Auth ----------------------------------------------------------
+
+#[derive(Clone)]
+pub struct Auth {
+    /// The lookup for authenticating clients.
+    credentials: Credentials,
+
+    /// The SCRAM-SHA-256 credentials derived from passwords so far.
+    scram_cache: Rc<ScramCache>,
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Auth { credentials: Credentials(None),
+               scram_cache: Rc::new(ScramCache::new()) }
+    }
+
+    /// Sets the lookup for the secrets of users who may authenticate.
+    pub fn set_credentials<L>(&mut self, lookup: L)
+                           where L: CredentialLookup + 'static {
+        self.credentials = Credentials(Some(Rc::new(lookup)))
+    }
+
+    /// Returns the credential lookup.
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    /// Derives SCRAM-SHA-256 credentials unless they are cached.
+    ///
+    /// Without a pool, there is no choice but to derive them right away.
+    pub fn derive(&self, derivation: Derivation, pool: Option<&Pool>,
+                  notifier: Option<&Notifier>)
+                  -> Hesitant<Option<ScramCredentials>, Derive> {
+        if let Some(credentials) = self.scram_cache.get(&derivation) {
+            return Hesitant::Final(Some(credentials))
+        }
+        let (pool, notifier) = match (pool, notifier) {
+            (Some(pool), Some(notifier)) => (pool, notifier),
+            _ => {
+                let credentials = derivation.derive();
+                self.scram_cache.insert(derivation, credentials.clone());
+                return Hesitant::Final(Some(credentials))
+            }
+        };
+        let job = derivation.clone();
+        let pending = pool.run(notifier, move || job.derive());
+        Hesitant::Defer(Deferred::new(pending,
+                                      (self.scram_cache.clone(), derivation),
+                                      derived))
+    }
+
+    /// Checks the credentials a client has presented.
+    ///
+    /// Clients can only ever act as themselves.
+    pub fn check(&self, credentials: &sasl::Credentials) -> bool {
+        if !credentials.authzid.is_empty()
+                && credentials.authzid != credentials.authcid {
+            return false
+        }
+        let valid = match credentials.password {
+            Some(ref password) => {
+                match self.credentials.password(&credentials.authcid) {
+                    Some(expected) => {
+                        sasl::equal_constant_time(password, &expected)
+                    }
+                    None => false
+                }
+            }
+            // The mechanism has checked the secret already.
+            None => true
+        };
+        if valid {
+            info!("MTA: client authenticated as {}",
+                  String::from_utf8_lossy(&credentials.authcid));
+        }
+        else {
+            info!("MTA: authentication failed for {}",
+                  String::from_utf8_lossy(&credentials.authcid));
+        }
+        valid
+    }
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth::new()
+    }
+}
+
+
+//------------ Derive --------------------------------------------------------
+
+/// A derivation of SCRAM-SHA-256 credentials running on the pool.
+pub type Derive = Deferred<ScramCredentials, (Rc<ScramCache>, Derivation),
+                           Option<ScramCredentials>>;
+
+/// Keeps SCRAM-SHA-256 credentials derived on the pool.
+fn derived((cache, derivation): (Rc<ScramCache>, Derivation),
+           credentials: Option<ScramCredentials>)
+           -> Option<ScramCredentials> {
+    if let Some(ref credentials) = credentials {
+        cache.insert(derivation, credentials.clone())
+    }
+    credentials
+}
+
+
+//------------ Credentials ---------------------------------------------------
+
+/// The credential lookup of an MTA session.
+///
+/// Without a lookup set through `Mta::set_credentials()`, nobody is
+/// known.
+#[derive(Clone)]
+pub struct Credentials(Option<Rc<CredentialLookup>>);
+
+impl CredentialLookup for Credentials {
+    fn password(&self, authcid: &[u8]) -> Option<Vec<u8>> {
+        self.0.as_ref().and_then(|lookup| lookup.password(authcid))
+    }
+
+    fn scram_sha256(&self, authcid: &[u8]) -> Option<ScramCredentials> {
+        self.0.as_ref().and_then(|lookup| lookup.scram_sha256(authcid))
+    }
+}
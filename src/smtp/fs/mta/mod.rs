@@ -0,0 +1,1180 @@
+//! A protocol that spools all mail into a queue.
+//!
+//! The MTA itself only composes the steps a mail goes through. Each of
+//! the optional checks lives in a module of its own.
+
+use std::ascii::AsciiExt;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::sync::Arc;
+use netmachines::sockets::Certificate;
+use rotor::{Notifier, Void};
+use ::net::dns::Resolver;
+use ::smtp::authres;
+use ::smtp::dkim::sign::{Keys, Signer};
+use ::smtp::dkim::verify::Verifier;
+use ::smtp::dmarc::Suffixes;
+use ::smtp::dmarc::report::Store;
+use ::smtp::server::config::Capabilities;
+use ::smtp::server::dnsbl::Dnsbl;
+use ::smtp::server::protocol::{AncillaryHandler, DataHandler, Hesitant,
+                               MailHandler, Protocol, SessionHandler};
+use ::smtp::local::aliases::Aliases;
+use ::smtp::local::users::Users;
+use ::smtp::local::validator::Validator;
+use ::smtp::relay::Handle;
+use ::smtp::server::reply::{EtrnReply, ReplyBuf};
+use ::smtp::server::sasl::{self, CredentialLookup, Derivation,
+                           ScramCredentials};
+use ::smtp::server::trace::Trace;
+use ::smtp::server::worker::{Deferred, DeferredReply, Pool};
+use ::smtp::spf::Spf;
+use ::smtp::syntax;
+use ::util::text::is_within;
+use super::queue::{Envelope, Queue, Recipient, Spool};
+use self::auth::{Auth, Credentials};
+use self::screen::{Client, Screen, Screening};
+use self::sign::Signing;
+use self::spf::SpfCheck;
+use self::verify::{Authenticated, Verify};
+
+mod auth;
+mod screen;
+mod sign;
+mod spf;
+mod verify;
+
+
+//------------ Mta -----------------------------------------------------------
+
+/// A protocol that accepts all mail into a queue.
+///
+/// A mail is only accepted once it has safely been committed to the
+/// queue. The reply contains the queue ID. Each mail gets a Received
+/// field with its queue ID.
+///
+/// If the MTA has been given a handle to the relay scheduler working on
+/// the queue, the ETRN command can be used to ask for held mail to be
+/// delivered.
+///
+/// Recipients, VRFY, and EXPN are handled by a `Validator`. Clients are
+/// screened when they connect, senders checked with SPF, and mail
+/// verified with DKIM and DMARC once received if these have been
+/// enabled. Authenticated clients may relay and have their mail signed.
+/// All DNS lookups run on the worker pool set with `set_pool()`. Without
+/// a pool, the checks that need them are not done.
+pub struct Mta {
+    session: Session,
+}
+
+impl Mta {
+    pub fn new(queue: Queue) -> Self {
+        Mta {
+            session: Session { queue: Rc::new(queue), relay: None,
+                               validator: Rc::new(Validator::new()),
+                               pool: None, notifier: None, peer: None,
+                               screen: Screen::new(),
+                               client: Client::default(),
+                               spf: SpfCheck::new(), verify: Verify::new(),
+                               hostname: Vec::new(), auth: Auth::new(),
+                               authenticated: None,
+                               signing: Signing::new() }
+        }
+    }
+
+    pub fn with_relay(queue: Queue, relay: Handle) -> Self {
+        let mut res = Mta::new(queue);
+        res.session.relay = Some(relay);
+        res
+    }
+
+    /// Sets the table of local users to validate recipients against.
+    pub fn set_users(&mut self, users: Users) {
+        Rc::make_mut(&mut self.session.validator).set_users(users)
+    }
+
+    /// Sets the aliases to expand recipients and EXPN commands with.
+    pub fn set_aliases(&mut self, aliases: Aliases) {
+        Rc::make_mut(&mut self.session.validator).set_aliases(aliases)
+    }
+
+    /// Sets whether VRFY is answered from the table of local users.
+    pub fn set_verify(&mut self, enable: bool) {
+        Rc::make_mut(&mut self.session.validator).set_verify(enable)
+    }
+
+    /// Sets whether EXPN is answered from the table of local users.
+    pub fn set_expand(&mut self, enable: bool) {
+        Rc::make_mut(&mut self.session.validator).set_expand(enable)
+    }
+
+    /// Sets the pool to run lookups on.
+    ///
+    /// DNS lookups are only ever done on the pool. Enabling them without
+    /// a pool has no effect.
+    pub fn set_pool(&mut self, pool: Pool) {
+        self.session.pool = Some(pool)
+    }
+
+    /// Sets the blocklists to check clients against.
+    pub fn set_dnsbl(&mut self, dnsbl: Dnsbl) {
+        self.session.screen.set_dnsbl(dnsbl)
+    }
+
+    /// Enables looking up the name of clients using *resolver*.
+    pub fn set_reverse_dns(&mut self, resolver: Arc<Resolver>) {
+        self.session.screen.set_reverse_dns(resolver)
+    }
+
+    /// Enables SPF checking using *resolver*.
+    pub fn set_spf(&mut self, resolver: Arc<Resolver>) {
+        self.session.spf.set_resolver(resolver)
+    }
+
+    /// Sets whether mail failing the SPF check is rejected.
+    pub fn set_spf_reject(&mut self, enable: bool) {
+        self.session.spf.set_reject(enable)
+    }
+
+    /// Enables DKIM verification using *resolver* to look up keys.
+    pub fn set_dkim(&mut self, resolver: Arc<Resolver>) {
+        self.session.verify.set_dkim(resolver)
+    }
+
+    /// Enables DMARC using *resolver* to look up policies.
+    ///
+    /// Organizational domains are determined with *suffixes*. Since
+    /// DMARC needs the results of DKIM verification, this enables it,
+    /// too, if necessary.
+    pub fn set_dmarc(&mut self, suffixes: Suffixes,
+                     resolver: Arc<Resolver>) {
+        self.session.verify.set_dmarc(suffixes, resolver)
+    }
+
+    /// Sets whether mail is rejected if its DMARC policy asks for it.
+    pub fn set_dmarc_reject(&mut self, enable: bool) {
+        self.session.verify.set_reject(enable)
+    }
+
+    /// Sets the store to record DMARC results in for reporting.
+    pub fn set_dmarc_store(&mut self, store: Store) {
+        self.session.verify.set_store(store)
+    }
+
+    /// Sets the host name used in header fields added to mail.
+    ///
+    /// Without one, the host name from the server configuration is used.
+    /// Without a table of local users, this is also the local domain.
+    pub fn set_hostname(&mut self, hostname: &[u8]) {
+        Rc::make_mut(&mut self.session.validator).set_hostname(hostname);
+        self.session.hostname = hostname.into()
+    }
+
+    /// Sets the lookup for the secrets of users who may authenticate.
+    pub fn set_credentials<L>(&mut self, lookup: L)
+                           where L: CredentialLookup + 'static {
+        self.session.auth.set_credentials(lookup)
+    }
+
+    /// Sets the keys for signing mail of authenticated clients.
+    pub fn set_dkim_keys(&mut self, keys: Keys) {
+        self.session.signing.set_keys(keys)
+    }
+}
+
+impl Protocol for Mta {
+    type Session = Session;
+    type Mail = Mail;
+    type Data = Data;
+
+    fn accept(&mut self, addr: &SocketAddr) -> Option<Session> {
+        let mut res = self.session.clone();
+        res.peer = Some(addr.ip());
+        Some(res)
+    }
+}
+
+
+//------------ Session -------------------------------------------------------
+
+#[derive(Clone)]
+pub struct Session {
+    queue: Rc<Queue>,
+    relay: Option<Handle>,
+
+    /// The validator for recipients, VRFY, and EXPN.
+    validator: Rc<Validator>,
+
+    /// The pool to run lookups on.
+    pool: Option<Pool>,
+
+    /// The notifier for waking up the session after a lookup.
+    notifier: Option<Notifier>,
+
+    /// The address of the client.
+    peer: Option<IpAddr>,
+
+    /// The checks for clients when they connect.
+    screen: Screen,
+
+    /// What screening has found out about the client.
+    client: Client,
+
+    /// The SPF check for each transaction.
+    spf: SpfCheck,
+
+    /// DKIM verification and DMARC for received mail.
+    verify: Verify,
+
+    /// Our host name for header fields.
+    ///
+    /// This is empty until set or taken from the trace of the first mail.
+    hostname: Vec<u8>,
+
+    /// Authentication of clients.
+    auth: Auth,
+
+    /// The user name the client has authenticated as if it has.
+    authenticated: Option<Vec<u8>>,
+
+    /// Signing of mail submitted by authenticated clients.
+    signing: Signing,
+}
+
+impl Session {
+    /// Decides on the session once the client has been looked at.
+    fn screened(mut self, res: Option<Screening>) -> Option<Self> {
+        Client::screened(res).map(|client| {
+            self.client = client;
+            self
+        })
+    }
+
+    /// Decides on a transaction once the SPF check is done.
+    fn spf_checked(state: (Session, Envelope), spf: Option<Spf>,
+                   reply: ReplyBuf) -> Result<Mail, Session> {
+        let (session, envelope) = state;
+        match session.spf.admit(spf.as_ref(), reply) {
+            Ok(reply) => Ok(session.open_mail(envelope, spf, reply)),
+            Err(()) => Err(session)
+        }
+    }
+
+    /// Starts a transaction.
+    fn open_mail(self, envelope: Envelope, spf: Option<Spf>,
+                 reply: ReplyBuf) -> Mail {
+        reply.reply(250, (2, 1, 0), b"Ok\r\n");
+        Mail { session: self, envelope: envelope, spf: spf }
+    }
+
+    /// Returns the number of queued mails waiting for *domain*.
+    ///
+    /// Also returns the domains of all recipients of these mails that
+    /// match. If *subdomains* is `true`, recipients in subdomains of
+    /// *domain* match, too.
+    fn pending(&self, domain: &[u8], subdomains: bool)
+               -> io::Result<(usize, Vec<Vec<u8>>)> {
+        let mut count = 0;
+        let mut domains = Vec::new();
+        for id in try!(self.queue.list()) {
+            let envelope = match self.queue.envelope(&id) {
+                Ok(envelope) => envelope,
+                // The mail may have been delivered and removed meanwhile.
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound
+                    => continue,
+                Err(err) => return Err(err)
+            };
+            let mut matched = false;
+            for rcpt in &envelope.recipients {
+                let matches = if subdomains {
+                    is_within(rcpt.domain(), domain)
+                }
+                else {
+                    rcpt.domain().eq_ignore_ascii_case(domain)
+                };
+                if rcpt.state.is_final() || !matches {
+                    continue
+                }
+                matched = true;
+                let rcpt_domain = rcpt.domain().to_ascii_lowercase();
+                if !domains.contains(&rcpt_domain) {
+                    domains.push(rcpt_domain)
+                }
+            }
+            if matched {
+                count += 1
+            }
+        }
+        Ok((count, domains))
+    }
+}
+
+impl AncillaryHandler for Session {
+    type Verify = Void;
+    type Expand = Void;
+    type Help = Void;
+
+    fn verify(self, what: syntax::Word, _params: syntax::VrfyParameters,
+              reply: ReplyBuf) -> Hesitant<Self, Void> {
+        self.validator.verify(what, reply);
+        Hesitant::Final(self)
+    }
+
+    fn expand(self, what: syntax::Word, _params: syntax::ExpnParameters,
+              reply: ReplyBuf) -> Hesitant<Self, Void> {
+        self.validator.expand(what, reply);
+        Hesitant::Final(self)
+    }
+
+    fn help(self, _what: Option<syntax::Word>, reply: ReplyBuf)
+            -> Hesitant<Self, Void> {
+        reply.reply(214, (2, 0, 0), b"See RFC 5321.\r\n");
+        Hesitant::Final(self)
+    }
+}
+
+impl SessionHandler<Mta> for Session {
+    type Seed = Session;
+    type Start = Deferred<Screening, Session, Option<Session>>;
+    type Hello = Void;
+    type CheckTls = Void;
+    type Auth = Void;
+    type Derive = auth::Derive;
+    type Mail = DeferredReply<Spf, (Session, Envelope), Result<Mail, Session>>;
+    type Etrn = Void;
+    type Lookup = Credentials;
+
+    fn start(mut seed: Session, notifier: Notifier)
+             -> Hesitant<Option<Self>, Self::Start> {
+        seed.notifier = Some(notifier.clone());
+        if !seed.screen.is_enabled() {
+            return Hesitant::Final(Some(seed))
+        }
+        let pending = match (seed.pool.clone(), seed.peer) {
+            (Some(pool), Some(peer)) => {
+                let screen = seed.screen.clone();
+                pool.run(&notifier, move || screen.check(peer))
+            }
+            _ => return Hesitant::Final(Some(seed))
+        };
+        Hesitant::Defer(Deferred::new(pending, seed, Session::screened))
+    }
+
+    fn hello(mut self, domain: syntax::MailboxDomain)
+             -> Hesitant<Option<Self>, Void> {
+        info!("MTA: client hello from {}", domain);
+        self.spf.hello(self.peer, &domain);
+        Hesitant::Final(Some(self))
+    }
+
+    fn capabilities(&self, caps: &mut Capabilities) {
+        // Without a relay there is nothing ETRN could start.
+        if self.relay.is_none() {
+            caps.etrn = false
+        }
+        if !self.validator.expands() {
+            caps.expn = false
+        }
+    }
+
+    fn check_tls<C: Certificate>(self, _peer_cert: Option<C>)
+                                 -> Hesitant<Option<Self>, Void> {
+        Hesitant::Final(Some(self))
+    }
+
+    fn credential_lookup(&self) -> &Credentials {
+        self.auth.credentials()
+    }
+
+    fn derive_scram(&self, derivation: Derivation)
+                    -> Hesitant<Option<ScramCredentials>, Self::Derive> {
+        self.auth.derive(derivation, self.pool.as_ref(),
+                         self.notifier.as_ref())
+    }
+
+    fn auth(mut self, _mechanism: &[u8], credentials: sasl::Credentials)
+            -> Hesitant<Result<Self, Self>, Void> {
+        if !self.auth.check(&credentials) {
+            return Hesitant::Final(Err(self))
+        }
+        self.authenticated = Some(credentials.authcid);
+        Hesitant::Final(Ok(self))
+    }
+
+    fn mail(self, path: syntax::ReversePath, params: syntax::MailParameters,
+            reply: ReplyBuf) -> Hesitant<Result<Mail, Self>, Self::Mail> {
+        let mut envelope = Envelope::new(path.to_string().into_bytes());
+        envelope.body = params.body;
+        envelope.size = params.size;
+        envelope.ret = params.ret;
+        envelope.envid = params.envid.map(|envid| envid.as_bytes().into());
+        let pending = self.spf.check(&path, self.pool.as_ref(),
+                                     self.notifier.as_ref());
+        match pending {
+            Some(pending) => {
+                Hesitant::Defer(DeferredReply::new(pending, (self, envelope),
+                                                   Session::spf_checked))
+            }
+            None => Hesitant::Final(Ok(self.open_mail(envelope, None, reply)))
+        }
+    }
+
+    fn etrn(self, node: syntax::EtrnNode, reply: ReplyBuf)
+            -> Hesitant<Self, Void> {
+        let (domain, subdomains) = match node {
+            syntax::EtrnNode::Domain(ref domain) => (domain, false),
+            syntax::EtrnNode::Subdomains(ref domain) => (domain, true),
+            syntax::EtrnNode::Queue(_) => {
+                reply.etrn(EtrnReply::NotAllowed(b"no named queues"), &node);
+                return Hesitant::Final(self)
+            }
+        };
+        let domain = domain.to_string().into_bytes();
+        let res = match self.relay {
+            Some(ref relay) => {
+                match self.pending(&domain, subdomains) {
+                    Ok((0, _)) => EtrnReply::NoMessages,
+                    Ok((count, domains)) => {
+                        for domain in domains {
+                            relay.flush(Some(&domain[..]))
+                        }
+                        EtrnReply::Pending(count)
+                    }
+                    Err(err) => {
+                        error!("MTA: reading queue for ETRN failed: {}", err);
+                        EtrnReply::Unable
+                    }
+                }
+            }
+            None => EtrnReply::Unable
+        };
+        info!("MTA: ETRN for {}", node);
+        reply.etrn(res, &node);
+        Hesitant::Final(self)
+    }
+}
+
+
+//------------ Mail ----------------------------------------------------------
+
+pub struct Mail {
+    session: Session,
+    envelope: Envelope,
+
+    /// The result of the SPF check if there was one.
+    spf: Option<Spf>,
+}
+
+impl AncillaryHandler for Mail {
+    type Verify = Void;
+    type Expand = Void;
+    type Help = Void;
+
+    fn verify(self, what: syntax::Word, _params: syntax::VrfyParameters,
+              reply: ReplyBuf) -> Hesitant<Self, Void> {
+        self.session.validator.verify(what, reply);
+        Hesitant::Final(self)
+    }
+
+    fn expand(self, what: syntax::Word, _params: syntax::ExpnParameters,
+              reply: ReplyBuf) -> Hesitant<Self, Void> {
+        self.session.validator.expand(what, reply);
+        Hesitant::Final(self)
+    }
+
+    fn help(self, _what: Option<syntax::Word>, reply: ReplyBuf)
+            -> Hesitant<Self, Void> {
+        reply.reply(214, (2, 0, 0), b"See RFC 5321.\r\n");
+        Hesitant::Final(self)
+    }
+}
+
+impl MailHandler<Mta> for Mail {
+    type Recipient = Void;
+    type Data = Void;
+
+    fn recipient(mut self, path: syntax::RcptPath,
+                 params: syntax::RcptParameters, reply: ReplyBuf)
+                 -> Hesitant<Result<Self, Session>, Void> {
+        let authenticated = self.session.authenticated.is_some();
+        let accepted = match self.session.validator.rcpt(&path, &params,
+                                                         authenticated,
+                                                         reply) {
+            Some(accepted) => accepted,
+            None => return Hesitant::Final(Ok(self))
+        };
+        for mailbox in accepted.mailboxes {
+            // An alias may name a mailbox that is a recipient already.
+            if self.envelope.recipients.iter().any(|rcpt| {
+                rcpt.path.eq_ignore_ascii_case(&mailbox)
+            }) {
+                continue
+            }
+            let mut rcpt = Recipient::new(mailbox);
+            rcpt.notify = params.notify;
+            rcpt.orcpt = accepted.orcpt.clone();
+            self.envelope.recipients.push(rcpt);
+        }
+        Hesitant::Final(Ok(self))
+    }
+
+    fn data(self) -> Hesitant<Result<Data, Session>, Void> {
+        if self.envelope.recipients.is_empty() {
+            return Hesitant::Final(Err(self.session))
+        }
+        match self.session.queue.create() {
+            Ok(spool) => {
+                let dkim = self.session.verify.verifier();
+                let signer = {
+                    let user = self.session.authenticated.as_ref();
+                    self.session.signing.signer(user.map(|user| &user[..]))
+                };
+                Hesitant::Final(Ok(Data { session: self.session,
+                                          envelope: self.envelope,
+                                          spf: self.spf, spool: spool,
+                                          failed: false, dkim: dkim,
+                                          signer: signer, filter: None }))
+            }
+            Err(err) => {
+                error!("MTA: cannot create spool file: {}", err);
+                Hesitant::Final(Err(self.session))
+            }
+        }
+    }
+
+    fn reset(self) -> Session {
+        self.session
+    }
+}
+
+
+//------------ Data ----------------------------------------------------------
+
+pub struct Data {
+    session: Session,
+    envelope: Envelope,
+
+    /// The result of the SPF check if there was one.
+    spf: Option<Spf>,
+
+    spool: Spool,
+
+    /// Has writing to the spool failed?
+    failed: bool,
+
+    /// The DKIM verifier if verification is enabled.
+    dkim: Option<Verifier>,
+
+    /// The DKIM signer if the mail is to be signed.
+    signer: Option<Signer>,
+
+    /// The filter for our own Authentication-Results fields.
+    filter: Option<authres::Filter>,
+}
+
+impl Data {
+    /// Writes *data* to the spool.
+    ///
+    /// Our own header fields go here directly so they don’t get in the
+    /// way of verifying or signing.
+    fn write(&mut self, data: &[u8]) {
+        if self.failed {
+            return
+        }
+        if let Err(err) = self.spool.write_all(data) {
+            error!("MTA: writing spool file {} failed: {}",
+                   self.spool.id(), err);
+            self.failed = true;
+        }
+    }
+
+    /// Passes filtered message *data* to the signer and the spool.
+    fn pass(&mut self, data: &[u8]) {
+        if let Some(ref mut signer) = self.signer {
+            signer.chunk(data)
+        }
+        self.write(data)
+    }
+
+    /// Adds the authentication results to the mail and queues it.
+    ///
+    /// If DMARC asks for the mail to be rejected, it is dropped instead.
+    fn authenticated(mut self, res: Option<Authenticated>, reply: ReplyBuf)
+                     -> Session {
+        let res = match res {
+            Some(res) => res,
+            None => {
+                error!("MTA: DKIM verification failed");
+                return self.commit(reply)
+            }
+        };
+        let field = verify::results_field(&res, &self.session.hostname);
+        match verify::admit(&res, reply) {
+            Ok(reply) => {
+                self.spool.prepend(&field);
+                self.commit(reply)
+            }
+            Err(()) => {
+                if let Err(err) = self.spool.abort() {
+                    error!("MTA: removing spool file failed: {}", err);
+                }
+                self.session
+            }
+        }
+    }
+
+    /// Queues the mail.
+    fn commit(self, reply: ReplyBuf) -> Session {
+        if self.failed {
+            let _ = self.spool.abort();
+            reply.reply(451, (4, 3, 0), b"Local error in processing\r\n");
+            return self.session
+        }
+        match self.spool.commit(&self.envelope) {
+            Ok(id) => {
+                info!("MTA: queued {}", id);
+                let mut reply = reply.start(250, Some((2, 0, 0)));
+                scribble!(&mut reply, b"Ok: queued as ", id.as_str(),
+                          b"\r\n");
+            }
+            Err(err) => {
+                error!("MTA: committing mail failed: {}", err);
+                reply.reply(451, (4, 3, 0), b"Local error in processing\r\n");
+            }
+        }
+        self.session
+    }
+}
+
+impl DataHandler<Mta> for Data {
+    type Complete = DeferredReply<Authenticated, Data, Session>;
+
+    fn chunk(&mut self, data: &[u8]) {
+        if self.failed {
+            return
+        }
+        if let Some(ref mut verifier) = self.dkim {
+            verifier.chunk(data)
+        }
+        let data = match self.filter {
+            Some(ref mut filter) => filter.chunk(data),
+            None => data.into()
+        };
+        self.pass(&data)
+    }
+
+    fn trace(&mut self, mut trace: Trace) {
+        if self.session.hostname.is_empty() {
+            self.session.hostname = trace.hostname().into()
+        }
+        self.filter = Some(authres::Filter::new(&self.session.hostname));
+        trace.set_id(self.spool.id().as_str().as_bytes());
+        if let Some(ref name) = self.session.client.name {
+            trace.set_peer_name(name)
+        }
+        // Received-SPF has to go above our Received field, RFC 7208,
+        // section 9.1, and X-DNSBL keeps it company.
+        if let Some(field) = self.spf.as_ref().map(Spf::received_spf) {
+            self.write(&field);
+        }
+        let fields = self.session.client.dnsbl_fields();
+        self.write(&fields);
+        self.write(&trace.to_field())
+    }
+
+    fn complete(mut self, reply: ReplyBuf)
+                -> Hesitant<Session, Self::Complete> {
+        if let Some(filter) = self.filter.take() {
+            self.pass(&filter.finish())
+        }
+        if self.failed {
+            return Hesitant::Final(self.commit(reply))
+        }
+        if let Some(signer) = self.signer.take() {
+            if let Some(field) = signer.finish() {
+                self.spool.prepend(&field)
+            }
+        }
+        let pending = match self.dkim.take() {
+            Some(verifier) => {
+                self.session.verify.start(verifier, self.session.peer,
+                                          self.spf.clone(),
+                                          self.session.pool.as_ref(),
+                                          self.session.notifier.as_ref())
+            }
+            None => None
+        };
+        match pending {
+            Some(pending) => {
+                Hesitant::Defer(DeferredReply::new(pending, self,
+                                                   Data::authenticated))
+            }
+            None => Hesitant::Final(self.commit(reply))
+        }
+    }
+
+    fn reset(self) -> Session {
+        if let Err(err) = self.spool.abort() {
+            error!("MTA: removing spool file failed: {}", err);
+        }
+        self.session
+    }
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use ::net::dns::{Record, Zone};
+    use ::net::test::FakeCertificate;
+    use ::smtp::dkim::Canon;
+    use ::smtp::dkim::sign;
+    use ::smtp::dkim::verify;
+    use ::smtp::server::dnsbl::{Action, Dnsbl, List};
+    use ::smtp::server::worker::Pool;
+    use ::smtp::local::aliases::Aliases;
+    use ::smtp::local::users::{Entry, Users};
+    use ::smtp::server::harness::Harness;
+    use ::smtp::server::protocol::Protocol;
+    use ::util::test::TempDir;
+    use super::*;
+    use super::super::queue::{Queue, QueueId};
+
+    /// Creates an MTA with its queue in a new temporary directory.
+    fn mta(name: &str) -> (Mta, TempDir) {
+        let dir = TempDir::new(name);
+        let mut mta = Mta::new(Queue::open(&dir).unwrap());
+        mta.set_hostname(b"mx.test");
+        (mta, dir)
+    }
+
+    /// Connects a client from *addr* and waits for the greeting.
+    fn connect(mta: &mut Mta, addr: &str) -> Harness<Mta> {
+        let seed = mta.accept(&addr.parse().unwrap()).unwrap();
+        let mut harness = Harness::<Mta>::new(seed);
+        harness.settle();
+        harness
+    }
+
+    /// Connects a client from *addr* that says EHLO.
+    fn ehlo(mta: &mut Mta, addr: &str) -> Harness<Mta> {
+        let mut harness = connect(mta, addr);
+        harness.replies(220).says(b"EHLO client.test\r\n").replies(250);
+        harness
+    }
+
+    /// Returns everything in the queue in *dir*.
+    fn queued(dir: &TempDir) -> Vec<(QueueId, Envelope, Vec<u8>)> {
+        let queue = Queue::open(dir).unwrap();
+        queue.list().unwrap().into_iter().map(|id| {
+            let envelope = queue.envelope(&id).unwrap();
+            let mut message = Vec::new();
+            queue.message(&id).unwrap().read_to_end(&mut message).unwrap();
+            (id, envelope, message)
+        }).collect()
+    }
+
+    #[test]
+    fn queue_mail() {
+        let (mut mta, dir) = mta("mta");
+        let mut harness = ehlo(&mut mta, "127.0.0.1:25");
+        harness.says(b"MAIL FROM:<a@client.test>\r\n").replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"Subject: Test\r\n\r\n..Hello\r\n.\r\n").replies(250)
+               .says(b"ETRN mx.test\r\n").replies(502)
+               .says(b"QUIT\r\n").replies(221);
+        assert!(harness.is_closed());
+
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        let (ref id, ref envelope, ref message) = queued[0];
+        assert_eq!(envelope.reverse_path, b"a@client.test");
+        assert_eq!(envelope.recipients.len(), 1);
+        assert_eq!(envelope.recipients[0].path, b"b@mx.test");
+        let received = format!("Received: from client.test ([127.0.0.1])\r\n\
+                                \tby mx.test with ESMTP id {}\r\n\
+                                \tfor <b@mx.test>;\r\n\t", id);
+        assert!(message.starts_with(received.as_bytes()));
+        assert!(message.ends_with(b"\r\nSubject: Test\r\n\r\n.Hello\r\n"));
+    }
+
+    #[test]
+    fn no_relay() {
+        let (mut mta, dir) = mta("mta-no-relay");
+        let mut harness = ehlo(&mut mta, "127.0.0.1:25");
+        harness.says(b"MAIL FROM:<a@client.test>\r\n").replies(250)
+               .says(b"RCPT TO:<x@elsewhere.test>\r\n").replies(550)
+               .says(b"RCPT TO:<Postmaster@elsewhere.test>\r\n")
+               .replies(550)
+               .says(b"RCPT TO:<Postmaster@MX.test>\r\n").replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"Subject: Test\r\n\r\n.\r\n").replies(250);
+
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        let recipients = &queued[0].1.recipients;
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(recipients[0].path, b"Postmaster@MX.test");
+        assert_eq!(recipients[1].path, b"b@mx.test");
+    }
+
+    #[test]
+    fn reverse_dns() {
+        let mut zone = Zone::new();
+        zone.insert(b"1.0.0.127.in-addr.arpa",
+                    Record::Ptr(b"client.test".to_vec()));
+        zone.insert(b"client.test", Record::A(Ipv4Addr::new(127, 0, 0, 1)));
+        let (mut mta, dir) = mta("mta-reverse-dns");
+        mta.set_pool(Pool::new(1));
+        mta.set_reverse_dns(Arc::new(zone));
+        let mut harness = connect(&mut mta, "127.0.0.1:25");
+        harness.replies(220)
+               .says(b"HELO client.test\r\n").replies(250)
+               .says(b"MAIL FROM:<a@client.test>\r\n").replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"RCPT TO:<c@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"Subject: Test\r\n\r\n.\r\n").replies(250);
+
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        let (ref id, _, ref message) = queued[0];
+        let received = format!("Received: from client.test \
+                                (client.test [127.0.0.1])\r\n\
+                                \tby mx.test with SMTP id {};\r\n\t", id);
+        assert!(message.starts_with(received.as_bytes()));
+    }
+
+    struct Alice;
+
+    impl CredentialLookup for Alice {
+        fn password(&self, authcid: &[u8]) -> Option<Vec<u8>> {
+            if authcid == b"alice" || authcid == b"alice@example.com" {
+                Some(b"secret".to_vec())
+            }
+            else { None }
+        }
+    }
+
+    #[test]
+    fn submission() {
+        let mut users = Users::new();
+        users.add_domain(b"mx.test");
+        users.insert(b"alice", Entry::Local);
+        let (mut mta, dir) = mta("mta-submission");
+        mta.set_users(users);
+        mta.set_credentials(Alice);
+        let mut harness = ehlo(&mut mta, "127.0.0.1:25");
+        harness.says(b"MAIL FROM:<alice@mx.test>\r\n").replies(250)
+               .says(b"RCPT TO:<b@example.com>\r\n").replies(550)
+               .says(b"RSET\r\n").replies(250)
+               .says(b"STARTTLS\r\n").replies(220)
+               .cipher(b"TLSv1.3 with cipher TLS_AES_128_GCM_SHA256 \
+                         (128/128 bits)")
+               .secure(Some(FakeCertificate))
+               .says(b"EHLO client.test\r\n").replies(250)
+               .says(b"AUTH PLAIN AGFsaWNlAHNlY3JldA==\r\n").replies(235)
+               .says(b"MAIL FROM:<alice@mx.test>\r\n").replies(250)
+               .says(b"RCPT TO:<b@example.com>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"Subject: Test\r\n\r\n.\r\n").replies(250);
+
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].1.recipients[0].path, b"b@example.com");
+        let using = b" with ESMTPSA\r\n\
+                      \t(using TLSv1.3 with cipher TLS_AES_128_GCM_SHA256 \
+                      (128/128 bits)) id ";
+        assert!(queued[0].2.windows(using.len()).any(|w| w == &using[..]));
+    }
+
+    #[test]
+    fn local_users() {
+        let mut users = Users::new();
+        users.add_domain(b"mx.test");
+        users.read_users(&b"alice\n\
+                            bob forward bob@example.com\n\
+                            carol moved carol@example.com\n"[..]).unwrap();
+        let (mut mta, dir) = mta("mta-users");
+        mta.set_users(users);
+        mta.set_verify(true);
+        let mut harness = ehlo(&mut mta, "127.0.0.1:25");
+        harness.says(b"VRFY alice\r\n").replies(250)
+               .says(b"VRFY bob\r\n").replies(251)
+               .says(b"VRFY dave\r\n").replies(550)
+               .says(b"EXPN alice\r\n").replies(252)
+               .says(b"MAIL FROM:<a@client.test>\r\n").replies(250)
+               .says(b"RCPT TO:<alice+test@mx.test>\r\n").replies(250)
+               .says(b"RCPT TO:<bob@mx.test>\r\n").replies(251)
+               .says(b"RCPT TO:<carol@mx.test>\r\n").replies(551)
+               .says(b"RCPT TO:<dave@mx.test>\r\n").replies(550)
+               .says(b"RCPT TO:<alice@example.com>\r\n").replies(550)
+               .says(b"RCPT TO:<Postmaster>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"Subject: Test\r\n\r\n.\r\n").replies(250);
+
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        let envelope = &queued[0].1;
+        let paths: Vec<_> = envelope.recipients.iter()
+                                    .map(|rcpt| &rcpt.path[..]).collect();
+        assert_eq!(paths, [&b"alice+test@mx.test"[..],
+                           &b"bob@example.com"[..], &b"Postmaster"[..]]);
+        assert_eq!(envelope.recipients[1].orcpt,
+                   Some(b"rfc822;bob@mx.test".to_vec()));
+    }
+
+    #[test]
+    fn aliases() {
+        let mut users = Users::new();
+        users.add_domain(b"mx.test");
+        users.insert(b"alice", Entry::Local);
+        let mut aliases = Aliases::new();
+        aliases.read(&b"staff: alice, bob@example.com\n\
+                        loop1: loop2\n\
+                        loop2: loop1\n\
+                        archive: alice, \"|/bin/archive\"\n\
+                        spaced: \"carol smith\"\n"[..]).unwrap();
+        let (mut mta, dir) = mta("mta-aliases");
+        mta.set_users(users);
+        mta.set_aliases(aliases);
+        mta.set_expand(true);
+        let mut harness = ehlo(&mut mta, "127.0.0.1:25");
+        harness.says(b"EXPN staff\r\n");
+        let reply = harness.reply().unwrap();
+        assert_eq!(reply.code, 250);
+        assert!(reply.text.ends_with(b"<bob@example.com>"));
+        harness.says(b"EXPN loop1\r\n").replies(550)
+               .says(b"EXPN archive\r\n").replies(550)
+               .says(b"VRFY archive\r\n").replies(550)
+               .says(b"MAIL FROM:<a@client.test>\r\n").replies(250)
+               .says(b"RCPT TO:<staff@mx.test>\r\n").replies(250)
+               .says(b"RCPT TO:<loop2@mx.test>\r\n").replies(550)
+               .says(b"RCPT TO:<archive@mx.test>\r\n").replies(550)
+               .says(b"RCPT TO:<spaced@mx.test>\r\n").replies(550)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"Subject: Test\r\n\r\n.\r\n").replies(250);
+
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        let envelope = &queued[0].1;
+        let paths: Vec<_> = envelope.recipients.iter()
+                                    .map(|rcpt| &rcpt.path[..]).collect();
+        assert_eq!(paths, [&b"alice@mx.test"[..], &b"bob@example.com"[..]]);
+        assert_eq!(envelope.recipients[1].orcpt,
+                   Some(b"rfc822;staff@mx.test".to_vec()));
+    }
+
+    #[test]
+    fn dnsbl() {
+        let mut zone = Zone::new();
+        zone.insert(b"2.0.0.127.bl.test",
+                    Record::A(Ipv4Addr::new(127, 0, 0, 2)));
+        zone.insert(b"3.0.0.127.tag.test",
+                    Record::A(Ipv4Addr::new(127, 0, 0, 3)));
+        let mut dnsbl = Dnsbl::new(Arc::new(zone));
+        dnsbl.add(List::new(b"bl.test", Action::Reject));
+        dnsbl.add(List::new(b"tag.test", Action::Tag));
+        let (mut mta, dir) = mta("mta-dnsbl");
+        mta.set_pool(Pool::new(1));
+        mta.set_dnsbl(dnsbl);
+
+        let mut harness = connect(&mut mta, "127.0.0.2:25");
+        harness.replies(554);
+        assert!(harness.is_closed());
+
+        let mut harness = ehlo(&mut mta, "127.0.0.3:25");
+        harness.says(b"MAIL FROM:<a@client.test>\r\n").replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"Subject: Test\r\n\r\n.\r\n").replies(250);
+
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        let message = &queued[0].2;
+        assert!(message.starts_with(b"X-DNSBL: 127.0.0.3 listed in tag.test \
+                                      (127.0.0.3)\r\n\
+                                      Received: "));
+        assert!(message.ends_with(b"\r\nSubject: Test\r\n\r\n"));
+    }
+
+    #[test]
+    fn spf() {
+        let mut zone = Zone::new();
+        zone.insert(b"client.test",
+                    Record::Txt(b"v=spf1 ip4:127.0.0.3 -all".to_vec()));
+        let (mut mta, dir) = mta("mta-spf");
+        mta.set_pool(Pool::new(1));
+        mta.set_spf(Arc::new(zone));
+
+        let mut harness = ehlo(&mut mta, "127.0.0.4:25");
+        harness.says(b"MAIL FROM:<a@client.test>\r\n").settle();
+        let reply = harness.reply().unwrap();
+        assert_eq!(reply.code, 550);
+        assert_eq!(reply.status, Some((5, 7, 23)));
+
+        let mut harness = ehlo(&mut mta, "127.0.0.3:25");
+        harness.says(b"MAIL FROM:<a@client.test>\r\n").settle().replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"Subject: Test\r\n\r\n.\r\n").replies(250);
+
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        let message = &queued[0].2;
+        assert!(message.starts_with(b"Received-SPF: pass (domain of \
+                                      a@client.test designates 127.0.0.3 \
+                                      as permitted sender)\r\n\
+                                      \tclient-ip=127.0.0.3; \
+                                      envelope-from=\"a@client.test\"; \
+                                      helo=\"client.test\";\r\n\
+                                      \tidentity=mailfrom;\r\n\
+                                      Received: from client.test "));
+        assert!(message.ends_with(b"Subject: Test\r\n\r\n"));
+    }
+
+    #[test]
+    fn dkim() {
+        let (mut mta, dir) = mta("mta-dkim");
+        mta.set_hostname(b"mx.test");
+        mta.set_pool(Pool::new(1));
+        mta.set_dkim(Arc::new(Zone::new()));
+        let mut harness = ehlo(&mut mta, "127.0.0.1:25");
+        harness.says(b"MAIL FROM:<a@client.test>\r\n").replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"Authentication-Results: mx.test; dkim=pass\r\n\
+                       Subject: Test\r\n\r\nHello\r\n.\r\n").settle()
+               .replies(250);
+
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        let message = &queued[0].2;
+        assert!(message.starts_with(b"Authentication-Results: mx.test;\r\n\
+                                      \tdkim=none\r\n\
+                                      Received: from client.test "));
+        assert!(message.ends_with(b"\r\nSubject: Test\r\n\r\nHello\r\n"));
+        assert!(!message.windows(9).any(|part| part == b"dkim=pass"));
+    }
+
+    #[test]
+    fn dkim_sign() {
+        let mut keys = Keys::new();
+        keys.add(sign::test::key(Canon::Relaxed, Canon::Relaxed));
+        keys.add(sign::test::key_for(b"example.org"));
+        let (mut mta, dir) = mta("mta-dkim-sign");
+        mta.set_credentials(Alice);
+        mta.set_dkim_keys(keys);
+        let mut harness = ehlo(&mut mta, "127.0.0.1:25");
+        harness.says(b"MAIL FROM:<a@example.com>\r\n").replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"From: a@example.com\r\n\r\nUnsigned\r\n.\r\n")
+               .replies(250)
+               .says(b"STARTTLS\r\n").replies(220)
+               .secure(Some(FakeCertificate))
+               .says(b"EHLO client.test\r\n").replies(250)
+               // "\0alice\0wrong" and "\0alice@example.com\0secret"
+               .says(b"AUTH PLAIN AGFsaWNlAHdyb25n\r\n").replies(535)
+               .says(b"AUTH PLAIN AGFsaWNlQGV4YW1wbGUuY29tAHNlY3JldA==\r\n")
+               .replies(235)
+               .says(b"MAIL FROM:<a@example.com>\r\n").replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"From: a@example.com\r\n\r\nSigned\r\n.\r\n")
+               .replies(250)
+               // Alice has nothing to do with example.org, whatever
+               // the envelope sender says.
+               .says(b"MAIL FROM:<x@example.org>\r\n").replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"From: x@example.org\r\n\r\nForged\r\n.\r\n")
+               .replies(250);
+
+        let mut messages: Vec<_> = queued(&dir).into_iter()
+                                   .map(|(_, _, message)| message).collect();
+        messages.sort();
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].starts_with(b"DKIM-Signature: v=1; \
+                                          a=rsa-sha256; c=relaxed/relaxed; \
+                                          d=example.com;\r\n"));
+        assert!(messages[0].ends_with(b"\r\nFrom: a@example.com\r\n\r\n\
+                                        Signed\r\n"));
+        assert_eq!(sign::test::verify(&messages[0]),
+                   [(verify::Outcome::Pass, None)]);
+        let with = b"\tby mx.test with ESMTPSA id ";
+        assert!(messages[0].windows(with.len()).any(|w| w == &with[..]));
+        assert!(messages[1].starts_with(b"Received: from client.test \
+                                          ([127.0.0.1])\r\n\
+                                          \tby mx.test with ESMTP id "));
+        assert!(messages[1].ends_with(b"\r\nFrom: a@example.com\r\n\r\n\
+                                        Unsigned\r\n"));
+        assert!(messages[2].starts_with(b"Received: from client.test \
+                                          ([127.0.0.1])\r\n\
+                                          \tby mx.test with ESMTPSA id "));
+        assert!(messages[2].ends_with(b"\r\nFrom: x@example.org\r\n\r\n\
+                                        Forged\r\n"));
+    }
+
+    #[test]
+    fn dmarc() {
+        let store_dir = TempDir::new("mta-dmarc-store");
+        let mut zone = Zone::new();
+        zone.insert(b"_dmarc.example.com",
+                    Record::Txt(b"v=DMARC1; p=reject; \
+                                  rua=mailto:dmarc@example.com".to_vec()));
+        let (mut mta, dir) = mta("mta-dmarc");
+        mta.set_hostname(b"mx.test");
+        mta.set_pool(Pool::new(1));
+        mta.set_dmarc(Suffixes::new(), Arc::new(zone));
+        mta.set_dmarc_store(Store::open(&store_dir).unwrap());
+
+        let mail = |mta: &mut Mta| {
+            let mut harness = ehlo(mta, "127.0.0.1:25");
+            harness.says(b"MAIL FROM:<a@client.test>\r\n").replies(250)
+                   .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+                   .says(b"DATA\r\n").replies(354)
+                   .says(b"From: a@example.com\r\n\r\nHello\r\n.\r\n")
+                   .settle();
+            harness
+        };
+
+        let mut harness = mail(&mut mta);
+        let reply = harness.reply().unwrap();
+        assert_eq!(reply.code, 550);
+        assert_eq!(reply.status, Some((5, 7, 1)));
+        assert!(queued(&dir).is_empty());
+        let reports = Store::open(&store_dir).unwrap().collect(!0).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].domain(), b"example.com");
+        assert_eq!(reports[0].count(), 1);
+
+        // Which policy applies is unclear with more than one author.
+        let mut harness = ehlo(&mut mta, "127.0.0.1:25");
+        harness.says(b"MAIL FROM:<a@client.test>\r\n").replies(250)
+               .says(b"RCPT TO:<b@mx.test>\r\n").replies(250)
+               .says(b"DATA\r\n").replies(354)
+               .says(b"From: a@client.test, a@example.com\r\n\r\n\
+                       Hello\r\n.\r\n").settle()
+               .replies(550);
+        assert!(queued(&dir).is_empty());
+
+        mta.set_dmarc_reject(false);
+        mail(&mut mta).replies(250);
+        let queued = queued(&dir);
+        assert_eq!(queued.len(), 1);
+        let message = &queued[0].2;
+        let needle = b"dmarc=fail header.from=example.com \
+                       policy.dmarc=quarantine";
+        assert!(message.windows(needle.len()).any(|w| w == &needle[..]));
+        assert!(message.ends_with(b"\r\nFrom: a@example.com\r\n\r\n\
+                                    Hello\r\n"));
+    }
+}
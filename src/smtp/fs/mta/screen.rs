@@ -0,0 +1,129 @@
+//! Screening clients before they are greeted.
+//!
+//! If blocklists have been set, clients are checked against them.
+//! Clients listed on a rejecting list are turned away while listings on
+//! tagging lists are noted in an X-DNSBL header field added to each mail
+//! of the session. If reverse DNS lookups have been enabled, the name of
+//! the client is looked up for the Received field.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use ::net::dns::Resolver;
+use ::smtp::server::dnsbl::{Dnsbl, Listing, Verdict};
+use ::smtp::server::trace;
+
+
+//------------ Screen --------------------------------------------------------
+
+/// The checks a client goes through when it connects.
+#[derive(Clone)]
+pub struct Screen {
+    /// The blocklists to check the client against.
+    dnsbl: Option<Arc<Dnsbl>>,
+
+    /// The resolver for the name of the client if lookups are enabled.
+    rdns: Option<Arc<Resolver>>,
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Screen { dnsbl: None, rdns: None }
+    }
+
+    /// Sets the blocklists to check clients against.
+    pub fn set_dnsbl(&mut self, dnsbl: Dnsbl) {
+        self.dnsbl = Some(Arc::new(dnsbl))
+    }
+
+    /// Enables looking up the name of clients using *resolver*.
+    pub fn set_reverse_dns(&mut self, resolver: Arc<Resolver>) {
+        self.rdns = Some(resolver)
+    }
+
+    /// Returns whether there is anything to check.
+    pub fn is_enabled(&self) -> bool {
+        self.dnsbl.is_some() || self.rdns.is_some()
+    }
+
+    /// Checks the client at *peer*.
+    ///
+    /// This does DNS lookups and therefore has to run on the pool.
+    pub fn check(&self, peer: IpAddr) -> Screening {
+        (self.dnsbl.as_ref().map(|dnsbl| dnsbl.check(peer)),
+         self.rdns.as_ref().and_then(|rdns| trace::peer_name(&**rdns, peer)))
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen::new()
+    }
+}
+
+
+//------------ Screening -----------------------------------------------------
+
+/// The verdict of the blocklists, if any, and the name of the client.
+pub type Screening = (Option<Verdict>, Option<Vec<u8>>);
+
+
+//------------ Client --------------------------------------------------------
+
+/// What has been learned about a client that passed screening.
+#[derive(Clone, Debug, Default)]
+pub struct Client {
+    /// The blocklists the client is listed on.
+    pub listings: Vec<Listing>,
+
+    /// The name of the client if it has been looked up and confirmed.
+    pub name: Option<Vec<u8>>,
+}
+
+impl Client {
+    /// Decides on a client once it has been screened.
+    ///
+    /// Returns `None` if the client is to be turned away. A failed check
+    /// lets the client pass.
+    pub fn screened(res: Option<Screening>) -> Option<Self> {
+        let (verdict, name) = match res {
+            Some(res) => res,
+            None => {
+                error!("MTA: checking the client failed");
+                return Some(Client::default())
+            }
+        };
+        if let Some(ref name) = name {
+            info!("MTA: client is {}", String::from_utf8_lossy(name));
+        }
+        let listings = match verdict {
+            None | Some(Verdict::Clean) => Vec::new(),
+            Some(Verdict::Tag(listings)) => {
+                for listing in &listings {
+                    info!("MTA: {}", listing);
+                }
+                listings
+            }
+            Some(Verdict::Reject(listing)) => {
+                match listing.reason {
+                    Some(ref reason) => {
+                        info!("MTA: rejecting client: {}: {}", listing,
+                              String::from_utf8_lossy(reason));
+                    }
+                    None => info!("MTA: rejecting client: {}", listing),
+                }
+                return None
+            }
+        };
+        Some(Client { listings: listings, name: name })
+    }
+
+    /// Returns the X-DNSBL header fields for the listings.
+    pub fn dnsbl_fields(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        for listing in &self.listings {
+            res.extend_from_slice(format!("X-DNSBL: {}\r\n",
+                                          listing).as_bytes());
+        }
+        res
+    }
+}
@@ -0,0 +1,45 @@
+//! Signing submitted mail.
+//!
+//! If signing keys have been set, mail submitted by authenticated
+//! clients is signed with the DKIM key for the domain of its From
+//! address before it is queued. This only happens if the user name is
+//! an address in that domain.
+
+use std::rc::Rc;
+use ::smtp::dkim::sign::{Keys, Signer};
+
+
+//------------ Signing -------------------------------------------------------
+
+#[derive(Clone)]
+pub struct Signing {
+    /// The keys for signing mail of authenticated clients.
+    keys: Option<Rc<Keys>>,
+}
+
+impl Signing {
+    pub fn new() -> Self {
+        Signing { keys: None }
+    }
+
+    /// Sets the keys for signing mail of authenticated clients.
+    pub fn set_keys(&mut self, keys: Keys) {
+        self.keys = Some(Rc::new(keys))
+    }
+
+    /// Returns a signer for mail from the *authenticated* user, if any.
+    pub fn signer(&self, authenticated: Option<&[u8]>) -> Option<Signer> {
+        match (authenticated, &self.keys) {
+            (Some(user), &Some(ref keys)) => {
+                Some(Signer::new(keys.clone(), &[user]))
+            }
+            _ => None
+        }
+    }
+}
+
+impl Default for Signing {
+    fn default() -> Self {
+        Signing::new()
+    }
+}
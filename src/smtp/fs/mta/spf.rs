@@ -0,0 +1,109 @@
+//! Checking the sender of each transaction with SPF.
+//!
+//! If SPF checking has been enabled, the client’s HELO and MAIL
+//! identities are checked for each transaction and the result recorded
+//! in a Received-SPF header field right above the Received field. Mail
+//! failing the check is rejected unless this has been disabled.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use rotor::Notifier;
+use ::net::dns::Resolver;
+use ::smtp::server::reply::ReplyBuf;
+use ::smtp::server::worker::{Pending, Pool};
+use ::smtp::spf::{Outcome, Query, Spf};
+use ::smtp::syntax;
+
+
+//------------ SpfCheck ------------------------------------------------------
+
+#[derive(Clone)]
+pub struct SpfCheck {
+    /// The resolver if checks are enabled.
+    resolver: Option<Arc<Resolver>>,
+
+    /// Is mail failing the check rejected?
+    reject: bool,
+
+    /// The query prepared when the client said hello.
+    query: Option<Query>,
+}
+
+impl SpfCheck {
+    pub fn new() -> Self {
+        SpfCheck { resolver: None, reject: true, query: None }
+    }
+
+    /// Enables SPF checking using *resolver*.
+    pub fn set_resolver(&mut self, resolver: Arc<Resolver>) {
+        self.resolver = Some(resolver)
+    }
+
+    /// Sets whether mail failing the check is rejected.
+    pub fn set_reject(&mut self, enable: bool) {
+        self.reject = enable
+    }
+
+    /// Prepares the check when the client at *peer* says hello.
+    pub fn hello(&mut self, peer: Option<IpAddr>,
+                 domain: &syntax::MailboxDomain) {
+        if let (true, Some(peer)) = (self.resolver.is_some(), peer) {
+            self.query = Some(Query::new(peer, domain))
+        }
+    }
+
+    /// Starts the check for a transaction from *path* if it is enabled.
+    pub fn check(&self, path: &syntax::ReversePath, pool: Option<&Pool>,
+                 notifier: Option<&Notifier>) -> Option<Pending<Spf>> {
+        let (resolver, pool, notifier, query) = match (&self.resolver, pool,
+                                                      notifier,
+                                                      &self.query) {
+            (&Some(ref resolver), Some(pool), Some(notifier),
+             &Some(ref query)) => (resolver.clone(), pool, notifier, query),
+            _ => return None
+        };
+        let mut query = query.clone();
+        query.set_sender(path);
+        Some(pool.run(notifier, move || query.check(&*resolver)))
+    }
+
+    /// Decides on a transaction once the check is done.
+    ///
+    /// If the mail is rejected, the reply is written and `Err(())`
+    /// returned. Otherwise the reply is handed back.
+    pub fn admit<'a>(&self, spf: Option<&Spf>, reply: ReplyBuf<'a>)
+                     -> Result<ReplyBuf<'a>, ()> {
+        let spf = match spf {
+            Some(spf) => spf,
+            None => {
+                error!("MTA: SPF check failed");
+                return Ok(reply)
+            }
+        };
+        info!("MTA: SPF {} for {} from {}", spf.result,
+              String::from_utf8_lossy(&spf.sender), spf.ip);
+        if spf.result != Outcome::Fail || !self.reject {
+            return Ok(reply)
+        }
+        let mut reply = reply.start(550, Some((5, 7, 23)));
+        match spf.explanation {
+            // The explanation comes from the DNS, so only use it if it is
+            // harmless.
+            Some(ref exp) if exp.iter().all(|ch| {
+                *ch >= 0x20 && *ch < 0x7f
+            }) => {
+                scribble!(&mut reply, &exp[..], b"\r\n");
+            }
+            _ => {
+                scribble!(&mut reply, b"SPF validation failed\r\n");
+            }
+        }
+        Err(())
+    }
+}
+
+impl Default for SpfCheck {
+    fn default() -> Self {
+        SpfCheck::new()
+    }
+}
@@ -0,0 +1,203 @@
+//! Verifying DKIM signatures and evaluating DMARC policies.
+//!
+//! If DKIM verification has been enabled, the signatures of each mail
+//! are checked once it has been received and the results recorded in an
+//! Authentication-Results header field. The field is named after our
+//! host name. Authentication-Results fields by that name already present
+//! in incoming mail are removed since they can only be forged.
+//!
+//! If DMARC has been enabled, the policy of the domain in the From field
+//! of each mail is evaluated against the SPF and DKIM results. The
+//! outcome is added to the Authentication-Results field. Mail is rejected
+//! if the policy asks for it unless this has been disabled, in which case
+//! it is only marked. Since there is no quarantine, mail to be
+//! quarantined is marked, too. Mail with a missing From field or one
+//! naming more than one author is treated like mail whose policy asks for
+//! rejection. If a store has been set, the outcome is recorded there for
+//! aggregate reports.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use rotor::Notifier;
+use ::net::dns::Resolver;
+use ::smtp::authres::AuthResults;
+use ::smtp::dkim::verify::{self, Verification, Verifier};
+use ::smtp::dmarc::{self, Disposition, Evaluation, Override, Suffixes};
+use ::smtp::dmarc::report::Store;
+use ::smtp::server::reply::ReplyBuf;
+use ::smtp::server::worker::{Pending, Pool};
+use ::smtp::spf::Spf;
+use ::util::date;
+
+
+//------------ Verify --------------------------------------------------------
+
+#[derive(Clone)]
+pub struct Verify {
+    /// The resolver for DKIM keys if verification is enabled.
+    dkim: Option<Arc<Resolver>>,
+
+    /// The public suffixes and resolver for DMARC if it is enabled.
+    dmarc: Option<(Arc<Suffixes>, Arc<Resolver>)>,
+
+    /// Is mail rejected if its DMARC policy asks for it?
+    reject: bool,
+
+    /// The store for DMARC reporting.
+    store: Option<Arc<Store>>,
+}
+
+impl Verify {
+    pub fn new() -> Self {
+        Verify { dkim: None, dmarc: None, reject: true, store: None }
+    }
+
+    /// Enables DKIM verification using *resolver* to look up keys.
+    pub fn set_dkim(&mut self, resolver: Arc<Resolver>) {
+        self.dkim = Some(resolver)
+    }
+
+    /// Enables DMARC using *resolver* to look up policies.
+    ///
+    /// Since DMARC needs the results of DKIM verification, this enables
+    /// it, too, if necessary.
+    pub fn set_dmarc(&mut self, suffixes: Suffixes,
+                     resolver: Arc<Resolver>) {
+        if self.dkim.is_none() {
+            self.dkim = Some(resolver.clone());
+        }
+        self.dmarc = Some((Arc::new(suffixes), resolver));
+    }
+
+    /// Sets whether mail is rejected if its DMARC policy asks for it.
+    pub fn set_reject(&mut self, enable: bool) {
+        self.reject = enable
+    }
+
+    /// Sets the store to record DMARC results in for reporting.
+    pub fn set_store(&mut self, store: Store) {
+        self.store = Some(Arc::new(store))
+    }
+
+    /// Returns a verifier for a new mail if verification is enabled.
+    pub fn verifier(&self) -> Option<Verifier> {
+        self.dkim.as_ref().map(|_| Verifier::new())
+    }
+
+    /// Starts verifying the signatures and evaluating DMARC.
+    ///
+    /// The mail came from *peer* and has passed the SPF check with the
+    /// result *spf* if there was one. Returns `None` if there is no
+    /// pool to run on.
+    pub fn start(&self, verifier: Verifier, peer: Option<IpAddr>,
+                 spf: Option<Spf>, pool: Option<&Pool>,
+                 notifier: Option<&Notifier>)
+                 -> Option<Pending<Authenticated>> {
+        let signatures = verifier.finish();
+        let dmarc = match (&self.dmarc, peer) {
+            (&Some((ref suffixes, ref resolver)), Some(peer)) => {
+                let query = dmarc::Query::new(peer,
+                                              signatures.author_domain(),
+                                              spf);
+                Some((suffixes.clone(), resolver.clone(), query,
+                      self.store.clone(), self.reject))
+            }
+            _ => None
+        };
+        match (&self.dkim, pool, notifier) {
+            (&Some(ref resolver), Some(pool), Some(notifier)) => {
+                let resolver = resolver.clone();
+                Some(pool.run(notifier, move || {
+                    let res = signatures.verify(&*resolver);
+                    let evaluation = dmarc.map(|(suffixes, resolver, query,
+                                                 store, reject)| {
+                        evaluate(query, &res, &suffixes, &*resolver, store,
+                                 reject)
+                    });
+                    (res, evaluation)
+                }))
+            }
+            _ => None
+        }
+    }
+}
+
+impl Default for Verify {
+    fn default() -> Self {
+        Verify::new()
+    }
+}
+
+
+//------------ Authenticated -------------------------------------------------
+
+/// The DKIM results and DMARC evaluation of a mail.
+pub type Authenticated = (Vec<Verification>, Option<Evaluation>);
+
+/// Returns the Authentication-Results field for *res*.
+///
+/// The field is named after *hostname*.
+pub fn results_field(res: &Authenticated, hostname: &[u8]) -> Vec<u8> {
+    let (ref dkim, ref evaluation) = *res;
+    for item in dkim {
+        info!("MTA: DKIM {} for {}", item.result,
+              String::from_utf8_lossy(&item.domain));
+    }
+    let mut results = AuthResults::new(hostname);
+    verify::add_results(dkim, &mut results);
+    if let Some(ref evaluation) = *evaluation {
+        info!("MTA: DMARC {} for {}", evaluation.result.as_str(),
+              String::from_utf8_lossy(&evaluation.domain));
+        evaluation.add_to(&mut results);
+    }
+    results.to_field()
+}
+
+/// Decides on a mail once DMARC has been evaluated.
+///
+/// If the policy asks for the mail to be rejected, the reply is written
+/// and `Err(())` returned. Otherwise the reply is handed back.
+pub fn admit<'a>(res: &Authenticated, reply: ReplyBuf<'a>)
+                 -> Result<ReplyBuf<'a>, ()> {
+    let evaluation = match res.1 {
+        Some(ref evaluation) => evaluation,
+        None => return Ok(reply)
+    };
+    if evaluation.disposition != Disposition::Reject {
+        return Ok(reply)
+    }
+    let mut reply = reply.start(550, Some((5, 7, 1)));
+    if evaluation.domain.is_empty() {
+        info!("MTA: rejecting mail without a single author");
+        scribble!(&mut reply, b"Missing or ambiguous From field\r\n");
+    }
+    else {
+        info!("MTA: rejecting mail by DMARC policy of {}",
+              String::from_utf8_lossy(&evaluation.domain));
+        scribble!(&mut reply, b"Rejected by DMARC policy of ",
+                  &evaluation.domain[..], b"\r\n");
+    }
+    Err(())
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Evaluates the DMARC policy for a mail and records the outcome.
+///
+/// Unless *reject* is set, mail the policy wants rejected is only
+/// marked. Since recording writes to a file, this runs on the pool.
+fn evaluate(query: dmarc::Query, dkim: &[Verification], suffixes: &Suffixes,
+            resolver: &Resolver, store: Option<Arc<Store>>, reject: bool)
+            -> Evaluation {
+    let mut res = query.check(dkim, suffixes, resolver);
+    if res.disposition == Disposition::Reject && !reject {
+        res.set_override(Disposition::Quarantine, Override::LocalPolicy)
+    }
+    if let Some(store) = store {
+        if let Err(err) = store.record(&res, date::now()) {
+            error!("MTA: recording DMARC result failed: {}", err);
+        }
+    }
+    res
+}
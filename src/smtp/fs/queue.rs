@@ -0,0 +1,777 @@
+//! A persistent mail queue in a directory.
+//!
+//! The queue directory has two subdirectories. New messages are spooled
+//! into `tmp` and moved into `queue` once they are complete and safely on
+//! disk. Each queued mail consists of two files named after its queue ID:
+//! the message itself with the extension `msg` and its envelope with the
+//! extension `env`. Header fields added in front of the message after it
+//! has been spooled go into a third file with the extension `hdr`. The
+//! envelope is moved last, so a mail is only in the queue if its envelope
+//! is.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use openssl::crypto::rand::rand_bytes;
+use ::smtp::syntax::{BodyValue, NotifyValue, Reply, RetValue};
+use ::util::text;
+
+
+//------------ Queue ---------------------------------------------------------
+
+#[derive(Debug)]
+pub struct Queue {
+    tmp: PathBuf,
+    queue: PathBuf,
+}
+
+impl Queue {
+    /// Opens the queue in directory *dir*, creating it if necessary.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let tmp = dir.as_ref().join("tmp");
+        let queue = dir.as_ref().join("queue");
+        try!(fs::create_dir_all(&tmp));
+        try!(fs::create_dir_all(&queue));
+        Ok(Queue { tmp: tmp, queue: queue })
+    }
+
+    /// Starts spooling a new message.
+    pub fn create(&self) -> io::Result<Spool> {
+        loop {
+            let id = QueueId::new();
+            let path = self.tmp.join(id.file_name("msg"));
+            match OpenOptions::new().write(true).create_new(true)
+                                    .open(&path) {
+                Ok(file) => {
+                    return Ok(Spool { id: id, file: file, path: path,
+                                      header: Vec::new(),
+                                      tmp: self.tmp.clone(),
+                                      queue: self.queue.clone() })
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists
+                    => { }
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    /// Returns the IDs of all mails currently in the queue.
+    pub fn list(&self) -> io::Result<Vec<QueueId>> {
+        let mut res = Vec::new();
+        for entry in try!(fs::read_dir(&self.queue)) {
+            let path = try!(entry).path();
+            if path.extension().map_or(false, |ext| ext == "env") {
+                if let Some(stem) = path.file_stem()
+                                        .and_then(|stem| stem.to_str()) {
+                    res.push(QueueId(stem.into()))
+                }
+            }
+        }
+        res.sort();
+        Ok(res)
+    }
+
+    /// Reads the envelope of a queued mail.
+    pub fn envelope(&self, id: &QueueId) -> io::Result<Envelope> {
+        let file = try!(File::open(self.queue.join(id.file_name("env"))));
+        Envelope::read(BufReader::new(file))
+    }
+
+    /// Opens the message of a queued mail.
+    ///
+    /// Any header fields prepended while spooling come first.
+    pub fn message(&self, id: &QueueId) -> io::Result<Message> {
+        let body = try!(File::open(self.queue.join(id.file_name("msg"))));
+        let mut header = Vec::new();
+        match File::open(self.queue.join(id.file_name("hdr"))) {
+            Ok(mut file) => { try!(file.read_to_end(&mut header)); }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => { }
+            Err(err) => return Err(err)
+        }
+        Ok(Message::new(header, body))
+    }
+
+    /// Replaces the envelope of a queued mail.
+    ///
+    /// The new envelope is written to a temporary file first and then
+    /// renamed over the old one.
+    pub fn update(&self, id: &QueueId, envelope: &Envelope)
+                  -> io::Result<()> {
+        let tmp = self.tmp.join(id.file_name("env"));
+        try!(write_synced(&tmp, envelope));
+        try!(fs::rename(&tmp, self.queue.join(id.file_name("env"))));
+        sync_dir(&self.queue)
+    }
+
+    /// Removes a mail from the queue.
+    pub fn remove(&self, id: &QueueId) -> io::Result<()> {
+        try!(fs::remove_file(self.queue.join(id.file_name("env"))));
+        try!(fs::remove_file(self.queue.join(id.file_name("msg"))));
+        match fs::remove_file(self.queue.join(id.file_name("hdr"))) {
+            Ok(()) => { }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => { }
+            Err(err) => return Err(err)
+        }
+        sync_dir(&self.queue)
+    }
+
+    /// Cleans up after a crash.
+    ///
+    /// Removes everything from the temporary directory and all messages
+    /// and headers that don’t have an envelope. Only call this when
+    /// nobody else is using the queue.
+    pub fn clean(&self) -> io::Result<()> {
+        for entry in try!(fs::read_dir(&self.tmp)) {
+            try!(fs::remove_file(try!(entry).path()));
+        }
+        for entry in try!(fs::read_dir(&self.queue)) {
+            let path = try!(entry).path();
+            if path.extension().map_or(false, |ext| {
+                ext == "msg" || ext == "hdr"
+            }) && !path.with_extension("env").exists() {
+                try!(fs::remove_file(path));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+//------------ Spool ---------------------------------------------------------
+
+/// A message being spooled into the queue.
+///
+/// Write the message, then call `commit()` to add it to the queue. If the
+/// spool is dropped without being committed, the message stays in the
+/// temporary directory until the next `Queue::clean()`.
+///
+/// Header fields that are only known once the message has been received
+/// can be added in front of it via `prepend()`.
+///
+#[derive(Debug)]
+pub struct Spool {
+    id: QueueId,
+    file: File,
+    path: PathBuf,
+
+    /// Header fields to go before the message.
+    header: Vec<u8>,
+
+    tmp: PathBuf,
+    queue: PathBuf,
+}
+
+impl Spool {
+    pub fn id(&self) -> &QueueId {
+        &self.id
+    }
+
+    /// Adds the message to the queue.
+    ///
+    /// Everything is synced to disk before this returns, so it is safe to
+    /// accept responsibility for the message afterwards.
+    pub fn commit(mut self, envelope: &Envelope) -> io::Result<QueueId> {
+        try!(self.file.flush());
+        try!(self.file.sync_all());
+        if !self.header.is_empty() {
+            let hdr = self.tmp.join(self.id.file_name("hdr"));
+            let mut file = try!(File::create(&hdr));
+            try!(file.write_all(&self.header));
+            try!(file.sync_all());
+            try!(fs::rename(&hdr, self.queue.join(self.id.file_name("hdr"))));
+        }
+        let env = self.tmp.join(self.id.file_name("env"));
+        try!(write_synced(&env, envelope));
+        let msg = self.queue.join(self.id.file_name("msg"));
+        try!(fs::rename(&self.path, msg));
+        try!(fs::rename(&env, self.queue.join(self.id.file_name("env"))));
+        try!(sync_dir(&self.queue));
+        Ok(self.id)
+    }
+
+    /// Inserts *data* before everything written or prepended so far.
+    ///
+    /// The data is kept apart and only written to disk by `commit()`, so
+    /// the message itself never needs rewriting.
+    pub fn prepend(&mut self, data: &[u8]) {
+        let mut header = data.to_vec();
+        header.extend_from_slice(&self.header);
+        self.header = header;
+    }
+
+    /// Drops the message.
+    pub fn abort(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+
+//------------ Message -------------------------------------------------------
+
+/// A queued message for reading.
+///
+/// This is the message as spooled with any header fields that belong in
+/// front of it.
+pub struct Message(io::Chain<Cursor<Vec<u8>>, File>);
+
+impl Message {
+    /// Creates a message from the *header* to go first and the *body*.
+    ///
+    /// The body is everything that was written to the spool.
+    pub fn new(header: Vec<u8>, body: File) -> Self {
+        Message(Cursor::new(header).chain(body))
+    }
+
+    /// Adds the Return-Path field of *envelope* in front for final delivery.
+    ///
+    /// This has to happen before anything has been read from the message.
+    pub fn with_return_path(self, envelope: &Envelope) -> Self {
+        let (header, body) = self.0.into_inner();
+        let mut res = envelope.return_path();
+        res.extend_from_slice(&header.into_inner());
+        Message::new(res, body)
+    }
+}
+
+impl Read for Message {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+
+//------------ QueueId -------------------------------------------------------
+
+/// The identifier of a mail in the queue.
+///
+/// It is made of the time of creation and some random octets which keeps
+/// IDs unique and roughly sortable by age.
+///
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct QueueId(String);
+
+impl QueueId {
+    fn new() -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                                   .unwrap_or(Duration::new(0, 0));
+        let mut res = format!("{:010X}{:05X}", now.as_secs(),
+                              now.subsec_nanos() / 1000);
+        for octet in rand_bytes(4) {
+            res.push_str(&format!("{:02X}", octet));
+        }
+        QueueId(res)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn file_name(&self, ext: &str) -> String {
+        format!("{}.{}", self.0, ext)
+    }
+}
+
+impl fmt::Display for QueueId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+
+//------------ Envelope ------------------------------------------------------
+
+/// The envelope of a queued mail.
+///
+/// On disk, the envelope is a text file with one field per line. Each
+/// line starts with a keyword followed by a single space and the value.
+/// The `notify`, `orcpt`, `state`, `reply`, and `warned` lines belong to
+/// the `rcpt` line before them.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    /// The reverse path without angle brackets, empty for the null path.
+    pub reverse_path: Vec<u8>,
+    pub recipients: Vec<Recipient>,
+    pub body: Option<BodyValue>,
+    pub size: Option<u64>,
+    pub ret: Option<RetValue>,
+
+    /// The ENVID parameter still encoded as xtext.
+    pub envid: Option<Vec<u8>>,
+
+    /// When the mail arrived in seconds since the epoch.
+    pub arrival: u64,
+}
+
+impl Envelope {
+    pub fn new(reverse_path: Vec<u8>) -> Self {
+        let arrival = SystemTime::now().duration_since(UNIX_EPOCH)
+                                       .map(|now| now.as_secs())
+                                       .unwrap_or(0);
+        Envelope { reverse_path: reverse_path, recipients: Vec::new(),
+                   body: None, size: None, ret: None, envid: None,
+                   arrival: arrival }
+    }
+
+    /// Returns the Return-Path header field for the reverse path.
+    ///
+    /// The field is added by final delivery in front of the message as
+    /// described in RFC 5321, section 4.4. Relaying must not add it.
+    pub fn return_path(&self) -> Vec<u8> {
+        let mut res = b"Return-Path: <".to_vec();
+        res.extend_from_slice(&self.reverse_path);
+        res.extend_from_slice(b">\r\n");
+        res
+    }
+
+    pub fn write<W: Write>(&self, target: &mut W) -> io::Result<()> {
+        try!(write_field(target, "from", &self.reverse_path));
+        try!(writeln!(target, "arrival {}", self.arrival));
+        if let Some(body) = self.body {
+            try!(writeln!(target, "body {}", body));
+        }
+        if let Some(size) = self.size {
+            try!(writeln!(target, "size {}", size));
+        }
+        if let Some(ret) = self.ret {
+            try!(writeln!(target, "ret {}", ret));
+        }
+        if let Some(ref envid) = self.envid {
+            try!(write_field(target, "envid", envid));
+        }
+        for rcpt in &self.recipients {
+            try!(write_field(target, "rcpt", &rcpt.path));
+            if let Some(notify) = rcpt.notify {
+                try!(writeln!(target, "notify {}", notify));
+            }
+            if let Some(ref orcpt) = rcpt.orcpt {
+                try!(write_field(target, "orcpt", orcpt));
+            }
+            match rcpt.state {
+                DeliveryState::Pending => { }
+                DeliveryState::Deferred { attempts, retry } => {
+                    try!(writeln!(target, "state deferred {} {}", attempts,
+                                  retry));
+                }
+                DeliveryState::Delivered => {
+                    try!(writeln!(target, "state delivered"));
+                }
+                DeliveryState::Failed => {
+                    try!(writeln!(target, "state failed"));
+                }
+            }
+            if let Some(ref reply) = rcpt.reply {
+                try!(write_reply(target, reply));
+            }
+            if rcpt.warned {
+                try!(writeln!(target, "warned"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether all recipients have reached a final state.
+    pub fn is_complete(&self) -> bool {
+        self.recipients.iter().all(|rcpt| rcpt.state.is_final())
+    }
+
+    pub fn read<R: BufRead>(source: R) -> io::Result<Self> {
+        let mut res = Envelope::new(Vec::new());
+        let mut from = false;
+        for line in source.split(b'\n') {
+            let line = try!(line);
+            if line.is_empty() {
+                continue
+            }
+            let (key, value) = match line.iter().position(|ch| *ch == b' ') {
+                Some(pos) => (&line[..pos], &line[pos + 1..]),
+                None => (&line[..], &b""[..])
+            };
+            match key {
+                b"from" => {
+                    res.reverse_path = value.into();
+                    from = true;
+                }
+                b"arrival" => res.arrival = try!(parse_u64(value)),
+                b"body" => {
+                    res.body = Some(match value {
+                        b"7BIT" => BodyValue::SevenBit,
+                        b"8BITMIME" => BodyValue::EightBitMime,
+                        b"BINARYMIME" => BodyValue::BinaryMime,
+                        _ => return Err(invalid("body"))
+                    })
+                }
+                b"size" => res.size = Some(try!(parse_u64(value))),
+                b"ret" => {
+                    res.ret = Some(match value {
+                        b"FULL" => RetValue::Full,
+                        b"HDRS" => RetValue::Hdrs,
+                        _ => return Err(invalid("ret"))
+                    })
+                }
+                b"envid" => res.envid = Some(value.into()),
+                b"rcpt" => res.recipients.push(Recipient::new(value.into())),
+                b"notify" => {
+                    let notify = try!(parse_notify(value));
+                    match res.recipients.last_mut() {
+                        Some(rcpt) => rcpt.notify = Some(notify),
+                        None => return Err(invalid("notify"))
+                    }
+                }
+                b"orcpt" => {
+                    match res.recipients.last_mut() {
+                        Some(rcpt) => rcpt.orcpt = Some(value.into()),
+                        None => return Err(invalid("orcpt"))
+                    }
+                }
+                b"state" => {
+                    let state = try!(parse_state(value));
+                    match res.recipients.last_mut() {
+                        Some(rcpt) => rcpt.state = state,
+                        None => return Err(invalid("state"))
+                    }
+                }
+                b"reply" => {
+                    let reply = try!(parse_reply(value));
+                    match res.recipients.last_mut() {
+                        Some(rcpt) => rcpt.reply = Some(reply),
+                        None => return Err(invalid("reply"))
+                    }
+                }
+                b"warned" => {
+                    match res.recipients.last_mut() {
+                        Some(rcpt) => rcpt.warned = true,
+                        None => return Err(invalid("warned"))
+                    }
+                }
+                _ => return Err(invalid("unknown field"))
+            }
+        }
+        if !from {
+            return Err(invalid("missing reverse path"))
+        }
+        Ok(res)
+    }
+}
+
+
+//------------ Recipient -----------------------------------------------------
+
+/// A recipient in an envelope.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipient {
+    /// The forward path without angle brackets.
+    pub path: Vec<u8>,
+    pub notify: Option<NotifyValue>,
+
+    /// The ORCPT parameter as `addr-type ";" xtext`.
+    pub orcpt: Option<Vec<u8>>,
+
+    /// How far delivery to this recipient has come.
+    pub state: DeliveryState,
+
+    /// The last reply received while trying to deliver.
+    ///
+    /// Line breaks in the text are replaced by spaces when written.
+    pub reply: Option<Reply>,
+
+    /// Has the sender been notified that delivery is delayed?
+    pub warned: bool,
+}
+
+impl Recipient {
+    pub fn new(path: Vec<u8>) -> Self {
+        Recipient { path: path, notify: None, orcpt: None,
+                    state: DeliveryState::Pending, reply: None,
+                    warned: false }
+    }
+
+    /// Returns the domain of the recipient’s address.
+    ///
+    /// This is everything after the last `@` or the empty slice if there
+    /// is none, as is the case for the special `Postmaster` address.
+    pub fn domain(&self) -> &[u8] {
+        match self.path.iter().rposition(|ch| *ch == b'@') {
+            Some(pos) => &self.path[pos + 1..],
+            None => b""
+        }
+    }
+}
+
+
+//------------ DeliveryState -------------------------------------------------
+
+/// The delivery state of a recipient.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryState {
+    /// Delivery hasn’t been attempted yet.
+    Pending,
+
+    /// Delivery has failed temporarily.
+    ///
+    /// Contains the number of failed attempts so far and the time of the
+    /// next attempt in seconds since the epoch.
+    Deferred { attempts: u32, retry: u64 },
+
+    /// The recipient has been delivered to.
+    Delivered,
+
+    /// Delivery has failed for good.
+    Failed,
+}
+
+impl DeliveryState {
+    /// Returns whether nothing more is going to happen.
+    pub fn is_final(&self) -> bool {
+        match *self {
+            DeliveryState::Delivered | DeliveryState::Failed => true,
+            _ => false
+        }
+    }
+
+    /// Returns whether delivery should be attempted at time *now*.
+    pub fn is_due(&self, now: u64) -> bool {
+        match *self {
+            DeliveryState::Pending => true,
+            DeliveryState::Deferred { retry, .. } => retry <= now,
+            _ => false
+        }
+    }
+
+    /// Returns the number of failed delivery attempts.
+    pub fn attempts(&self) -> u32 {
+        match *self {
+            DeliveryState::Deferred { attempts, .. } => attempts,
+            _ => 0
+        }
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Writes an envelope to a new file and syncs it.
+fn write_synced(path: &Path, envelope: &Envelope) -> io::Result<()> {
+    let mut file = try!(File::create(path));
+    try!(envelope.write(&mut file));
+    file.sync_all()
+}
+
+/// Syncs a directory so that renames in it are on disk.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    try!(File::open(path)).sync_all()
+}
+
+fn write_field<W: Write>(target: &mut W, key: &str, value: &[u8])
+                         -> io::Result<()> {
+    if value.iter().any(|ch| *ch == b'\r' || *ch == b'\n') {
+        return Err(invalid(key))
+    }
+    try!(target.write_all(key.as_bytes()));
+    try!(target.write_all(b" "));
+    try!(target.write_all(value));
+    target.write_all(b"\n")
+}
+
+fn write_reply<W: Write>(target: &mut W, reply: &Reply) -> io::Result<()> {
+    try!(write!(target, "reply {}", reply.code));
+    if let Some((a, b, c)) = reply.status {
+        try!(write!(target, " {}.{}.{}", a, b, c));
+    }
+    try!(target.write_all(b" "));
+    let text: Vec<u8> = reply.text.iter().map(|ch| {
+        if *ch == b'\r' || *ch == b'\n' { b' ' } else { *ch }
+    }).collect();
+    try!(target.write_all(&text));
+    target.write_all(b"\n")
+}
+
+fn parse_state(value: &[u8]) -> io::Result<DeliveryState> {
+    let mut words = value.split(|ch| *ch == b' ');
+    match words.next() {
+        Some(b"delivered") => Ok(DeliveryState::Delivered),
+        Some(b"failed") => Ok(DeliveryState::Failed),
+        Some(b"deferred") => {
+            let attempts = try!(parse_u64(words.next().unwrap_or(b"")));
+            let retry = try!(parse_u64(words.next().unwrap_or(b"")));
+            Ok(DeliveryState::Deferred { attempts: attempts as u32,
+                                         retry: retry })
+        }
+        _ => Err(invalid("state"))
+    }
+}
+
+/// Parses a reply written as `code [status] text`.
+fn parse_reply(value: &[u8]) -> io::Result<Reply> {
+    if value.len() < 3 {
+        return Err(invalid("reply"))
+    }
+    let code = try!(parse_u64(&value[..3])) as u16;
+    let mut text = if value.len() > 4 { &value[4..] } else { &b""[..] };
+    let (word, rest) = match text.iter().position(|ch| *ch == b' ') {
+        Some(pos) => (&text[..pos], &text[pos + 1..]),
+        None => (text, &b""[..])
+    };
+    let mut status = None;
+    let parts: Vec<_> = word.split(|ch| *ch == b'.').collect();
+    if parts.len() == 3 {
+        if let (Ok(a), Ok(b), Ok(c)) = (parse_u64(parts[0]),
+                                        parse_u64(parts[1]),
+                                        parse_u64(parts[2])) {
+            status = Some((a as u16, b as u16, c as u16));
+            text = rest;
+        }
+    }
+    Ok(Reply::new(code, status, text.into()))
+}
+
+fn parse_u64(value: &[u8]) -> io::Result<u64> {
+    ::std::str::from_utf8(value).ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| invalid("number"))
+}
+
+fn parse_notify(value: &[u8]) -> io::Result<NotifyValue> {
+    let mut res = NotifyValue::new();
+    if value == b"NEVER" {
+        return Ok(res)
+    }
+    for item in value.split(|ch| *ch == b',') {
+        match item {
+            b"SUCCESS" => res.success = true,
+            b"FAILURE" => res.failure = true,
+            b"DELAY" => res.delay = true,
+            _ => return Err(invalid("notify"))
+        }
+    }
+    Ok(res)
+}
+
+fn invalid(what: &str) -> io::Error {
+    text::invalid("envelope", what)
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read, Write};
+    use ::smtp::syntax::{BodyValue, NotifyValue, Reply, RetValue};
+    use ::util::test::TempDir;
+    use super::*;
+
+    fn message(queue: &Queue, id: &QueueId) -> Vec<u8> {
+        let mut res = Vec::new();
+        queue.message(id).unwrap().read_to_end(&mut res).unwrap();
+        res
+    }
+
+    #[test]
+    fn spool() {
+        let dir = TempDir::new("queue-spool");
+        let queue = Queue::open(&dir).unwrap();
+        let envelope = Envelope::new(b"a@example.com".to_vec());
+
+        let mut spool = queue.create().unwrap();
+        spool.write_all(b"Subject: Hi\r\n\r\nHello\r\n").unwrap();
+        spool.prepend(b"X-Second: 2\r\n");
+        spool.prepend(b"X-First: 1\r\n");
+        let id = spool.commit(&envelope).unwrap();
+        let mut spool = queue.create().unwrap();
+        spool.write_all(b"Subject: Plain\r\n\r\n").unwrap();
+        let plain = spool.commit(&envelope).unwrap();
+        assert_eq!(queue.list().unwrap(), [id.clone(), plain.clone()]);
+        assert_eq!(message(&queue, &id),
+                   &b"X-First: 1\r\nX-Second: 2\r\n\
+                      Subject: Hi\r\n\r\nHello\r\n"[..]);
+        assert_eq!(message(&queue, &plain), &b"Subject: Plain\r\n\r\n"[..]);
+        let mut delivered = Vec::new();
+        queue.message(&id).unwrap().with_return_path(&envelope)
+             .read_to_end(&mut delivered).unwrap();
+        assert_eq!(delivered,
+                   &b"Return-Path: <a@example.com>\r\n\
+                      X-First: 1\r\nX-Second: 2\r\n\
+                      Subject: Hi\r\n\r\nHello\r\n"[..]);
+
+        queue.remove(&id).unwrap();
+        queue.remove(&plain).unwrap();
+        assert!(queue.list().unwrap().is_empty());
+        assert_eq!(fs::read_dir(dir.join("queue")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn envelope_roundtrip() {
+        let mut envelope = Envelope::new(b"foo@example.com".to_vec());
+        envelope.arrival = 1234567890;
+        envelope.body = Some(BodyValue::EightBitMime);
+        envelope.size = Some(4711);
+        envelope.ret = Some(RetValue::Hdrs);
+        envelope.envid = Some(b"QQ+2B314".to_vec());
+        envelope.recipients.push(Recipient::new(b"bar@example.com".to_vec()));
+        let mut rcpt = Recipient::new(b"\"b z\"@example.com".to_vec());
+        rcpt.notify = Some(NotifyValue { success: false, failure: true,
+                                         delay: true });
+        rcpt.orcpt = Some(b"rfc822;baz@example.com".to_vec());
+        rcpt.state = DeliveryState::Deferred { attempts: 2,
+                                               retry: 1234569999 };
+        rcpt.reply = Some(Reply::new(451, Some((4, 3, 0)),
+                                     b"Try again".to_vec()));
+        rcpt.warned = true;
+        envelope.recipients.push(rcpt);
+        let mut rcpt = Recipient::new(b"Postmaster".to_vec());
+        rcpt.state = DeliveryState::Failed;
+        rcpt.reply = Some(Reply::new(550, None, b"No".to_vec()));
+        envelope.recipients.push(rcpt);
+
+        let mut buf = Vec::new();
+        envelope.write(&mut buf).unwrap();
+        assert_eq!(buf, b"from foo@example.com\n\
+                          arrival 1234567890\n\
+                          body 8BITMIME\n\
+                          size 4711\n\
+                          ret HDRS\n\
+                          envid QQ+2B314\n\
+                          rcpt bar@example.com\n\
+                          rcpt \"b z\"@example.com\n\
+                          notify FAILURE,DELAY\n\
+                          orcpt rfc822;baz@example.com\n\
+                          state deferred 2 1234569999\n\
+                          reply 451 4.3.0 Try again\n\
+                          warned\n\
+                          rcpt Postmaster\n\
+                          state failed\n\
+                          reply 550 No\n".to_vec());
+        assert_eq!(Envelope::read(Cursor::new(buf)).unwrap(), envelope);
+        assert!(!envelope.is_complete());
+        assert_eq!(envelope.recipients[1].domain(), b"example.com");
+        assert_eq!(envelope.recipients[2].domain(), b"");
+        assert_eq!(envelope.return_path(),
+                   b"Return-Path: <foo@example.com>\r\n".to_vec());
+    }
+
+    #[test]
+    fn envelope_bad() {
+        assert!(Envelope::read(Cursor::new(b"rcpt foo@example.com\n"))
+                        .is_err());
+        assert!(Envelope::read(Cursor::new(b"from \nnotify NEVER\n"))
+                        .is_err());
+        assert!(Envelope::read(Cursor::new(b"from \nfoo bar\n")).is_err());
+        let envelope = Envelope::read(Cursor::new(b"from \n")).unwrap();
+        assert!(envelope.reverse_path.is_empty());
+        assert!(envelope.recipients.is_empty());
+        assert_eq!(envelope.return_path(), b"Return-Path: <>\r\n".to_vec());
+    }
+}
@@ -0,0 +1,548 @@
+//! Aliases and mailing lists.
+//!
+//! Aliases are read from files in the format of sendmail’s
+//! `/etc/aliases`:
+//!
+//! ```text
+//! # Role addresses.
+//! postmaster: root
+//! root:       alice, bob@example.com
+//!
+//! # Lines starting with white space continue the previous line.
+//! staff:      alice,
+//!             carol
+//!
+//! # Special targets.
+//! list:       :include:/etc/mail/list.members
+//! archive:    "|/usr/local/bin/archive", /var/mail/archive
+//! alice:      \alice, alice@example.org
+//! ```
+//!
+//! Each alias has a comma separated list of targets. A target is either
+//! an address, the name of another alias, a command to pipe the message
+//! to starting with `|`, the absolute path of a file to append the
+//! message to, or a file with more targets following `:include:`. A name
+//! prefixed with a backslash is a local mailbox that is never expanded
+//! any further. Targets containing commas or white space can be quoted.
+//!
+//! Names are compared ignoring ASCII case.
+//!
+//! Include files are read when an alias using them is added to the
+//! table so that expansion never needs to wait for the file system.
+//! Changes to them only take effect once the aliases are loaded again.
+//!
+//! Expansion follows targets until it only has mailboxes, pipes and
+//! files left. An alias that lists itself refers to the mailbox of that
+//! name. Any other loop is an error.
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use ::util::text::{self, trim};
+
+
+/// How deep aliases and includes may be nested.
+const MAX_DEPTH: usize = 16;
+
+
+//------------ Aliases -------------------------------------------------------
+
+#[derive(Clone, Debug)]
+pub struct Aliases {
+    /// The targets keyed by lower case name.
+    entries: HashMap<Vec<u8>, Vec<Target>>,
+
+    /// The targets of include files keyed by their path.
+    includes: HashMap<PathBuf, Vec<Target>>,
+}
+
+impl Aliases {
+    /// Creates an empty alias table.
+    pub fn new() -> Self {
+        Aliases { entries: HashMap::new(), includes: HashMap::new() }
+    }
+
+    /// Loads an alias file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut res = Aliases::new();
+        try!(res.read(BufReader::new(try!(File::open(path)))));
+        Ok(res)
+    }
+
+    /// Adds the aliases from *source*.
+    pub fn read<R: BufRead>(&mut self, source: R) -> io::Result<()> {
+        let mut entry: Option<Vec<u8>> = None;
+        for line in source.split(b'\n') {
+            let line = try!(line);
+            if line.starts_with(b"#") {
+                continue
+            }
+            if line.starts_with(b" ") || line.starts_with(b"\t") {
+                match entry {
+                    Some(ref mut entry) => entry.extend_from_slice(&line),
+                    None if trim(&line).is_empty() => { }
+                    None => return Err(invalid("continuation without alias"))
+                }
+                continue
+            }
+            if let Some(entry) = entry.take() {
+                try!(self.add_line(&entry));
+            }
+            if !trim(&line).is_empty() {
+                entry = Some(line)
+            }
+        }
+        if let Some(entry) = entry {
+            try!(self.add_line(&entry));
+        }
+        Ok(())
+    }
+
+    /// Adds an alias, replacing an earlier alias of the same name.
+    ///
+    /// Fails if an include file in *targets* can’t be read.
+    pub fn insert(&mut self, name: &[u8], targets: Vec<Target>)
+                  -> io::Result<()> {
+        try!(self.read_includes(&targets, 0));
+        self.entries.insert(name.to_ascii_lowercase(), targets);
+        Ok(())
+    }
+
+    /// Returns whether there is an alias called *name*.
+    pub fn contains(&self, name: &[u8]) -> bool {
+        self.entries.contains_key(&name.to_ascii_lowercase())
+    }
+
+    /// Expands the alias *name*.
+    ///
+    /// Returns `Ok(None)` if there is no such alias. Mailboxes in the
+    /// result are given as they appear in the targets, so they may or
+    /// may not have a domain. Each item appears only once.
+    pub fn expand(&self, name: &[u8])
+                  -> Result<Option<Vec<Expansion>>, ExpandError> {
+        let name = name.to_ascii_lowercase();
+        let targets = match self.entries.get(&name) {
+            Some(targets) => targets,
+            None => return Ok(None)
+        };
+        let mut expander = Expander { aliases: self, stack: vec![name],
+                                      includes: Vec::new(),
+                                      res: Vec::new() };
+        try!(expander.targets(targets));
+        Ok(Some(expander.res))
+    }
+
+    fn add_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let pos = match line.iter().position(|ch| *ch == b':') {
+            Some(pos) => pos,
+            None => return Err(invalid("missing colon"))
+        };
+        let name = trim(&line[..pos]);
+        if name.is_empty() {
+            return Err(invalid("empty alias name"))
+        }
+        let targets = try!(parse_targets(&line[pos + 1..]));
+        if targets.is_empty() {
+            return Err(invalid("alias without targets"))
+        }
+        self.insert(name, targets)
+    }
+
+    /// Reads the include files in *targets* that haven’t been read yet.
+    fn read_includes(&mut self, targets: &[Target], depth: usize)
+                     -> io::Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("include files nested too deeply"))
+        }
+        for target in targets {
+            if let Target::Include(ref path) = *target {
+                if self.includes.contains_key(path) {
+                    continue
+                }
+                let included = try!(read_include(path).map_err(|err| {
+                    io::Error::new(err.kind(),
+                                   format!("include file {}: {}",
+                                           path.display(), err))
+                }));
+                // Enter it first so that a loop doesn’t get us here again.
+                self.includes.insert(path.clone(), included.clone());
+                try!(self.read_includes(&included, depth + 1));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Aliases {
+    fn default() -> Self {
+        Aliases::new()
+    }
+}
+
+
+//------------ Target --------------------------------------------------------
+
+/// A target of an alias.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    /// An address or the name of another alias.
+    Name(Vec<u8>),
+
+    /// A local mailbox that must not be expanded.
+    Mailbox(Vec<u8>),
+
+    /// A command to pipe the message to.
+    Pipe(Vec<u8>),
+
+    /// A file to append the message to.
+    File(Vec<u8>),
+
+    /// A file containing more targets.
+    Include(PathBuf),
+}
+
+impl Target {
+    /// Parses a single target.
+    pub fn parse(target: &[u8]) -> io::Result<Self> {
+        let target = trim(target);
+        let target = if target.len() > 1 && target.starts_with(b"\"")
+                                          && target.ends_with(b"\"") {
+            &target[1..target.len() - 1]
+        }
+        else { target };
+        if target.is_empty() {
+            Err(invalid("empty target"))
+        }
+        else if target.starts_with(b"|") {
+            Ok(Target::Pipe(target[1..].into()))
+        }
+        else if target.starts_with(b"/") {
+            Ok(Target::File(target.into()))
+        }
+        else if target.len() >= 9
+                && target[..9].eq_ignore_ascii_case(b":include:") {
+            let path = trim(&target[9..]);
+            match String::from_utf8(path.into()) {
+                Ok(ref path) if !path.is_empty() => {
+                    Ok(Target::Include(path.into()))
+                }
+                _ => Err(invalid("bad include path"))
+            }
+        }
+        else if target.starts_with(b"\\") {
+            Ok(Target::Mailbox(target[1..].into()))
+        }
+        else if target.starts_with(b"<") && target.ends_with(b">") {
+            Ok(Target::Name(target[1..target.len() - 1].into()))
+        }
+        else {
+            Ok(Target::Name(target.into()))
+        }
+    }
+}
+
+
+//------------ Expansion -----------------------------------------------------
+
+/// An item in the result of an alias expansion.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expansion {
+    /// Deliver to a mailbox.
+    Mailbox(Vec<u8>),
+
+    /// Pipe the message into a command.
+    Pipe(Vec<u8>),
+
+    /// Append the message to a file.
+    File(Vec<u8>),
+}
+
+
+//------------ ExpandError ---------------------------------------------------
+
+#[derive(Debug)]
+pub enum ExpandError {
+    /// The alias of this name is part of a loop.
+    Loop(Vec<u8>),
+
+    /// Aliases or includes are nested too deeply.
+    TooDeep,
+}
+
+impl error::Error for ExpandError {
+    fn description(&self) -> &str {
+        match *self {
+            ExpandError::Loop(_) => "alias loop",
+            ExpandError::TooDeep => "aliases nested too deeply",
+        }
+    }
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpandError::Loop(ref name) => {
+                write!(f, "alias loop at {}", String::from_utf8_lossy(name))
+            }
+            ExpandError::TooDeep => f.write_str("aliases nested too deeply"),
+        }
+    }
+}
+
+
+//------------ Expander ------------------------------------------------------
+
+/// The state of an expansion.
+struct Expander<'a> {
+    aliases: &'a Aliases,
+
+    /// The names of the aliases currently being expanded.
+    stack: Vec<Vec<u8>>,
+
+    /// The include files currently being expanded.
+    includes: Vec<PathBuf>,
+
+    res: Vec<Expansion>,
+}
+
+impl<'a> Expander<'a> {
+    fn targets(&mut self, targets: &[Target]) -> Result<(), ExpandError> {
+        if self.stack.len() + self.includes.len() > MAX_DEPTH {
+            return Err(ExpandError::TooDeep)
+        }
+        for target in targets {
+            match *target {
+                Target::Name(ref name) => try!(self.name(name)),
+                Target::Mailbox(ref name) => {
+                    self.push(Expansion::Mailbox(name.clone()))
+                }
+                Target::Pipe(ref cmd) => {
+                    self.push(Expansion::Pipe(cmd.clone()))
+                }
+                Target::File(ref path) => {
+                    self.push(Expansion::File(path.clone()))
+                }
+                Target::Include(ref path) => try!(self.include(path)),
+            }
+        }
+        Ok(())
+    }
+
+    fn name(&mut self, name: &[u8]) -> Result<(), ExpandError> {
+        let aliases = self.aliases;
+        let key = name.to_ascii_lowercase();
+        let targets = match aliases.entries.get(&key) {
+            Some(targets) => targets,
+            None => {
+                self.push(Expansion::Mailbox(name.into()));
+                return Ok(())
+            }
+        };
+        if self.stack.last() == Some(&key) {
+            // An alias listing itself means the mailbox.
+            self.push(Expansion::Mailbox(name.into()));
+            return Ok(())
+        }
+        if self.stack.contains(&key) {
+            return Err(ExpandError::Loop(key))
+        }
+        self.stack.push(key);
+        try!(self.targets(targets));
+        self.stack.pop();
+        Ok(())
+    }
+
+    fn include(&mut self, path: &Path) -> Result<(), ExpandError> {
+        if self.includes.iter().any(|item| item == path) {
+            return Err(ExpandError::Loop(path.to_string_lossy()
+                                             .into_owned().into_bytes()))
+        }
+        // Adding the alias has read all its include files.
+        let aliases = self.aliases;
+        let targets = &aliases.includes[path];
+        self.includes.push(path.into());
+        try!(self.targets(targets));
+        self.includes.pop();
+        Ok(())
+    }
+
+    fn push(&mut self, item: Expansion) {
+        let exists = self.res.iter().any(|other| {
+            match (other, &item) {
+                (&Expansion::Mailbox(ref left),
+                 &Expansion::Mailbox(ref right)) => {
+                    left.eq_ignore_ascii_case(right)
+                }
+                (left, right) => left == right
+            }
+        });
+        if !exists {
+            self.res.push(item)
+        }
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Reads the targets from an include file.
+///
+/// Targets are separated by commas or line breaks. Lines starting with
+/// `#` are ignored.
+fn read_include(path: &Path) -> io::Result<Vec<Target>> {
+    let mut data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    let mut res = Vec::new();
+    for line in data.split(|ch| *ch == b'\n') {
+        if trim(line).is_empty() || trim(line).starts_with(b"#") {
+            continue
+        }
+        res.extend(try!(parse_targets(line)));
+    }
+    Ok(res)
+}
+
+/// Parses a comma separated list of targets.
+fn parse_targets(mut line: &[u8]) -> io::Result<Vec<Target>> {
+    let mut res = Vec::new();
+    loop {
+        let mut quoted = false;
+        let end = line.iter().position(|ch| {
+            if *ch == b'"' { quoted = !quoted }
+            *ch == b',' && !quoted
+        });
+        let (target, rest) = match end {
+            Some(end) => (&line[..end], Some(&line[end + 1..])),
+            None => (line, None)
+        };
+        if !trim(target).is_empty() {
+            res.push(try!(Target::parse(target)));
+        }
+        match rest {
+            Some(rest) => line = rest,
+            None => break
+        }
+    }
+    Ok(res)
+}
+
+fn invalid(what: &str) -> io::Error {
+    text::invalid("alias file", what)
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use ::util::test::TempDir;
+    use super::*;
+
+    fn mailbox(name: &str) -> Expansion {
+        Expansion::Mailbox(name.as_bytes().into())
+    }
+
+    #[test]
+    fn parse() {
+        let dir = TempDir::new("aliases-parse");
+        let list = dir.join("list");
+        File::create(&list).unwrap().write_all(b"alice\n").unwrap();
+        let mut aliases = Aliases::new();
+        aliases.read(format!("# Comment\n\
+                              Root:  alice, <bob@example.com>\n\
+                              staff: alice,\n  \"carol smith\"\n\
+                              \t , \\dave\n\
+                              \n\
+                              archive: \"|/bin/archive -v\", \
+                              /var/mail/archive\n\
+                              list:  :include:{}\n",
+                             list.display()).as_bytes()).unwrap();
+        assert!(aliases.contains(b"root"));
+        assert_eq!(aliases.entries[&b"root"[..]],
+                   [Target::Name(b"alice".to_vec()),
+                    Target::Name(b"bob@example.com".to_vec())]);
+        assert_eq!(aliases.entries[&b"staff"[..]],
+                   [Target::Name(b"alice".to_vec()),
+                    Target::Name(b"carol smith".to_vec()),
+                    Target::Mailbox(b"dave".to_vec())]);
+        assert_eq!(aliases.entries[&b"archive"[..]],
+                   [Target::Pipe(b"/bin/archive -v".to_vec()),
+                    Target::File(b"/var/mail/archive".to_vec())]);
+        assert_eq!(aliases.entries[&b"list"[..]],
+                   [Target::Include(list.clone())]);
+
+        assert!(Aliases::new().read(&b"root alice\n"[..]).is_err());
+        assert!(Aliases::new().read(&b"root:\n"[..]).is_err());
+        assert!(Aliases::new().read(&b"  alice\n"[..]).is_err());
+    }
+
+    #[test]
+    fn expand() {
+        let mut aliases = Aliases::new();
+        aliases.read(&b"postmaster: root\n\
+                        root: alice, bob@example.com, root\n\
+                        staff: root, carol, alice\n\
+                        alice: \\alice, |/bin/notify\n"[..]).unwrap();
+        assert_eq!(aliases.expand(b"carol").unwrap(), None);
+        assert_eq!(aliases.expand(b"Postmaster").unwrap().unwrap(),
+                   [mailbox("alice"), Expansion::Pipe(b"/bin/notify".to_vec()),
+                    mailbox("bob@example.com"), mailbox("root")]);
+        assert_eq!(aliases.expand(b"staff").unwrap().unwrap(),
+                   [mailbox("alice"), Expansion::Pipe(b"/bin/notify".to_vec()),
+                    mailbox("bob@example.com"), mailbox("root"),
+                    mailbox("carol")]);
+    }
+
+    #[test]
+    fn loops() {
+        let mut aliases = Aliases::new();
+        aliases.read(&b"a: b\nb: c\nc: a, d\n"[..]).unwrap();
+        match aliases.expand(b"a") {
+            Err(ExpandError::Loop(name)) => assert_eq!(name, b"a"),
+            res => panic!("{:?}", res)
+        }
+    }
+
+    #[test]
+    fn include() {
+        let dir = TempDir::new("aliases");
+        let path = dir.join("list");
+        File::create(&path).unwrap()
+            .write_all(b"# Members\nalice, bob\ncarol@example.com\n")
+            .unwrap();
+        let mut aliases = Aliases::new();
+        aliases.insert(b"list", vec![Target::Include(path)]).unwrap();
+        aliases.insert(b"bob", vec![Target::Name(b"robert".to_vec())])
+               .unwrap();
+        assert_eq!(aliases.expand(b"list").unwrap().unwrap(),
+                   [mailbox("alice"), mailbox("robert"),
+                    mailbox("carol@example.com")]);
+
+        // Changes only take effect once the alias is added again.
+        let path = dir.join("list");
+        File::create(&path).unwrap().write_all(b"dave\n").unwrap();
+        assert_eq!(aliases.expand(b"list").unwrap().unwrap().len(), 3);
+
+        let path = dir.join("loop");
+        File::create(&path).unwrap()
+            .write_all(format!("alice, :include:{}\n",
+                               path.display()).as_bytes())
+            .unwrap();
+        aliases.insert(b"loop", vec![Target::Include(path)]).unwrap();
+        match aliases.expand(b"loop") {
+            Err(ExpandError::Loop(_)) => { }
+            res => panic!("{:?}", res)
+        }
+
+        let path = dir.join("missing");
+        assert!(aliases.insert(b"missing",
+                               vec![Target::Include(path)]).is_err());
+        assert!(!aliases.contains(b"missing"));
+    }
+}
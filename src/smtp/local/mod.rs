@@ -0,0 +1,6 @@
+//! Local users and their addresses.
+//!
+
+pub mod aliases;
+pub mod users;
+pub mod validator;
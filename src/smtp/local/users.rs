@@ -0,0 +1,338 @@
+//! The table of local users.
+//!
+//! The table consists of two parts: the list of domains considered
+//! local and the entries for the local parts of addresses in these
+//! domains. Both are typically loaded from files.
+//!
+//! The domain file simply lists one domain per line. The user file has
+//! one entry per line. It starts with the key, optionally followed by
+//! white space and what should happen to mail for the key:
+//!
+//! ```text
+//! # Mailboxes for all local domains.
+//! alice
+//! bob         local
+//!
+//! # A mailbox for one domain only.
+//! carol@example.com
+//!
+//! # Users that aren’t here anymore.
+//! dave        forward dave@example.org
+//! eve         moved eve@example.net
+//!
+//! # Anything else for example.com goes to the local mailbox.
+//! *@example.com
+//! ```
+//!
+//! A key is either a local part which applies to all local domains or a
+//! complete address which only applies to its domain. The local part
+//! `*` is a catch-all. Keys without any further word are local
+//! mailboxes. With `forward`, mail is accepted and sent on to the given
+//! address while `moved` rejects mail, telling the client where the user
+//! can be reached now. Empty lines and lines starting with `#` are
+//! ignored. Keys and domains are compared ignoring ASCII case.
+//!
+//! Local parts can contain a sub-address separated from the user by a
+//! separator, `+` by default. If there is no entry for the complete local
+//! part, the sub-address is dropped and the user is tried instead.
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use ::util::text::{self, Words};
+
+
+//------------ Users ---------------------------------------------------------
+
+#[derive(Clone, Debug)]
+pub struct Users {
+    /// The local domains in lower case.
+    domains: Vec<Vec<u8>>,
+
+    /// The entries keyed by lower-case local part or address.
+    entries: HashMap<Vec<u8>, Entry>,
+
+    /// The sub-address separator.
+    separator: Option<u8>,
+}
+
+impl Users {
+    /// Creates an empty table.
+    pub fn new() -> Self {
+        Users { domains: Vec::new(), entries: HashMap::new(),
+                separator: Some(b'+') }
+    }
+
+    /// Loads a table from a domain file and a user file.
+    pub fn load<P, Q>(domains: P, users: Q) -> io::Result<Self>
+                where P: AsRef<Path>, Q: AsRef<Path> {
+        let mut res = Users::new();
+        try!(res.read_domains(BufReader::new(try!(File::open(domains)))));
+        try!(res.read_users(BufReader::new(try!(File::open(users)))));
+        Ok(res)
+    }
+
+    /// Adds the domains listed in *source*.
+    pub fn read_domains<R: BufRead>(&mut self, source: R) -> io::Result<()> {
+        for line in source.split(b'\n') {
+            let line = try!(line);
+            let mut words = Words::new(&line);
+            if let Some(domain) = words.next() {
+                if words.next().is_some() {
+                    return Err(invalid("trailing data after domain"))
+                }
+                self.add_domain(domain);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the entries listed in *source*.
+    pub fn read_users<R: BufRead>(&mut self, source: R) -> io::Result<()> {
+        for line in source.split(b'\n') {
+            let line = try!(line);
+            let mut words = Words::new(&line);
+            let key = match words.next() {
+                Some(key) => key,
+                None => continue
+            };
+            let entry = match words.next() {
+                None | Some(b"local") => Entry::Local,
+                Some(b"forward") => match words.next() {
+                    Some(addr) => Entry::Forward(addr.into()),
+                    None => return Err(invalid("missing forward address"))
+                },
+                Some(b"moved") => match words.next() {
+                    Some(addr) => Entry::Moved(addr.into()),
+                    None => return Err(invalid("missing new address"))
+                },
+                Some(_) => return Err(invalid("unknown action"))
+            };
+            if words.next().is_some() {
+                return Err(invalid("trailing data after entry"))
+            }
+            self.insert(key, entry);
+        }
+        Ok(())
+    }
+
+    /// Adds a local domain.
+    pub fn add_domain(&mut self, domain: &[u8]) {
+        let domain = domain.to_ascii_lowercase();
+        if !self.domains.contains(&domain) {
+            self.domains.push(domain)
+        }
+    }
+
+    /// Adds an entry, replacing an earlier entry for the same key.
+    pub fn insert(&mut self, key: &[u8], entry: Entry) {
+        self.entries.insert(key.to_ascii_lowercase(), entry);
+    }
+
+    /// Sets the sub-address separator.
+    ///
+    /// If *separator* is `None`, local parts are only used as a whole.
+    pub fn set_separator(&mut self, separator: Option<u8>) {
+        self.separator = separator
+    }
+
+    /// Returns the first local domain.
+    ///
+    /// This is the domain used for local parts given without a domain.
+    pub fn primary_domain(&self) -> Option<&[u8]> {
+        self.domains.first().map(|domain| &domain[..])
+    }
+
+    /// Returns whether *domain* is a local domain.
+    pub fn is_local(&self, domain: &[u8]) -> bool {
+        self.domains.iter().any(|item| item.eq_ignore_ascii_case(domain))
+    }
+
+    /// Looks up the address with *local* part and *domain*.
+    pub fn lookup(&self, local: &[u8], domain: &[u8]) -> Lookup {
+        if !self.is_local(domain) {
+            return Lookup::NotLocal
+        }
+        let local = local.to_ascii_lowercase();
+        let domain = domain.to_ascii_lowercase();
+        if let Some(entry) = self.find(&local, &domain) {
+            return entry.lookup()
+        }
+        if let Some(sep) = self.separator {
+            if let Some(pos) = local.iter().position(|ch| *ch == sep) {
+                if let Some(entry) = self.find(&local[..pos], &domain) {
+                    return entry.lookup()
+                }
+            }
+        }
+        match self.find(b"*", &domain) {
+            Some(entry) => entry.lookup(),
+            None => Lookup::Unknown
+        }
+    }
+
+    /// Looks up an address given as a single string.
+    ///
+    /// If there is no `@` in *addr*, the address is looked up in the
+    /// primary domain.
+    pub fn lookup_addr(&self, addr: &[u8]) -> Lookup {
+        match addr.iter().rposition(|ch| *ch == b'@') {
+            Some(pos) => self.lookup(&addr[..pos], &addr[pos + 1..]),
+            None => match self.primary_domain() {
+                Some(domain) => self.lookup(addr, domain),
+                None => Lookup::NotLocal
+            }
+        }
+    }
+
+    /// Finds the entry for a lower-case local part and domain.
+    fn find(&self, local: &[u8], domain: &[u8]) -> Option<&Entry> {
+        let mut key = Vec::with_capacity(local.len() + domain.len() + 1);
+        key.extend_from_slice(local);
+        key.push(b'@');
+        key.extend_from_slice(domain);
+        self.entries.get(&key).or_else(|| self.entries.get(local))
+    }
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Users::new()
+    }
+}
+
+
+//------------ Entry ---------------------------------------------------------
+
+/// What should happen to mail for a user.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entry {
+    /// The user has a local mailbox.
+    Local,
+
+    /// Mail is accepted and forwarded to the given address.
+    Forward(Vec<u8>),
+
+    /// Mail is rejected and the client is told about the new address.
+    Moved(Vec<u8>),
+}
+
+impl Entry {
+    fn lookup(&self) -> Lookup {
+        match *self {
+            Entry::Local => Lookup::Local,
+            Entry::Forward(ref addr) => Lookup::Forward(addr),
+            Entry::Moved(ref addr) => Lookup::Moved(addr),
+        }
+    }
+}
+
+
+//------------ Lookup --------------------------------------------------------
+
+/// The result of looking up an address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lookup<'a> {
+    /// The address has a local mailbox.
+    Local,
+
+    /// Mail for the address should be forwarded to the given address.
+    Forward(&'a [u8]),
+
+    /// The user can now be reached at the given address.
+    Moved(&'a [u8]),
+
+    /// The domain is local but there is no such user.
+    Unknown,
+
+    /// The domain isn’t local.
+    NotLocal,
+}
+
+
+fn invalid(what: &str) -> io::Error {
+    text::invalid("user table", what)
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn users() -> Users {
+        let mut users = Users::new();
+        users.read_domains(&b"# Local domains\n\
+                              example.com\n\
+                              Example.ORG\n"[..]).unwrap();
+        users.read_users(&b"alice\n\
+                            bob    local  # the builder\n\
+                            carol@example.com\n\
+                            dave   forward dave@example.net\n\
+                            eve    moved eve@example.net\n\
+                            *@example.org\n"[..]).unwrap();
+        users
+    }
+
+    #[test]
+    fn lookup() {
+        let users = users();
+        assert_eq!(users.lookup(b"alice", b"example.com"), Lookup::Local);
+        assert_eq!(users.lookup(b"Bob", b"EXAMPLE.com"), Lookup::Local);
+        assert_eq!(users.lookup(b"carol", b"example.com"), Lookup::Local);
+        assert_eq!(users.lookup(b"dave", b"example.com"),
+                   Lookup::Forward(b"dave@example.net"));
+        assert_eq!(users.lookup(b"eve", b"example.com"),
+                   Lookup::Moved(b"eve@example.net"));
+        assert_eq!(users.lookup(b"mallory", b"example.com"),
+                   Lookup::Unknown);
+        assert_eq!(users.lookup(b"alice", b"example.net"),
+                   Lookup::NotLocal);
+    }
+
+    #[test]
+    fn catch_all() {
+        let users = users();
+        assert_eq!(users.lookup(b"mallory", b"example.org"), Lookup::Local);
+        assert_eq!(users.lookup(b"carol", b"example.org"), Lookup::Local);
+        assert_eq!(users.lookup(b"eve", b"example.org"),
+                   Lookup::Moved(b"eve@example.net"));
+    }
+
+    #[test]
+    fn sub_address() {
+        let mut users = users();
+        assert_eq!(users.lookup(b"alice+lists", b"example.com"),
+                   Lookup::Local);
+        assert_eq!(users.lookup(b"dave+x", b"example.com"),
+                   Lookup::Forward(b"dave@example.net"));
+        assert_eq!(users.lookup(b"mallory+x", b"example.com"),
+                   Lookup::Unknown);
+        users.set_separator(Some(b'-'));
+        assert_eq!(users.lookup(b"alice+lists", b"example.com"),
+                   Lookup::Unknown);
+        assert_eq!(users.lookup(b"alice-lists", b"example.com"),
+                   Lookup::Local);
+    }
+
+    #[test]
+    fn lookup_addr() {
+        let users = users();
+        assert_eq!(users.lookup_addr(b"carol"), Lookup::Local);
+        assert_eq!(users.lookup_addr(b"carol@example.com"), Lookup::Local);
+        assert_eq!(users.lookup_addr(b"frank@example.com"), Lookup::Unknown);
+    }
+
+    #[test]
+    fn bad_tables() {
+        let mut users = Users::new();
+        assert!(users.read_users(&b"alice deliver\n"[..]).is_err());
+        assert!(users.read_users(&b"alice forward\n"[..]).is_err());
+        assert!(users.read_users(&b"alice moved a@b c@d\n"[..]).is_err());
+        assert!(users.read_domains(&b"example.com example.org\n"[..])
+                     .is_err());
+    }
+}
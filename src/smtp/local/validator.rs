@@ -0,0 +1,561 @@
+//! Validating recipients against local users and aliases.
+//!
+//! A validator answers the RCPT, VRFY, and EXPN commands from the table
+//! of local users and the aliases. It writes the reply itself so that
+//! all protocols that deliver locally answer the same way.
+//!
+//! Without a table of local users, the only local domain is the host
+//! name and all its users exist. Without a host name either, nothing
+//! but Postmaster is local. Recipients outside the local domains are
+//! only accepted from authenticated clients.
+
+use std::ascii::AsciiExt;
+use nom::IResult;
+use ::smtp::server::reply::ReplyBuf;
+use ::smtp::syntax;
+use super::aliases::{Aliases, ExpandError, Expansion};
+use super::users::{Lookup, Users};
+
+
+//------------ Validator -----------------------------------------------------
+
+#[derive(Clone, Debug)]
+pub struct Validator {
+    users: Option<Users>,
+    aliases: Option<Aliases>,
+
+    /// The local domain if there is no table of users.
+    hostname: Vec<u8>,
+
+    /// Is VRFY enabled?
+    verify: bool,
+
+    /// Is EXPN enabled?
+    expand: bool,
+}
+
+impl Validator {
+    /// Creates a validator for which only Postmaster is local.
+    pub fn new() -> Self {
+        Validator { users: None, aliases: None, hostname: Vec::new(),
+                    verify: false, expand: false }
+    }
+
+    /// Sets the table of local users to validate recipients against.
+    pub fn set_users(&mut self, users: Users) {
+        self.users = Some(users)
+    }
+
+    /// Sets the aliases to expand recipients and EXPN commands with.
+    ///
+    /// Aliases apply to the local domains of the table of users only.
+    pub fn set_aliases(&mut self, aliases: Aliases) {
+        self.aliases = Some(aliases)
+    }
+
+    /// Sets the local domain used without a table of users.
+    pub fn set_hostname(&mut self, hostname: &[u8]) {
+        self.hostname = hostname.into()
+    }
+
+    /// Sets whether VRFY is answered from the table of local users.
+    pub fn set_verify(&mut self, enable: bool) {
+        self.verify = enable
+    }
+
+    /// Sets whether EXPN is answered from the table of local users.
+    pub fn set_expand(&mut self, enable: bool) {
+        self.expand = enable
+    }
+
+    /// Returns whether EXPN is answered.
+    pub fn expands(&self) -> bool {
+        self.expand
+    }
+
+    /// Validates the recipient of a RCPT command and replies to it.
+    ///
+    /// Returns the mailboxes to deliver to if the recipient has been
+    /// accepted. Clients that have *authenticated* may send mail to
+    /// recipients that aren’t local.
+    pub fn rcpt(&self, path: &syntax::RcptPath,
+                params: &syntax::RcptParameters, authenticated: bool,
+                reply: ReplyBuf) -> Option<Accepted> {
+        if let (Some(users), Some(aliases)) = (self.users.as_ref(),
+                                               self.aliases.as_ref()) {
+            if let Some((local, domain)) = alias_address(users, path) {
+                match aliases.expand(&local) {
+                    Ok(Some(items)) => {
+                        return expansion(items, &domain, path, params, reply)
+                    }
+                    Ok(None) => { }
+                    Err(err) => {
+                        alias_error(err, reply);
+                        return None
+                    }
+                }
+            }
+        }
+        let lookup = match self.users {
+            None => host_lookup(&self.hostname, path),
+            Some(ref users) => rcpt_lookup(users, path)
+        };
+        let orcpt = params.orcpt.as_ref()
+                                .map(|orcpt| orcpt.to_string().into_bytes());
+        match lookup {
+            Lookup::Local => { }
+            Lookup::Forward(addr) => {
+                let mut reply = reply.start(251, Some((2, 1, 5)));
+                scribble!(&mut reply, b"User not local; will forward to <",
+                          addr, b">\r\n");
+                // Keep the original recipient for the sake of DSNs.
+                let orcpt = orcpt.unwrap_or_else(|| original(path));
+                return Some(Accepted { mailboxes: vec![addr.into()],
+                                       orcpt: Some(orcpt) })
+            }
+            Lookup::Moved(addr) => {
+                let mut reply = reply.start(551, Some((5, 1, 6)));
+                scribble!(&mut reply, b"User not local; please try <",
+                          addr, b">\r\n");
+                return None
+            }
+            Lookup::Unknown => {
+                reply.reply(550, (5, 1, 1), b"User unknown\r\n");
+                return None
+            }
+            Lookup::NotLocal if authenticated => { }
+            Lookup::NotLocal => {
+                reply.reply(550, (5, 7, 1), b"Relaying denied\r\n");
+                return None
+            }
+        }
+        reply.reply(250, (2, 1, 5), b"Ok\r\n");
+        Some(Accepted { mailboxes: vec![path.to_string().into_bytes()],
+                        orcpt: orcpt })
+    }
+
+    /// Answers a VRFY command.
+    pub fn verify(&self, what: syntax::Word, reply: ReplyBuf) {
+        if !self.verify {
+            reply.reply(252, (2, 7, 0), b"VRFY administratively disabled\r\n");
+            return
+        }
+        self.answer(what, false, reply)
+    }
+
+    /// Answers an EXPN command.
+    pub fn expand(&self, what: syntax::Word, reply: ReplyBuf) {
+        if !self.expand {
+            reply.reply(252, (2, 7, 0), b"EXPN administratively disabled\r\n");
+            return
+        }
+        self.answer(what, true, reply)
+    }
+
+    /// Answers a VRFY or EXPN command from aliases and local users.
+    fn answer(&self, what: syntax::Word, expand: bool, reply: ReplyBuf) {
+        let addr = match what {
+            syntax::Word::Atom(atom) => atom,
+            syntax::Word::Quoted(ref quoted) => quoted.as_bytes()
+        };
+        let addr = if addr.starts_with(b"<") && addr.ends_with(b">") {
+            &addr[1..addr.len() - 1]
+        }
+        else { addr };
+        let users = match self.users {
+            Some(ref users) => users,
+            None => {
+                reply.reply(252, (2, 7, 0), b"Cannot verify address\r\n");
+                return
+            }
+        };
+        if let Some(ref aliases) = self.aliases {
+            let at = addr.iter().rposition(|ch| *ch == b'@');
+            let (local, domain) = match at {
+                Some(pos) => (&addr[..pos], Some(&addr[pos + 1..])),
+                None => (addr, users.primary_domain())
+            };
+            let domain = domain.and_then(|domain| {
+                if users.is_local(domain) { Some(domain) } else { None }
+            });
+            if let Some(domain) = domain {
+                match aliases.expand(local) {
+                    Ok(Some(items)) => {
+                        let paths = match alias_mailboxes(items, domain) {
+                            Some(paths) => paths,
+                            None => {
+                                reply.reply(550, (5, 3, 5),
+                                            b"Alias cannot be delivered\r\n");
+                                return
+                            }
+                        };
+                        let mut reply = reply.start(250, Some((2, 1, 5)));
+                        if expand {
+                            for path in &paths {
+                                scribble!(&mut reply, b"<", &path[..],
+                                          b">\r\n");
+                            }
+                        }
+                        if !expand || paths.is_empty() {
+                            scribble!(&mut reply, b"<", local, b"@", domain,
+                                      b">\r\n");
+                        }
+                        return
+                    }
+                    Ok(None) => { }
+                    Err(err) => {
+                        alias_error(err, reply);
+                        return
+                    }
+                }
+            }
+        }
+        match users.lookup_addr(addr) {
+            Lookup::Local => {
+                let mut reply = reply.start(250, Some((2, 1, 5)));
+                if addr.contains(&b'@') {
+                    scribble!(&mut reply, b"<", addr, b">\r\n");
+                }
+                else {
+                    let domain = users.primary_domain().unwrap_or(b"");
+                    scribble!(&mut reply, b"<", addr, b"@", domain,
+                              b">\r\n");
+                }
+            }
+            Lookup::Forward(fwd) => {
+                if expand {
+                    let mut reply = reply.start(250, Some((2, 1, 5)));
+                    scribble!(&mut reply, b"<", fwd, b">\r\n");
+                }
+                else {
+                    let mut reply = reply.start(251, Some((2, 1, 5)));
+                    scribble!(&mut reply, b"User not local; will forward to <",
+                              fwd, b">\r\n");
+                }
+            }
+            Lookup::Moved(fwd) => {
+                let mut reply = reply.start(551, Some((5, 1, 6)));
+                scribble!(&mut reply, b"User not local; please try <",
+                          fwd, b">\r\n");
+            }
+            Lookup::Unknown => {
+                reply.reply(550, (5, 1, 1), b"User unknown\r\n");
+            }
+            Lookup::NotLocal => {
+                reply.reply(550, (5, 1, 2), b"Not a local address\r\n");
+            }
+        }
+    }
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Validator::new()
+    }
+}
+
+
+//------------ Accepted ------------------------------------------------------
+
+/// The mailboxes an accepted recipient is to be delivered to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Accepted {
+    /// The addresses of the mailboxes.
+    ///
+    /// An alias can expand to no mailboxes at all.
+    pub mailboxes: Vec<Vec<u8>>,
+
+    /// The original recipient for DSNs if there is one.
+    pub orcpt: Option<Vec<u8>>,
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Looks up the recipient *path* in the table of local *users*.
+///
+/// Postmaster addresses are always local as required by RFC 5321.
+fn rcpt_lookup<'a>(users: &'a Users, path: &syntax::RcptPath) -> Lookup<'a> {
+    match *path {
+        syntax::RcptPath::Postmaster => Lookup::Local,
+        syntax::RcptPath::DomainPostmaster(ref domain) => {
+            if users.is_local(domain.as_bytes()) { Lookup::Local }
+            else { Lookup::NotLocal }
+        }
+        syntax::RcptPath::ForwardPath(ref path) => {
+            let mailbox = path.mailbox();
+            let domain = match *mailbox.domain() {
+                syntax::MailboxDomain::Domain(ref domain) => domain.as_bytes(),
+                syntax::MailboxDomain::Address(_) => return Lookup::NotLocal
+            };
+            match *mailbox.local() {
+                syntax::LocalPart::Dotted(local) => {
+                    users.lookup(local, domain)
+                }
+                syntax::LocalPart::Quoted(ref quoted) => {
+                    users.lookup(quoted.as_bytes(), domain)
+                }
+            }
+        }
+    }
+}
+
+/// Looks up the recipient *path* without a table of local users.
+///
+/// Only the domain *hostname* is local then and all its users exist.
+/// The bare Postmaster address is always local.
+fn host_lookup(hostname: &[u8], path: &syntax::RcptPath) -> Lookup<'static> {
+    let domain = match *path {
+        syntax::RcptPath::Postmaster => return Lookup::Local,
+        syntax::RcptPath::DomainPostmaster(ref domain) => domain.as_bytes(),
+        syntax::RcptPath::ForwardPath(ref path) => {
+            match *path.mailbox().domain() {
+                syntax::MailboxDomain::Domain(ref domain) => domain.as_bytes(),
+                syntax::MailboxDomain::Address(_) => return Lookup::NotLocal
+            }
+        }
+    };
+    if !hostname.is_empty() && domain.eq_ignore_ascii_case(hostname) {
+        Lookup::Local
+    }
+    else {
+        Lookup::NotLocal
+    }
+}
+
+/// Returns the local part and domain of an alias for *path*.
+///
+/// Returns `None` if the recipient isn’t in one of the local domains of
+/// *users*.
+fn alias_address(users: &Users, path: &syntax::RcptPath)
+                 -> Option<(Vec<u8>, Vec<u8>)> {
+    let (local, domain) = match *path {
+        syntax::RcptPath::Postmaster => {
+            match users.primary_domain() {
+                Some(domain) => (&b"postmaster"[..], domain),
+                None => return None
+            }
+        }
+        syntax::RcptPath::DomainPostmaster(ref domain) => {
+            (&b"postmaster"[..], domain.as_bytes())
+        }
+        syntax::RcptPath::ForwardPath(ref path) => {
+            let mailbox = path.mailbox();
+            let domain = match *mailbox.domain() {
+                syntax::MailboxDomain::Domain(ref domain) => domain.as_bytes(),
+                syntax::MailboxDomain::Address(_) => return None
+            };
+            match *mailbox.local() {
+                syntax::LocalPart::Dotted(local) => (local, domain),
+                syntax::LocalPart::Quoted(ref quoted) => {
+                    (quoted.as_bytes(), domain)
+                }
+            }
+        }
+    };
+    if users.is_local(domain) {
+        Some((local.into(), domain.into()))
+    }
+    else {
+        None
+    }
+}
+
+/// Accepts the mailboxes an alias for *path* has expanded to.
+///
+/// Bare names are qualified with *domain*. All mailboxes share the
+/// original recipient of the alias.
+fn expansion(items: Vec<Expansion>, domain: &[u8], path: &syntax::RcptPath,
+             params: &syntax::RcptParameters, reply: ReplyBuf)
+             -> Option<Accepted> {
+    let mailboxes = match alias_mailboxes(items, domain) {
+        Some(ref mailboxes) if mailboxes.is_empty() => {
+            reply.reply(550, (5, 3, 5),
+                        b"Alias has no deliverable targets\r\n");
+            return None
+        }
+        Some(mailboxes) => mailboxes,
+        None => {
+            reply.reply(550, (5, 3, 5), b"Alias cannot be delivered\r\n");
+            return None
+        }
+    };
+    let orcpt = match params.orcpt {
+        Some(ref orcpt) => orcpt.to_string().into_bytes(),
+        None => original(path)
+    };
+    reply.reply(250, (2, 1, 5), b"Ok\r\n");
+    Some(Accepted { mailboxes: mailboxes, orcpt: Some(orcpt) })
+}
+
+/// Adds *domain* to *name* unless it already has one.
+fn qualify(mut name: Vec<u8>, domain: &[u8]) -> Vec<u8> {
+    if !name.contains(&b'@') {
+        name.push(b'@');
+        name.extend_from_slice(domain);
+    }
+    name
+}
+
+/// Returns the addresses of the mailboxes an alias has expanded to.
+///
+/// Bare names are qualified with *domain*. Since the queue only knows
+/// how to relay mail to mailboxes, the alias can’t be used if it has
+/// pipe or file targets or names that aren’t valid addresses. This is
+/// logged and `None` returned.
+fn alias_mailboxes(items: Vec<Expansion>, domain: &[u8])
+                   -> Option<Vec<Vec<u8>>> {
+    let mut res = Vec::new();
+    for item in items {
+        match item {
+            Expansion::Mailbox(name) => {
+                let path = qualify(name, domain);
+                if !is_mailbox(&path) {
+                    error!("Aliases: invalid alias target {}",
+                           String::from_utf8_lossy(&path));
+                    return None
+                }
+                res.push(path)
+            }
+            Expansion::Pipe(cmd) => {
+                error!("Aliases: unsupported alias target |{}",
+                       String::from_utf8_lossy(&cmd));
+                return None
+            }
+            Expansion::File(path) => {
+                error!("Aliases: unsupported alias target {}",
+                       String::from_utf8_lossy(&path));
+                return None
+            }
+        }
+    }
+    Some(res)
+}
+
+/// Returns whether *path* is a valid address.
+fn is_mailbox(path: &[u8]) -> bool {
+    // The parser needs to see where the address ends.
+    let mut input = path.to_vec();
+    input.push(b'>');
+    match syntax::Mailbox::parse(&input) {
+        IResult::Done(rest, _) => rest == &b">"[..],
+        _ => false
+    }
+}
+
+/// Replies to a failed alias expansion.
+fn alias_error(err: ExpandError, reply: ReplyBuf) {
+    error!("Aliases: {}", err);
+    reply.reply(550, (5, 4, 6), b"Alias loop detected\r\n");
+}
+
+/// Returns the original recipient parameter for *path*.
+fn original(path: &syntax::RcptPath) -> Vec<u8> {
+    let mut res = b"rfc822;".to_vec();
+    encode_xtext(path.to_string().as_bytes(), &mut res);
+    res
+}
+
+/// Appends *data* encoded as xtext per RFC 3461 to *target*.
+fn encode_xtext(data: &[u8], target: &mut Vec<u8>) {
+    for &ch in data {
+        if ch < 33 || ch > 126 || ch == b'+' || ch == b'=' {
+            target.extend_from_slice(format!("+{:02X}", ch).as_bytes())
+        }
+        else {
+            target.push(ch)
+        }
+    }
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use nom::IResult;
+    use ::smtp::local::aliases::Aliases;
+    use ::smtp::local::users::Users;
+    use ::smtp::server::buf::SendBuf;
+    use ::smtp::server::reply::ReplyBuf;
+    use ::smtp::syntax::Command;
+    use super::*;
+
+    /// Runs *command* through *validator*.
+    ///
+    /// Returns the reply and, for RCPT, what has been accepted.
+    fn run(validator: &Validator, command: &[u8], authenticated: bool)
+           -> (Vec<u8>, Option<Accepted>) {
+        let mut send = SendBuf::new();
+        let res = match Command::parse(command) {
+            IResult::Done(_, Command::Rcpt(path, params)) => {
+                validator.rcpt(&path, &params, authenticated,
+                               ReplyBuf::new(&mut send))
+            }
+            IResult::Done(_, Command::Vrfy(what, _)) => {
+                validator.verify(what, ReplyBuf::new(&mut send));
+                None
+            }
+            IResult::Done(_, Command::Expn(what, _)) => {
+                validator.expand(what, ReplyBuf::new(&mut send));
+                None
+            }
+            _ => panic!("bad command")
+        };
+        (send.as_slice().into(), res)
+    }
+
+    fn accepted(mailboxes: &[&[u8]], orcpt: Option<&[u8]>) -> Accepted {
+        Accepted { mailboxes: mailboxes.iter().map(|m| m.to_vec()).collect(),
+                   orcpt: orcpt.map(Into::into) }
+    }
+
+    #[test]
+    fn hostname() {
+        let mut validator = Validator::new();
+        assert_eq!(run(&validator, b"RCPT TO:<a@mx.test>\r\n", false),
+                   (b"550 5.7.1 Relaying denied\r\n".to_vec(), None));
+        assert_eq!(run(&validator, b"RCPT TO:<Postmaster>\r\n", false).1,
+                   Some(accepted(&[b"Postmaster"], None)));
+        validator.set_hostname(b"mx.test");
+        assert_eq!(run(&validator, b"RCPT TO:<a@MX.test>\r\n", false),
+                   (b"250 2.1.5 Ok\r\n".to_vec(),
+                    Some(accepted(&[b"a@MX.test"], None))));
+        assert_eq!(run(&validator, b"RCPT TO:<a@example.com>\r\n", true).1,
+                   Some(accepted(&[b"a@example.com"], None)));
+        assert_eq!(run(&validator, b"VRFY a\r\n", false).0,
+                   b"252 2.7.0 VRFY administratively disabled\r\n".to_vec());
+    }
+
+    #[test]
+    fn users_and_aliases() {
+        let mut users = Users::new();
+        users.add_domain(b"mx.test");
+        users.read_users(&b"alice\n\
+                            bob forward bob@example.com\n"[..]).unwrap();
+        let mut aliases = Aliases::new();
+        aliases.read(&b"staff: alice, bob@example.com\n"[..]).unwrap();
+        let mut validator = Validator::new();
+        validator.set_users(users);
+        validator.set_aliases(aliases);
+        validator.set_verify(true);
+        validator.set_expand(true);
+
+        assert_eq!(run(&validator, b"RCPT TO:<bob@mx.test>\r\n", false),
+                   (b"251 2.1.5 User not local; will forward to \
+                      <bob@example.com>\r\n".to_vec(),
+                    Some(accepted(&[b"bob@example.com"],
+                                  Some(b"rfc822;bob@mx.test")))));
+        assert_eq!(run(&validator, b"RCPT TO:<staff@mx.test> \
+                                     ORCPT=rfc822;x@mx.test\r\n", false).1,
+                   Some(accepted(&[b"alice@mx.test", b"bob@example.com"],
+                                 Some(b"rfc822;x@mx.test"))));
+        assert_eq!(run(&validator, b"RCPT TO:<dave@mx.test>\r\n", true).1,
+                   None);
+        assert_eq!(run(&validator, b"VRFY alice\r\n", false).0,
+                   b"250 2.1.5 <alice@mx.test>\r\n".to_vec());
+        assert_eq!(run(&validator, b"EXPN staff\r\n", false).0,
+                   b"250-2.1.5 <alice@mx.test>\r\n\
+                     250 2.1.5 <bob@example.com>\r\n".to_vec());
+    }
+}
@@ -14,15 +14,25 @@
 //! * STARTTLS (defines the STARTTLS verb, see RFC 3207)
 //! * AUTH (defines the AUTH verb and the AUTH mail-parameter, see RFC 4954)
 //! * SMTPUTF8 (defines the mail-parameter SMTPUTF8, see RFC 6531)
+//! * CHUNKING (defines the BDAT verb, see RFC 3030)
+//! * BINARYMIME (defines the BINARYMIME body-value, see RFC 3030)
 //!
 //! This implementation may later support these extensions:
 //!
-//! * BINARYMIME (see RFC 3030)
-//! * CHUNKING (see RFC 3030)
 //! * DELIVERBY (see RFC 2852)
 //! * BURL (see RFC 4468)
 //! * FUTURERELEASE (see RFC 4865)
 //!
 
+pub mod authres;
+pub mod client;
+pub mod dkim;
+pub mod dmarc;
+pub mod dotstuff;
+pub mod dsn;
+pub mod fs;
+pub mod local;
+pub mod relay;
 pub mod server;
+pub mod spf;
 pub mod syntax;
@@ -0,0 +1,121 @@
+//! Configuration for the relay scheduler.
+
+use std::time::Duration;
+
+
+pub struct Config {
+    hostname: Vec<u8>,
+    retry_base: Duration,
+    retry_max: Duration,
+    lifetime: Duration,
+    delay_notice: Duration,
+    interval: Duration,
+}
+
+impl Config {
+    pub fn new(hostname: Vec<u8>) -> Self {
+        Config { hostname: hostname,
+                 retry_base: Duration::from_secs(15 * 60),
+                 retry_max: Duration::from_secs(4 * 60 * 60),
+                 lifetime: Duration::from_secs(5 * 24 * 60 * 60),
+                 delay_notice: Duration::from_secs(4 * 60 * 60),
+                 interval: Duration::from_secs(60) }
+    }
+
+    /// Returns the host name to use in delivery status notifications.
+    pub fn hostname(&self) -> &[u8] {
+        &self.hostname
+    }
+
+    /// Returns how long to wait after the first failed attempt.
+    ///
+    /// The wait doubles with every further attempt. The default is
+    /// fifteen minutes.
+    pub fn retry_base(&self) -> Duration {
+        self.retry_base
+    }
+
+    pub fn set_retry_base(&mut self, base: Duration) {
+        self.retry_base = base
+    }
+
+    /// Returns the longest time to wait between two attempts.
+    ///
+    /// The default is four hours.
+    pub fn retry_max(&self) -> Duration {
+        self.retry_max
+    }
+
+    pub fn set_retry_max(&mut self, max: Duration) {
+        self.retry_max = max
+    }
+
+    /// Returns how long a mail may stay in the queue.
+    ///
+    /// Recipients that still fail temporarily after this time are given
+    /// up on. The default is five days as suggested by RFC 5321, section
+    /// 4.5.4.1.
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    pub fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime
+    }
+
+    /// Returns after how long the sender is told that a mail is delayed.
+    ///
+    /// This happens only once per recipient. The default is four hours.
+    pub fn delay_notice(&self) -> Duration {
+        self.delay_notice
+    }
+
+    pub fn set_delay_notice(&mut self, delay: Duration) {
+        self.delay_notice = delay
+    }
+
+    /// Returns how often the queue is checked for due recipients.
+    ///
+    /// The default is once a minute.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval
+    }
+
+    /// Returns how long to wait after *attempts* failed attempts.
+    pub fn retry_delay(&self, attempts: u32) -> Duration {
+        let base = self.retry_base.as_secs();
+        let max = self.retry_max.as_secs();
+        let shift = if attempts > 0 { attempts - 1 } else { 0 };
+        if shift >= 32 {
+            return self.retry_max
+        }
+        match base.checked_mul(1 << shift) {
+            Some(delay) if delay < max => Duration::from_secs(delay),
+            _ => self.retry_max
+        }
+    }
+}
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn retry_delay() {
+        let mut config = Config::new(b"localhost".to_vec());
+        config.set_retry_base(Duration::from_secs(60));
+        config.set_retry_max(Duration::from_secs(300));
+        assert_eq!(config.retry_delay(1), Duration::from_secs(60));
+        assert_eq!(config.retry_delay(2), Duration::from_secs(120));
+        assert_eq!(config.retry_delay(3), Duration::from_secs(240));
+        assert_eq!(config.retry_delay(4), Duration::from_secs(300));
+        assert_eq!(config.retry_delay(100), Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,22 @@
+//! Relaying queued mail to other servers.
+//!
+//! The `Scheduler` regularly goes over the mail queue, groups all
+//! recipients that are due for delivery by their domain, and hands each
+//! group to a `DeliveryTransport`. What happened to each recipient is
+//! recorded in the envelope. Recipients that failed temporarily are tried
+//! again with exponentially growing intervals until the mail has been in
+//! the queue for too long. A mail leaves the queue once all its
+//! recipients have been delivered to or have failed for good. Senders
+//! are told about failures, long delays, and, if asked for, success via
+//! delivery status notifications that are put into the same queue.
+//!
+//! The `SmtpTransport` delivers via SMTP using the client and a `Router`
+//! that decides which servers to connect to for a domain.
+
+pub use self::config::Config;
+pub use self::scheduler::{Delivery, DeliveryTransport, Handle, Scheduler};
+pub use self::smtp::{DirectRouter, Router, SmartHost, SmtpTransport};
+
+pub mod config;
+pub mod scheduler;
+pub mod smtp;
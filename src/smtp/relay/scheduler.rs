@@ -0,0 +1,557 @@
+//! The scheduler that runs the queue.
+
+use std::ascii::AsciiExt;
+use std::io::{self, BufReader, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use ::smtp::dsn::{Action, Dsn};
+use ::smtp::fs::queue::{DeliveryState, Envelope, Message, Queue, QueueId,
+                        Recipient};
+use ::smtp::syntax::Reply;
+use ::util::date::now;
+use super::config::Config;
+
+
+//------------ DeliveryTransport ---------------------------------------------
+
+/// A type that can deliver a mail to the recipients of one domain.
+///
+pub trait DeliveryTransport {
+    /// Delivers a mail.
+    ///
+    /// The mail should be delivered to those recipients of *envelope*
+    /// whose indexes are given in *recipients*. They all belong to
+    /// *domain* which is in lower case. The message is read from
+    /// *message*.
+    ///
+    /// Transports that do final delivery rather than relay the mail
+    /// should add the Return-Path field via `Message::with_return_path()`.
+    ///
+    /// Returns what happened to each recipient in the order given in
+    /// *recipients*. Recipients missing from the result are considered
+    /// deferred.
+    fn deliver(&mut self, domain: &[u8], envelope: &Envelope,
+               recipients: &[usize], message: Message) -> Vec<Delivery>;
+}
+
+
+//------------ Delivery ------------------------------------------------------
+
+/// The outcome of a delivery attempt for a single recipient.
+///
+/// If the failure happened locally or the remote server never had a
+/// say, the transport should make up a suitable reply.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum Delivery {
+    /// The recipient has been delivered to.
+    ///
+    /// Whoever took the mail is now responsible for it, including any
+    /// further delivery status notifications.
+    Delivered(Reply),
+
+    /// The mail has been passed on to a place that won’t send delivery
+    /// status notifications.
+    ///
+    /// If the recipient asked to be notified of success, we do it now.
+    Relayed(Reply),
+
+    /// Delivery failed temporarily.
+    Deferred(Reply),
+
+    /// Delivery failed for good.
+    Failed(Reply),
+}
+
+
+//------------ Scheduler -----------------------------------------------------
+
+pub struct Scheduler<T: DeliveryTransport> {
+    queue: Queue,
+    transport: T,
+    config: Config,
+    handle: Handle,
+}
+
+impl<T: DeliveryTransport> Scheduler<T> {
+    pub fn new(queue: Queue, transport: T, config: Config) -> Self {
+        Scheduler { queue: queue, transport: transport, config: config,
+                    handle: Handle::new() }
+    }
+
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns a handle for controlling `run()` from other threads.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Runs the queue until told to stop via a handle.
+    ///
+    /// The queue is run once right away and then whenever the interval
+    /// given in the config has passed.
+    pub fn run(&mut self) {
+        loop {
+            if let Err(err) = self.run_once(now()) {
+                error!("Relay: cannot run queue: {}", err);
+            }
+            let flush = match self.handle.wait(&self.config) {
+                Some(flush) => flush,
+                None => return
+            };
+            for domain in flush {
+                let domain = domain.as_ref().map(|domain| &domain[..]);
+                if let Err(err) = self.flush(now(), domain) {
+                    error!("Relay: cannot flush queue: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Attempts delivery to all recipients due at time *now*.
+    ///
+    /// The time is in seconds since the epoch.
+    pub fn run_once(&mut self, now: u64) -> io::Result<()> {
+        self.process(now, |rcpt| rcpt.state.is_due(now))
+    }
+
+    /// Attempts delivery to all recipients of *domain* regardless of when
+    /// they are due.
+    ///
+    /// If *domain* is `None`, delivery is attempted for all recipients.
+    pub fn flush(&mut self, now: u64, domain: Option<&[u8]>)
+                 -> io::Result<()> {
+        match domain {
+            Some(domain) => {
+                self.process(now, |rcpt| {
+                    rcpt.domain().eq_ignore_ascii_case(domain)
+                })
+            }
+            None => self.process(now, |_| true)
+        }
+    }
+}
+
+impl<T: DeliveryTransport> Scheduler<T> {
+    fn process<F>(&mut self, now: u64, select: F) -> io::Result<()>
+               where F: Fn(&Recipient) -> bool {
+        for id in try!(self.queue.list()) {
+            if let Err(err) = self.process_mail(&id, now, &select) {
+                error!("Relay: processing {} failed: {}", id, err);
+            }
+        }
+        Ok(())
+    }
+
+    fn process_mail<F>(&mut self, id: &QueueId, now: u64, select: &F)
+                       -> io::Result<()>
+                    where F: Fn(&Recipient) -> bool {
+        let mut envelope = try!(self.queue.envelope(id));
+        let mut domains: Vec<(Vec<u8>, Vec<usize>)> = Vec::new();
+        for (idx, rcpt) in envelope.recipients.iter().enumerate() {
+            if rcpt.state.is_final() || !select(rcpt) {
+                continue
+            }
+            let domain = rcpt.domain().to_ascii_lowercase();
+            match domains.iter().position(|item| item.0 == domain) {
+                Some(pos) => domains[pos].1.push(idx),
+                None => domains.push((domain, vec![idx]))
+            }
+        }
+        if domains.is_empty() {
+            return Ok(())
+        }
+        let mut actions = Vec::new();
+        let mut error = None;
+        for (domain, recipients) in domains {
+            // If the message is gone, what has happened so far still
+            // needs recording below so it doesn’t happen again.
+            let message = match self.queue.message(id) {
+                Ok(message) => message,
+                Err(err) => {
+                    error = Some(err);
+                    break
+                }
+            };
+            let mut res = self.transport.deliver(&domain, &envelope,
+                                                 &recipients, message)
+                              .into_iter();
+            for idx in recipients {
+                let delivery = res.next().unwrap_or_else(|| {
+                    Delivery::Deferred(Reply::new(451, Some((4, 3, 0)),
+                                       b"No result from transport".to_vec()))
+                });
+                let arrival = envelope.arrival;
+                if let Some(action) = self.record(&mut envelope
+                                                      .recipients[idx],
+                                                  delivery, arrival, now) {
+                    actions.push((idx, action))
+                }
+                let rcpt = &envelope.recipients[idx];
+                info!("Relay: {} to {}: {:?}", id,
+                      String::from_utf8_lossy(&rcpt.path), rcpt.state);
+            }
+        }
+        if !actions.is_empty() {
+            self.notify(id, &envelope, &actions, now);
+        }
+        let res = if envelope.is_complete() {
+            info!("Relay: {} done", id);
+            self.queue.remove(id)
+        }
+        else {
+            self.queue.update(id, &envelope)
+        };
+        match error {
+            Some(err) => Err(err),
+            None => res
+        }
+    }
+
+    /// Records the outcome of a delivery attempt.
+    ///
+    /// A recipient that fails temporarily once its mail has expired fails
+    /// for good instead. It keeps the transient reply, which is how an
+    /// expired recipient can be told apart.
+    ///
+    /// Returns the action to notify the sender of, if any.
+    fn record(&self, rcpt: &mut Recipient, delivery: Delivery,
+              arrival: u64, now: u64) -> Option<Action> {
+        match delivery {
+            Delivery::Delivered(reply) => {
+                rcpt.state = DeliveryState::Delivered;
+                rcpt.reply = Some(reply);
+                None
+            }
+            Delivery::Relayed(reply) => {
+                rcpt.state = DeliveryState::Delivered;
+                rcpt.reply = Some(reply);
+                Some(Action::Relayed)
+            }
+            Delivery::Failed(reply) => {
+                rcpt.state = DeliveryState::Failed;
+                rcpt.reply = Some(reply);
+                Some(Action::Failed)
+            }
+            Delivery::Deferred(reply) => {
+                let expires = arrival + self.config.lifetime().as_secs();
+                let attempts = rcpt.state.attempts() + 1;
+                let retry = now + self.config.retry_delay(attempts)
+                                             .as_secs();
+                rcpt.reply = Some(reply);
+                if now >= expires {
+                    rcpt.state = DeliveryState::Failed;
+                    return Some(Action::Failed)
+                }
+                // Make sure there is one last try at expiry.
+                let retry = if retry > expires { expires } else { retry };
+                rcpt.state = DeliveryState::Deferred {
+                    attempts: attempts, retry: retry
+                };
+                let notice = arrival + self.config.delay_notice().as_secs();
+                if !rcpt.warned && now >= notice {
+                    rcpt.warned = true;
+                    Some(Action::Delayed)
+                }
+                else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Queues a delivery status notification to the sender of a mail.
+    ///
+    /// Failing to do so is logged but otherwise ignored.
+    fn notify(&self, id: &QueueId, envelope: &Envelope,
+              actions: &[(usize, Action)], now: u64) {
+        let mut dsn = Dsn::new(self.config.hostname(), envelope, now);
+        for &(idx, action) in actions {
+            dsn.add(idx, action);
+        }
+        dsn.set_expires(envelope.arrival + self.config.lifetime().as_secs());
+        let dsn_envelope = match dsn.envelope() {
+            Some(dsn_envelope) => dsn_envelope,
+            None => return
+        };
+        if dsn.is_empty() {
+            return
+        }
+        let res = self.queue.create().and_then(|mut spool| {
+            let res = self.queue.message(id).and_then(|message| {
+                try!(dsn.write(&mut spool, BufReader::new(message)));
+                spool.flush()
+            });
+            match res {
+                Ok(()) => spool.commit(&dsn_envelope),
+                Err(err) => {
+                    let _ = spool.abort();
+                    Err(err)
+                }
+            }
+        });
+        match res {
+            Ok(dsn_id) => info!("Relay: {} notification queued as {}",
+                                id, dsn_id),
+            Err(err) => error!("Relay: {} notification failed: {}", id, err)
+        }
+    }
+}
+
+
+//------------ Handle --------------------------------------------------------
+
+/// A handle to control a running scheduler from another thread.
+#[derive(Clone)]
+pub struct Handle {
+    inner: Arc<(Mutex<Control>, Condvar)>,
+}
+
+struct Control {
+    /// The domains to flush, `None` meaning all of them.
+    flush: Vec<Option<Vec<u8>>>,
+
+    /// Should the scheduler stop?
+    stop: bool,
+}
+
+impl Handle {
+    fn new() -> Self {
+        Handle {
+            inner: Arc::new((Mutex::new(Control { flush: Vec::new(),
+                                                  stop: false }),
+                             Condvar::new()))
+        }
+    }
+
+    /// Asks the scheduler to attempt delivery for *domain* right away.
+    ///
+    /// If *domain* is `None`, the whole queue is flushed.
+    pub fn flush(&self, domain: Option<&[u8]>) {
+        let &(ref lock, ref cvar) = &*self.inner;
+        let mut control = lock.lock().unwrap();
+        control.flush.push(domain.map(|domain| domain.to_ascii_lowercase()));
+        cvar.notify_one();
+    }
+
+    /// Asks the scheduler to stop.
+    ///
+    /// A delivery attempt in progress is finished first.
+    pub fn stop(&self) {
+        let &(ref lock, ref cvar) = &*self.inner;
+        lock.lock().unwrap().stop = true;
+        cvar.notify_one();
+    }
+
+    /// Waits for the next run of the scheduler.
+    ///
+    /// Returns the domains to flush or `None` if the scheduler should stop.
+    fn wait(&self, config: &Config) -> Option<Vec<Option<Vec<u8>>>> {
+        let &(ref lock, ref cvar) = &*self.inner;
+        let mut control = lock.lock().unwrap();
+        if !control.stop && control.flush.is_empty() {
+            control = cvar.wait_timeout(control, config.interval()).unwrap().0;
+        }
+        if control.stop {
+            None
+        }
+        else {
+            Some(control.flush.drain(..).collect())
+        }
+    }
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use ::smtp::fs::queue::{DeliveryState, Envelope, Queue, Recipient};
+    use ::smtp::syntax::Reply;
+    use ::util::test::TempDir;
+    use super::super::config::Config;
+    use super::*;
+
+    /// A transport that plays back a fixed outcome per domain.
+    struct Fake {
+        calls: Vec<(Vec<u8>, Vec<usize>)>,
+    }
+
+    impl DeliveryTransport for Fake {
+        fn deliver(&mut self, domain: &[u8], _envelope: &Envelope,
+                   recipients: &[usize], _message: Message)
+                   -> Vec<Delivery> {
+            self.calls.push((domain.into(), recipients.into()));
+            recipients.iter().map(|_| match domain {
+                b"ok.example" => {
+                    Delivery::Delivered(Reply::new(250, None, b"Ok".to_vec()))
+                }
+                b"bad.example" => {
+                    Delivery::Failed(Reply::new(550, None, b"No".to_vec()))
+                }
+                _ => Delivery::Deferred(Reply::new(451, None,
+                                                   b"Later".to_vec()))
+            }).collect()
+        }
+    }
+
+    /// A transport that delivers and then loses the message.
+    struct Lossy(PathBuf);
+
+    impl DeliveryTransport for Lossy {
+        fn deliver(&mut self, _domain: &[u8], _envelope: &Envelope,
+                   recipients: &[usize], _message: Message)
+                   -> Vec<Delivery> {
+            for entry in fs::read_dir(&self.0).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().map_or(false, |ext| ext == "msg") {
+                    fs::remove_file(path).unwrap()
+                }
+            }
+            recipients.iter().map(|_| {
+                Delivery::Delivered(Reply::new(250, None, b"Ok".to_vec()))
+            }).collect()
+        }
+    }
+
+    /// Creates a scheduler with its queue in a new temporary directory.
+    ///
+    /// The directory is removed once the returned value is dropped.
+    fn scheduler(name: &str) -> (Scheduler<Fake>, TempDir) {
+        let dir = TempDir::new(&format!("relay-{}", name));
+        let mut config = Config::new(b"mx.example.com".to_vec());
+        config.set_retry_base(Duration::from_secs(100));
+        config.set_retry_max(Duration::from_secs(1000));
+        config.set_lifetime(Duration::from_secs(10000));
+        (Scheduler::new(Queue::open(&dir).unwrap(),
+                        Fake { calls: Vec::new() }, config),
+         dir)
+    }
+
+    fn enqueue(queue: &Queue, recipients: &[&[u8]]) {
+        let mut envelope = Envelope::new(b"me@example.com".to_vec());
+        envelope.arrival = 1000;
+        for rcpt in recipients {
+            envelope.recipients.push(Recipient::new(rcpt.to_vec()));
+        }
+        let mut spool = queue.create().unwrap();
+        spool.write_all(b"Subject: Test\r\n\r\nHello\r\n").unwrap();
+        spool.commit(&envelope).unwrap();
+    }
+
+    /// Returns the envelopes of all notifications in the queue.
+    fn notifications(queue: &Queue) -> Vec<Envelope> {
+        queue.list().unwrap().iter()
+             .map(|id| queue.envelope(id).unwrap())
+             .filter(|envelope| envelope.reverse_path.is_empty())
+             .collect()
+    }
+
+    #[test]
+    fn groups_by_domain() {
+        let (mut sched, _dir) = scheduler("groups");
+        enqueue(sched.queue(), &[b"a@ok.example", b"b@bad.example",
+                                 b"c@OK.example"]);
+        sched.run_once(1000).unwrap();
+        assert_eq!(sched.transport().calls,
+                   vec![(b"ok.example".to_vec(), vec![0, 2]),
+                        (b"bad.example".to_vec(), vec![1])]);
+
+        // Only the failure notification is left.
+        assert_eq!(sched.queue().list().unwrap().len(), 1);
+        let dsn = notifications(sched.queue()).pop().unwrap();
+        assert_eq!(dsn.recipients[0].path, b"me@example.com".to_vec());
+    }
+
+    #[test]
+    fn retry_and_expire() {
+        let (mut sched, _dir) = scheduler("retry");
+        enqueue(sched.queue(), &[b"a@ok.example", b"b@slow.example"]);
+        sched.run_once(1000).unwrap();
+        let id = sched.queue().list().unwrap().pop().unwrap();
+        let envelope = sched.queue().envelope(&id).unwrap();
+        assert_eq!(envelope.recipients[0].state, DeliveryState::Delivered);
+        assert_eq!(envelope.recipients[1].state,
+                   DeliveryState::Deferred { attempts: 1, retry: 1100 });
+
+        // Not due yet: nothing happens.
+        sched.run_once(1050).unwrap();
+        assert_eq!(sched.transport().calls.len(), 2);
+
+        // Due: only the deferred recipient is tried, backoff doubles.
+        sched.run_once(1100).unwrap();
+        assert_eq!(sched.transport().calls[2],
+                   (b"slow.example".to_vec(), vec![1]));
+        let envelope = sched.queue().envelope(&id).unwrap();
+        assert_eq!(envelope.recipients[1].state,
+                   DeliveryState::Deferred { attempts: 2, retry: 1300 });
+
+        // Retries never go past expiry ...
+        sched.run_once(10700).unwrap();
+        let envelope = sched.queue().envelope(&id).unwrap();
+        assert_eq!(envelope.recipients[1].state,
+                   DeliveryState::Deferred { attempts: 3, retry: 11000 });
+
+        // ... where the recipient fails and the mail leaves the queue,
+        // leaving only the failure notification.
+        sched.run_once(11000).unwrap();
+        assert_eq!(sched.queue().list().unwrap().len(), 1);
+        assert_eq!(notifications(sched.queue()).len(), 1);
+    }
+
+    #[test]
+    fn flush() {
+        let (mut sched, _dir) = scheduler("flush");
+        enqueue(sched.queue(), &[b"a@slow.example", b"b@other.example"]);
+        sched.run_once(1000).unwrap();
+        sched.flush(1001, Some(b"SLOW.example")).unwrap();
+        assert_eq!(sched.transport().calls.len(), 3);
+        assert_eq!(sched.transport().calls[2],
+                   (b"slow.example".to_vec(), vec![0]));
+    }
+
+    #[test]
+    fn delay_notice() {
+        let (mut sched, _dir) = scheduler("delay");
+        sched.config.set_delay_notice(Duration::from_secs(50));
+        enqueue(sched.queue(), &[b"a@slow.example"]);
+        sched.run_once(1000).unwrap();
+        assert!(notifications(sched.queue()).is_empty());
+        sched.run_once(1100).unwrap();
+        assert_eq!(notifications(sched.queue()).len(), 1);
+
+        // Only one notice per recipient.
+        sched.run_once(1300).unwrap();
+        assert_eq!(notifications(sched.queue()).len(), 1);
+    }
+
+    #[test]
+    fn lost_message() {
+        let dir = TempDir::new("relay-lost");
+        let queue = Queue::open(&dir).unwrap();
+        enqueue(&queue, &[b"a@one.example", b"b@two.example"]);
+        let config = Config::new(b"mx.example.com".to_vec());
+        let mut sched = Scheduler::new(queue, Lossy(dir.join("queue")),
+                                       config);
+        sched.run_once(1000).unwrap();
+
+        // The delivery to a is kept, b is still waiting.
+        let id = sched.queue().list().unwrap().pop().unwrap();
+        let envelope = sched.queue().envelope(&id).unwrap();
+        assert_eq!(envelope.recipients[0].state, DeliveryState::Delivered);
+        assert!(!envelope.recipients[1].state.is_final());
+    }
+}
@@ -0,0 +1,628 @@
+//! Delivery via SMTP.
+//!
+//! This drives the sans-IO client session over a blocking socket. Since
+//! the scheduler works through the queue one mail at a time, there is
+//! little to be gained from an event loop here.
+
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::Instant;
+use openssl::ssl::{MaybeSslStream, SslStream};
+use ::smtp::client::{self, Action, Error, Handler, Outcome, Report,
+                     Session};
+use ::smtp::fs::queue::{Envelope, Message};
+use ::smtp::server::buf::{RecvBuf, SendBuf};
+use ::smtp::syntax::{BodyValue, Reply};
+use super::scheduler::{Delivery, DeliveryTransport};
+
+
+//------------ Router --------------------------------------------------------
+
+/// A type that knows which servers to deliver mail for a domain to.
+pub trait Router {
+    /// Returns the addresses of the servers for *domain*.
+    ///
+    /// The addresses are tried in the order given. An empty list means
+    /// that there is no server for the domain at all and delivery fails
+    /// for good. An error means that this can’t be known right now and
+    /// delivery is tried again later.
+    fn route(&mut self, domain: &[u8]) -> io::Result<Vec<SocketAddr>>;
+}
+
+
+//------------ SmartHost -----------------------------------------------------
+
+/// A router that sends everything to the same servers.
+pub struct SmartHost(Vec<SocketAddr>);
+
+impl SmartHost {
+    pub fn new(addrs: Vec<SocketAddr>) -> Self {
+        SmartHost(addrs)
+    }
+}
+
+impl Router for SmartHost {
+    fn route(&mut self, _domain: &[u8]) -> io::Result<Vec<SocketAddr>> {
+        Ok(self.0.clone())
+    }
+}
+
+
+//------------ DirectRouter --------------------------------------------------
+
+/// A router that connects to the address records of the domain itself.
+///
+/// This ignores MX records and is therefore only useful for testing and
+/// closed environments.
+pub struct DirectRouter {
+    port: u16,
+}
+
+impl DirectRouter {
+    pub fn new(port: u16) -> Self {
+        DirectRouter { port: port }
+    }
+}
+
+impl Router for DirectRouter {
+    fn route(&mut self, domain: &[u8]) -> io::Result<Vec<SocketAddr>> {
+        let domain = match ::std::str::from_utf8(domain) {
+            Ok(domain) => domain,
+            Err(_) => return Ok(Vec::new())
+        };
+        Ok(try!((domain, self.port).to_socket_addrs()).collect())
+    }
+}
+
+
+//------------ SmtpTransport -------------------------------------------------
+
+/// A delivery transport that speaks SMTP to the servers of a domain.
+///
+/// The servers given by the router are tried in turn until one of them
+/// accepts a mail transaction. Once it has, the outcome of the
+/// transaction is final for this attempt.
+pub struct SmtpTransport<R: Router> {
+    router: R,
+    config: Rc<client::Config>,
+}
+
+impl<R: Router> SmtpTransport<R> {
+    pub fn new(router: R, config: client::Config) -> Self {
+        SmtpTransport { router: router, config: Rc::new(config) }
+    }
+
+    pub fn router(&self) -> &R {
+        &self.router
+    }
+
+    pub fn config(&self) -> &client::Config {
+        &self.config
+    }
+}
+
+impl<R: Router> DeliveryTransport for SmtpTransport<R> {
+    fn deliver(&mut self, domain: &[u8], envelope: &Envelope,
+               recipients: &[usize], message: Message) -> Vec<Delivery> {
+        // The client doesn’t do BDAT, so we can’t relay binary bodies.
+        if envelope.body == Some(BodyValue::BinaryMime) {
+            return all(recipients, Delivery::Failed(
+                Reply::new(554, Some((5, 6, 3)),
+                           b"Cannot relay binary message".to_vec())))
+        }
+        let addrs = match self.router.route(domain) {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                return all(recipients, Delivery::Deferred(
+                    Reply::new(451, Some((4, 4, 3)),
+                               format!("Routing failed: {}", err)
+                                   .into_bytes())))
+            }
+        };
+        if addrs.is_empty() {
+            return all(recipients, Delivery::Failed(
+                Reply::new(550, Some((5, 1, 2)),
+                           b"No server for domain".to_vec())))
+        }
+
+        let mut single = Single::new(mail(envelope, recipients, message));
+        for addr in addrs {
+            single = self.session(&addr, single);
+            if single.mail.is_none() {
+                break
+            }
+        }
+        single.deliveries(recipients.len())
+    }
+}
+
+impl<R: Router> SmtpTransport<R> {
+    /// Runs a session with the server at *addr*.
+    fn session(&self, addr: &SocketAddr, mut single: Single) -> Single {
+        let sock = match TcpStream::connect(addr) {
+            Ok(sock) => sock,
+            Err(err) => {
+                info!("Relay: cannot connect to {}: {}", addr, err);
+                single.failed(Error::Io(err));
+                return single
+            }
+        };
+        let timeout = Some(self.config.timeout());
+        if let Err(err) = sock.set_read_timeout(timeout)
+                              .and_then(|_| sock.set_write_timeout(timeout)) {
+            single.failed(Error::Io(err));
+            return single
+        }
+        single.error = None;
+        let (session, action) = Session::new(single, self.config.clone());
+        let session = drive(session, action, MaybeSslStream::Normal(sock));
+        let dsn = session.extensions().dsn;
+        let mut single = session.into_handler();
+        single.dsn = dsn;
+        single
+    }
+}
+
+
+//------------ Single --------------------------------------------------------
+
+/// The client handler for delivering a single mail.
+struct Single {
+    /// The mail until the session asks for it.
+    mail: Option<client::Mail>,
+
+    /// The outcome of the transaction once there is one.
+    report: Option<Report>,
+
+    /// Why the last session failed.
+    error: Option<Error>,
+
+    /// Did the last server support DSN?
+    dsn: bool,
+}
+
+impl Single {
+    fn new(mail: client::Mail) -> Self {
+        Single { mail: Some(mail), report: None, error: None, dsn: false }
+    }
+
+    fn deliveries(self, count: usize) -> Vec<Delivery> {
+        let Single { report, error, dsn, .. } = self;
+        let report = match report {
+            Some(report) => report,
+            None => {
+                let reply = error_reply(error.as_ref());
+                return (0..count).map(|_| Delivery::Deferred(reply.clone()))
+                                 .collect()
+            }
+        };
+        (0..count).map(|idx| match report.outcome(idx) {
+            Outcome::Delivered(reply) if dsn => {
+                Delivery::Delivered(reply.clone())
+            }
+            Outcome::Delivered(reply) => Delivery::Relayed(reply.clone()),
+            Outcome::Failed(reply) => Delivery::Failed(reply.clone()),
+            Outcome::Deferred(Some(reply)) => {
+                Delivery::Deferred(reply.clone())
+            }
+            Outcome::Deferred(None) => {
+                Delivery::Deferred(error_reply(error.as_ref()))
+            }
+        }).collect()
+    }
+}
+
+impl Handler for Single {
+    fn next_mail(&mut self) -> Option<client::Mail> {
+        self.mail.take()
+    }
+
+    fn done(&mut self, _mail: client::Mail, report: Report) {
+        self.report = Some(report)
+    }
+
+    fn failed(&mut self, error: Error) {
+        info!("Relay: session failed: {:?}", error);
+        self.error = Some(error)
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+type Stream = MaybeSslStream<TcpStream>;
+
+/// Runs a client session over a blocking stream until it is done.
+///
+/// The stream’s own timeouts make reads and writes return eventually, but
+/// they may also return without anything having happened. The session
+/// therefore only times out once there hasn’t been any progress for the
+/// timeout from the config.
+fn drive<H: Handler>(mut session: Session<H>, mut action: Action,
+                     mut stream: Stream) -> Session<H> {
+    let timeout = session.config().timeout();
+    let mut deadline = Instant::now() + timeout;
+    let mut recv = RecvBuf::new();
+    let mut send = SendBuf::new();
+    loop {
+        while !send.is_empty() {
+            let left = send.as_slice().len();
+            match send.try_write(&mut stream) {
+                Ok(_) => { }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => { }
+                Err(err) => return session.abort(Error::Io(err))
+            }
+            if send.is_empty() || send.as_slice().len() < left {
+                deadline = Instant::now() + timeout;
+            }
+            else if Instant::now() >= deadline {
+                return session.abort(Error::Timeout)
+            }
+        }
+        let (next, next_action) = match action {
+            Action::Read => {
+                match recv.try_read(&mut stream) {
+                    Ok(Some(0)) => return session.abort(Error::Closed),
+                    Ok(Some(_)) => {
+                        deadline = Instant::now() + timeout;
+                        session.recv(&mut recv, &mut send)
+                    }
+                    Ok(None) => {
+                        if Instant::now() >= deadline {
+                            return session.abort(Error::Timeout)
+                        }
+                        (session, Action::Read)
+                    }
+                    Err(ref err)
+                            if err.kind() == io::ErrorKind::Interrupted => {
+                        (session, Action::Read)
+                    }
+                    Err(err) => return session.abort(Error::Io(err))
+                }
+            }
+            Action::Feed => session.feed(&mut send),
+            Action::StartTls => {
+                let sock = match stream {
+                    MaybeSslStream::Normal(sock) => sock,
+                    _ => return session.abort(Error::Tls)
+                };
+                match SslStream::connect(session.config().ssl_context(),
+                                         sock) {
+                    Ok(tls) => stream = MaybeSslStream::Ssl(tls),
+                    Err(err) => {
+                        info!("Relay: TLS handshake failed: {}", err);
+                        return session.abort(Error::Tls)
+                    }
+                }
+                recv = RecvBuf::new();
+                session.tls_ready(&mut send)
+            }
+            Action::Close => return session
+        };
+        session = next;
+        action = next_action;
+    }
+}
+
+/// Creates the client mail for some recipients of an envelope.
+fn mail(envelope: &Envelope, recipients: &[usize], message: Message)
+        -> client::Mail {
+    let rcpts = recipients.iter().map(|idx| {
+        let rcpt = &envelope.recipients[*idx];
+        client::Recipient { path: rcpt.path.clone(), notify: rcpt.notify,
+                            orcpt: rcpt.orcpt.clone() }
+    }).collect();
+    let mut res = client::Mail::new(envelope.reverse_path.clone(), rcpts,
+                                    Box::new(BufReader::new(message)));
+    res.eightbitmime = envelope.body == Some(BodyValue::EightBitMime);
+    res.size = envelope.size;
+    res.ret = envelope.ret;
+    res.envid = envelope.envid.clone();
+    res
+}
+
+/// Returns the same delivery for all recipients.
+fn all(recipients: &[usize], delivery: Delivery) -> Vec<Delivery> {
+    recipients.iter().map(|_| delivery.clone()).collect()
+}
+
+/// Makes up a reply for a session that failed before the transaction.
+fn error_reply(error: Option<&Error>) -> Reply {
+    match error {
+        Some(&Error::Greeting(ref reply)) | Some(&Error::Hello(ref reply))
+                | Some(&Error::StartTls(ref reply)) => {
+            // The server turned us away. Even if it did so permanently,
+            // things may look different later.
+            Reply::new(451, Some((4, 4, 0)), reply.text.clone())
+        }
+        Some(&Error::TlsRequired) | Some(&Error::Tls) => {
+            Reply::new(451, Some((4, 7, 5)), b"TLS not available".to_vec())
+        }
+        Some(&Error::Timeout) => {
+            Reply::new(451, Some((4, 4, 2)), b"Connection timed out".to_vec())
+        }
+        Some(&Error::Source(_)) => {
+            Reply::new(451, Some((4, 3, 0)), b"Cannot read message".to_vec())
+        }
+        Some(&Error::Io(_)) | Some(&Error::Closed)
+                | Some(&Error::Syntax) => {
+            Reply::new(451, Some((4, 4, 2)), b"Connection broken".to_vec())
+        }
+        None => {
+            Reply::new(451, Some((4, 4, 1)), b"No answer from host".to_vec())
+        }
+    }
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use netmachines::sockets::openssl::StartTlsListener;
+    use openssl::crypto::hash::Type;
+    use openssl::ssl::{SslContext, SslMethod};
+    use openssl::x509::X509Generator;
+    use rotor;
+    use ::smtp::client::{self, TlsPolicy};
+    use ::smtp::fs::mta::Mta;
+    use ::smtp::fs::queue::{Envelope, Message, Queue, Recipient};
+    use ::smtp::local::users::{Entry, Users};
+    use ::smtp::server;
+    use ::util::test::TempDir;
+    use super::super::config::Config;
+    use super::super::scheduler::{Delivery, DeliveryTransport, Scheduler};
+    use super::*;
+
+    fn client_config(timeout: Duration) -> client::Config {
+        let mut res = client::Config::new(
+            SslContext::new(SslMethod::Tlsv1).unwrap(),
+            b"relay.test".to_vec());
+        res.set_tls_policy(TlsPolicy::Never);
+        res.set_timeout(timeout);
+        res
+    }
+
+    /// Runs a scripted server for a single session.
+    ///
+    /// The server accepts everything except recipient b. It returns all
+    /// the client has sent.
+    fn serve(lsnr: TcpListener) -> thread::JoinHandle<String> {
+        thread::spawn(move || {
+            let (mut sock, _) = lsnr.accept().unwrap();
+            let mut reader = BufReader::new(sock.try_clone().unwrap());
+            let mut transcript = Vec::new();
+            let mut data = false;
+            sock.write_all(b"220 mx.test\r\n").unwrap();
+            loop {
+                let mut line = Vec::new();
+                if reader.read_until(b'\n', &mut line).unwrap() == 0 {
+                    break
+                }
+                transcript.extend_from_slice(&line);
+                let reply: &[u8] = if data {
+                    if line != b".\r\n" {
+                        continue
+                    }
+                    data = false;
+                    b"250 Queued\r\n"
+                }
+                else if line.starts_with(b"EHLO ") {
+                    b"250-mx.test\r\n250-PIPELINING\r\n250 DSN\r\n"
+                }
+                else if line.starts_with(b"RCPT TO:<b@") {
+                    b"550 No such user\r\n"
+                }
+                else if line == b"DATA\r\n" {
+                    data = true;
+                    b"354 Go ahead\r\n"
+                }
+                else if line == b"QUIT\r\n" {
+                    sock.write_all(b"221 Bye\r\n").unwrap();
+                    break
+                }
+                else {
+                    b"250 Ok\r\n"
+                };
+                sock.write_all(reply).unwrap();
+            }
+            String::from_utf8(transcript).unwrap()
+        })
+    }
+
+    /// Creates an SSL context with a freshly generated certificate.
+    fn server_context() -> SslContext {
+        let (cert, pkey) = X509Generator::new()
+                               .set_bitlength(2048)
+                               .set_valid_period(1)
+                               .add_name("CN".to_string(),
+                                         "mx.test".to_string())
+                               .set_sign_hash(Type::SHA256)
+                               .generate().unwrap();
+        let mut res = SslContext::new(SslMethod::Tlsv1).unwrap();
+        res.set_certificate(&cert).unwrap();
+        res.set_private_key(&pkey).unwrap();
+        res
+    }
+
+    /// Starts a cloudship server that queues into *dir*.
+    ///
+    /// The server is responsible for dst.test which only has user a. It
+    /// only offers STARTTLS if *tls* is true. It runs until the test
+    /// process ends. Returns the server’s address.
+    fn start_server(dir: PathBuf, tls: bool) -> SocketAddr {
+        // Let the system pick a port.
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap()
+                               .local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let context = if tls { server_context() }
+                          else { SslContext::new(SslMethod::Tlsv1).unwrap() };
+            let config = server::Config::new(context, b"mx.test".to_vec(),
+                                             b"Cloudship".to_vec(), 0);
+            let lsnr = StartTlsListener::bind(&addr,
+                                              config.ssl_context().clone())
+                                        .unwrap();
+            let mut users = Users::new();
+            users.add_domain(b"dst.test");
+            users.insert(b"a", Entry::Local);
+            let mut mta = Mta::new(Queue::open(dir).unwrap());
+            mta.set_users(users);
+            let mut l = rotor::Loop::new(&rotor::Config::new()).unwrap();
+            l.add_machine_with(|scope| {
+                server::Server::new(lsnr, config, mta, scope).0
+            }).unwrap();
+            tx.send(()).unwrap();
+            l.run(()).unwrap();
+        });
+        rx.recv().unwrap();
+        addr
+    }
+
+    #[test]
+    fn relay_to_cloudship() {
+        let src = TempDir::new("smtp-src");
+        let dst = TempDir::new("smtp-dst");
+        let addr = start_server(dst.path().to_path_buf(), false);
+
+        let queue = Queue::open(&src).unwrap();
+        let mut envelope = Envelope::new(b"me@example.com".to_vec());
+        envelope.recipients.push(Recipient::new(b"a@dst.test".to_vec()));
+        envelope.recipients.push(Recipient::new(b"b@dst.test".to_vec()));
+        let mut spool = queue.create().unwrap();
+        spool.write_all(b"Subject: Test\r\n\r\n.Hello\r\n").unwrap();
+        spool.commit(&envelope).unwrap();
+
+        let transport = SmtpTransport::new(
+            SmartHost::new(vec![addr]),
+            client_config(Duration::from_secs(10)));
+        let mut sched = Scheduler::new(queue, transport,
+                                       Config::new(b"relay.test".to_vec()));
+        sched.run_once(envelope.arrival).unwrap();
+
+        // The server has the mail for a ...
+        let dst = Queue::open(&dst).unwrap();
+        let ids = dst.list().unwrap();
+        assert_eq!(ids.len(), 1);
+        let received = dst.envelope(&ids[0]).unwrap();
+        assert_eq!(received.reverse_path, b"me@example.com");
+        assert_eq!(received.recipients.len(), 1);
+        assert_eq!(received.recipients[0].path, b"a@dst.test");
+        let mut message = String::new();
+        dst.message(&ids[0]).unwrap().read_to_string(&mut message).unwrap();
+        assert!(message.ends_with("\r\nSubject: Test\r\n\r\n.Hello\r\n"));
+
+        // ... and we only have the notification for b.
+        let ids = sched.queue().list().unwrap();
+        assert_eq!(ids.len(), 1);
+        let dsn = sched.queue().envelope(&ids[0]).unwrap();
+        assert!(dsn.reverse_path.is_empty());
+    }
+
+    #[test]
+    fn relay_to_cloudship_over_tls() {
+        let src = TempDir::new("smtp-tls-src");
+        let dst = TempDir::new("smtp-tls-dst");
+        let addr = start_server(dst.path().to_path_buf(), true);
+
+        let queue = Queue::open(&src).unwrap();
+        let mut envelope = Envelope::new(b"me@example.com".to_vec());
+        envelope.recipients.push(Recipient::new(b"a@dst.test".to_vec()));
+        let mut spool = queue.create().unwrap();
+        spool.write_all(b"Subject: Test\r\n\r\nHello\r\n").unwrap();
+        spool.commit(&envelope).unwrap();
+
+        let mut config = client_config(Duration::from_secs(10));
+        config.set_tls_policy(TlsPolicy::Required);
+        let transport = SmtpTransport::new(SmartHost::new(vec![addr]),
+                                           config);
+        let mut sched = Scheduler::new(queue, transport,
+                                       Config::new(b"relay.test".to_vec()));
+        sched.run_once(envelope.arrival).unwrap();
+        assert!(sched.queue().list().unwrap().is_empty());
+
+        // The Received field names the cipher the server actually used.
+        let dst = Queue::open(&dst).unwrap();
+        let ids = dst.list().unwrap();
+        assert_eq!(ids.len(), 1);
+        let mut message = String::new();
+        dst.message(&ids[0]).unwrap().read_to_string(&mut message).unwrap();
+        let received = &message[..message.find("\r\nSubject").unwrap()];
+        assert!(received.contains(" with ESMTPS\r\n\t(using TLSv"));
+        assert!(received.contains(" with cipher "));
+    }
+
+    #[test]
+    fn relay_to_server() {
+        let dir = TempDir::new("smtp");
+        let queue = Queue::open(&dir).unwrap();
+        let mut envelope = Envelope::new(b"me@example.com".to_vec());
+        envelope.recipients.push(Recipient::new(b"a@dst.test".to_vec()));
+        envelope.recipients.push(Recipient::new(b"b@dst.test".to_vec()));
+        let mut spool = queue.create().unwrap();
+        spool.write_all(b"Subject: Test\r\n\r\n.Hello\r\n").unwrap();
+        spool.commit(&envelope).unwrap();
+
+        let lsnr = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = lsnr.local_addr().unwrap();
+        let server = serve(lsnr);
+        let transport = SmtpTransport::new(
+            SmartHost::new(vec![addr]),
+            client_config(Duration::from_secs(10)));
+        let mut sched = Scheduler::new(queue, transport,
+                                       Config::new(b"relay.test".to_vec()));
+        sched.run_once(envelope.arrival).unwrap();
+
+        let transcript = server.join().unwrap();
+        assert!(transcript.starts_with("EHLO relay.test\r\n\
+                                        MAIL FROM:<me@example.com>"));
+        assert!(transcript.contains("\r\nRCPT TO:<a@dst.test>\r\n\
+                                     RCPT TO:<b@dst.test>\r\n"));
+        assert!(transcript.ends_with("\r\nDATA\r\n\
+                                      Subject: Test\r\n\r\n..Hello\r\n\
+                                      .\r\nQUIT\r\n"));
+
+        // The mail is gone, only the notification for b is left.
+        let ids = sched.queue().list().unwrap();
+        assert_eq!(ids.len(), 1);
+        let dsn = sched.queue().envelope(&ids[0]).unwrap();
+        assert!(dsn.reverse_path.is_empty());
+    }
+
+    #[test]
+    fn timeout() {
+        let dir = TempDir::new("smtp-timeout");
+        let path = dir.join("message");
+        File::create(&path).unwrap().write_all(b"Hello\r\n").unwrap();
+        let mut envelope = Envelope::new(b"me@example.com".to_vec());
+        envelope.recipients.push(Recipient::new(b"a@dst.test".to_vec()));
+
+        // A server that never says anything.
+        let lsnr = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = lsnr.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut sock, _) = lsnr.accept().unwrap();
+            let mut buf = Vec::new();
+            let _ = sock.read_to_end(&mut buf);
+        });
+
+        let mut transport = SmtpTransport::new(
+            SmartHost::new(vec![addr]),
+            client_config(Duration::from_millis(200)));
+        let res = transport.deliver(b"dst.test", &envelope, &[0],
+                                    Message::new(Vec::new(),
+                                                 File::open(&path).unwrap()));
+        server.join().unwrap();
+        assert_eq!(res, vec![Delivery::Deferred(
+            Reply::new(451, Some((4, 4, 2)),
+                       b"Connection timed out".to_vec()))]);
+    }
+}
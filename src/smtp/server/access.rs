@@ -0,0 +1,550 @@
+//! Access control for incoming connections.
+//!
+//! Before a connection is handed to the protocol, its peer address is
+//! checked against the `Access` of the server configuration. Addresses
+//! on the allow list are always accepted. Other addresses are denied if
+//! they are on the deny list, have too many connections open already,
+//! or have connected too often recently.
+//!
+//! A denied connection can either be rejected, in which case the server
+//! greets it with a 554 reply and closes it, or refused, in which case
+//! it is closed right away without saying anything.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use nom::IResult;
+use ::util::abnf::ipaddr::{ipv4_addr, ipv6_addr};
+use ::util::text::{invalid, trim};
+
+
+/// After how many checks hosts without connections are forgotten.
+const SWEEP_INTERVAL: usize = 1024;
+
+
+//------------ Access --------------------------------------------------------
+
+#[derive(Clone, Debug)]
+pub struct Access {
+    allow: Vec<Network>,
+    deny: Vec<Network>,
+
+    /// What happens to addresses on the deny list.
+    denied: Denial,
+
+    /// The maximum number of concurrent connections per address.
+    max_connections: Option<usize>,
+
+    /// The maximum number of connections per address within a period.
+    max_rate: Option<(usize, Duration)>,
+
+    /// What happens to addresses exceeding a limit.
+    exceeded: Denial,
+
+    hosts: Rc<RefCell<Hosts>>,
+}
+
+impl Access {
+    /// Creates an access control that accepts everyone.
+    pub fn new() -> Self {
+        Access {
+            allow: Vec::new(), deny: Vec::new(), denied: Denial::Reject,
+            max_connections: None, max_rate: None, exceeded: Denial::Refuse,
+            hosts: Rc::new(RefCell::new(Hosts::new()))
+        }
+    }
+
+    /// Adds a network to the allow list.
+    pub fn allow(&mut self, network: Network) {
+        self.allow.push(network)
+    }
+
+    /// Adds a network to the deny list.
+    pub fn deny(&mut self, network: Network) {
+        self.deny.push(network)
+    }
+
+    /// Adds the networks listed in *source* to the allow list.
+    ///
+    /// See `read_networks()` for the format.
+    pub fn read_allow<R: BufRead>(&mut self, source: R) -> io::Result<()> {
+        self.allow.extend(try!(read_networks(source)));
+        Ok(())
+    }
+
+    /// Adds the networks listed in *source* to the deny list.
+    ///
+    /// See `read_networks()` for the format.
+    pub fn read_deny<R: BufRead>(&mut self, source: R) -> io::Result<()> {
+        self.deny.extend(try!(read_networks(source)));
+        Ok(())
+    }
+
+    /// Sets what happens to addresses on the deny list.
+    ///
+    /// The default is to reject them.
+    pub fn set_denied(&mut self, denial: Denial) {
+        self.denied = denial
+    }
+
+    /// Limits the number of connections open at the same time.
+    ///
+    /// The limit applies to each address separately. By default, there
+    /// is no limit.
+    pub fn set_connection_limit(&mut self, limit: Option<usize>) {
+        self.max_connections = limit
+    }
+
+    /// Limits the number of connections within *period*.
+    ///
+    /// The limit applies to each address separately. By default, there
+    /// is no limit.
+    pub fn set_rate_limit(&mut self, limit: Option<(usize, Duration)>) {
+        self.max_rate = limit
+    }
+
+    /// Sets what happens to addresses exceeding a limit.
+    ///
+    /// The default is to refuse them.
+    pub fn set_exceeded(&mut self, denial: Denial) {
+        self.exceeded = denial
+    }
+
+    /// Decides whether a connection from *addr* is admitted.
+    pub fn check(&self, addr: IpAddr) -> Admission {
+        let addr = unmap(addr);
+        if self.allow.iter().any(|net| net.contains(addr)) {
+            return Admission::Accept(Ticket::untracked())
+        }
+        if self.deny.iter().any(|net| net.contains(addr)) {
+            info!("SMTP access: {} is on the deny list", addr);
+            return self.denied.into()
+        }
+        if self.max_connections.is_none() && self.max_rate.is_none() {
+            return Admission::Accept(Ticket::untracked())
+        }
+
+        let now = Instant::now();
+        let mut hosts = self.hosts.borrow_mut();
+        hosts.sweep(now, self.max_rate.map(|rate| rate.1));
+        let exceeded = {
+            let host = hosts.map.entry(addr).or_insert_with(Host::new);
+            let mut exceeded = false;
+            if let Some(max) = self.max_connections {
+                if host.connections >= max {
+                    info!("SMTP access: {} has too many connections", addr);
+                    exceeded = true;
+                }
+            }
+            if let Some((max, period)) = self.max_rate {
+                host.expire(now, period);
+                if host.recent.len() >= max {
+                    info!("SMTP access: {} connects too often", addr);
+                    exceeded = true;
+                }
+                // Attempts count even when denied so that clients have
+                // to actually back off.
+                host.recent.push_back(now);
+            }
+            if !exceeded {
+                host.connections += 1;
+            }
+            exceeded
+        };
+        if exceeded {
+            self.exceeded.into()
+        }
+        else {
+            Admission::Accept(Ticket { hosts: Some((self.hosts.clone(),
+                                                    addr)) })
+        }
+    }
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::new()
+    }
+}
+
+
+//------------ Network -------------------------------------------------------
+
+/// An IPv4 or IPv6 network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// Creates a network from an address and a prefix length.
+    ///
+    /// Returns `None` if the prefix is too long for the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > max { None }
+        else { Some(Network { addr: addr, prefix: prefix }) }
+    }
+
+    /// Parses a network in CIDR notation.
+    ///
+    /// If the prefix length is missing, the network is the single
+    /// address.
+    pub fn parse(input: &[u8]) -> Option<Self> {
+        let (addr, prefix) = match input.iter().position(|ch| *ch == b'/') {
+            Some(pos) => (&input[..pos], Some(&input[pos + 1..])),
+            None => (input, None)
+        };
+
+        // The address parsers want to see what follows the address.
+        let mut addr = addr.to_vec();
+        addr.push(b'/');
+        let addr = match ipv4_addr(&addr) {
+            IResult::Done(b"/", addr) => IpAddr::V4(addr),
+            _ => match ipv6_addr(&addr) {
+                IResult::Done(b"/", addr) => IpAddr::V6(addr),
+                _ => return None
+            }
+        };
+        let prefix = match prefix {
+            Some(prefix) => {
+                let digits = prefix.iter().all(|ch| {
+                    *ch >= b'0' && *ch <= b'9'
+                });
+                if prefix.is_empty() || prefix.len() > 3 || !digits {
+                    return None
+                }
+                prefix.iter().fold(0u16, |res, ch| {
+                    res * 10 + (*ch - b'0') as u16
+                })
+            }
+            None => match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128
+            }
+        };
+        if prefix > 128 {
+            return None
+        }
+        Network::new(addr, prefix as u8)
+    }
+
+    /// Returns the address of the network.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns whether *addr* is part of the network.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, unmap(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&v6_octets(net), &v6_octets(addr),
+                               self.prefix)
+            }
+            _ => false
+        }
+    }
+}
+
+
+//------------ Denial --------------------------------------------------------
+
+/// What happens to a connection that is denied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Denial {
+    /// Greet with a 554 reply and close.
+    Reject,
+
+    /// Close without a word.
+    Refuse,
+}
+
+
+//------------ Admission -----------------------------------------------------
+
+/// The result of checking a connection.
+#[derive(Debug)]
+pub enum Admission {
+    /// Go ahead and keep the ticket for as long as the connection lasts.
+    Accept(Ticket),
+
+    /// Greet with a 554 reply and close.
+    Reject,
+
+    /// Close without a word.
+    Refuse,
+}
+
+impl From<Denial> for Admission {
+    fn from(denial: Denial) -> Self {
+        match denial {
+            Denial::Reject => Admission::Reject,
+            Denial::Refuse => Admission::Refuse,
+        }
+    }
+}
+
+
+//------------ Ticket --------------------------------------------------------
+
+/// Proof of an admitted connection.
+///
+/// The connection counts towards the connection limit of its address
+/// until the ticket is dropped.
+#[derive(Debug)]
+pub struct Ticket {
+    hosts: Option<(Rc<RefCell<Hosts>>, IpAddr)>,
+}
+
+impl Ticket {
+    fn untracked() -> Self {
+        Ticket { hosts: None }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if let Some((ref hosts, addr)) = self.hosts {
+            if let Some(host) = hosts.borrow_mut().map.get_mut(&addr) {
+                host.connections -= 1;
+            }
+        }
+    }
+}
+
+
+//------------ Hosts and Host ------------------------------------------------
+
+/// The connection state for all addresses seen recently.
+#[derive(Debug)]
+struct Hosts {
+    map: HashMap<IpAddr, Host>,
+
+    /// Number of checks since the last sweep.
+    checks: usize,
+}
+
+impl Hosts {
+    fn new() -> Self {
+        Hosts { map: HashMap::new(), checks: 0 }
+    }
+
+    /// Forgets about idle hosts every once in a while.
+    fn sweep(&mut self, now: Instant, period: Option<Duration>) {
+        self.checks += 1;
+        if self.checks < SWEEP_INTERVAL {
+            return
+        }
+        self.checks = 0;
+        let idle: Vec<IpAddr> = self.map.iter_mut().filter_map(|(addr, host)| {
+            if let Some(period) = period {
+                host.expire(now, period)
+            }
+            if host.connections == 0 && host.recent.is_empty() {
+                Some(*addr)
+            }
+            else { None }
+        }).collect();
+        for addr in idle {
+            self.map.remove(&addr);
+        }
+    }
+}
+
+/// The connection state of a single address.
+#[derive(Debug)]
+struct Host {
+    /// Number of open connections.
+    connections: usize,
+
+    /// When the address connected within the rate limit period.
+    recent: VecDeque<Instant>,
+}
+
+impl Host {
+    fn new() -> Self {
+        Host { connections: 0, recent: VecDeque::new() }
+    }
+
+    /// Drops connection times older than *period*.
+    fn expire(&mut self, now: Instant, period: Duration) {
+        while let Some(&time) = self.recent.front() {
+            if now.duration_since(time) < period {
+                break
+            }
+            self.recent.pop_front();
+        }
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Reads a list of networks.
+///
+/// There is one network in CIDR notation per line. Empty lines and lines
+/// starting with `#` are ignored.
+pub fn read_networks<R: BufRead>(source: R) -> io::Result<Vec<Network>> {
+    let mut res = Vec::new();
+    for line in source.split(b'\n') {
+        let line = try!(line);
+        let line = trim(&line);
+        if line.is_empty() || line.starts_with(b"#") {
+            continue
+        }
+        match Network::parse(line) {
+            Some(net) => res.push(net),
+            None => {
+                return Err(invalid("network",
+                                   &String::from_utf8_lossy(line)))
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Turns IPv4-mapped IPv6 addresses into IPv4 addresses.
+pub fn unmap(addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = addr {
+        let seg = v6.segments();
+        if seg[..5] == [0, 0, 0, 0, 0] && seg[5] == 0xffff {
+            return IpAddr::V4(Ipv4Addr::new((seg[6] >> 8) as u8,
+                                            seg[6] as u8,
+                                            (seg[7] >> 8) as u8,
+                                            seg[7] as u8))
+        }
+    }
+    addr
+}
+
+fn v6_octets(addr: Ipv6Addr) -> [u8; 16] {
+    let mut res = [0u8; 16];
+    for (i, seg) in addr.segments().iter().enumerate() {
+        res[i * 2] = (seg >> 8) as u8;
+        res[i * 2 + 1] = *seg as u8;
+    }
+    res
+}
+
+/// Returns whether the first *prefix* bits of *left* and *right* match.
+fn prefix_matches(left: &[u8], right: &[u8], prefix: u8) -> bool {
+    let prefix = prefix as usize;
+    let (bytes, bits) = (prefix / 8, prefix % 8);
+    if left[..bytes] != right[..bytes] {
+        return false
+    }
+    if bits == 0 {
+        return true
+    }
+    let mask = 0xffu8 << (8 - bits);
+    left[bytes] & mask == right[bytes] & mask
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn net(s: &str) -> Network {
+        Network::parse(s.as_bytes()).unwrap()
+    }
+
+    fn is_accept(admission: &Admission) -> bool {
+        if let Admission::Accept(_) = *admission { true } else { false }
+    }
+
+    #[test]
+    fn network() {
+        assert!(net("192.0.2.0/24").contains(ip("192.0.2.17")));
+        assert!(!net("192.0.2.0/24").contains(ip("192.0.3.17")));
+        assert!(net("192.0.2.128/25").contains(ip("192.0.2.200")));
+        assert!(!net("192.0.2.128/25").contains(ip("192.0.2.100")));
+        assert!(net("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!net("192.0.2.1").contains(ip("192.0.2.2")));
+        assert!(net("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(net("192.0.2.0/24").contains(ip("::ffff:192.0.2.5")));
+        assert!(!net("192.0.2.0/24").contains(ip("2001:db8::1")));
+        assert!(net("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!net("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(net("::1").contains(ip("::1")));
+
+        assert_eq!(Network::parse(b"192.0.2.0/33"), None);
+        assert_eq!(Network::parse(b"2001:db8::/129"), None);
+        assert_eq!(Network::parse(b"192.0.2.0/"), None);
+        assert_eq!(Network::parse(b"192.0.2/24"), None);
+        assert_eq!(Network::parse(b"example.com"), None);
+    }
+
+    #[test]
+    fn lists() {
+        let mut access = Access::new();
+        access.read_deny(&b"# Bad neighbourhood\n\
+                            192.0.2.0/24\n\
+                            2001:db8::/32\n"[..]).unwrap();
+        access.allow(net("192.0.2.25"));
+        assert!(is_accept(&access.check(ip("192.0.2.25"))));
+        assert!(is_accept(&access.check(ip("198.51.100.1"))));
+        match access.check(ip("192.0.2.26")) {
+            Admission::Reject => { }
+            res => panic!("{:?}", res)
+        }
+        access.set_denied(Denial::Refuse);
+        match access.check(ip("2001:db8::25")) {
+            Admission::Refuse => { }
+            res => panic!("{:?}", res)
+        }
+        assert!(access.read_deny(&b"192.0.2.300\n"[..]).is_err());
+    }
+
+    #[test]
+    fn connection_limit() {
+        let mut access = Access::new();
+        access.set_connection_limit(Some(2));
+        let first = access.check(ip("192.0.2.1"));
+        let second = access.check(ip("192.0.2.1"));
+        assert!(is_accept(&first));
+        assert!(is_accept(&second));
+        match access.check(ip("192.0.2.1")) {
+            Admission::Refuse => { }
+            res => panic!("{:?}", res)
+        }
+        assert!(is_accept(&access.check(ip("192.0.2.2"))));
+        drop(first);
+        assert!(is_accept(&access.check(ip("192.0.2.1"))));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut access = Access::new();
+        access.set_rate_limit(Some((2, Duration::from_secs(3600))));
+        access.set_exceeded(Denial::Reject);
+        assert!(is_accept(&access.check(ip("192.0.2.1"))));
+        assert!(is_accept(&access.check(ip("192.0.2.1"))));
+        match access.check(ip("192.0.2.1")) {
+            Admission::Reject => { }
+            res => panic!("{:?}", res)
+        }
+        assert!(is_accept(&access.check(ip("192.0.2.2"))));
+
+        access.set_rate_limit(Some((2, Duration::from_secs(0))));
+        assert!(is_accept(&access.check(ip("192.0.2.1"))));
+    }
+}
@@ -4,7 +4,7 @@ use std::ptr;
 use bytes::buf::Buf;
 use nom::IResult;
 use rotor::mio::{TryRead, TryWrite};
-use ::smtp::syntax::Command;
+use ::smtp::syntax::{self, Command};
 use ::util::scribe::Scribe;
 use super::reply::Reply;
 
@@ -31,6 +31,11 @@ impl RecvBuf {
         transport.try_read_buf(&mut self.inner)
     }
 
+    /// Appends *data* as if it had been received.
+    pub fn push(&mut self, data: &[u8]) {
+        self.inner.extend_from_slice(data)
+    }
+
     pub fn advance(&mut self, len: usize) {
         self.rpos = min(self.inner.len(), self.rpos + len);
         if self.is_empty() {
@@ -55,34 +60,72 @@ impl RecvBuf {
         self.rpos = 0;
     }
 
-    /// Finds the index of `CRLF "." CRLF` in the buffer.
+    /// Takes a line off the beginning of the buffer.
     ///
-    pub fn find_data_end(&self) -> Option<usize> {
-        let slice = self.as_slice();
-        if slice.len() >= 5 {
-            for i in 0..slice.len() - 4 {
-                if &slice[i..i+5] == b"\r\n.\r\n" {
-                    return Some(i)
-                }
-            }
+    /// Returns the line without the final CRLF or `None` if there isn’t
+    /// a complete line in the buffer yet.
+    ///
+    /// If the line is longer than *limit* octets without the CRLF, or
+    /// will be once it is complete, everything in the buffer is dropped
+    /// and `Err(())` is returned.
+    ///
+    pub fn take_line(&mut self, limit: usize)
+                     -> Result<Option<Vec<u8>>, ()> {
+        let res = {
+            let slice = self.as_slice();
+            slice.windows(2).position(|w| w[0] == b'\r' && w[1] == b'\n')
+                 .map(|idx| (slice[..idx].to_vec(), idx + 2))
+        };
+        match res {
+            Some((ref line, _)) if line.len() > limit => {}
+            Some((line, len)) => { self.advance(len); return Ok(Some(line)) }
+            // The CR may still be missing its LF.
+            None if self.len() <= limit + 1 => return Ok(None),
+            None => {}
         }
-        None
+        self.clear();
+        Err(())
     }
 
+    /// Parses a command off the beginning of the buffer.
+    ///
+    /// The closure *f* receives the command or `None` if there isn’t a
+    /// complete command yet. Its second argument is whether there is more
+    /// data in the buffer after the command.
+    ///
     pub fn parse_command<F, T>(&mut self, f: F) -> Result<T, ()>
-                         where F: FnOnce(Option<Command>) -> T {
+                         where F: FnOnce(Option<Command>, bool) -> T {
         let len = self.len();
         let (advance, res) = match Command::parse(self.as_slice()) {
             IResult::Done(rest, cmd) => {
-                (len - rest.len(), Ok(f(Some(cmd))))
+                (len - rest.len(), Ok(f(Some(cmd), !rest.is_empty())))
             }
             IResult::Error(err) => {
                 error!("SMTP server: parse error: {:?}", err);
                 (0, Err(()))
             }
             IResult::Incomplete(..) => {
-                (0, Ok(f(None)))
+                (0, Ok(f(None, false)))
+            }
+        };
+        self.advance(advance);
+        res
+    }
+
+    /// Parses a reply off the beginning of the buffer.
+    ///
+    /// Returns `Ok(None)` if there isn’t a complete reply yet and
+    /// `Err(())` if the data isn’t a reply at all.
+    ///
+    pub fn parse_reply(&mut self) -> Result<Option<syntax::Reply>, ()> {
+        let len = self.len();
+        let (advance, res) = match syntax::Reply::parse(self.as_slice()) {
+            IResult::Done(rest, reply) => (len - rest.len(), Ok(Some(reply))),
+            IResult::Error(err) => {
+                error!("SMTP client: parse error: {:?}", err);
+                (0, Err(()))
             }
+            IResult::Incomplete(..) => (0, Ok(None))
         };
         self.advance(advance);
         res
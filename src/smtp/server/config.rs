@@ -1,19 +1,31 @@
 //! Configuration for SMTP servers.
 
+use std::time::Duration;
 use openssl::ssl::SslContext;
+use super::access::Access;
+use super::sasl::{self, SaslMechanism};
 
 pub struct Config {
     context: SslContext,
     hostname: Vec<u8>,
     systemname: Vec<u8>,
     size_limit: u64,
+    sasl_mechanisms: Vec<Box<SaslMechanism>>,
+    capabilities: Capabilities,
+    timeouts: Timeouts,
+    access: Access,
 }
 
 impl Config {
     pub fn new(context: SslContext, hostname: Vec<u8>, systemname: Vec<u8>,
                message_size_limit: u64) ->  Self {
         Config { context: context, hostname: hostname,
-                 systemname: systemname, size_limit: message_size_limit }
+                 systemname: systemname, size_limit: message_size_limit,
+                 sasl_mechanisms: vec![Box::new(sasl::Plain),
+                                       Box::new(sasl::Login)],
+                 capabilities: Capabilities::default(),
+                 timeouts: Timeouts::default(),
+                 access: Access::default() }
     }
 
     pub fn ssl_context(&self) -> &SslContext {
@@ -28,8 +40,190 @@ impl Config {
         &self.systemname
     }
 
+    /// Returns the maximum message size in octets.
+    ///
+    /// A value of zero means that there is no limit.
     pub fn message_size_limit(&self) -> u64 {
         self.size_limit
     }
+
+    /// Returns the SASL mechanisms in the order they are advertised.
+    ///
+    /// By default, these are PLAIN and LOGIN.
+    pub fn sasl_mechanisms(&self) -> &[Box<SaslMechanism>] {
+        &self.sasl_mechanisms
+    }
+
+    /// Returns the SASL mechanism with the given name.
+    pub fn sasl_mechanism(&self, name: &[u8]) -> Option<&SaslMechanism> {
+        use std::ascii::AsciiExt;
+
+        self.sasl_mechanisms.iter()
+            .find(|mech| mech.name().eq_ignore_ascii_case(name))
+            .map(|mech| mech.as_ref())
+    }
+
+    /// Adds a SASL mechanism to the end of the list.
+    pub fn add_sasl_mechanism<M>(&mut self, mechanism: M)
+                              where M: SaslMechanism + 'static {
+        self.sasl_mechanisms.push(Box::new(mechanism))
+    }
+
+    /// Removes all SASL mechanisms, effectively disabling AUTH.
+    pub fn clear_sasl_mechanisms(&mut self) {
+        self.sasl_mechanisms.clear()
+    }
+
+    /// Returns the extensions the server offers.
+    ///
+    /// Sessions may trim this further through
+    /// `SessionHandler::capabilities()`.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities
+    }
+
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts
+    }
+
+    /// Returns the access control for incoming connections.
+    ///
+    /// By default, all connections are accepted.
+    pub fn access(&self) -> &Access {
+        &self.access
+    }
+
+    pub fn set_access(&mut self, access: Access) {
+        self.access = access
+    }
 }
 
+
+//------------ Capabilities --------------------------------------------------
+
+/// The SMTP service extensions offered to a client.
+///
+/// The EHLO reply is generated from this set and the session rejects
+/// commands and parameters of extensions that haven’t been advertised.
+/// Commands are answered with 502, MAIL and RCPT parameters with 555.
+/// After HELO or before any greeting, no extensions are available at all.
+///
+/// ENHANCEDSTATUSCODES is always advertised since all replies carry
+/// status codes. EXPN and HELP are part of the base protocol, so their
+/// fields only control whether they are listed.
+///
+/// By default, all extensions are offered.
+///
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    /// EXPN (RFC 5321).
+    pub expn: bool,
+
+    /// HELP (RFC 5321).
+    pub help: bool,
+
+    /// 8BITMIME (RFC 6152) which includes the BODY parameter.
+    pub eightbitmime: bool,
+
+    /// SIZE (RFC 1870).
+    pub size: bool,
+
+    /// PIPELINING (RFC 2920).
+    pub pipelining: bool,
+
+    /// DSN (RFC 3461).
+    pub dsn: bool,
+
+    /// ETRN (RFC 1985).
+    pub etrn: bool,
+
+    /// SMTPUTF8 (RFC 6531).
+    pub smtputf8: bool,
+
+    /// CHUNKING (RFC 3030).
+    pub chunking: bool,
+
+    /// BINARYMIME (RFC 3030). This requires CHUNKING.
+    pub binarymime: bool,
+
+    /// STARTTLS (RFC 3207). Never offered on a secure connection.
+    pub starttls: bool,
+
+    /// AUTH (RFC 4954).
+    ///
+    /// Only offered on a secure connection if there are SASL mechanisms.
+    /// Without TLS, the AUTH command is refused even if a session
+    /// handler tries to offer it.
+    pub auth: bool,
+}
+
+impl Capabilities {
+    /// Returns a set with no extensions at all.
+    pub fn none() -> Self {
+        Capabilities {
+            expn: false, help: false, eightbitmime: false, size: false,
+            pipelining: false, dsn: false, etrn: false, smtputf8: false,
+            chunking: false, binarymime: false, starttls: false, auth: false
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            expn: true, help: true, eightbitmime: true, size: true,
+            pipelining: true, dsn: true, etrn: true, smtputf8: true,
+            chunking: true, binarymime: true, starttls: true, auth: true
+        }
+    }
+}
+
+
+//------------ Timeouts ------------------------------------------------------
+
+/// How long the server waits for the client.
+///
+/// When a timeout expires, the server replies with 421 and closes the
+/// connection. The defaults are the values from RFC 5321, section
+/// 4.5.3.2.
+///
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    /// Time for the first command after the greeting.
+    pub greeting: Duration,
+
+    /// Time for any further command.
+    pub command: Duration,
+
+    /// Time between two blocks of message data.
+    ///
+    /// This starts over whenever data arrives, so there is no limit on
+    /// how long the whole message may take as long as it keeps coming.
+    pub data_block: Duration,
+
+    /// Time for the server to come up with a reply.
+    ///
+    /// This is the data termination timeout, the longest a client waits
+    /// for the reply to the final dot and thus for any reply. It applies
+    /// while the session handler takes its time to decide on something.
+    pub data_termination: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            greeting: Duration::from_secs(5 * 60),
+            command: Duration::from_secs(5 * 60),
+            data_block: Duration::from_secs(3 * 60),
+            data_termination: Duration::from_secs(10 * 60),
+        }
+    }
+}
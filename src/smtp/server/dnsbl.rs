@@ -0,0 +1,338 @@
+//! Checking clients against DNS-based blocklists.
+//!
+//! A DNS-based blocklist publishes the addresses it lists as names in a
+//! zone. For IPv4 addresses, the name consists of the four octets of the
+//! address in reverse order followed by the zone, so 192.0.2.1 is listed
+//! in bl.example.org if 1.2.0.192.bl.example.org has an A record. IPv6
+//! addresses are reversed nibble by nibble. The address of the A record,
+//! which is from 127.0.0.0/24, tells why the address is listed.
+//!
+//! A `Dnsbl` holds a list of zones and what to do if a client is listed
+//! in them. Since the lookups block, `check()` must be run on a worker
+//! pool. A protocol can do so from `SessionHandler::start()`, deferring
+//! the start of the session until the result is in.
+//!
+//! The list of zones can be read from a file with one zone per line:
+//!
+//! ```text
+//! # Reject anything listed for spam or exploits.
+//! zen.example.org     reject 2 3 4 9 10 11
+//!
+//! # Only note anything else.
+//! bl.example.net      tag
+//! ```
+//!
+//! The zone is followed by the action, `reject` by default, and the
+//! return codes that count as listed, given as the last octet of the
+//! address. Without any codes, all addresses from 127.0.0.0/24 count.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::sync::Arc;
+use ::net::dns::{self, Resolver};
+use ::util::text::Words;
+use super::access::unmap;
+
+
+//------------ Dnsbl ---------------------------------------------------------
+
+#[derive(Clone)]
+pub struct Dnsbl {
+    lists: Vec<List>,
+    resolver: Arc<Resolver>,
+}
+
+impl Dnsbl {
+    /// Creates a checker without any lists using *resolver*.
+    pub fn new(resolver: Arc<Resolver>) -> Self {
+        Dnsbl { lists: Vec::new(), resolver: resolver }
+    }
+
+    /// Creates a checker with the lists in the file at *path*.
+    pub fn load<P: AsRef<Path>>(resolver: Arc<Resolver>, path: P)
+                                -> io::Result<Self> {
+        let mut res = Dnsbl::new(resolver);
+        try!(res.read(BufReader::new(try!(File::open(path)))));
+        Ok(res)
+    }
+
+    /// Adds the lists given in *source*.
+    pub fn read<R: BufRead>(&mut self, source: R) -> io::Result<()> {
+        for line in source.split(b'\n') {
+            let line = try!(line);
+            let mut words = Words::new(&line);
+            let mut list = match words.next() {
+                Some(zone) => List::new(zone, Action::Reject),
+                None => continue
+            };
+            for word in words {
+                match word {
+                    b"reject" => list.action = Action::Reject,
+                    b"tag" => list.action = Action::Tag,
+                    code => match parse_code(code) {
+                        Some(code) => list.codes.push(code),
+                        None => return Err(invalid(code))
+                    }
+                }
+            }
+            self.add(list);
+        }
+        Ok(())
+    }
+
+    /// Adds a list.
+    pub fn add(&mut self, list: List) {
+        self.lists.push(list)
+    }
+
+    /// Returns whether there are no lists to check.
+    pub fn is_empty(&self) -> bool {
+        self.lists.is_empty()
+    }
+
+    /// Checks *addr* against all lists.
+    ///
+    /// Lists are checked in order. The first list with a reject action
+    /// that has *addr* listed ends the check. Lookups that fail are
+    /// logged and treated as if the address wasn’t listed.
+    ///
+    /// This blocks until all lookups are done.
+    pub fn check(&self, addr: IpAddr) -> Verdict {
+        let mut tags = Vec::new();
+        for list in &self.lists {
+            let listing = match self.lookup(list, addr) {
+                Some(listing) => listing,
+                None => continue
+            };
+            match list.action {
+                Action::Reject => return Verdict::Reject(listing),
+                Action::Tag => tags.push(listing),
+            }
+        }
+        if tags.is_empty() { Verdict::Clean }
+        else { Verdict::Tag(tags) }
+    }
+
+    fn lookup(&self, list: &List, addr: IpAddr) -> Option<Listing> {
+        let name = query_name(addr, &list.zone);
+        let codes = match self.resolver.lookup_a(&name) {
+            Ok(codes) => codes,
+            Err(dns::Error::NotFound) => return None,
+            Err(err) => {
+                warn!("DNSBL: lookup of {} failed: {}",
+                      String::from_utf8_lossy(&name), err);
+                return None
+            }
+        };
+        let codes: Vec<_> = codes.into_iter().filter(|code| {
+            let octets = code.octets();
+            octets[..3] == [127, 0, 0]
+                && (list.codes.is_empty() || list.codes.contains(&octets[3]))
+        }).collect();
+        if codes.is_empty() {
+            return None
+        }
+        // The reason is nice to have, so failing to get it is fine.
+        let reason = self.resolver.lookup_txt(&name).ok()
+                         .and_then(|mut txt| txt.pop());
+        Some(Listing { addr: addr, zone: list.zone.clone(), codes: codes,
+                       reason: reason })
+    }
+}
+
+impl fmt::Debug for Dnsbl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Dnsbl").field("lists", &self.lists).finish()
+    }
+}
+
+
+//------------ List ----------------------------------------------------------
+
+/// A blocklist to check.
+#[derive(Clone, Debug)]
+pub struct List {
+    zone: Vec<u8>,
+    action: Action,
+
+    /// The last octets of the return codes that count as listed.
+    ///
+    /// If empty, all codes count.
+    codes: Vec<u8>,
+}
+
+impl List {
+    pub fn new(zone: &[u8], action: Action) -> Self {
+        let zone = if zone.ends_with(b".") { &zone[..zone.len() - 1] }
+                   else { zone };
+        List { zone: zone.into(), action: action, codes: Vec::new() }
+    }
+
+    /// Only counts the return codes 127.0.0.*code*.
+    pub fn with_codes(mut self, codes: &[u8]) -> Self {
+        self.codes = codes.into();
+        self
+    }
+}
+
+
+//------------ Action --------------------------------------------------------
+
+/// What happens to clients found on a list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// The session is refused.
+    Reject,
+
+    /// The session continues but the listing is noted.
+    Tag,
+}
+
+
+//------------ Verdict -------------------------------------------------------
+
+/// The result of checking an address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// The address isn’t listed anywhere.
+    Clean,
+
+    /// The address is only listed on lists that tag.
+    Tag(Vec<Listing>),
+
+    /// The address is listed on a list that rejects.
+    Reject(Listing),
+}
+
+
+//------------ Listing -------------------------------------------------------
+
+/// An address found on a list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listing {
+    pub addr: IpAddr,
+    pub zone: Vec<u8>,
+
+    /// The return codes that counted.
+    pub codes: Vec<Ipv4Addr>,
+
+    /// The text given by the list, if any.
+    pub reason: Option<Vec<u8>>,
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} listed in {} (", self.addr,
+                    String::from_utf8_lossy(&self.zone)));
+        for (i, code) in self.codes.iter().enumerate() {
+            if i > 0 {
+                try!(f.write_str(", "))
+            }
+            try!(write!(f, "{}", code))
+        }
+        f.write_str(")")
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the name to look up for *addr* in *zone*.
+pub fn query_name(addr: IpAddr, zone: &[u8]) -> Vec<u8> {
+    dns::reverse_name(unmap(addr), zone)
+}
+
+fn parse_code(word: &[u8]) -> Option<u8> {
+    ::std::str::from_utf8(word).ok().and_then(|s| s.parse().ok())
+}
+
+fn invalid(word: &[u8]) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("invalid blocklist entry: {}",
+                           String::from_utf8_lossy(word)))
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use ::net::dns::{Record, Zone};
+    use super::*;
+
+    fn dnsbl() -> Dnsbl {
+        let mut zone = Zone::new();
+        let code = |last| Record::A(Ipv4Addr::new(127, 0, 0, last));
+        zone.insert(b"2.0.0.127.bl.test", code(2));
+        zone.insert(b"2.0.0.127.bl.test", Record::Txt(b"Spam".to_vec()));
+        zone.insert(b"2.0.0.127.tag.test", code(2));
+        zone.insert(b"3.0.0.127.bl.test", code(3));
+        zone.insert(b"3.0.0.127.tag.test", code(3));
+        zone.insert(b"3.0.0.127.tag.test", code(5));
+        zone.insert(b"4.0.0.127.tag.test",
+                    Record::A(Ipv4Addr::new(127, 255, 255, 254)));
+        let mut res = Dnsbl::new(Arc::new(zone));
+        res.read(&b"# Blocklists\n\
+                    bl.test. reject 2\n\
+                    tag.test tag\n"[..]).unwrap();
+        res
+    }
+
+    #[test]
+    fn query_name() {
+        assert_eq!(super::query_name("192.0.2.1".parse().unwrap(),
+                                     b"bl.test"),
+                   b"1.2.0.192.bl.test".to_vec());
+        assert_eq!(super::query_name("::ffff:192.0.2.1".parse().unwrap(),
+                                     b"bl.test"),
+                   b"1.2.0.192.bl.test".to_vec());
+        assert_eq!(super::query_name("2001:db8::567:89ab".parse().unwrap(),
+                                     b"bl.test"),
+                   &b"b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.\
+                      0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.bl.test"[..]);
+    }
+
+    #[test]
+    fn check() {
+        let dnsbl = dnsbl();
+        let addr = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(dnsbl.check(addr("127.0.0.1")), Verdict::Clean);
+
+        let listing = match dnsbl.check(addr("127.0.0.2")) {
+            Verdict::Reject(listing) => listing,
+            verdict => panic!("unexpected verdict {:?}", verdict)
+        };
+        assert_eq!(listing.zone, b"bl.test");
+        assert_eq!(listing.codes, [Ipv4Addr::new(127, 0, 0, 2)]);
+        assert_eq!(listing.reason, Some(b"Spam".to_vec()));
+        assert_eq!(listing.to_string(),
+                   "127.0.0.2 listed in bl.test (127.0.0.2)");
+
+        // The code for bl.test doesn’t count, so it is only tagged.
+        let listings = match dnsbl.check(addr("127.0.0.3")) {
+            Verdict::Tag(listings) => listings,
+            verdict => panic!("unexpected verdict {:?}", verdict)
+        };
+        assert_eq!(listings.len(), 1);
+        assert_eq!(listings[0].zone, b"tag.test");
+        assert_eq!(listings[0].codes, [Ipv4Addr::new(127, 0, 0, 3),
+                                       Ipv4Addr::new(127, 0, 0, 5)]);
+        assert_eq!(listings[0].reason, None);
+
+        // Codes outside of 127.0.0.0/24 are errors, not listings.
+        assert_eq!(dnsbl.check(addr("127.0.0.4")), Verdict::Clean);
+    }
+
+    #[test]
+    fn bad_lists() {
+        let mut dnsbl = Dnsbl::new(Arc::new(Zone::new()));
+        assert!(dnsbl.read(&b"bl.test deny\n"[..]).is_err());
+        assert!(dnsbl.read(&b"bl.test reject 256\n"[..]).is_err());
+        assert!(dnsbl.is_empty());
+    }
+}
@@ -0,0 +1,483 @@
+//! Testing sessions without a network.
+//!
+//! The `Harness` drives a session directly through its buffers the way
+//! the transport would, only that everything the server sends is kept
+//! for inspection and that deferred decisions and TLS handshakes happen
+//! when the test says so. This makes it possible to test protocol
+//! implementations without a loop or sockets.
+//!
+//! A session is scripted through a sequence of steps, either by
+//! chaining the methods named after them or by handing a slice of
+//! `Step`s to `Harness::run()`:
+//!
+//! ```ignore
+//! Harness::new(())
+//!     .replies(220)
+//!     .says(b"EHLO client.test\r\n").replies(250)
+//!     .says(b"QUIT\r\n").replies(221);
+//! ```
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use openssl::ssl::{SslContext, SslMethod};
+use rotor::Loop;
+use ::net::test::{self, FakeCertificate};
+use ::smtp::syntax::Reply;
+use super::buf::{RecvBuf, SendBuf};
+use super::config::Config;
+use super::protocol::{Protocol, SessionHandler};
+use super::session::{Action, Session};
+
+
+//------------ Step ----------------------------------------------------------
+
+/// A step in a scripted session.
+#[derive(Clone, Debug)]
+pub enum Step<'a> {
+    /// The client sends some data.
+    Says(&'a [u8]),
+
+    /// The server sends a reply with the given code.
+    Replies(u16),
+
+    /// The protocol is ready with a deferred decision.
+    Wakeup,
+
+    /// The protocol makes its deferred decisions in its own time.
+    Settle,
+
+    /// The TLS handshake finishes with the given peer certificate.
+    Secure(Option<FakeCertificate>),
+}
+
+
+//------------ Harness -------------------------------------------------------
+
+pub struct Harness<P: Protocol> {
+    /// The session under test.
+    ///
+    /// This is only `None` while one of its methods is running.
+    session: Option<Session<P>>,
+
+    /// What the client has sent but the session hasn’t processed yet.
+    recv: RecvBuf,
+
+    /// What the session is sending right now.
+    send: SendBuf,
+
+    /// The replies sent by the server that haven’t been looked at yet.
+    output: RecvBuf,
+
+    /// The last action requested by the session.
+    action: Action,
+
+    /// Is TLS running?
+    is_secure: bool,
+
+    /// The cipher reported by the next TLS handshake.
+    cipher: Option<Vec<u8>>,
+
+    /// The loop behind the session’s notifier.
+    _notifier: Loop<test::Idle>,
+}
+
+impl<P: Protocol> Harness<P> {
+    /// Starts a session with the configuration from `config()`.
+    pub fn new(seed: <P::Session as SessionHandler<P>>::Seed) -> Self {
+        Harness::with_config(seed, config())
+    }
+
+    /// Starts a session with the given configuration.
+    ///
+    /// The client connects from 127.0.0.1.
+    pub fn with_config(seed: <P::Session as SessionHandler<P>>::Seed,
+                       config: Config) -> Self {
+        let (lp, notifier) = test::notifier();
+        let mut send = SendBuf::new();
+        let peer = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let (session, action) = Session::new(seed, peer, Rc::new(config),
+                                             notifier, &mut send);
+        let mut res = Harness {
+            session: Some(session), recv: RecvBuf::new(), send: send,
+            output: RecvBuf::new(), action: Action::Read, is_secure: false,
+            cipher: None, _notifier: lp
+        };
+        res.proceed(action);
+        res
+    }
+
+    /// Performs all the steps in order.
+    pub fn run(&mut self, steps: &[Step]) -> &mut Self {
+        for step in steps {
+            match *step {
+                Step::Says(data) => { self.says(data); }
+                Step::Replies(code) => { self.replies(code); }
+                Step::Wakeup => { self.wakeup(); }
+                Step::Settle => { self.settle(); }
+                Step::Secure(ref cert) => { self.secure(cert.clone()); }
+            }
+        }
+        self
+    }
+
+    /// The client sends *data*.
+    ///
+    /// If the session is waiting for a deferred decision, the data is
+    /// only processed after the next wakeup.
+    pub fn says(&mut self, data: &[u8]) -> &mut Self {
+        assert!(!self.is_closed(), "connection has been closed");
+        assert!(!self.is_handshaking(), "TLS handshake pending");
+        self.recv.push(data);
+        if !self.is_waiting() {
+            let action = self.recv_session();
+            self.proceed(action);
+        }
+        self
+    }
+
+    /// Checks that the next reply of the server has the given code.
+    pub fn replies(&mut self, code: u16) -> &mut Self {
+        match self.reply() {
+            Some(reply) => {
+                assert!(reply.code == code, "expected {}, got {} {}",
+                        code, reply.code,
+                        String::from_utf8_lossy(&reply.text));
+            }
+            None => panic!("expected {}, got nothing", code)
+        }
+        self
+    }
+
+    /// The protocol is ready with the decision it deferred.
+    pub fn wakeup(&mut self) -> &mut Self {
+        assert!(self.is_waiting(), "session isn’t waiting");
+        let more = !self.recv.is_empty();
+        let session = self.session.take().unwrap();
+        let (session, action) = session.wakeup(&mut self.send, more,
+                                               self.is_secure);
+        self.session = Some(session);
+        let action = match action {
+            // Whatever arrived while waiting needs processing now.
+            Action::Read => self.recv_session(),
+            action => action
+        };
+        self.proceed(action);
+        self
+    }
+
+    /// Waits until the protocol has made its deferred decisions.
+    ///
+    /// This is for protocols that hand their work to a worker pool. The
+    /// session is woken up until it doesn’t wait anymore which needs to
+    /// happen within five seconds. If it isn’t waiting to begin with,
+    /// nothing happens.
+    pub fn settle(&mut self) -> &mut Self {
+        let start = Instant::now();
+        while self.is_waiting() {
+            assert!(start.elapsed() < Duration::from_secs(5),
+                    "deferred decision not made in time");
+            thread::sleep(Duration::from_millis(1));
+            self.wakeup();
+        }
+        self
+    }
+
+    /// Sets the description of the cipher TLS handshakes report.
+    pub fn cipher(&mut self, cipher: &[u8]) -> &mut Self {
+        self.cipher = Some(cipher.into());
+        self
+    }
+
+    /// The TLS handshake finishes successfully.
+    pub fn secure(&mut self, peer_cert: Option<FakeCertificate>)
+                  -> &mut Self {
+        assert!(self.is_handshaking(), "no TLS handshake pending");
+        self.is_secure = true;
+        let session = self.session.take().unwrap();
+        let (session, action) = session.confirm_tls(peer_cert,
+                                                    self.cipher.clone());
+        self.session = Some(session);
+        self.proceed(action);
+        self
+    }
+
+    /// Takes the next reply sent by the server.
+    ///
+    /// Returns `None` if the server hasn’t sent another reply.
+    pub fn reply(&mut self) -> Option<Reply> {
+        self.output.parse_reply().expect("server sent garbage")
+    }
+
+    /// Returns whether all replies have been looked at.
+    pub fn is_quiet(&self) -> bool {
+        self.output.is_empty()
+    }
+
+    /// Returns whether the session waits for a deferred decision.
+    pub fn is_waiting(&self) -> bool {
+        if let Action::Wait = self.action { true } else { false }
+    }
+
+    /// Returns whether the session waits for a TLS handshake.
+    pub fn is_handshaking(&self) -> bool {
+        if let Action::StartTls = self.action { true } else { false }
+    }
+
+    /// Returns whether the server has closed the connection.
+    pub fn is_closed(&self) -> bool {
+        if let Action::Close = self.action { true } else { false }
+    }
+}
+
+impl<P: Protocol> Harness<P> {
+    fn recv_session(&mut self) -> Action {
+        let session = self.session.take().unwrap();
+        let (session, action) = session.recv(&mut self.recv, &mut self.send,
+                                             self.is_secure);
+        self.session = Some(session);
+        action
+    }
+
+    /// Does what the transport does with an action.
+    ///
+    /// Since writing always succeeds right away here, there is no need
+    /// to collect replies.
+    fn proceed(&mut self, mut action: Action) {
+        loop {
+            self.flush();
+            match action {
+                Action::Write | Action::Collect if !self.recv.is_empty() => {
+                    action = self.recv_session()
+                }
+                Action::StartTls => {
+                    // Anything sent before the handshake is dropped.
+                    self.recv.clear();
+                    break
+                }
+                _ => break
+            }
+        }
+        self.action = action;
+    }
+
+    /// Moves everything sent over to the output.
+    fn flush(&mut self) {
+        let len = {
+            let data = self.send.as_slice();
+            self.output.push(data);
+            data.len()
+        };
+        self.send.advance(len);
+    }
+}
+
+
+//------------ config --------------------------------------------------------
+
+/// Returns a server configuration for testing.
+///
+/// The server is called mx.test and there is no message size limit.
+pub fn config() -> Config {
+    Config::new(SslContext::new(SslMethod::Tlsv1).unwrap(),
+                b"mx.test".to_vec(), b"Cloudship".to_vec(), 0)
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use ::net::test::FakeCertificate;
+    use super::super::NullProtocol;
+    use super::super::config::Capabilities;
+    use super::*;
+
+    /// Says EHLO and returns the keywords of the reply.
+    fn ehlo(harness: &mut Harness<NullProtocol>) -> Vec<String> {
+        harness.says(b"EHLO client.test\r\n");
+        let reply = harness.reply().unwrap();
+        assert_eq!(reply.code, 250);
+        String::from_utf8(reply.text).unwrap().split("\r\n").skip(1)
+                                     .map(String::from).collect()
+    }
+
+    #[test]
+    fn starttls() {
+        let mut harness = Harness::<NullProtocol>::new(());
+        harness.run(&[
+            Step::Replies(220),
+            Step::Says(b"EHLO client.test\r\n"), Step::Replies(250),
+            Step::Says(b"AUTH PLAIN AHVzZXIAcGFzcw==\r\n"), Step::Replies(502),
+
+            // Commands pipelined after STARTTLS are dropped.
+            Step::Says(b"STARTTLS\r\nMAIL FROM:<a@client.test>\r\n"),
+            Step::Replies(220),
+            Step::Secure(Some(FakeCertificate)),
+        ]);
+        assert!(harness.is_quiet());
+
+        // The session starts over after the handshake.
+        harness.run(&[
+            Step::Says(b"MAIL FROM:<a@client.test>\r\n"), Step::Replies(503),
+            Step::Says(b"EHLO client.test\r\n"), Step::Replies(250),
+            Step::Says(b"STARTTLS\r\n"), Step::Replies(502),
+            Step::Says(b"AUTH PLAIN AHVzZXIAcGFzcw==\r\n"), Step::Replies(535),
+            Step::Says(b"QUIT\r\n"), Step::Replies(221),
+        ]);
+        assert!(harness.is_closed());
+    }
+
+    #[test]
+    fn auth_line_limit() {
+        let mut harness = Harness::<NullProtocol>::new(());
+        harness.run(&[
+            Step::Replies(220),
+            Step::Says(b"EHLO client.test\r\n"), Step::Replies(250),
+            Step::Says(b"STARTTLS\r\n"), Step::Replies(220),
+            Step::Secure(None),
+            Step::Says(b"EHLO client.test\r\n"), Step::Replies(250),
+            Step::Says(b"AUTH LOGIN\r\n"), Step::Replies(334),
+        ]);
+
+        // An overlong response is refused even before it is complete ...
+        harness.says(&vec![b'A'; 12290]).replies(500)
+               .says(b"NOOP\r\n").replies(250);
+
+        // ... and everything that came with it is dropped.
+        harness.says(b"AUTH LOGIN\r\n").replies(334)
+               .says(&vec![b'A'; 12289]).says(b"\r\nNOOP\r\n")
+               .replies(500)
+               .says(b"NOOP\r\n").replies(250);
+        assert!(harness.is_quiet());
+    }
+
+    #[test]
+    fn pipelining() {
+        let mut harness = Harness::<NullProtocol>::new(());
+        harness.replies(220)
+               .says(b"EHLO client.test\r\n").replies(250)
+               .says(b"MAIL FROM:<a@client.test>\r\n\
+                       RCPT TO:<b@mx.test>\r\nDATA\r\n")
+               .replies(250).replies(250).replies(354)
+               .says(b"Hello\r\n.\r\nQUIT\r\n")
+               .replies(250).replies(221);
+        assert!(harness.is_quiet());
+        assert!(harness.is_closed());
+    }
+
+    #[test]
+    fn capabilities() {
+        let mut harness = Harness::<NullProtocol>::new(());
+        harness.replies(220);
+        assert_eq!(ehlo(&mut harness),
+                   ["EXPN", "HELP", "8BITMIME", "SIZE 0", "PIPELINING",
+                    "DSN", "ETRN", "ENHANCEDSTATUSCODES", "SMTPUTF8",
+                    "CHUNKING", "BINARYMIME", "STARTTLS"]);
+        harness.says(b"STARTTLS\r\n").replies(220)
+               .secure(Some(FakeCertificate));
+        assert_eq!(ehlo(&mut harness),
+                   ["EXPN", "HELP", "8BITMIME", "SIZE 0", "PIPELINING",
+                    "DSN", "ETRN", "ENHANCEDSTATUSCODES", "SMTPUTF8",
+                    "CHUNKING", "BINARYMIME", "AUTH PLAIN LOGIN"]);
+
+        // BINARYMIME goes along with CHUNKING.
+        let mut config = config();
+        let mut caps = Capabilities::none();
+        caps.binarymime = true;
+        config.set_capabilities(caps);
+        let mut harness = Harness::<NullProtocol>::with_config((), config);
+        harness.replies(220);
+        assert_eq!(ehlo(&mut harness), ["ENHANCEDSTATUSCODES"]);
+    }
+
+    #[test]
+    fn unoffered() {
+        let mut config = config();
+        config.set_capabilities(Capabilities::none());
+        let mut harness = Harness::<NullProtocol>::with_config((), config);
+        harness.replies(220);
+        ehlo(&mut harness);
+        harness.run(&[
+            Step::Says(b"MAIL FROM:<a@client.test> BODY=8BITMIME\r\n"),
+            Step::Replies(555),
+            Step::Says(b"MAIL FROM:<a@client.test> BODY=BINARYMIME\r\n"),
+            Step::Replies(555),
+            Step::Says(b"MAIL FROM:<a@client.test> SIZE=100\r\n"),
+            Step::Replies(555),
+            Step::Says(b"MAIL FROM:<a@client.test> RET=HDRS\r\n"),
+            Step::Replies(555),
+            Step::Says(b"MAIL FROM:<a@client.test> SMTPUTF8\r\n"),
+            Step::Replies(555),
+            Step::Says(b"MAIL FROM:<a@client.test>\r\n"), Step::Replies(250),
+            Step::Says(b"RCPT TO:<b@mx.test> NOTIFY=NEVER\r\n"),
+            Step::Replies(555),
+            Step::Says(b"RCPT TO:<b@mx.test>\r\n"), Step::Replies(250),
+            Step::Says(b"VRFY b SMTPUTF8\r\n"), Step::Replies(555),
+            Step::Says(b"EXPN b SMTPUTF8\r\n"), Step::Replies(555),
+            Step::Says(b"ETRN mx.test\r\n"), Step::Replies(502),
+            Step::Says(b"STARTTLS\r\n"), Step::Replies(502),
+            Step::Says(b"AUTH PLAIN AHVzZXIAcGFzcw==\r\n"), Step::Replies(502),
+
+            // The chunk of a rejected BDAT is skipped.
+            Step::Says(b"BDAT 6 LAST\r\nQUIT\r\n"), Step::Replies(502),
+            Step::Says(b"NOOP\r\n"), Step::Replies(250),
+            Step::Says(b"QUIT\r\n"), Step::Replies(221),
+        ]);
+        assert!(harness.is_quiet());
+        assert!(harness.is_closed());
+    }
+
+    #[test]
+    fn body() {
+        let mut harness = Harness::<NullProtocol>::new(());
+        harness.replies(220);
+        ehlo(&mut harness);
+        harness.run(&[
+            // 8BITMIME goes through DATA.
+            Step::Says(b"MAIL FROM:<a@client.test> BODY=8BITMIME\r\n"),
+            Step::Replies(250),
+            Step::Says(b"RCPT TO:<b@mx.test>\r\n"), Step::Replies(250),
+            Step::Says(b"DATA\r\n"), Step::Replies(354),
+            Step::Says(b"H\xc3\xa9llo\r\n.\r\n"), Step::Replies(250),
+
+            // BINARYMIME only goes through BDAT.
+            Step::Says(b"MAIL FROM:<a@client.test> BODY=BINARYMIME\r\n"),
+            Step::Replies(250),
+            Step::Says(b"RCPT TO:<b@mx.test>\r\n"), Step::Replies(250),
+            Step::Says(b"DATA\r\n"), Step::Replies(503),
+            Step::Says(b"BDAT 7 LAST\r\nH\x00llo\r\n"), Step::Replies(250),
+
+            // The body type ends with the transaction.
+            Step::Says(b"MAIL FROM:<a@client.test> BODY=BINARYMIME\r\n"),
+            Step::Replies(250),
+            Step::Says(b"RSET\r\n"), Step::Replies(250),
+            Step::Says(b"MAIL FROM:<a@client.test>\r\n"), Step::Replies(250),
+            Step::Says(b"RCPT TO:<b@mx.test>\r\n"), Step::Replies(250),
+            Step::Says(b"DATA\r\n"), Step::Replies(354),
+            Step::Says(b"Hello\r\n.\r\n"), Step::Replies(250),
+
+            // A nested MAIL doesn't change the body type ...
+            Step::Says(b"MAIL FROM:<a@client.test>\r\n"), Step::Replies(250),
+            Step::Says(b"MAIL FROM:<a@client.test> BODY=BINARYMIME\r\n"),
+            Step::Replies(503),
+            Step::Says(b"RCPT TO:<b@mx.test>\r\n"), Step::Replies(250),
+            Step::Says(b"DATA\r\n"), Step::Replies(354),
+            Step::Says(b"Hello\r\n.\r\n"), Step::Replies(250),
+
+            // ... in either direction.
+            Step::Says(b"MAIL FROM:<a@client.test> BODY=BINARYMIME\r\n"),
+            Step::Replies(250),
+            Step::Says(b"MAIL FROM:<a@client.test>\r\n"), Step::Replies(503),
+            Step::Says(b"RCPT TO:<b@mx.test>\r\n"), Step::Replies(250),
+            Step::Says(b"DATA\r\n"), Step::Replies(503),
+            Step::Says(b"BDAT 7 LAST\r\nH\x00llo\r\n"), Step::Replies(250),
+
+            // DATA outside a transaction gets exactly one reply.
+            Step::Says(b"DATA\r\nNOOP\r\n"), Step::Replies(503),
+            Step::Replies(250),
+        ]);
+        assert!(harness.is_quiet());
+    }
+}
@@ -1,13 +1,20 @@
 
-pub use self::config::Config;
+pub use self::access::{Access, Denial, Network};
+pub use self::config::{Capabilities, Config, Timeouts};
 pub use self::server::Server;
 pub use self::null::NullProtocol;
 
+pub mod access;
 pub mod buf;
 pub mod config;
+pub mod dnsbl;
+#[cfg(any(test, feature = "test-harness"))] pub mod harness;
 pub mod null;
 pub mod protocol;
 pub mod reply;
+pub mod sasl;
 pub mod server;
 pub mod session;
+pub mod trace;
 pub mod transport;
+pub mod worker;
@@ -5,13 +5,16 @@ use rotor::{Notifier, Void};
 use ::smtp::syntax;
 use super::protocol::{AncillaryHandler, DataHandler, Hesitant, MailHandler,
                       Protocol, SessionHandler};
-use super::reply::ReplyBuf;
+use super::reply::{EtrnReply, ReplyBuf};
+use super::sasl;
 
 
 //------------ NullProtocol --------------------------------------------------
 
 pub struct NullProtocol;
 
+static NO_LOOKUP: sasl::NoLookup = sasl::NoLookup;
+
 impl Protocol for NullProtocol {
     type Session = Self;
     type Mail = Self;
@@ -54,7 +57,11 @@ impl SessionHandler<NullProtocol> for NullProtocol {
     type Start = Void;
     type Hello = Void;
     type CheckTls = Void;
+    type Auth = Void;
+    type Derive = Void;
     type Mail = Void;
+    type Etrn = Void;
+    type Lookup = sasl::NoLookup;
 
     fn start(_seed: (), _notifier: Notifier) -> Hesitant<Option<Self>, Void> {
         Hesitant::Final(Some(NullProtocol))
@@ -70,11 +77,27 @@ impl SessionHandler<NullProtocol> for NullProtocol {
         Hesitant::Final(Some(self))
     }
 
+    fn credential_lookup(&self) -> &sasl::NoLookup {
+        &NO_LOOKUP
+    }
+
+    fn auth(self, _mechanism: &[u8], _credentials: sasl::Credentials)
+            -> Hesitant<Result<Self, Self>, Void> {
+        // There are no users, so nobody can authenticate.
+        Hesitant::Final(Err(self))
+    }
+
     fn mail(self, _path: syntax::ReversePath, _params: syntax::MailParameters,
             reply: ReplyBuf) -> Hesitant<Result<Self, Self>, Void> {
         reply.reply(250, (2, 1, 0), b"Ok\r\n");
         Hesitant::Final(Ok(self))
     }
+
+    fn etrn(self, node: syntax::EtrnNode, reply: ReplyBuf)
+            -> Hesitant<Self, Void> {
+        reply.etrn(EtrnReply::NoMessages, &node);
+        Hesitant::Final(self)
+    }
 }
 
 
@@ -109,4 +132,8 @@ impl DataHandler<NullProtocol> for NullProtocol {
         reply.reply(250, (2, 1, 0), b"Ok\r\n");
         Hesitant::Final(self)
     }
+
+    fn reset(self) -> Self {
+        self
+    }
 }
@@ -4,7 +4,10 @@ use std::net::SocketAddr;
 use netmachines::sockets::Certificate;
 use rotor::{Notifier, Void};
 use ::smtp::syntax;
-use super::reply::ReplyBuf;
+use super::config::Capabilities;
+use super::reply::{EtrnReply, ReplyBuf};
+use super::sasl;
+use super::trace::Trace;
 
 
 //============ Handling of Deferred Decisions ================================
@@ -128,7 +131,11 @@ pub trait SessionHandler<P: Protocol>: AncillaryHandler {
     type Start: Undecided<Option<Self>>;
     type Hello: Undecided<Option<Self>>;
     type CheckTls: Undecided<Option<Self>>;
+    type Auth: Undecided<Result<Self, Self>>;
+    type Derive: Undecided<Option<sasl::ScramCredentials>>;
     type Mail: UndecidedReply<Result<P::Mail, P::Session>>;
+    type Etrn: UndecidedReply<Self>;
+    type Lookup: sasl::CredentialLookup;
 
     /// Start the session.
     fn start(seed: Self::Seed, notifier: Notifier)
@@ -143,11 +150,66 @@ pub trait SessionHandler<P: Protocol>: AncillaryHandler {
     fn hello(self, domain: syntax::MailboxDomain)
              -> Hesitant<Option<Self>, Self::Hello>;
 
+    /// Adjusts the extensions to be advertised in reply to an EHLO.
+    ///
+    /// This is called once the hello has been accepted with *caps* set
+    /// to the capabilities from the server configuration. Extensions
+    /// that can’t be used on this connection are removed afterwards
+    /// regardless of what happens here. These are STARTTLS once TLS is
+    /// running, BINARYMIME without CHUNKING, and AUTH unless TLS is
+    /// running and there are SASL mechanisms. AUTH is refused with 538
+    /// on connections without TLS, so it can’t be turned on for them
+    /// here either.
+    ///
+    /// By default, the configured extensions are advertised unchanged.
+    fn capabilities(&self, _caps: &mut Capabilities) {
+    }
+
     /// A TLS handshake has finished.
     fn check_tls<C: Certificate>(self, peer_cert: Option<C>)
                                  -> Hesitant<Option<Self>,
                                              Self::CheckTls>;
 
+    /// Returns the lookup for user secrets.
+    ///
+    /// This is used by SASL mechanisms that need to know the secret of a
+    /// user in order to verify the client’s response, such as CRAM-MD5
+    /// and SCRAM-SHA-256.
+    fn credential_lookup(&self) -> &Self::Lookup;
+
+    /// Derives SCRAM-SHA-256 credentials from a plain text password.
+    ///
+    /// This happens during a SCRAM-SHA-256 exchange if the credential
+    /// lookup has a password but no stored credentials for the user.
+    /// Since deriving takes thousands of rounds of HMAC, it should be
+    /// done on a worker pool and the result kept, for instance in a
+    /// `sasl::ScramCache`, so that it is done only once per password.
+    ///
+    /// The final response is `None` if there are no credentials after
+    /// all. This is what the default implementation does, so unless this
+    /// is implemented, SCRAM-SHA-256 only works for users with stored
+    /// credentials.
+    fn derive_scram(&self, derivation: sasl::Derivation)
+                    -> Hesitant<Option<sasl::ScramCredentials>,
+                                Self::Derive> {
+        let _ = derivation;
+        Hesitant::Final(None)
+    }
+
+    /// An AUTH exchange has been completed.
+    ///
+    /// The name of the SASL mechanism used is given in *mechanism* and
+    /// the credentials presented by the client in *credentials*. If the
+    /// mechanism transferred a password, it is included in the
+    /// credentials and you have to check it. Otherwise, the mechanism
+    /// has already verified the secret through the credential lookup.
+    ///
+    /// The final response is `Ok(_)` if the credentials are valid and the
+    /// client may act as the requested authorization identity or `Err(_)`
+    /// otherwise. The reply is generated by the underlying machine.
+    fn auth(self, mechanism: &[u8], credentials: sasl::Credentials)
+            -> Hesitant<Result<Self, Self>, Self::Auth>;
+
     /// A MAIL command was received.
     ///
     /// The arguments to the command are given as parameters. The final
@@ -155,10 +217,26 @@ pub trait SessionHandler<P: Protocol>: AncillaryHandler {
     /// transport will generate a success response and proceed with the
     /// mail transaction, or an error reply, in which case the transport
     /// will send that reply and listen to the next command.
+    ///
+    /// The `auth` field of *params* is only present if the client has
+    /// successfully authenticated. Otherwise it has been removed as
+    /// required by RFC 4954, section 5.
     fn mail(self, path: syntax::ReversePath, params: syntax::MailParameters,
             reply: ReplyBuf)
             -> Hesitant<Result<P::Mail, P::Session>, Self::Mail>;
 
+    /// An ETRN command was received.
+    ///
+    /// The client asks for delivery of mail held for *node* to be
+    /// started. The reply should be generated through
+    /// `ReplyBuf::etrn()` which provides the replies defined in RFC 1985.
+    ///
+    /// By default, there is no queue to start and the reply says so.
+    fn etrn(self, node: syntax::EtrnNode, reply: ReplyBuf)
+            -> Hesitant<Self, Self::Etrn> {
+        reply.etrn(EtrnReply::Unable, &node);
+        Hesitant::Final(self)
+    }
 }
 
 
@@ -205,10 +283,27 @@ pub trait DataHandler<P: Protocol>: Sized {
     /// the chunk of data in *data* or loose it.
     fn chunk(&mut self, data: &[u8]);
 
+    /// The trace information for the message is available.
+    ///
+    /// This happens once before the first chunk of data. By default, the
+    /// Received field made from *trace* is passed to `chunk()` so that it
+    /// ends up at the top of the message. Override this if you want to
+    /// add something to the trace, such as the queue ID, or need to
+    /// treat the field differently from the message.
+    fn trace(&mut self, trace: Trace) {
+        self.chunk(&trace.to_field())
+    }
+
     /// The last chunk was received.
     ///
     /// The final response should be the reply to be sent.
     fn complete(self, reply: ReplyBuf)
                 -> Hesitant<P::Session, Self::Complete>;
+
+    /// The transaction was aborted before all data was received.
+    ///
+    /// This happens, for instance, if the client sends RSET between two
+    /// BDAT chunks. Any data received so far should be discarded.
+    fn reset(self) -> P::Session;
 }
 
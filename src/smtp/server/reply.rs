@@ -2,6 +2,7 @@
 //!
 
 use util::scribe::{Scribe, Scribble};
+use ::smtp::syntax::EtrnNode;
 use super::buf::SendBuf;
 
 
@@ -42,6 +43,86 @@ impl<'a> ReplyBuf<'a> {
                  text: &[u8]) {
         Reply::reply(self.buf, code, status, text)
     }
+
+    /// Buffers the reply to an ETRN command for *node*.
+    ///
+    pub fn etrn(self, etrn: EtrnReply, node: &EtrnNode) {
+        let node = node.to_string();
+        let status = match etrn {
+            EtrnReply::Unable => (4, 3, 0),
+            EtrnReply::NotAllowed(_) => (4, 7, 1),
+            _ => (2, 0, 0)
+        };
+        let mut reply = self.start(etrn.code(), Some(status));
+        match etrn {
+            EtrnReply::Started => {
+                scribble!(&mut reply, b"OK, queuing for node ", &node[..],
+                          b" started\r\n")
+            }
+            EtrnReply::NoMessages => {
+                scribble!(&mut reply, b"OK, no messages waiting for node ",
+                          &node[..], b"\r\n")
+            }
+            EtrnReply::PendingStarted => {
+                scribble!(&mut reply, b"OK, pending messages for node ",
+                          &node[..], b" started\r\n")
+            }
+            EtrnReply::Pending(count) => {
+                scribble!(&mut reply, b"OK, ", count,
+                          b" pending messages for node ", &node[..],
+                          b" started\r\n")
+            }
+            EtrnReply::Unable => {
+                scribble!(&mut reply, b"Unable to queue messages for node ",
+                          &node[..], b"\r\n")
+            }
+            EtrnReply::NotAllowed(reason) => {
+                scribble!(&mut reply, b"Node ", &node[..], b" not allowed: ",
+                          reason, b"\r\n")
+            }
+        }
+    }
+}
+
+
+//------------ EtrnReply ----------------------------------------------------
+
+/// The possible outcomes of an ETRN command.
+///
+/// These are the replies defined in RFC 1985, section 5.
+///
+#[derive(Clone, Copy, Debug)]
+pub enum EtrnReply<'a> {
+    /// 250: Delivery for the node has been started.
+    Started,
+
+    /// 251: There are no messages waiting for the node.
+    NoMessages,
+
+    /// 252: Delivery of pending messages for the node has been started.
+    PendingStarted,
+
+    /// 253: Delivery of this many pending messages has been started.
+    Pending(usize),
+
+    /// 458: Messages for the node can’t be delivered now.
+    Unable,
+
+    /// 459: The client may not ask for the node for the given reason.
+    NotAllowed(&'a [u8]),
+}
+
+impl<'a> EtrnReply<'a> {
+    fn code(&self) -> u16 {
+        match *self {
+            EtrnReply::Started => 250,
+            EtrnReply::NoMessages => 251,
+            EtrnReply::PendingStarted => 252,
+            EtrnReply::Pending(_) => 253,
+            EtrnReply::Unable => 458,
+            EtrnReply::NotAllowed(_) => 459,
+        }
+    }
 }
 
 
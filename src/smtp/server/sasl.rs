@@ -0,0 +1,1119 @@
+//! SASL authentication for the AUTH command.
+//!
+//! The SMTP side of things is defined in RFC 4954, SASL itself in RFC 4422.
+//!
+//! Mechanisms are implemented through the `SaslMechanism` trait. The
+//! server’s `Config` holds a list of them which is used both for the AUTH
+//! keyword in the EHLO reply and for finding the mechanism requested by an
+//! AUTH command. Each exchange is represented by a value of the
+//! `Exchange` trait which the session drives through however many
+//! challenge/response rounds it needs. The resulting credentials are
+//! handed to `SessionHandler::auth()` for the final verdict.
+//!
+//! Mechanisms that need to know a user’s secret, such as CRAM-MD5 and
+//! SCRAM-SHA-256, ask the `CredentialLookup` supplied by the session
+//! handler. If there are only plain text passwords, SCRAM-SHA-256
+//! credentials have to be derived from them first. Since that takes a
+//! while, the exchange asks the session handler to do it through
+//! `SessionHandler::derive_scram()`.
+
+use std::ascii::AsciiExt;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use openssl::crypto::hash::{self, Type};
+use openssl::crypto::hmac::hmac;
+use openssl::crypto::rand::rand_bytes;
+use ::util::base64;
+
+
+//------------ SaslMechanism -------------------------------------------------
+
+/// A trait for SASL mechanisms.
+///
+pub trait SaslMechanism {
+    /// Returns the name of the mechanism as used with the AUTH command.
+    fn name(&self) -> &[u8];
+
+    /// Starts a new exchange.
+    ///
+    /// The *hostname* is the server’s host name from its config. Some
+    /// mechanisms use it in their challenges.
+    fn start(&self, hostname: &[u8]) -> Box<Exchange>;
+}
+
+
+//------------ Exchange ------------------------------------------------------
+
+/// A trait for the state of an ongoing SASL exchange.
+///
+pub trait Exchange {
+    /// Processes the next client response.
+    ///
+    /// The *response* has already been base 64 decoded. It is `None` for
+    /// the very first step if the client didn’t give an initial response
+    /// with the AUTH command.
+    ///
+    /// If the mechanism needs to know a user’s secret, it can look it up
+    /// via *lookup*.
+    fn step(&mut self, response: Option<&[u8]>, lookup: &CredentialLookup)
+            -> Step;
+
+    /// Continues after credentials have been derived.
+    ///
+    /// This is called with the outcome of the derivation requested by
+    /// returning `Step::Derive(_)`. The default implementation fails
+    /// since only mechanisms that ask for a derivation should ever get
+    /// here.
+    fn derived(&mut self, credentials: Option<ScramCredentials>) -> Step {
+        let _ = credentials;
+        Step::Failed
+    }
+}
+
+
+//------------ Step ----------------------------------------------------------
+
+/// The outcome of processing a client response.
+///
+#[derive(Debug)]
+pub enum Step {
+    /// Send this challenge to the client and wait for the next response.
+    ///
+    /// The challenge is not yet base 64 encoded.
+    Challenge(Vec<u8>),
+
+    /// Derive SCRAM-SHA-256 credentials and continue with them.
+    ///
+    /// The session hands the derivation to the session handler and
+    /// continues the exchange via `Exchange::derived()`.
+    Derive(Derivation),
+
+    /// The exchange is complete with these credentials.
+    Done(Credentials),
+
+    /// The exchange failed.
+    Failed,
+}
+
+
+//------------ Credentials ---------------------------------------------------
+
+/// The credentials presented by a client through a SASL exchange.
+///
+/// The `Debug` implementation leaves out the password so credentials
+/// can safely end up in log messages.
+///
+#[derive(Clone)]
+pub struct Credentials {
+    /// The authorization identity.
+    ///
+    /// This is the identity the client wants to act as. If it is empty,
+    /// the client wants to act as the authentication identity.
+    pub authzid: Vec<u8>,
+
+    /// The authentication identity.
+    ///
+    /// This is the identity whose secret has been presented.
+    pub authcid: Vec<u8>,
+
+    /// The password.
+    ///
+    /// This is only present for mechanisms that transfer the password
+    /// itself, such as PLAIN and LOGIN, in which case you have to check
+    /// it. Challenge/response mechanisms have already verified the
+    /// client’s knowledge of the secret via the `CredentialLookup` and
+    /// leave this as `None`.
+    pub password: Option<Vec<u8>>,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+         .field("authzid", &String::from_utf8_lossy(&self.authzid))
+         .field("authcid", &String::from_utf8_lossy(&self.authcid))
+         .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+         .finish()
+    }
+}
+
+
+//------------ CredentialLookup ----------------------------------------------
+
+/// A trait for looking up the secrets of users.
+///
+/// This is provided by the session handler through
+/// `SessionHandler::credential_lookup()`. All methods have a default
+/// implementation that knows nobody.
+///
+pub trait CredentialLookup {
+    /// Returns the plain text password of the user *authcid*.
+    fn password(&self, authcid: &[u8]) -> Option<Vec<u8>> {
+        let _ = authcid;
+        None
+    }
+
+    /// Returns the stored SCRAM-SHA-256 credentials of the user *authcid*.
+    ///
+    /// If there are none but there is a plain text password, credentials
+    /// are derived from the password instead. The default implementation
+    /// doesn’t store any credentials.
+    fn scram_sha256(&self, authcid: &[u8]) -> Option<ScramCredentials> {
+        let _ = authcid;
+        None
+    }
+}
+
+
+//------------ NoLookup ------------------------------------------------------
+
+/// A credential lookup that doesn’t know anyone.
+///
+pub struct NoLookup;
+
+impl CredentialLookup for NoLookup { }
+
+
+//============ Mechanisms ====================================================
+
+//------------ Plain ---------------------------------------------------------
+
+/// The PLAIN mechanism defined in RFC 4616.
+///
+pub struct Plain;
+
+impl SaslMechanism for Plain {
+    fn name(&self) -> &[u8] { b"PLAIN" }
+
+    fn start(&self, _hostname: &[u8]) -> Box<Exchange> {
+        Box::new(PlainExchange)
+    }
+}
+
+struct PlainExchange;
+
+impl Exchange for PlainExchange {
+    /// Processes the PLAIN response.
+    ///
+    /// > message   = [authzid] UTF8NUL authcid UTF8NUL passwd
+    ///
+    fn step(&mut self, response: Option<&[u8]>, _lookup: &CredentialLookup)
+            -> Step {
+        let response = match response {
+            Some(response) => response,
+            None => return Step::Challenge(Vec::new())
+        };
+        let mut parts = response.split(|ch| *ch == 0);
+        let authzid = parts.next();
+        let authcid = parts.next();
+        let password = parts.next();
+        match (authzid, authcid, password, parts.next()) {
+            (Some(authzid), Some(authcid), Some(password), None) => {
+                if authcid.is_empty() {
+                    Step::Failed
+                }
+                else {
+                    Step::Done(Credentials {
+                        authzid: authzid.to_vec(),
+                        authcid: authcid.to_vec(),
+                        password: Some(password.to_vec())
+                    })
+                }
+            }
+            _ => Step::Failed
+        }
+    }
+}
+
+
+//------------ Login ---------------------------------------------------------
+
+/// The LOGIN mechanism described in draft-murchison-sasl-login.
+///
+pub struct Login;
+
+impl SaslMechanism for Login {
+    fn name(&self) -> &[u8] { b"LOGIN" }
+
+    fn start(&self, _hostname: &[u8]) -> Box<Exchange> {
+        Box::new(LoginExchange(None))
+    }
+}
+
+/// The LOGIN exchange holding the user name once we have it.
+struct LoginExchange(Option<Vec<u8>>);
+
+impl Exchange for LoginExchange {
+    fn step(&mut self, response: Option<&[u8]>, _lookup: &CredentialLookup)
+            -> Step {
+        let response = match response {
+            Some(response) => response,
+            None => return Step::Challenge(b"Username:".to_vec())
+        };
+        match self.0.take() {
+            None => {
+                self.0 = Some(response.to_vec());
+                Step::Challenge(b"Password:".to_vec())
+            }
+            Some(user) => {
+                Step::Done(Credentials { authzid: Vec::new(),
+                                         authcid: user,
+                                         password: Some(response.to_vec()) })
+            }
+        }
+    }
+}
+
+
+//------------ CramMd5 -------------------------------------------------------
+
+/// The CRAM-MD5 mechanism defined in RFC 2195.
+///
+/// This needs the plain text password from the credential lookup.
+///
+pub struct CramMd5;
+
+impl SaslMechanism for CramMd5 {
+    fn name(&self) -> &[u8] { b"CRAM-MD5" }
+
+    fn start(&self, hostname: &[u8]) -> Box<Exchange> {
+        let mut challenge = Vec::new();
+        challenge.push(b'<');
+        challenge.extend_from_slice(&hex(&rand_bytes(8)));
+        challenge.push(b'.');
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                                   .map(|d| d.as_secs()).unwrap_or(0);
+        challenge.extend_from_slice(now.to_string().as_bytes());
+        challenge.push(b'@');
+        challenge.extend_from_slice(hostname);
+        challenge.push(b'>');
+        Box::new(CramMd5Exchange { challenge: challenge, sent: false })
+    }
+}
+
+struct CramMd5Exchange {
+    challenge: Vec<u8>,
+    sent: bool,
+}
+
+impl Exchange for CramMd5Exchange {
+    /// Processes the CRAM-MD5 response.
+    ///
+    /// The response is the user name followed by a space and the
+    /// lowercase hex representation of the keyed MD5 digest of the
+    /// challenge.
+    ///
+    fn step(&mut self, response: Option<&[u8]>, lookup: &CredentialLookup)
+            -> Step {
+        let response = match response {
+            // The client must not send an initial response.
+            Some(_) if !self.sent => return Step::Failed,
+            Some(response) => response,
+            None => {
+                self.sent = true;
+                return Step::Challenge(self.challenge.clone())
+            }
+        };
+        let pos = match response.iter().rposition(|ch| *ch == b' ') {
+            Some(pos) => pos,
+            None => return Step::Failed
+        };
+        let (user, digest) = (&response[..pos], &response[pos + 1..]);
+        let password = match lookup.password(user) {
+            Some(password) => password,
+            None => return Step::Failed
+        };
+        let expected = hex(&hmac(Type::MD5, &password, &self.challenge));
+        if equal_constant_time(&expected,
+                               &digest.to_ascii_lowercase()) {
+            Step::Done(Credentials { authzid: Vec::new(),
+                                     authcid: user.to_vec(),
+                                     password: None })
+        }
+        else {
+            Step::Failed
+        }
+    }
+}
+
+
+//------------ ScramSha256 ---------------------------------------------------
+
+/// The SCRAM-SHA-256 mechanism defined in RFC 7677.
+///
+/// Channel binding is not supported, so the -PLUS variant isn’t either.
+/// Passwords are used as they are without SASLprep normalization.
+///
+/// Salts for credentials derived on the fly are an HMAC of the user name
+/// keyed with a secret picked when the mechanism is created, so they stay
+/// the same from one exchange to the next. Users without credentials go
+/// through the same derivation with a dummy password and get a
+/// server-first-message just like everyone else. The exchange only fails
+/// at the client’s proof and doesn’t reveal whether a user exists.
+///
+pub struct ScramSha256 {
+    secret: Vec<u8>,
+}
+
+/// The iteration count used when deriving credentials on the fly.
+pub const SCRAM_ITERATIONS: u32 = 4096;
+
+impl ScramSha256 {
+    pub fn new() -> Self {
+        ScramSha256 { secret: rand_bytes(32) }
+    }
+}
+
+impl Default for ScramSha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaslMechanism for ScramSha256 {
+    fn name(&self) -> &[u8] { b"SCRAM-SHA-256" }
+
+    fn start(&self, _hostname: &[u8]) -> Box<Exchange> {
+        Box::new(ScramExchange::Start { secret: self.secret.clone() })
+    }
+}
+
+enum ScramExchange {
+    /// Waiting for the client-first-message.
+    Start {
+        secret: Vec<u8>,
+    },
+
+    /// Waiting for credentials to be derived from the password.
+    ///
+    /// If *known* is false, the user doesn’t have a password and the
+    /// derivation only happens for show.
+    Deriving {
+        secret: Vec<u8>,
+        known: bool,
+        gs2_header: Vec<u8>,
+        authzid: Vec<u8>,
+        authcid: Vec<u8>,
+        bare: Vec<u8>,
+        nonce: Vec<u8>,
+    },
+
+    /// Waiting for the client-final-message.
+    First {
+        gs2_header: Vec<u8>,
+        authzid: Vec<u8>,
+        authcid: Vec<u8>,
+        nonce: Vec<u8>,
+        credentials: ScramCredentials,
+        auth_message: Vec<u8>,
+    },
+
+    /// Waiting for the empty response after the server-final-message.
+    Final {
+        authzid: Vec<u8>,
+        authcid: Vec<u8>,
+    },
+
+    /// Something went wrong before.
+    Failed
+}
+
+impl Exchange for ScramExchange {
+    fn step(&mut self, response: Option<&[u8]>, lookup: &CredentialLookup)
+            -> Step {
+        let response = match response {
+            Some(response) => response,
+            None => {
+                if let ScramExchange::Start { .. } = *self {
+                    return Step::Challenge(Vec::new())
+                }
+                *self = ScramExchange::Failed;
+                return Step::Failed
+            }
+        };
+        let (next, step) = match ::std::mem::replace(self,
+                                                     ScramExchange::Failed) {
+            ScramExchange::Start { secret } => {
+                ScramExchange::client_first(response, lookup, &secret,
+                                            &base64::encode(&rand_bytes(18)))
+            }
+            ScramExchange::First { gs2_header, authzid, authcid, nonce,
+                                   credentials, auth_message } => {
+                ScramExchange::client_final(response, gs2_header, authzid,
+                                            authcid, nonce, credentials,
+                                            auth_message)
+            }
+            ScramExchange::Final { authzid, authcid } => {
+                if response.is_empty() {
+                    (ScramExchange::Failed,
+                     Step::Done(Credentials { authzid: authzid,
+                                              authcid: authcid,
+                                              password: None }))
+                }
+                else {
+                    (ScramExchange::Failed, Step::Failed)
+                }
+            }
+            _ => (ScramExchange::Failed, Step::Failed)
+        };
+        *self = next;
+        step
+    }
+
+    fn derived(&mut self, credentials: Option<ScramCredentials>) -> Step {
+        let (next, step) = match ::std::mem::replace(self,
+                                                     ScramExchange::Failed) {
+            ScramExchange::Deriving { secret, known, gs2_header, authzid,
+                                      authcid, bare, nonce } => {
+                let credentials = match credentials {
+                    Some(credentials) if known => credentials,
+                    _ => ScramCredentials::fake(&authcid, &secret)
+                };
+                ScramExchange::server_first(gs2_header, authzid, authcid,
+                                            &bare, nonce, credentials)
+            }
+            _ => (ScramExchange::Failed, Step::Failed)
+        };
+        *self = next;
+        step
+    }
+}
+
+impl ScramExchange {
+    /// Processes the client-first-message.
+    ///
+    /// > client-first-message = gs2-header client-first-message-bare
+    /// > gs2-header = gs2-cbind-flag "," [ authzid ] ","
+    /// > client-first-message-bare = [reserved-mext ","]
+    /// >                             username "," nonce ["," extensions]
+    ///
+    /// The server’s part of the nonce is given in *server_nonce*. The
+    /// mechanism’s secret for the salt of unknown users is *secret*.
+    fn client_first(response: &[u8], lookup: &CredentialLookup,
+                    secret: &[u8], server_nonce: &[u8]) -> (Self, Step) {
+        let failed = (ScramExchange::Failed, Step::Failed);
+
+        // gs2-header: we don’t do channel binding, so "p=" is out.
+        if response.len() < 3 || response[1] != b',' ||
+                (response[0] != b'n' && response[0] != b'y') {
+            return failed
+        }
+        let rest = &response[2..];
+        let comma = match rest.iter().position(|ch| *ch == b',') {
+            Some(comma) => comma,
+            None => return failed
+        };
+        let authzid = if comma == 0 { Vec::new() }
+                      else if rest.starts_with(b"a=") {
+                          match scram_name(&rest[2..comma]) {
+                              Some(authzid) => authzid,
+                              None => return failed
+                          }
+                      }
+                      else { return failed };
+        let gs2_header = response[..comma + 3].to_vec();
+        let bare = &rest[comma + 1..];
+
+        let mut attrs = bare.split(|ch| *ch == b',');
+        let authcid = match attrs.next() {
+            Some(attr) if attr.starts_with(b"n=") => {
+                match scram_name(&attr[2..]) {
+                    Some(authcid) => authcid,
+                    None => return failed
+                }
+            }
+            _ => return failed
+        };
+        let client_nonce = match attrs.next() {
+            Some(attr) if attr.starts_with(b"r=") && attr.len() > 2 => {
+                &attr[2..]
+            }
+            _ => return failed
+        };
+        let mut nonce = client_nonce.to_vec();
+        nonce.extend_from_slice(server_nonce);
+        if let Some(credentials) = lookup.scram_sha256(&authcid) {
+            return ScramExchange::server_first(gs2_header, authzid, authcid,
+                                               bare, nonce, credentials)
+        }
+        let salt = scram_salt(secret, &authcid);
+        let (known, password) = match lookup.password(&authcid) {
+            Some(password) => (true, password),
+            None => (false, hmac(Type::SHA256, secret, &salt))
+        };
+        let derivation = Derivation { authcid: authcid.clone(),
+                                      password: password, salt: salt };
+        (ScramExchange::Deriving { secret: secret.to_vec(), known: known,
+                                   gs2_header: gs2_header,
+                                   authzid: authzid,
+                                   authcid: authcid,
+                                   bare: bare.to_vec(),
+                                   nonce: nonce },
+         Step::Derive(derivation))
+    }
+
+    /// Produces the server-first-message.
+    ///
+    /// > server-first-message =
+    /// >                   [reserved-mext ","] nonce "," salt ","
+    /// >                   iteration-count ["," extensions]
+    ///
+    fn server_first(gs2_header: Vec<u8>, authzid: Vec<u8>,
+                    authcid: Vec<u8>, bare: &[u8], nonce: Vec<u8>,
+                    credentials: ScramCredentials) -> (Self, Step) {
+        let mut server_first = b"r=".to_vec();
+        server_first.extend_from_slice(&nonce);
+        server_first.extend_from_slice(b",s=");
+        server_first.extend_from_slice(&base64::encode(&credentials.salt));
+        server_first.extend_from_slice(b",i=");
+        server_first.extend_from_slice(credentials.iterations.to_string()
+                                                             .as_bytes());
+
+        let mut auth_message = bare.to_vec();
+        auth_message.push(b',');
+        auth_message.extend_from_slice(&server_first);
+        (ScramExchange::First { gs2_header: gs2_header, authzid: authzid,
+                                authcid: authcid, nonce: nonce,
+                                credentials: credentials,
+                                auth_message: auth_message },
+         Step::Challenge(server_first))
+    }
+
+    /// Processes the client-final-message.
+    ///
+    /// > client-final-message-without-proof =
+    /// >                            channel-binding "," nonce [","
+    /// >                            extensions]
+    /// > client-final-message =
+    /// >                            client-final-message-without-proof ","
+    /// >                            proof
+    ///
+    fn client_final(response: &[u8], gs2_header: Vec<u8>, authzid: Vec<u8>,
+                    authcid: Vec<u8>, nonce: Vec<u8>,
+                    credentials: ScramCredentials, mut auth_message: Vec<u8>)
+                    -> (Self, Step) {
+        let failed = (ScramExchange::Failed, Step::Failed);
+
+        let proof_pos = match response.windows(3).rposition(|w| w == b",p=") {
+            Some(pos) => pos,
+            None => return failed
+        };
+        let without_proof = &response[..proof_pos];
+        let proof = match base64::decode(&response[proof_pos + 3..]) {
+            Ok(proof) => proof,
+            Err(_) => return failed
+        };
+
+        let mut attrs = without_proof.split(|ch| *ch == b',');
+        match attrs.next() {
+            Some(attr) if attr.starts_with(b"c=") => {
+                if base64::decode(&attr[2..]) != Ok(gs2_header) {
+                    return failed
+                }
+            }
+            _ => return failed
+        }
+        match attrs.next() {
+            Some(attr) if attr.starts_with(b"r=") => {
+                if &attr[2..] != nonce.as_slice() { return failed }
+            }
+            _ => return failed
+        }
+
+        auth_message.push(b',');
+        auth_message.extend_from_slice(without_proof);
+        let signature = hmac(Type::SHA256, &credentials.stored_key,
+                             &auth_message);
+        if proof.len() != signature.len() {
+            return failed
+        }
+        let client_key: Vec<u8> = proof.iter().zip(signature.iter())
+                                       .map(|(p, s)| p ^ s).collect();
+        if !equal_constant_time(&hash::hash(Type::SHA256, &client_key),
+                                &credentials.stored_key) {
+            return failed
+        }
+
+        let mut server_final = b"v=".to_vec();
+        server_final.extend_from_slice(
+            &base64::encode(&hmac(Type::SHA256, &credentials.server_key,
+                                  &auth_message)));
+        (ScramExchange::Final { authzid: authzid, authcid: authcid },
+         Step::Challenge(server_final))
+    }
+}
+
+
+//------------ ScramCredentials ----------------------------------------------
+
+/// The stored credentials for SCRAM-SHA-256.
+///
+/// See RFC 5802, section 3.
+///
+#[derive(Clone, Debug)]
+pub struct ScramCredentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    /// Derives the credentials from a password, salt and iteration count.
+    pub fn derive(password: &[u8], salt: &[u8], iterations: u32) -> Self {
+        let salted = pbkdf2_hmac_sha256(password, salt, iterations);
+        let client_key = hmac(Type::SHA256, &salted, b"Client Key");
+        ScramCredentials {
+            salt: salt.to_vec(),
+            iterations: iterations,
+            stored_key: hash::hash(Type::SHA256, &client_key),
+            server_key: hmac(Type::SHA256, &salted, b"Server Key"),
+        }
+    }
+
+    /// Creates credentials for a user that doesn’t have any.
+    ///
+    /// The salt is the one a derivation for *authcid* would use. The keys
+    /// are random, so no proof will ever match them.
+    fn fake(authcid: &[u8], secret: &[u8]) -> Self {
+        ScramCredentials {
+            salt: scram_salt(secret, authcid),
+            iterations: SCRAM_ITERATIONS,
+            stored_key: rand_bytes(32),
+            server_key: rand_bytes(32),
+        }
+    }
+}
+
+
+//------------ Derivation ----------------------------------------------------
+
+/// A request to derive SCRAM-SHA-256 credentials from a password.
+///
+/// Like with `Credentials`, the `Debug` implementation leaves out the
+/// password.
+///
+#[derive(Clone)]
+pub struct Derivation {
+    /// The user the credentials are for.
+    pub authcid: Vec<u8>,
+
+    /// The user’s plain text password.
+    pub password: Vec<u8>,
+
+    /// The salt to derive the credentials with.
+    pub salt: Vec<u8>,
+}
+
+impl Derivation {
+    /// Derives the credentials.
+    ///
+    /// This runs `SCRAM_ITERATIONS` rounds of HMAC and should therefore
+    /// not be run on the event loop.
+    pub fn derive(&self) -> ScramCredentials {
+        ScramCredentials::derive(&self.password, &self.salt,
+                                 SCRAM_ITERATIONS)
+    }
+}
+
+impl fmt::Debug for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Derivation")
+         .field("authcid", &String::from_utf8_lossy(&self.authcid))
+         .finish()
+    }
+}
+
+
+//------------ ScramCache ----------------------------------------------------
+
+/// A cache for SCRAM-SHA-256 credentials derived from passwords.
+///
+/// Each set of credentials is kept together with a hash of the password
+/// it was derived from, so credentials are only derived once per
+/// password and salt. A changed password replaces the credentials. The
+/// passwords themselves are never kept.
+///
+/// The hash is an HMAC keyed with random bytes picked when the cache is
+/// created, so it is of no use outside of the cache.
+///
+/// The cache holds credentials for at most a given number of users.
+/// Once it is full, an arbitrary entry makes room for a new one.
+///
+pub struct ScramCache {
+    key: Vec<u8>,
+    capacity: usize,
+    entries: RefCell<HashMap<Vec<u8>, (Vec<u8>, ScramCredentials)>>,
+}
+
+/// The number of users a cache created via `ScramCache::new()` holds.
+pub const SCRAM_CACHE_SIZE: usize = 1024;
+
+impl ScramCache {
+    pub fn new() -> Self {
+        Self::with_capacity(SCRAM_CACHE_SIZE)
+    }
+
+    /// Creates a cache for credentials of at most *capacity* users.
+    pub fn with_capacity(capacity: usize) -> Self {
+        ScramCache { key: rand_bytes(32), capacity: capacity,
+                     entries: RefCell::new(HashMap::new()) }
+    }
+
+    /// Returns the credentials for *derivation* if they are known.
+    pub fn get(&self, derivation: &Derivation) -> Option<ScramCredentials> {
+        match self.entries.borrow().get(&derivation.authcid) {
+            Some(&(ref hash, ref credentials))
+                    if credentials.salt == derivation.salt &&
+                       equal_constant_time(hash, &self.hash(derivation)) => {
+                Some(credentials.clone())
+            }
+            _ => None
+        }
+    }
+
+    /// Keeps the *credentials* derived through *derivation*.
+    pub fn insert(&self, derivation: Derivation,
+                  credentials: ScramCredentials) {
+        if self.capacity == 0 {
+            return
+        }
+        let hash = self.hash(&derivation);
+        let mut entries = self.entries.borrow_mut();
+        if entries.len() >= self.capacity &&
+                !entries.contains_key(&derivation.authcid) {
+            let victim = entries.keys().next().cloned();
+            if let Some(victim) = victim {
+                entries.remove(&victim);
+            }
+        }
+        entries.insert(derivation.authcid, (hash, credentials));
+    }
+
+    /// Returns the hash of the password of *derivation*.
+    fn hash(&self, derivation: &Derivation) -> Vec<u8> {
+        hmac(Type::SHA256, &self.key, &derivation.password)
+    }
+}
+
+impl Default for ScramCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+//============ Helpers =======================================================
+
+/// Returns the salt for credentials of *authcid* derived on the fly.
+fn scram_salt(secret: &[u8], authcid: &[u8]) -> Vec<u8> {
+    let mut res = hmac(Type::SHA256, secret, authcid);
+    res.truncate(16);
+    res
+}
+
+/// Decodes a SCRAM saslname, replacing "=2C" and "=3D".
+fn scram_name(name: &[u8]) -> Option<Vec<u8>> {
+    let mut res = Vec::with_capacity(name.len());
+    let mut i = 0;
+    while i < name.len() {
+        if name[i] == b'=' {
+            if name[i + 1..].starts_with(b"2C") { res.push(b',') }
+            else if name[i + 1..].starts_with(b"3D") { res.push(b'=') }
+            else { return None }
+            i += 3;
+        }
+        else {
+            res.push(name[i]);
+            i += 1;
+        }
+    }
+    if res.is_empty() { None } else { Some(res) }
+}
+
+/// PBKDF2 as defined in RFC 2898 with HMAC-SHA-256 as the PRF.
+///
+/// Since we only ever need a key of the length of the hash, this only
+/// calculates the first block.
+fn pbkdf2_hmac_sha256(password: &[u8], salt: &[u8], iterations: u32)
+                      -> Vec<u8> {
+    let mut input = salt.to_vec();
+    input.extend_from_slice(&[0, 0, 0, 1]);
+    let mut u = hmac(Type::SHA256, password, &input);
+    let mut res = u.clone();
+    for _ in 1..iterations {
+        u = hmac(Type::SHA256, password, &u);
+        for (r, x) in res.iter_mut().zip(u.iter()) {
+            *r ^= *x
+        }
+    }
+    res
+}
+
+/// Returns the lowercase hex representation of *data*.
+fn hex(data: &[u8]) -> Vec<u8> {
+    const DIGITS: &'static [u8; 16] = b"0123456789abcdef";
+    let mut res = Vec::with_capacity(data.len() * 2);
+    for ch in data {
+        res.push(DIGITS[(*ch >> 4) as usize]);
+        res.push(DIGITS[(*ch & 0x0F) as usize]);
+    }
+    res
+}
+
+/// Compares two byte slices without bailing out early.
+///
+/// Use this for checking passwords so that the time taken doesn’t give
+/// away how much of a guess was right.
+pub fn equal_constant_time(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false
+    }
+    left.iter().zip(right.iter()).fold(0, |acc, (l, r)| acc | (l ^ r)) == 0
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use ::util::base64;
+    use super::*;
+
+    /// The users from the examples in RFC 2195 and RFC 7677.
+    struct Users;
+
+    impl CredentialLookup for Users {
+        fn password(&self, authcid: &[u8]) -> Option<Vec<u8>> {
+            match authcid {
+                b"tim" => Some(b"tanstaaftanstaaf".to_vec()),
+                b"user" => Some(b"pencil".to_vec()),
+                _ => None
+            }
+        }
+    }
+
+    /// The same users with stored SCRAM credentials.
+    struct Stored;
+
+    impl CredentialLookup for Stored {
+        fn scram_sha256(&self, authcid: &[u8]) -> Option<ScramCredentials> {
+            Users.password(authcid).map(|password| {
+                ScramCredentials::derive(&password, &salt(), 4096)
+            })
+        }
+    }
+
+    fn salt() -> Vec<u8> {
+        base64::decode(b"W22ZaJ0SNY7soEsUEjb6gQ==").unwrap()
+    }
+
+    fn challenge(step: Step) -> Vec<u8> {
+        match step {
+            Step::Challenge(challenge) => challenge,
+            step => panic!("expected challenge, got {:?}", step)
+        }
+    }
+
+    /// Completes the exchange from RFC 7677, section 3.
+    fn scram_final(mut exchange: ScramExchange, lookup: &CredentialLookup) {
+        assert_eq!(challenge(exchange.step(
+                        Some(b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAf\
+                               uxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjf\
+                               MHgsqmmiz7AndVQ="),
+                        lookup)),
+                   &b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="[..]);
+        match exchange.step(Some(b""), lookup) {
+            Step::Done(credentials) => {
+                assert_eq!(credentials.authcid, b"user");
+                assert!(credentials.authzid.is_empty());
+                assert!(credentials.password.is_none());
+            }
+            step => panic!("expected done, got {:?}", step)
+        }
+    }
+
+    const CLIENT_FIRST: &'static [u8] = b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+    const SERVER_NONCE: &'static [u8] = b"%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    const SECRET: &'static [u8] = b"secret";
+    const SERVER_FIRST: &'static [u8] =
+        b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+          s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+
+    #[test]
+    fn credentials_debug() {
+        let credentials = Credentials { authzid: Vec::new(),
+                                        authcid: b"tim".to_vec(),
+                                        password: Some(b"secret".to_vec()) };
+        let debug = format!("{:?}", credentials);
+        assert!(debug.contains("tim"));
+        assert!(!debug.contains("secret"));
+    }
+
+    #[test]
+    fn pbkdf2() {
+        // RFC 7914, section 11.
+        assert_eq!(hex(&pbkdf2_hmac_sha256(b"passwd", b"salt", 1)),
+                   &b"55ac046e56e3089fec1691c22544b605\
+                      f94185216dde0465e68b9d57c20dacbc"[..]);
+        assert_eq!(hex(&pbkdf2_hmac_sha256(b"Password", b"NaCl", 80000)),
+                   &b"4ddcd8f60b98be21830cee5ef22701f9\
+                      641a4418d04c0414aeff08876b34ab56"[..]);
+    }
+
+    #[test]
+    fn cram_md5() {
+        let challenge_text = b"<1896.697170952@postoffice.reston.mci.net>";
+        let mut exchange = CramMd5Exchange {
+            challenge: challenge_text.to_vec(), sent: false
+        };
+        assert_eq!(challenge(exchange.step(None, &Users)),
+                   &challenge_text[..]);
+        match exchange.step(Some(b"tim b913a602c7eda7a495b4e6e7334d3890"),
+                            &Users) {
+            Step::Done(credentials) => {
+                assert_eq!(credentials.authcid, b"tim");
+                assert!(credentials.password.is_none());
+            }
+            step => panic!("expected done, got {:?}", step)
+        }
+
+        let mut exchange = CramMd5Exchange {
+            challenge: challenge_text.to_vec(), sent: false
+        };
+        challenge(exchange.step(None, &Users));
+        match exchange.step(Some(b"tim b913a602c7eda7a495b4e6e7334d3891"),
+                            &Users) {
+            Step::Failed => { }
+            step => panic!("expected failure, got {:?}", step)
+        }
+    }
+
+    #[test]
+    fn scram_sha256_stored() {
+        let (exchange, step) = ScramExchange::client_first(CLIENT_FIRST,
+                                                           &Stored, SECRET,
+                                                           SERVER_NONCE);
+        assert_eq!(challenge(step), SERVER_FIRST);
+        scram_final(exchange, &Stored);
+    }
+
+    #[test]
+    fn scram_sha256_derived() {
+        let (mut exchange, step) = ScramExchange::client_first(CLIENT_FIRST,
+                                                               &Users, SECRET,
+                                                               SERVER_NONCE);
+        let mut derivation = match step {
+            Step::Derive(derivation) => derivation,
+            step => panic!("expected derive, got {:?}", step)
+        };
+        assert_eq!(derivation.authcid, b"user");
+        assert_eq!(derivation.salt, scram_salt(SECRET, b"user"));
+
+        // Use the salt from the RFC so we get its messages.
+        derivation.salt = salt();
+        let cache = ScramCache::new();
+        assert!(cache.get(&derivation).is_none());
+        cache.insert(derivation.clone(), derivation.derive());
+        let credentials = cache.get(&derivation).unwrap();
+        assert_eq!(credentials.salt, salt());
+        assert!(cache.get(&Derivation { authcid: b"user".to_vec(),
+                                        password: b"pen".to_vec(),
+                                        salt: salt() })
+                     .is_none());
+        assert!(cache.get(&Derivation { authcid: b"user".to_vec(),
+                                        password: b"pencil".to_vec(),
+                                        salt: b"salt".to_vec() })
+                     .is_none());
+
+        assert_eq!(challenge(exchange.derived(Some(credentials))),
+                   SERVER_FIRST);
+        scram_final(exchange, &Users);
+    }
+
+    #[test]
+    fn scram_cache() {
+        let derivation = |authcid: &[u8]| {
+            Derivation { authcid: authcid.to_vec(),
+                         password: b"pencil".to_vec(), salt: salt() }
+        };
+        let credentials = ScramCredentials::derive(b"pencil", &salt(), 1);
+        let cache = ScramCache::with_capacity(2);
+        for authcid in &[&b"a"[..], b"b", b"c"] {
+            cache.insert(derivation(authcid), credentials.clone());
+            assert!(cache.get(&derivation(authcid)).is_some());
+        }
+        let entries = cache.entries.borrow();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.values().all(|&(ref hash, _)| hash != b"pencil"));
+        assert!(!format!("{:?}", derivation(b"a")).contains("pencil"));
+    }
+
+    #[test]
+    fn scram_sha256_unknown_user() {
+        const NOBODY_FIRST: &'static [u8] =
+            b"n,,n=nobody,r=rOprNGfwEbeRWgbNEkqO";
+        const CLIENT_FINAL: &'static [u8] =
+            b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+              p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+
+        // Known and unknown users both go through a derivation ...
+        let first = |client_first: &[u8]| {
+            let (mut exchange, step) = ScramExchange::client_first(
+                client_first, &Users, SECRET, SERVER_NONCE
+            );
+            let derivation = match step {
+                Step::Derive(derivation) => derivation,
+                step => panic!("expected derive, got {:?}", step)
+            };
+            assert_eq!(derivation.salt,
+                       scram_salt(SECRET, &derivation.authcid));
+            let reply = challenge(exchange.derived(Some(derivation.derive())));
+            (exchange, reply)
+        };
+        let (_, user) = first(CLIENT_FIRST);
+        let (mut exchange, nobody) = first(NOBODY_FIRST);
+
+        // ... and get server-first-messages that only differ in the salt
+        // ...
+        let prefix = &SERVER_FIRST[..SERVER_FIRST.len() - 31];
+        assert!(user.starts_with(prefix) && nobody.starts_with(prefix));
+        assert!(user.ends_with(b"==,i=4096"));
+        assert!(nobody.ends_with(b"==,i=4096"));
+        assert_eq!(user.len(), SERVER_FIRST.len());
+        assert_eq!(nobody.len(), SERVER_FIRST.len());
+        assert!(user != nobody);
+
+        // ... which stays the same every time.
+        assert_eq!(first(CLIENT_FIRST).1, user);
+        assert_eq!(first(NOBODY_FIRST).1, nobody);
+
+        // The exchange for the unknown user only fails at the proof.
+        match exchange.step(Some(CLIENT_FINAL), &Users) {
+            Step::Failed => { }
+            step => panic!("expected failure, got {:?}", step)
+        }
+
+        // A known user whose credentials couldn’t be derived gets the
+        // same server-first-message as always.
+        let (mut exchange, _) = ScramExchange::client_first(CLIENT_FIRST,
+                                                            &Users, SECRET,
+                                                            SERVER_NONCE);
+        assert_eq!(challenge(exchange.derived(None)), user);
+    }
+
+    #[test]
+    fn scram_sha256_wrong_proof() {
+        let (mut exchange, _) = ScramExchange::client_first(CLIENT_FIRST,
+                                                            &Stored, SECRET,
+                                                            SERVER_NONCE);
+        match exchange.step(Some(b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2Ra\
+                                   TCAfuxFIlj)hNlF$k0,p=eHzbZapWIk4jUhN+Ute9\
+                                   ytag9zjfMHgsqmmiz7AndVQ="),
+                            &Stored) {
+            Step::Failed => { }
+            step => panic!("expected failure, got {:?}", step)
+        }
+    }
+}
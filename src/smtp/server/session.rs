@@ -1,16 +1,22 @@
 //! An SMTP session.
 
+use std::cmp::min;
 use std::marker::PhantomData;
+use std::net::IpAddr;
 use std::rc::Rc;
 use netmachines::sockets::Certificate;
 use rotor::Notifier;
+use ::smtp::dotstuff::Unstuffer;
 use ::smtp::syntax::{self, Command};
+use ::util::{base64, date};
 use super::buf::{RecvBuf, SendBuf};
-use super::config::Config;
+use super::config::{Capabilities, Config};
 use super::protocol::{AncillaryHandler, DataHandler, Hesitant, Protocol,
                       SessionHandler, MailHandler, Undecided,
                       UndecidedReply};
 use super::reply::{ReplyBuf, Reply};
+use super::sasl::{self, CredentialLookup};
+use super::trace::Trace;
 
 
 //------------ Action -------------------------------------------------------
@@ -43,41 +49,108 @@ pub enum Action {
     Close
 }
 
+impl Action {
+    /// Returns the action for a reply that may or may not be held back.
+    ///
+    /// If *collect* is `true`, the reply can wait for the replies to any
+    /// further pipelined commands, otherwise it has to be sent right
+    /// away. Other actions than writing aren’t affected.
+    fn collect(self, collect: bool) -> Self {
+        match self {
+            Action::Write | Action::Collect => {
+                if collect { Action::Collect }
+                else { Action::Write }
+            }
+            action => action
+        }
+    }
+}
+
+
+//------------ Phase ---------------------------------------------------------
+
+/// What the session is waiting for.
+///
+/// This determines which timeout applies.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// The first command after the greeting.
+    Greeting,
+
+    /// The next command.
+    Command,
+
+    /// Message data.
+    Data,
+
+    /// A decision from the protocol.
+    ///
+    /// If it takes too long, the client gets a 421 reply before it gives
+    /// up on us and whatever the protocol decides later is dropped.
+    Processing,
+}
+
 
 //------------ Session -------------------------------------------------------
 
 pub struct Session<P: Protocol> {
     state: State<P>,
     config: Rc<Config>,
+    status: Status,
 }
 
 impl<P: Protocol> Session<P> {
-    pub fn new(seed: <P::Session as SessionHandler<P>>::Seed,
+    /// Creates a session for a client connecting from *peer*.
+    pub fn new(seed: <P::Session as SessionHandler<P>>::Seed, peer: IpAddr,
                config: Rc<Config>, notifier: Notifier, send: &mut SendBuf)
                -> (Self, Action) {
         let (state, action) = Start::recv(seed, notifier)
                                     .process(send, &config);
-        (Session { state: state, config: config }, action)
+        (Session { state: state, config: config,
+                   status: Status::new(Some(peer)) },
+         action)
+    }
+
+    /// Creates a session for a connection that was denied access.
+    ///
+    /// The client is greeted with a 554 reply and the connection closed.
+    pub fn reject(config: Rc<Config>, send: &mut SendBuf) -> (Self, Action) {
+        scribble!(send, b"554 5.7.1 ", config.hostname(),
+                  b" Access denied\r\n");
+        (Session { state: State::Dead, config: config,
+                   status: Status::new(None) },
+         Action::Close)
     }
 
     pub fn recv(mut self, recv: &mut RecvBuf, send: &mut SendBuf,
                 is_secure: bool) -> (Self, Action) {
         let (state, action) = match self.state {
             State::Idle(idle) => idle.recv(recv, send, is_secure,
-                                           &self.config),
+                                           &self.config, &mut self.status),
             State::Wait(wait) => (State::Wait(wait), Action::Wait),
-            State::Data(data) => data.recv(recv, send),
+            State::Data(data) => data.recv(recv, send, &self.config),
+            State::Chunk(chunk) => chunk.recv(recv, send),
+            State::Chunking(chunking) => chunking.recv(recv, send,
+                                                       &self.config),
+            State::Auth(auth) => auth.recv(recv, send, &mut self.status),
             State::Dead => Session::recv_dead(recv, send)
         };
         self.state = state;
+        let action = self.pipeline(action);
         (self, action)
     }
 
-    pub fn wakeup(mut self, send: &mut SendBuf, is_secure: bool)
+    /// Processes a deferred decision.
+    ///
+    /// If *more* is `true`, there is data waiting in the receive buffer.
+    pub fn wakeup(mut self, send: &mut SendBuf, more: bool, is_secure: bool)
                   -> (Self, Action) {
         if let State::Wait(wait) = self.state {
-            let (state, action) = wait.wakeup(send, &self.config, is_secure);
+            let (state, action) = wait.wakeup(send, &self.config, more,
+                                              is_secure, &mut self.status);
             self.state = state;
+            let action = self.pipeline(action);
             (self, action)
         }
         else {
@@ -85,9 +158,36 @@ impl<P: Protocol> Session<P> {
         }
     }
 
-    pub fn confirm_tls<C: Certificate>(mut self, peer_cert: Option<C>)
+    /// Gives up on the client after a timeout.
+    pub fn timeout(self, send: &mut SendBuf) -> (Self, Action) {
+        send.reply(421, (4,4,2), b"Timeout\r\n");
+        (Session { state: State::Dead, config: self.config,
+                   status: self.status },
+         Action::Close)
+    }
+
+    pub fn phase(&self) -> Phase {
+        match self.state {
+            State::Idle(Idle(Level::Early(_))) => Phase::Greeting,
+            State::Idle(_) | State::Chunking(_) | State::Auth(_)
+                | State::Dead => Phase::Command,
+            State::Data(_) | State::Chunk(_) => Phase::Data,
+            State::Wait(_) => Phase::Processing,
+        }
+    }
+
+    /// Finishes the TLS handshake.
+    ///
+    /// The *cipher* describes the cipher negotiated for the Received
+    /// field if the stream knows it.
+    pub fn confirm_tls<C: Certificate>(mut self, peer_cert: Option<C>,
+                                       cipher: Option<Vec<u8>>)
                                        -> (Self, Action) {
         if let State::Idle(idle) = self.state {
+            // RFC 3207 wants us to forget everything we learned before
+            // the TLS handshake.
+            self.status = Status::new(self.status.peer);
+            self.status.cipher = cipher;
             let (state, action) = idle.confirm_tls(peer_cert);
             self.state = state;
             (self, action)
@@ -101,9 +201,15 @@ impl<P: Protocol> Session<P> {
 
 
 impl<P: Protocol> Session<P> {
+    /// Only holds back replies if the client was offered pipelining.
+    fn pipeline(&self, action: Action) -> Action {
+        if self.status.capabilities.pipelining { action }
+        else { action.collect(false) }
+    }
+
     fn recv_dead(recv: &mut RecvBuf, send: &mut SendBuf)
                  -> (State<P>, Action) {
-        let res = recv.parse_command(|cmd| match cmd {
+        let res = recv.parse_command(|cmd, _| match cmd {
             Some(Command::Quit) => {
                 send.reply(221, (2, 0, 0), b"Bye\r\n");
                 Action::Close
@@ -123,7 +229,7 @@ impl<P: Protocol> Session<P> {
                            b"Please leave now\r\n");
                 Action::Write
             },
-            None => Action::Write,
+            None => Action::Read,
         });
         match res {
             Ok(action) => (State::Dead, action),
@@ -133,6 +239,76 @@ impl<P: Protocol> Session<P> {
 }
 
 
+//------------ Status --------------------------------------------------------
+
+/// Information about the session kept outside of the state machine.
+///
+struct Status {
+    /// Has the client successfully authenticated?
+    authenticated: bool,
+
+    /// Did the current mail transaction ask for BODY=BINARYMIME?
+    ///
+    /// If so, the message must be transferred with BDAT.
+    binarymime: bool,
+
+    /// The extensions advertised in the last EHLO reply.
+    capabilities: Capabilities,
+
+    /// The address of the client.
+    peer: Option<IpAddr>,
+
+    /// The domain given in the last HELO or EHLO.
+    helo: Option<Vec<u8>>,
+
+    /// Was the last hello an EHLO?
+    esmtp: bool,
+
+    /// The path of the RCPT command currently being processed.
+    rcpt: Option<Vec<u8>>,
+
+    /// The accepted recipients of the current mail transaction.
+    recipients: Vec<Vec<u8>>,
+
+    /// The description of the TLS cipher if it is known.
+    cipher: Option<Vec<u8>>,
+}
+
+impl Status {
+    fn new(peer: Option<IpAddr>) -> Self {
+        Status { authenticated: false, binarymime: false,
+                 capabilities: Capabilities::none(), peer: peer,
+                 helo: None, esmtp: false, rcpt: None,
+                 recipients: Vec::new(), cipher: None }
+    }
+
+    /// Records the hello of the client.
+    fn hello<D: ToString>(&mut self, domain: &D, esmtp: bool) {
+        self.helo = Some(domain.to_string().into_bytes());
+        self.esmtp = esmtp;
+    }
+
+    /// Returns the trace information for a message received now.
+    fn trace(&self, config: &Config, is_secure: bool) -> Trace {
+        let mut res = Trace::new(config.hostname(), date::now());
+        if let Some(ref helo) = self.helo {
+            res.set_helo(helo)
+        }
+        if let Some(peer) = self.peer {
+            res.set_peer(peer)
+        }
+        res.set_protocol(self.esmtp, is_secure, self.authenticated);
+        if let (true, Some(cipher)) = (is_secure, self.cipher.as_ref()) {
+            res.set_cipher(cipher)
+        }
+        if self.recipients.len() == 1 {
+            res.set_recipient(&self.recipients[0])
+        }
+        res
+    }
+}
+
+
 //------------ State ---------------------------------------------------------
 
 enum State<P: Protocol> {
@@ -145,6 +321,15 @@ enum State<P: Protocol> {
     /// Reading message data.
     Data(ReadData<P>),
 
+    /// Reading the octets of a BDAT chunk.
+    Chunk(ReadChunk<P>),
+
+    /// Between two BDAT chunks.
+    Chunking(Chunking<P>),
+
+    /// Reading responses of a SASL exchange.
+    Auth(ReadAuth<P>),
+
     /// Waiting for a QUIT.
     Dead
 }
@@ -168,6 +353,24 @@ impl<P: Protocol> From<ReadData<P>> for State<P> {
     }
 }
 
+impl<P: Protocol> From<ReadChunk<P>> for State<P> {
+    fn from(chunk: ReadChunk<P>) -> State<P> {
+        State::Chunk(chunk)
+    }
+}
+
+impl<P: Protocol> From<Chunking<P>> for State<P> {
+    fn from(chunking: Chunking<P>) -> State<P> {
+        State::Chunking(chunking)
+    }
+}
+
+impl<P: Protocol> From<ReadAuth<P>> for State<P> {
+    fn from(auth: ReadAuth<P>) -> State<P> {
+        State::Auth(auth)
+    }
+}
+
 
 //------------ Idle ----------------------------------------------------------
 
@@ -190,58 +393,149 @@ impl<P: Protocol> Idle<P> {
 
 impl<P: Protocol> Idle<P> {
     fn recv(self, recv: &mut RecvBuf, send: &mut SendBuf, is_secure: bool,
-            config: &Rc<Config>) -> (State<P>, Action) {
-        let res = recv.parse_command(|cmd| match cmd {
-            Some(Command::Helo(domain))
-                => Helo::recv(self, domain).process(send, config),
-            Some(Command::Ehlo(domain))
-                => Ehlo::recv(self, domain).process(send, config, is_secure),
-            Some(Command::Mail(path, params))
-                => Mail::recv(self, path, params, send).process(),
-            Some(Command::Rcpt(path, params))
-                => Rcpt::recv(self, path, params, send).process(),
-            Some(Command::Data)
-                => Data::recv(self, send).process(send),
-            Some(Command::Rset)
+            config: &Rc<Config>, status: &mut Status) -> (State<P>, Action) {
+        let res = recv.parse_command(|cmd, more| match cmd {
+            Some(cmd) => {
+                let collect = cmd.allow_pipeline();
+                let (state, action) = self.command(cmd, more, send,
+                                                   is_secure, config,
+                                                   status);
+                (state, action.collect(collect))
+            }
+            None => (State::Idle(self), Action::Read),
+        });
+        match res {
+            Ok((state, action)) => (state, action),
+            Err(()) => (State::Dead, Action::Close)
+        }
+    }
+
+    /// Processes a command.
+    ///
+    /// If *more* is `true`, the client has sent more data after the
+    /// command.
+    fn command(self, cmd: Command, more: bool, send: &mut SendBuf,
+               is_secure: bool, config: &Rc<Config>, status: &mut Status)
+               -> (State<P>, Action) {
+        match cmd {
+            Command::Helo(domain) => {
+                status.hello(&domain, false);
+                Helo::recv(self, domain).process(send, config, status)
+            }
+            Command::Ehlo(domain) => {
+                status.hello(&domain, true);
+                Ehlo::recv(self, domain).process(send, config, is_secure,
+                                                 status)
+            }
+            Command::Mail(path, mut params) => {
+                if !mail_params_offered(&params, &status.capabilities) {
+                    return self.unoffered_param(send)
+                }
+                if !status.authenticated {
+                    params.auth = None
+                }
+                // A nested MAIL is rejected and the transaction goes on.
+                let in_mail = if let Level::Mail(_) = self.0 { true }
+                              else { false };
+                if !in_mail {
+                    status.recipients.clear();
+                    status.binarymime = match params.body {
+                        Some(syntax::BodyValue::BinaryMime) => true,
+                        _ => false
+                    };
+                }
+                Mail::recv(self, path, params, send,
+                           config.message_size_limit()).process()
+            }
+            Command::Rcpt(path, params) => {
+                if !rcpt_params_offered(&params, &status.capabilities) {
+                    return self.unoffered_param(send)
+                }
+                status.rcpt = Some(path.to_string().into_bytes());
+                Rcpt::recv(self, path, params, send).process(status)
+            }
+            Command::Data => {
+                let in_mail = if let Level::Mail(_) = self.0 { true }
+                              else { false };
+                if in_mail && status.binarymime {
+                    send.reply(503, (5,5,1), b"BINARYMIME requires BDAT\r\n");
+                    (self.into(), Action::Write)
+                }
+                else {
+                    match Data::recv(self, send) {
+                        Ok(data) => data.process(send, more, config,
+                                                 is_secure, status),
+                        Err(idle) => (idle.into(), Action::Write)
+                    }
+                }
+            }
+            Command::Bdat { size, last } if !status.capabilities.chunking => {
+                // The chunk follows right away and needs skipping.
+                let reply = if let Level::Early(_) = self.0 {
+                    (503, (5,5,1), &b"Please say 'Hello' first\r\n"[..])
+                }
+                else {
+                    (502, (5,5,1), &b"Command not implemented\r\n"[..])
+                };
+                ReadChunk::new(ChunkSink::Discard(self, reply), size, last,
+                               size)
+                          .start(send)
+            }
+            Command::Bdat { size, last }
+                => Bdat::recv(self, size, last).process(send, config,
+                                                        is_secure, status),
+            Command::Rset
                 => Rset::recv(self, send),
-            Some(Command::Vrfy(what, params))
-                => Vrfy::recv(self, what, params, send).process(),
-            Some(Command::Expn(what, params))
-                => Expn::recv(self, what, params, send).process(),
-            Some(Command::Help(what))
+            Command::Vrfy(what, params) => {
+                if params.smtputf8.is_some()
+                        && !status.capabilities.smtputf8 {
+                    return self.unoffered_param(send)
+                }
+                Vrfy::recv(self, what, params, send).process()
+            }
+            Command::Expn(what, params) => {
+                if params.smtputf8.is_some()
+                        && !status.capabilities.smtputf8 {
+                    return self.unoffered_param(send)
+                }
+                Expn::recv(self, what, params, send).process()
+            }
+            Command::Help(what)
                 => Help::recv(self, what, send).process(),
-            Some(Command::Noop) => {
+            Command::Etrn(_) if !status.capabilities.etrn
+                => self.unoffered(send),
+            Command::Etrn(node)
+                => Etrn::recv(self, node, send).process(),
+            Command::Noop => {
                 send.reply(250, (2,2,0), b"Ok\r\n");
                 (self.into(), Action::Write)
             }
-            Some(Command::Quit) => {
+            Command::Quit => {
                 send.reply(221, (2,0,0), b"Bye\r\n");
                 (State::Dead, Action::Close)
             }
-            Some(Command::StartTls) => {
-                if is_secure {
-                    send.reply(500, (5,5,2), b"Unrecognized command\r\n");
-                    (self.into(), Action::Write)
+            Command::StartTls => {
+                if !status.capabilities.starttls {
+                    self.unoffered(send)
                 }
                 else {
                     send.reply(220, (2,7,0), b"Ready to start TLS\r\n");
                     (self.into(), Action::StartTls)
                 }
             }
-            Some(Command::Auth{..}) | 
-            Some(Command::Unrecognized) => {
+            Command::Auth { .. } if !status.capabilities.auth
+                => self.unoffered(send),
+            Command::Auth { mechanism, initial }
+                => Auth::recv(self, mechanism, initial, send, config,
+                              is_secure, status),
+            Command::Unrecognized => {
                 send.reply(500, (5,5,2), b"Unrecognized command.\r\n");
                 (State::Idle(self), Action::Write)
             }
-            Some(Command::ParameterError) => {
+            Command::ParameterError => {
                 send.reply(501, (5,5,4), b"Error in command parameters.\r\n");
                 (State::Idle(self), Action::Write)
             }
-            None => (State::Idle(self), Action::Read),
-        });
-        match res {
-            Ok((state, action)) => (state, action),
-            Err(()) => (State::Dead, Action::Close)
         }
     }
 
@@ -249,6 +543,29 @@ impl<P: Protocol> Idle<P> {
                                    -> (State<P>, Action) {
         CheckTls::recv(self, peer_cert).process()
     }
+
+    /// Rejects a command of an extension that hasn’t been advertised.
+    fn unoffered(self, send: &mut SendBuf) -> (State<P>, Action) {
+        if let Level::Early(_) = self.0 {
+            send.reply(503, (5,5,1), b"Please say 'Hello' first\r\n");
+        }
+        else {
+            send.reply(502, (5,5,1), b"Command not implemented\r\n");
+        }
+        (self.into(), Action::Write)
+    }
+
+    /// Rejects a command with a parameter that hasn’t been advertised.
+    fn unoffered_param(self, send: &mut SendBuf) -> (State<P>, Action) {
+        if let Level::Early(_) = self.0 {
+            send.reply(503, (5,5,1), b"Please say 'Hello' first\r\n");
+        }
+        else {
+            send.reply(555, (5,5,4), b"Parameter not recognized or not \
+                                       implemented\r\n");
+        }
+        (self.into(), Action::Write)
+    }
 }
 
 
@@ -261,29 +578,44 @@ enum Wait<P: Protocol> {
     Mail(<P::Session as SessionHandler<P>>::Mail),
     Rcpt(<P::Mail as MailHandler<P>>::Recipient),
     Data(<P::Mail as MailHandler<P>>::Data),
+    Bdat(<P::Mail as MailHandler<P>>::Data, u64, bool),
     Vrfy(WaitVrfy<P>),
     Expn(WaitExpn<P>),
     Help(WaitHelp<P>),
+    Etrn(<P::Session as SessionHandler<P>>::Etrn),
     CheckTls(<P::Session as SessionHandler<P>>::CheckTls),
+    Auth(<P::Session as SessionHandler<P>>::Auth),
+    Derive(WaitDerive<P>),
     DataComplete(<P::Data as DataHandler<P>>::Complete),
 }
 
 
 impl<P: Protocol> Wait<P> {
-    fn wakeup(self, send: &mut SendBuf, config: &Rc<Config>, is_secure: bool)
-              -> (State<P>, Action) {
+    fn wakeup(self, send: &mut SendBuf, config: &Rc<Config>, more: bool,
+              is_secure: bool, status: &mut Status) -> (State<P>, Action) {
         match self {
             Wait::Start(defer) => Start::wakeup(defer).process(send, config),
-            Wait::Helo(defer) => Helo::wakeup(defer).process(send, config),
+            Wait::Helo(defer)
+                => Helo::wakeup(defer).process(send, config, status),
             Wait::Ehlo(defer)
-                => Ehlo::wakeup(defer).process(send, config, is_secure),
+                => Ehlo::wakeup(defer).process(send, config, is_secure,
+                                               status),
             Wait::Mail(defer) => Mail::wakeup(defer, send).process(),
-            Wait::Rcpt(defer) => Rcpt::wakeup(defer, send).process(),
-            Wait::Data(defer) => Data::wakeup(defer).process(send),
+            Wait::Rcpt(defer) => Rcpt::wakeup(defer, send).process(status),
+            Wait::Data(defer)
+                => Data::wakeup(defer).process(send, more, config, is_secure,
+                                               status),
+            Wait::Bdat(defer, size, last)
+                => Bdat::wakeup(defer, size, last).process(send, config,
+                                                           is_secure, status),
             Wait::Vrfy(defer) => Vrfy::wakeup(defer, send).process(),
             Wait::Expn(defer) => Expn::wakeup(defer, send).process(),
             Wait::Help(defer) => Help::wakeup(defer, send).process(),
+            Wait::Etrn(defer) => Etrn::wakeup(defer, send).process(),
             Wait::CheckTls(defer) => CheckTls::wakeup(defer).process(),
+            Wait::Auth(defer)
+                => AuthCheck::wakeup(defer).process(send, status),
+            Wait::Derive(defer) => defer.wakeup(send, status),
             Wait::DataComplete(defer)
                 => DataComplete::wakeup(defer, send).process(),
         }
@@ -293,35 +625,250 @@ impl<P: Protocol> Wait<P> {
 
 //------------ ReadData ------------------------------------------------------
 
-pub struct ReadData<P: Protocol>(P::Data);
+/// Reading message data after a DATA command.
+///
+/// The data is unstuffed and passed on to the data handler as it arrives.
+/// If the message grows beyond the size limit or contains a bare LF or
+/// bare CR, the rest of it is dropped and the transaction fails once the
+/// end of data is reached.
+///
+/// Bare line endings are refused since servers further down the line may
+/// take them for CRLF and find an end of data where we didn’t. Whatever
+/// follows it would then be run as commands.
+///
+pub struct ReadData<P: Protocol> {
+    data: P::Data,
+    unstuffer: Unstuffer,
+
+    /// Buffer for the unstuffed data, kept for reuse.
+    buf: Vec<u8>,
+
+    /// The number of octets of the message received so far.
+    size: u64,
+
+    /// Has the message exceeded the size limit?
+    exceeded: bool,
+}
 
 
 impl<P: Protocol> ReadData<P> {
     fn new(data: P::Data) -> Self {
-        ReadData(data)
+        ReadData { data: data, unstuffer: Unstuffer::new(), buf: Vec::new(),
+                   size: 0, exceeded: false }
+    }
+
+    fn recv(mut self, recv: &mut RecvBuf, send: &mut SendBuf,
+            config: &Rc<Config>) -> (State<P>, Action) {
+        let end = self.unstuffer.decode(recv.as_slice(), &mut self.buf);
+        let len = match end {
+            Some(len) => len,
+            None => recv.len()
+        };
+        recv.advance(len);
+        self.feed(config.message_size_limit());
+        if end.is_some() {
+            self.complete(send)
+        }
+        else {
+            (State::Data(self), Action::Read)
+        }
+    }
+
+    /// Passes the unstuffed data on unless we are over the limit.
+    fn feed(&mut self, limit: u64) {
+        self.size += self.buf.len() as u64;
+        if exceeds_limit(self.size, limit) {
+            self.exceeded = true;
+        }
+        if !self.exceeded && !self.is_bare() && !self.buf.is_empty() {
+            self.data.chunk(&self.buf);
+        }
+        self.buf.clear();
+    }
+
+    /// Has the data contained a bare LF or bare CR so far?
+    fn is_bare(&self) -> bool {
+        self.unstuffer.bare_lf() || self.unstuffer.bare_cr()
+    }
+
+    fn complete(self, send: &mut SendBuf) -> (State<P>, Action) {
+        if self.exceeded {
+            send.reply(552, (5,3,4), b"Message size exceeds fixed \
+                                       maximum message size\r\n");
+            (Idle::greeted(self.data.reset()).into(),
+             Action::Collect)
+        }
+        else if self.is_bare() {
+            debug!("SMTP server: message data contains bare LF or CR");
+            send.reply(550, (5,6,0), b"Bare LF or CR in message data\r\n");
+            (Idle::greeted(self.data.reset()).into(),
+             Action::Collect)
+        }
+        else {
+            DataComplete::recv(self.data, send).process()
+        }
+    }
+}
+
+
+//------------ ReadChunk -----------------------------------------------------
+
+/// Reading the octets of a BDAT chunk.
+///
+/// The octets are passed on as they are, there is no dot-transparency
+/// with BDAT.
+///
+pub struct ReadChunk<P: Protocol> {
+    sink: ChunkSink<P>,
+    size: u64,
+    remaining: u64,
+    last: bool,
+
+    /// The size of the message including this chunk.
+    total: u64,
+}
+
+/// Where the octets of a chunk go.
+enum ChunkSink<P: Protocol> {
+    /// Feed them to the data handler.
+    Data(P::Data),
+
+    /// Drop them, then send the reply and continue with the idle state.
+    ///
+    /// This is necessary because the client sends the chunk right after
+    /// the BDAT command regardless of whether we like it.
+    Discard(Idle<P>, ChunkReply),
+}
+
+/// The reply to a dropped chunk.
+///
+/// This is the reply code, the enhanced status code, and the text.
+type ChunkReply = (u16, (u8, u16, u16), &'static [u8]);
+
+impl<P: Protocol> ReadChunk<P> {
+    fn new(sink: ChunkSink<P>, size: u64, last: bool, total: u64) -> Self {
+        ReadChunk { sink: sink, size: size, remaining: size, last: last,
+                    total: total }
+    }
+
+    /// Creates a chunk for the data handler.
+    ///
+    /// *total* is the size of the message before this chunk. If the chunk
+    /// would grow the message beyond the size limit, the transaction is
+    /// aborted and the chunk dropped.
+    fn data(data: P::Data, size: u64, last: bool, total: u64,
+            config: &Rc<Config>) -> Self {
+        let total = total.saturating_add(size);
+        if exceeds_limit(total, config.message_size_limit()) {
+            ReadChunk::new(ChunkSink::Discard(Idle::greeted(data.reset()),
+                                              (552, (5,3,4),
+                                               &b"Message size exceeds \
+                                                  fixed maximum message \
+                                                  size\r\n"[..])),
+                           size, last, total)
+        }
+        else {
+            ReadChunk::new(ChunkSink::Data(data), size, last, total)
+        }
+    }
+
+    /// Starts reading the chunk.
+    ///
+    /// Since the chunk may well be in the receive buffer already, this
+    /// asks for collecting. An empty chunk is complete right away.
+    fn start(self, send: &mut SendBuf) -> (State<P>, Action) {
+        if self.remaining == 0 {
+            self.complete(send)
+        }
+        else {
+            (self.into(), Action::Collect)
+        }
     }
 
     fn recv(mut self, recv: &mut RecvBuf, send: &mut SendBuf)
             -> (State<P>, Action) {
-        if let Some(idx) = recv.find_data_end() {
-            self.0.chunk(&recv.as_slice()[0..idx]);
-            recv.advance(idx + 5);
-            DataComplete::recv(self.0, send).process()
+        let len = min(self.remaining, recv.len() as u64) as usize;
+        if let ChunkSink::Data(ref mut data) = self.sink {
+            data.chunk(&recv.as_slice()[..len]);
+        }
+        recv.advance(len);
+        self.remaining -= len as u64;
+        if self.remaining > 0 {
+            (self.into(), Action::Read)
         }
         else {
-            let end = {
-                let slice = recv.as_slice();
-                if slice.len() >= 5 {
-                    let end = slice.len() - 5;
-                    self.0.chunk(&recv.as_slice()[0..end]);
-                    end
+            self.complete(send)
+        }
+    }
+
+    fn complete(self, send: &mut SendBuf) -> (State<P>, Action) {
+        match self.sink {
+            ChunkSink::Data(data) => {
+                if self.last {
+                    DataComplete::recv(data, send).process()
                 }
                 else {
-                    0
+                    let mut reply = Reply::new(send, 250, Some((2,0,0)));
+                    scribble!(&mut reply, self.size,
+                              b" octets received\r\n");
+                    (Chunking(data, self.total).into(), Action::Collect)
                 }
-            };
-            recv.advance(end);
-            (State::Data(self), Action::Read)
+            }
+            ChunkSink::Discard(idle, (code, status, text)) => {
+                send.reply(code, status, text);
+                (idle.into(), Action::Collect)
+            }
+        }
+    }
+}
+
+
+//------------ Chunking ------------------------------------------------------
+
+/// Between two BDAT chunks of a message.
+///
+/// Only BDAT, RSET, NOOP, and QUIT are acceptable here. The second
+/// element is the size of the message so far.
+///
+pub struct Chunking<P: Protocol>(P::Data, u64);
+
+impl<P: Protocol> Chunking<P> {
+    fn recv(self, recv: &mut RecvBuf, send: &mut SendBuf,
+            config: &Rc<Config>) -> (State<P>, Action) {
+        let res = recv.parse_command(|cmd, _| match cmd {
+            Some(Command::Bdat { size, last }) => {
+                ReadChunk::data(self.0, size, last, self.1, config)
+                          .start(send)
+            }
+            Some(Command::Rset) => {
+                send.reply(250, (2,0,0), b"Ok\r\n");
+                (Idle::greeted(self.0.reset()).into(), Action::Collect)
+            }
+            Some(Command::Noop) => {
+                send.reply(250, (2,0,0), b"Ok\r\n");
+                (self.into(), Action::Write)
+            }
+            Some(Command::Quit) => {
+                send.reply(221, (2,0,0), b"Bye\r\n");
+                (State::Dead, Action::Close)
+            }
+            Some(Command::Unrecognized) => {
+                send.reply(500, (5,5,2), b"Unrecognized command.\r\n");
+                (self.into(), Action::Write)
+            }
+            Some(Command::ParameterError) => {
+                send.reply(501, (5,5,4), b"Error in command parameters.\r\n");
+                (self.into(), Action::Write)
+            }
+            Some(_) => {
+                send.reply(503, (5,5,1), b"Expecting BDAT\r\n");
+                (self.into(), Action::Write)
+            }
+            None => (self.into(), Action::Read)
+        });
+        match res {
+            Ok((state, action)) => (state, action),
+            Err(()) => (State::Dead, Action::Close)
         }
     }
 }
@@ -336,6 +883,16 @@ enum Level<S, M> {
 }
 
 
+//------------ exceeds_limit -------------------------------------------------
+
+/// Returns whether a message of *size* octets is too big.
+///
+/// A *limit* of zero means there is no limit.
+fn exceeds_limit(size: u64, limit: u64) -> bool {
+    limit > 0 && size > limit
+}
+
+
 //============ Helper Types for Command Processing ==========================
 //
 // These types exist to keep the three steps of processing a deferable
@@ -345,6 +902,32 @@ enum Level<S, M> {
 // processing.
 
 
+//------------ mail_params_offered -------------------------------------------
+
+/// Returns whether all MAIL parameters belong to advertised extensions.
+fn mail_params_offered(params: &syntax::MailParameters,
+                       caps: &Capabilities) -> bool {
+    let body = match params.body {
+        Some(syntax::BodyValue::BinaryMime) => caps.binarymime,
+        Some(_) => caps.eightbitmime,
+        None => true
+    };
+    body && (params.size.is_none() || caps.size)
+         && (params.ret.is_none() && params.envid.is_none() || caps.dsn)
+         && (params.auth.is_none() || caps.auth)
+         && (params.smtputf8.is_none() || caps.smtputf8)
+}
+
+
+//------------ rcpt_params_offered -------------------------------------------
+
+/// Returns whether all RCPT parameters belong to advertised extensions.
+fn rcpt_params_offered(params: &syntax::RcptParameters,
+                       caps: &Capabilities) -> bool {
+    params.notify.is_none() && params.orcpt.is_none() || caps.dsn
+}
+
+
 //------------ Start ---------------------------------------------------------
 
 struct Start<P: Protocol>(Hesitant<Option<P::Session>,
@@ -402,10 +985,12 @@ impl<P: Protocol> Helo<P> {
         Helo(defer.wakeup())
     }
 
-    fn process(self, send: &mut SendBuf, config: &Rc<Config>)
-               -> (State<P>, Action) {
+    fn process(self, send: &mut SendBuf, config: &Rc<Config>,
+               status: &mut Status) -> (State<P>, Action) {
         match self.0 {
             Hesitant::Final(Some(session)) => {
+                // HELO means no extensions.
+                status.capabilities = Capabilities::none();
                 let mut reply = Reply::new(send, 250, None);
                 scribble!(&mut reply, config.hostname(), b"\r\n");
                 (Idle::greeted(session).into(), Action::Write)
@@ -441,19 +1026,25 @@ impl<P: Protocol> Ehlo<P> {
         Ehlo(defer.wakeup())
     }
 
-    fn process(self, send: &mut SendBuf, config: &Rc<Config>, is_secure: bool)
-               -> (State<P>, Action) {
+    fn process(self, send: &mut SendBuf, config: &Rc<Config>, is_secure: bool,
+               status: &mut Status) -> (State<P>, Action) {
         match self.0 {
             Hesitant::Final(Some(session)) => {
-                let mut reply = Reply::new(send, 250, None);
-                scribble!(&mut reply, config.hostname(),
-                          b"\r\nEXPN\r\nHELP\r\n8BITMIME\r\nSIZE ",
-                          config.message_size_limit(),
-                          b"\r\nPIPELINING\r\nDSN\r\n\
-                          ETRN\r\nENHANCEDSTATUSCODES\r\nSMTPUTF8\r\n");
-                if !is_secure {
-                    scribble!(&mut reply, b"STARTTLS\r\n");
+                let mut caps = config.capabilities().clone();
+                session.capabilities(&mut caps);
+                if is_secure {
+                    caps.starttls = false;
                 }
+                // Auth::recv() refuses to authenticate without TLS, so
+                // offering AUTH here would be a lie.
+                if !is_secure || config.sasl_mechanisms().is_empty() {
+                    caps.auth = false;
+                }
+                if !caps.chunking {
+                    caps.binarymime = false;
+                }
+                Ehlo::<P>::write_keywords(send, config, &caps);
+                status.capabilities = caps;
                 (Idle::greeted(session).into(), Action::Write)
             }
             Hesitant::Final(None) => {
@@ -466,6 +1057,54 @@ impl<P: Protocol> Ehlo<P> {
             }
         }
     }
+
+    fn write_keywords(send: &mut SendBuf, config: &Config,
+                      caps: &Capabilities) {
+        let mut reply = Reply::new(send, 250, None);
+        scribble!(&mut reply, config.hostname(), b"\r\n");
+        if caps.expn {
+            scribble!(&mut reply, b"EXPN\r\n");
+        }
+        if caps.help {
+            scribble!(&mut reply, b"HELP\r\n");
+        }
+        if caps.eightbitmime {
+            scribble!(&mut reply, b"8BITMIME\r\n");
+        }
+        if caps.size {
+            scribble!(&mut reply, b"SIZE ", config.message_size_limit(),
+                      b"\r\n");
+        }
+        if caps.pipelining {
+            scribble!(&mut reply, b"PIPELINING\r\n");
+        }
+        if caps.dsn {
+            scribble!(&mut reply, b"DSN\r\n");
+        }
+        if caps.etrn {
+            scribble!(&mut reply, b"ETRN\r\n");
+        }
+        scribble!(&mut reply, b"ENHANCEDSTATUSCODES\r\n");
+        if caps.smtputf8 {
+            scribble!(&mut reply, b"SMTPUTF8\r\n");
+        }
+        if caps.chunking {
+            scribble!(&mut reply, b"CHUNKING\r\n");
+        }
+        if caps.binarymime {
+            scribble!(&mut reply, b"BINARYMIME\r\n");
+        }
+        if caps.starttls {
+            scribble!(&mut reply, b"STARTTLS\r\n");
+        }
+        if caps.auth {
+            scribble!(&mut reply, b"AUTH");
+            for mechanism in config.sasl_mechanisms() {
+                scribble!(&mut reply, b" ", mechanism.name());
+            }
+            scribble!(&mut reply, b"\r\n");
+        }
+    }
 }
 
 
@@ -476,13 +1115,19 @@ struct Mail<P: Protocol>(Hesitant<Idle<P>,
 
 impl<P: Protocol> Mail<P> {
     fn recv(idle: Idle<P>, path: syntax::ReversePath,
-            params: syntax::MailParameters, send: &mut SendBuf) -> Self {
+            params: syntax::MailParameters, send: &mut SendBuf,
+            limit: u64) -> Self {
         match idle.0 {
             Level::Early(session) => {
                 send.reply(503, (5,5,1), b"Please say 'Hello' first\r\n");
                 Mail(Hesitant::Final(Idle::early(session)))
             }
             Level::Greeted(session) => {
+                if exceeds_limit(params.size.unwrap_or(0), limit) {
+                    send.reply(552, (5,3,4), b"Message size exceeds fixed \
+                                               maximum message size\r\n");
+                    return Mail(Hesitant::Final(Idle::greeted(session)))
+                }
                 Mail(session.mail(path, params, ReplyBuf::new(send))
                             .map_final(Mail::translate))
             }
@@ -548,9 +1193,19 @@ impl<P: Protocol> Rcpt<P> {
         Rcpt(defer.wakeup(ReplyBuf::new(send)).map_final(Rcpt::translate))
     }
 
-    fn process(self) -> (State<P>, Action) {
+    /// Produces the next state.
+    ///
+    /// If the recipient was accepted, it is added to the recipients in
+    /// *status*.
+    fn process(self, status: &mut Status) -> (State<P>, Action) {
         match self.0 {
-            Hesitant::Final(idle) => (State::Idle(idle), Action::Collect),
+            Hesitant::Final(idle) => {
+                let rcpt = status.rcpt.take();
+                if let (&Level::Mail(_), Some(rcpt)) = (&idle.0, rcpt) {
+                    status.recipients.push(rcpt)
+                }
+                (State::Idle(idle), Action::Collect)
+            }
             Hesitant::Defer(defer) => {
                 (State::Wait(Wait::Rcpt(defer)), Action::Wait)
             }
@@ -573,18 +1228,22 @@ struct Data<P>(Hesitant<Result<P::Data, Idle<P>>,
             where P: Protocol;
 
 impl<P: Protocol> Data<P> {
-    fn recv(idle: Idle<P>, send: &mut SendBuf) -> Self {
+    /// Starts processing a DATA command.
+    ///
+    /// If there is no mail transaction, the error reply has been sent
+    /// and the idle state is returned as the error.
+    fn recv(idle: Idle<P>, send: &mut SendBuf) -> Result<Self, Idle<P>> {
         match idle.0 {
             Level::Early(session) => {
                 send.reply(503, (5,5,1), b"Please say 'Hello' first\r\n");
-                Data(Hesitant::Final(Err(Idle::early(session))))
+                Err(Idle::early(session))
             }
             Level::Greeted(session) => {
                 send.reply(503, (5,5,1), b"Need MAIL command first\r\n");
-                Data(Hesitant::Final(Err(Idle::greeted(session))))
+                Err(Idle::greeted(session))
             }
             Level::Mail(mail) => {
-                Data(mail.data().map_final(Data::translate))
+                Ok(Data(mail.data().map_final(Data::translate)))
             }
         }
     }
@@ -594,9 +1253,25 @@ impl<P: Protocol> Data<P> {
         Data(defer.wakeup().map_final(Data::translate))
     }
 
-    fn process(self, send: &mut SendBuf) -> (State<P>, Action) {
+    /// Produces the reply to DATA.
+    ///
+    /// If *more* is `true`, the client has sent something after the
+    /// DATA command. Since RFC 2920 requires the client to wait for the
+    /// 354 reply before sending the message, this is a synchronization
+    /// error. There is no telling whether the rest is message data or
+    /// commands, so the connection is closed.
+    ///
+    /// Otherwise, the data handler receives the trace before any data.
+    fn process(self, send: &mut SendBuf, more: bool, config: &Config,
+               is_secure: bool, status: &Status) -> (State<P>, Action) {
         match self.0 {
-            Hesitant::Final(Ok(data)) => {
+            Hesitant::Final(Ok(data)) if more => {
+                let _ = data.reset();
+                send.reply(554, (5,5,0), b"SMTP synchronization error\r\n");
+                (State::Dead, Action::Close)
+            }
+            Hesitant::Final(Ok(mut data)) => {
+                data.trace(status.trace(config, is_secure));
                 let mut reply = Reply::new(send, 354, None);
                 scribble!(&mut reply, b"Go ahead.\r\n");
                 (State::Data(ReadData::new(data)), Action::Write)
@@ -621,6 +1296,73 @@ impl<P: Protocol> Data<P> {
 }
 
 
+//------------ Bdat ----------------------------------------------------------
+
+/// Processing of the first BDAT command of a transaction.
+///
+/// If there is no transaction or the protocol rejects the data, the
+/// chunk still needs to be read and is then dropped.
+///
+struct Bdat<P>(Hesitant<Result<P::Data, (Idle<P>, ChunkReply)>,
+                        <P::Mail as MailHandler<P>>::Data>,
+               u64, bool)
+            where P: Protocol;
+
+impl<P: Protocol> Bdat<P> {
+    fn recv(idle: Idle<P>, size: u64, last: bool) -> Self {
+        let res = match idle.0 {
+            Level::Early(session) => {
+                Hesitant::Final(Err((Idle::early(session),
+                                     (503, (5,5,1),
+                                      &b"Please say 'Hello' first\r\n"[..]))))
+            }
+            Level::Greeted(session) => {
+                Hesitant::Final(Err((Idle::greeted(session),
+                                     (503, (5,5,1),
+                                      &b"Need MAIL command first\r\n"[..]))))
+            }
+            Level::Mail(mail) => {
+                mail.data().map_final(Bdat::translate)
+            }
+        };
+        Bdat(res, size, last)
+    }
+
+    fn wakeup(defer: <P::Mail as MailHandler<P>>::Data, size: u64,
+              last: bool) -> Self {
+        Bdat(defer.wakeup().map_final(Bdat::translate), size, last)
+    }
+
+    fn process(self, send: &mut SendBuf, config: &Rc<Config>,
+               is_secure: bool, status: &Status) -> (State<P>, Action) {
+        let Bdat(res, size, last) = self;
+        match res {
+            Hesitant::Final(Ok(mut data)) => {
+                data.trace(status.trace(config, is_secure));
+                ReadChunk::data(data, size, last, 0, config).start(send)
+            }
+            Hesitant::Final(Err((idle, reply))) => {
+                ReadChunk::new(ChunkSink::Discard(idle, reply), size, last,
+                               size)
+                          .start(send)
+            }
+            Hesitant::Defer(defer) => {
+                (Wait::Bdat(defer, size, last).into(), Action::Wait)
+            }
+        }
+    }
+
+    fn translate(res: Result<P::Data, P::Session>)
+                 -> Result<P::Data, (Idle<P>, ChunkReply)> {
+        match res {
+            Ok(data) => Ok(data),
+            Err(session) => Err((Idle::greeted(session),
+                                 (554, (5,5,0), &b"Mail failed\r\n"[..])))
+        }
+    }
+}
+
+
 //------------ Rset ---------------------------------------------------------
 
 struct Rset<P: Protocol>(PhantomData<P>);
@@ -824,6 +1566,47 @@ impl<P: Protocol> Into<(State<P>, Action)> for WaitHelp<P> {
 }
 
 
+//------------ Etrn ----------------------------------------------------------
+
+struct Etrn<P: Protocol>(Hesitant<Idle<P>,
+                                  <P::Session as SessionHandler<P>>::Etrn>);
+
+impl<P: Protocol> Etrn<P> {
+    fn recv(idle: Idle<P>, node: syntax::EtrnNode, send: &mut SendBuf)
+            -> Self {
+        match idle.0 {
+            Level::Early(session) => {
+                send.reply(503, (5,5,1), b"Please say 'Hello' first\r\n");
+                Etrn(Hesitant::Final(Idle::early(session)))
+            }
+            Level::Greeted(session) => {
+                Etrn(session.etrn(node, ReplyBuf::new(send))
+                            .map_final(Idle::greeted))
+            }
+            Level::Mail(mail) => {
+                send.reply(503, (5,5,1), b"ETRN not allowed during a mail \
+                                           transaction\r\n");
+                Etrn(Hesitant::Final(Idle::mail(mail)))
+            }
+        }
+    }
+
+    fn wakeup(defer: <P::Session as SessionHandler<P>>::Etrn,
+              send: &mut SendBuf) -> Self {
+        Etrn(defer.wakeup(ReplyBuf::new(send)).map_final(Idle::greeted))
+    }
+
+    fn process(self) -> (State<P>, Action) {
+        match self.0 {
+            Hesitant::Final(idle) => (State::Idle(idle), Action::Write),
+            Hesitant::Defer(defer) => {
+                (State::Wait(Wait::Etrn(defer)), Action::Wait)
+            }
+        }
+    }
+}
+
+
 //------------ CheckTls ------------------------------------------------------
 
 struct CheckTls<P>(Hesitant<Option<P::Session>,
@@ -882,3 +1665,384 @@ impl<P: Protocol> DataComplete<P> {
     }
 }
 
+
+//------------ Auth ----------------------------------------------------------
+
+/// Processing of the AUTH command.
+///
+/// This only checks whether AUTH is permissible right now and then starts
+/// the SASL exchange. The exchange itself happens in `ReadAuth` and the
+/// verdict of the protocol is processed by `AuthCheck`.
+///
+struct Auth<P: Protocol>(PhantomData<P>);
+
+impl<P: Protocol> Auth<P> {
+    fn recv(idle: Idle<P>, mechanism: &[u8], initial: Option<&[u8]>,
+            send: &mut SendBuf, config: &Rc<Config>, is_secure: bool,
+            status: &mut Status) -> (State<P>, Action) {
+        let session = match idle.0 {
+            Level::Early(session) => {
+                send.reply(503, (5,5,1), b"Please say 'Hello' first\r\n");
+                return (Idle::early(session).into(), Action::Write)
+            }
+            Level::Greeted(session) => session,
+            Level::Mail(mail) => {
+                send.reply(503, (5,5,1),
+                           b"AUTH not permitted during a mail \
+                             transaction\r\n");
+                return (Idle::mail(mail).into(), Action::Write)
+            }
+        };
+        if status.authenticated {
+            send.reply(503, (5,5,1), b"Already authenticated\r\n");
+            return (Idle::greeted(session).into(), Action::Write)
+        }
+        let mechanism = match config.sasl_mechanism(mechanism) {
+            Some(mechanism) => mechanism,
+            None => {
+                send.reply(504, (5,5,4),
+                           b"Unrecognized authentication type\r\n");
+                return (Idle::greeted(session).into(), Action::Write)
+            }
+        };
+        if !is_secure {
+            send.reply(538, (5,7,11), b"Encryption required for \
+                                        requested authentication \
+                                        mechanism\r\n");
+            return (Idle::greeted(session).into(), Action::Write)
+        }
+
+        // An initial response of "=" is an empty response.
+        let initial = match initial {
+            None => None,
+            Some(initial) if initial == b"=" => Some(Vec::new()),
+            Some(initial) => match base64::decode(initial) {
+                Ok(initial) => Some(initial),
+                Err(_) => {
+                    send.reply(501, (5,5,2), b"Invalid base64 data\r\n");
+                    return (Idle::greeted(session).into(), Action::Write)
+                }
+            }
+        };
+        ReadAuth::new(session, mechanism.name().to_vec(),
+                      mechanism.start(config.hostname()))
+                 .step(initial, send, status)
+    }
+}
+
+
+//------------ ReadAuth ------------------------------------------------------
+
+/// The maximum length of a client response in a SASL exchange.
+///
+/// This is the length RFC 4954, section 4 asks servers to accept at
+/// least. It does not include the final CRLF.
+const AUTH_LINE_LIMIT: usize = 12288;
+
+/// Reading the client responses of a SASL exchange.
+///
+pub struct ReadAuth<P: Protocol> {
+    session: P::Session,
+    mechanism: Vec<u8>,
+    exchange: Box<sasl::Exchange>,
+}
+
+impl<P: Protocol> ReadAuth<P> {
+    fn new(session: P::Session, mechanism: Vec<u8>,
+           exchange: Box<sasl::Exchange>) -> Self {
+        ReadAuth { session: session, mechanism: mechanism,
+                   exchange: exchange }
+    }
+
+    fn recv(self, recv: &mut RecvBuf, send: &mut SendBuf,
+            status: &mut Status) -> (State<P>, Action) {
+        let line = match recv.take_line(AUTH_LINE_LIMIT) {
+            Ok(Some(line)) => line,
+            Ok(None) => return (self.into(), Action::Read),
+            Err(()) => {
+                send.reply(500, (5,5,6),
+                           b"Authentication exchange line is too long\r\n");
+                return (Idle::greeted(self.session).into(), Action::Write)
+            }
+        };
+        if line == b"*" {
+            send.reply(501, (5,0,0), b"Authentication cancelled\r\n");
+            return (Idle::greeted(self.session).into(), Action::Write)
+        }
+        match base64::decode(&line) {
+            Ok(response) => self.step(Some(response), send, status),
+            Err(_) => {
+                send.reply(501, (5,5,2), b"Invalid base64 data\r\n");
+                (Idle::greeted(self.session).into(), Action::Write)
+            }
+        }
+    }
+
+    fn step(self, response: Option<Vec<u8>>, send: &mut SendBuf,
+            status: &mut Status) -> (State<P>, Action) {
+        let ReadAuth { session, mechanism, mut exchange } = self;
+        let step = {
+            let lookup: &CredentialLookup = session.credential_lookup();
+            exchange.step(response.as_ref().map(|r| r.as_slice()), lookup)
+        };
+        ReadAuth::new(session, mechanism, exchange).process(step, send,
+                                                            status)
+    }
+
+    fn process(self, step: sasl::Step, send: &mut SendBuf,
+               status: &mut Status) -> (State<P>, Action) {
+        let ReadAuth { session, mechanism, mut exchange } = self;
+        match step {
+            sasl::Step::Challenge(challenge) => {
+                let challenge = base64::encode(&challenge);
+                scribble!(send, b"334 ", challenge.as_slice(), b"\r\n");
+                (ReadAuth::new(session, mechanism, exchange).into(),
+                 Action::Write)
+            }
+            sasl::Step::Derive(derivation) => {
+                match session.derive_scram(derivation) {
+                    Hesitant::Final(credentials) => {
+                        let step = exchange.derived(credentials);
+                        ReadAuth::new(session, mechanism, exchange)
+                                 .process(step, send, status)
+                    }
+                    Hesitant::Defer(defer) => {
+                        let auth = ReadAuth::new(session, mechanism,
+                                                 exchange);
+                        (Wait::Derive(WaitDerive(auth, defer)).into(),
+                         Action::Wait)
+                    }
+                }
+            }
+            sasl::Step::Done(credentials) => {
+                AuthCheck::recv(session, &mechanism, credentials)
+                          .process(send, status)
+            }
+            sasl::Step::Failed => {
+                send.reply(535, (5,7,8),
+                           b"Authentication credentials invalid\r\n");
+                (Idle::greeted(session).into(), Action::Write)
+            }
+        }
+    }
+}
+
+
+//------------ WaitDerive ----------------------------------------------------
+
+/// Waiting for SCRAM credentials to be derived during a SASL exchange.
+///
+struct WaitDerive<P: Protocol>(ReadAuth<P>,
+                               <P::Session as SessionHandler<P>>::Derive);
+
+impl<P: Protocol> WaitDerive<P> {
+    fn wakeup(self, send: &mut SendBuf, status: &mut Status)
+              -> (State<P>, Action) {
+        let WaitDerive(mut auth, defer) = self;
+        match defer.wakeup() {
+            Hesitant::Final(credentials) => {
+                let step = auth.exchange.derived(credentials);
+                auth.process(step, send, status)
+            }
+            Hesitant::Defer(defer) => {
+                (Wait::Derive(WaitDerive(auth, defer)).into(), Action::Wait)
+            }
+        }
+    }
+}
+
+
+//------------ AuthCheck -----------------------------------------------------
+
+struct AuthCheck<P>(Hesitant<Result<P::Session, P::Session>,
+                             <P::Session as SessionHandler<P>>::Auth>)
+                 where P: Protocol;
+
+impl<P: Protocol> AuthCheck<P> {
+    fn recv(session: P::Session, mechanism: &[u8],
+            credentials: sasl::Credentials) -> Self {
+        AuthCheck(session.auth(mechanism, credentials))
+    }
+
+    fn wakeup(defer: <P::Session as SessionHandler<P>>::Auth) -> Self {
+        AuthCheck(defer.wakeup())
+    }
+
+    fn process(self, send: &mut SendBuf, status: &mut Status)
+               -> (State<P>, Action) {
+        match self.0 {
+            Hesitant::Final(Ok(session)) => {
+                status.authenticated = true;
+                send.reply(235, (2,7,0), b"Authentication successful\r\n");
+                (Idle::greeted(session).into(), Action::Write)
+            }
+            Hesitant::Final(Err(session)) => {
+                send.reply(535, (5,7,8),
+                           b"Authentication credentials invalid\r\n");
+                (Idle::greeted(session).into(), Action::Write)
+            }
+            Hesitant::Defer(defer)
+                => (Wait::Auth(defer).into(), Action::Wait)
+        }
+    }
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use openssl::ssl::{SslContext, SslMethod};
+    use super::super::{Config, NullProtocol};
+    use super::super::harness::Harness;
+
+    /// Runs a client session for the null protocol and checks the replies.
+    ///
+    /// Each step is data to send and the codes of the replies expected
+    /// for it. There are several codes if the data contains pipelined
+    /// commands. The message size limit is 10000 octets.
+    fn script(steps: &[(&str, &[u16])]) {
+        let context = SslContext::new(SslMethod::Tlsv1).unwrap();
+        let config = Config::new(context, b"mx.test".to_vec(),
+                                 b"Cloudship".to_vec(), 10000);
+        let mut harness = Harness::<NullProtocol>::with_config((), config);
+        harness.replies(220);
+        for &(data, codes) in steps {
+            harness.says(data.as_bytes());
+            for &code in codes {
+                let reply = harness.reply().map(|reply| reply.code);
+                assert!(reply == Some(code), "{:?}: expected {}, got {:?}",
+                        data, code, reply);
+            }
+        }
+        assert!(harness.is_quiet());
+    }
+
+    #[test]
+    fn verbs() {
+        script(&[
+            ("EHLO client.test\r\n", &[250]),
+            ("MAIL FROM:<a@client.test>\r\n", &[250]),
+            ("RCPT TO:<b@mx.test>\r\n", &[250]),
+            ("DATA\r\n", &[354]),
+            ("Subject: Test\r\n\r\nHello\r\n.\r\n", &[250]),
+            ("MAIL FROM:<a@client.test>\r\n", &[250]),
+            ("RCPT TO:<b@mx.test>\r\n", &[250]),
+            ("BDAT 5 LAST\r\nHello", &[250]),
+            ("MAIL FROM:<a@client.test>\r\n", &[250]),
+            ("RSET\r\n", &[250]),
+            ("VRFY b\r\n", &[252]),
+            ("EXPN staff\r\n", &[252]),
+            ("HELP\r\n", &[214]),
+            ("HELP MAIL\r\n", &[214]),
+            ("NOOP\r\n", &[250]),
+            ("ETRN mx.test\r\n", &[251]),
+            ("HELO client.test\r\n", &[250]),
+            ("QUIT\r\n", &[221]),
+        ])
+    }
+
+    #[test]
+    fn bare_line_endings() {
+        script(&[
+            ("EHLO client.test\r\n", &[250]),
+            ("MAIL FROM:<a@client.test>\r\n", &[250]),
+            ("RCPT TO:<b@mx.test>\r\n", &[250]),
+            ("DATA\r\n", &[354]),
+            // Neither the LF nor the CR end the line, so this is one
+            // message which is refused.
+            ("Hello\n.\r\nMAIL FROM:<x@client.test>\r\n\
+              RCPT TO:<y@mx.test>\r\nDATA\r\n\
+              Hi\r.\r\nRSET\r\n.\r\n", &[550]),
+            ("MAIL FROM:<a@client.test>\r\n", &[250]),
+            ("RCPT TO:<b@mx.test>\r\n", &[250]),
+            ("DATA\r\n", &[354]),
+            ("Hello\r\n.\r\n", &[250]),
+            ("QUIT\r\n", &[221]),
+        ])
+    }
+
+    #[test]
+    fn case_insensitive() {
+        script(&[
+            ("ehlo client.test\r\n", &[250]),
+            ("mail from:<a@client.test> size=100\r\n", &[250]),
+            ("rcpt to:<b@mx.test>\r\n", &[250]),
+            ("data\r\n", &[354]),
+            ("Hello\r\n.\r\n", &[250]),
+            ("rset\r\n", &[250]),
+            ("NoOp\r\n", &[250]),
+            ("quit\r\n", &[221]),
+        ])
+    }
+
+    #[test]
+    fn parameter_errors() {
+        script(&[
+            ("HELO\r\n", &[501]),
+            ("EHLO client.test\r\n", &[250]),
+            ("MAIL FROM:\r\n", &[501]),
+            ("MAIL FROM:<a@client.test> SIZE=lots\r\n", &[501]),
+            ("MAIL FROM:<a@client.test> SIZE=20000\r\n", &[552]),
+            ("MAIL FROM:<a@client.test>\r\n", &[250]),
+            ("RCPT TO:\r\n", &[501]),
+            ("RCPT TO:<b@mx.test>\r\n", &[250]),
+            ("DATA now\r\n", &[501]),
+            ("RSET all\r\n", &[501]),
+            ("XYZZY\r\n", &[500]),
+            ("QUIT\r\n", &[221]),
+        ])
+    }
+
+    #[test]
+    fn out_of_sequence() {
+        script(&[
+            ("MAIL FROM:<a@client.test>\r\n", &[503]),
+            ("RCPT TO:<b@mx.test>\r\n", &[503]),
+            ("DATA\r\n", &[503]),
+            ("ETRN mx.test\r\n", &[503]),
+            ("EHLO client.test\r\n", &[250]),
+            ("RCPT TO:<b@mx.test>\r\n", &[503]),
+            ("DATA\r\n", &[503]),
+            ("NOOP\r\n", &[250]),
+            ("MAIL FROM:<a@client.test>\r\n", &[250]),
+            ("MAIL FROM:<a@client.test>\r\n", &[503]),
+            ("ETRN mx.test\r\n", &[503]),
+            ("RSET\r\n", &[250]),
+            ("QUIT\r\n", &[221]),
+        ])
+    }
+
+    #[test]
+    fn unadvertised() {
+        script(&[
+            ("STARTTLS\r\n", &[503]),
+            ("HELO client.test\r\n", &[250]),
+            ("MAIL FROM:<a@client.test> BODY=8BITMIME\r\n", &[555]),
+            ("MAIL FROM:<a@client.test> SIZE=100\r\n", &[555]),
+            ("MAIL FROM:<a@client.test>\r\n", &[250]),
+            ("RCPT TO:<b@mx.test> NOTIFY=NEVER\r\n", &[555]),
+            ("BDAT 5 LAST\r\nHello", &[502]),
+            ("ETRN mx.test\r\n", &[502]),
+            ("STARTTLS\r\n", &[502]),
+            ("AUTH PLAIN\r\n", &[502]),
+            ("NOOP\r\n", &[250]),
+            ("QUIT\r\n", &[221]),
+        ])
+    }
+
+    #[test]
+    fn pipelining() {
+        script(&[
+            ("EHLO client.test\r\n", &[250]),
+            ("MAIL FROM:<a@client.test>\r\nRCPT TO:<b@mx.test>\r\n\
+              RCPT TO:<c@mx.test>\r\nDATA\r\n", &[250, 250, 250, 354]),
+            ("Hello\r\n.\r\nMAIL FROM:<a@client.test>\r\nRSET\r\n\
+              NOOP\r\n", &[250, 250, 250, 250]),
+            ("MAIL FROM:<a@client.test>\r\nRCPT TO:<b@mx.test>\r\n\
+              BDAT 5\r\nHelloBDAT 0 LAST\r\n", &[250, 250, 250, 250]),
+            ("MAIL FROM:<a@client.test>\r\nRCPT TO:<b@mx.test>\r\n\
+              DATA\r\nHello\r\n.\r\n", &[250, 250, 554]),
+        ])
+    }
+}
@@ -0,0 +1,296 @@
+//! Trace header fields.
+//!
+//! Each server that takes responsibility for a message adds a Received
+//! field to the top of its header as described in RFC 5321, section 4.4.
+//! The session collects what it knows about the client and hands a
+//! `Trace` to the data handler before the first chunk of data. The
+//! handler can add what only it knows, such as the queue ID, and turns
+//! the trace into the field via `to_field()`.
+//!
+//! The name of the client in the from clause comes from the reverse DNS.
+//! Since looking it up blocks, `peer_name()` has to be run on a worker
+//! pool by whoever wants to have it.
+
+use std::ascii::AsciiExt;
+use std::net::IpAddr;
+use ::net::dns::Resolver;
+use ::util::date;
+use super::access::unmap;
+
+
+//------------ Trace ---------------------------------------------------------
+
+/// The information for the Received field of a message.
+#[derive(Clone, Debug)]
+pub struct Trace {
+    /// The domain the client gave in HELO or EHLO.
+    helo: Option<Vec<u8>>,
+
+    /// The address of the client.
+    peer: Option<IpAddr>,
+
+    /// The confirmed reverse DNS name of the client.
+    peer_name: Option<Vec<u8>>,
+
+    /// Our own host name.
+    hostname: Vec<u8>,
+
+    /// The protocol keyword from RFC 3848.
+    protocol: &'static str,
+
+    /// The description of the TLS cipher if TLS is running.
+    cipher: Option<Vec<u8>>,
+
+    /// The queue ID of the message.
+    id: Option<Vec<u8>>,
+
+    /// The recipient if the message has exactly one.
+    recipient: Option<Vec<u8>>,
+
+    /// When the message was received in seconds since the epoch.
+    date: u64,
+}
+
+impl Trace {
+    /// Creates a trace for a message received by *hostname* at *date*.
+    ///
+    /// The protocol is plain SMTP until set otherwise.
+    pub fn new(hostname: &[u8], date: u64) -> Self {
+        Trace { helo: None, peer: None, peer_name: None,
+                hostname: hostname.into(), protocol: "SMTP", cipher: None,
+                id: None, recipient: None, date: date }
+    }
+
+    /// Sets the domain the client gave in HELO or EHLO.
+    pub fn set_helo(&mut self, helo: &[u8]) {
+        self.helo = Some(helo.into())
+    }
+
+    /// Sets the address of the client.
+    pub fn set_peer(&mut self, peer: IpAddr) {
+        self.peer = Some(unmap(peer))
+    }
+
+    /// Sets the name of the client found in the reverse DNS.
+    pub fn set_peer_name(&mut self, name: &[u8]) {
+        self.peer_name = Some(name.into())
+    }
+
+    /// Sets the protocol keyword according to RFC 3848.
+    ///
+    /// The keyword depends on whether the client said EHLO, whether TLS
+    /// is running, and whether the client has authenticated.
+    pub fn set_protocol(&mut self, esmtp: bool, secure: bool,
+                        authenticated: bool) {
+        self.protocol = match (esmtp, secure, authenticated) {
+            (false, _, _) => "SMTP",
+            (true, false, false) => "ESMTP",
+            (true, true, false) => "ESMTPS",
+            (true, false, true) => "ESMTPA",
+            (true, true, true) => "ESMTPSA",
+        }
+    }
+
+    /// Returns our own host name.
+    pub fn hostname(&self) -> &[u8] {
+        &self.hostname
+    }
+
+    /// Returns the protocol keyword.
+    pub fn protocol(&self) -> &'static str {
+        self.protocol
+    }
+
+    /// Sets the description of the TLS cipher.
+    ///
+    /// This goes into a comment after the protocol keyword and should
+    /// look something like `TLSv1.2 with cipher AES256-SHA (256/256
+    /// bits)`.
+    pub fn set_cipher(&mut self, cipher: &[u8]) {
+        self.cipher = Some(cipher.into())
+    }
+
+    /// Sets the queue ID of the message.
+    pub fn set_id(&mut self, id: &[u8]) {
+        self.id = Some(id.into())
+    }
+
+    /// Sets the recipient of a message with a single recipient.
+    ///
+    /// For messages with more than one recipient, none of them should
+    /// be given so as not to disclose them to each other.
+    pub fn set_recipient(&mut self, recipient: &[u8]) {
+        self.recipient = Some(recipient.into())
+    }
+
+    /// Returns the Received header field including the final CRLF.
+    ///
+    /// Each clause goes on a line of its own.
+    pub fn to_field(&self) -> Vec<u8> {
+        let mut res = b"Received:".to_vec();
+        let literal = self.peer.map(address_literal);
+        match (&self.helo, &literal) {
+            (&Some(ref helo), _) => {
+                res.extend_from_slice(b" from ");
+                res.extend_from_slice(helo);
+                if let Some(ref literal) = literal {
+                    res.extend_from_slice(b" (");
+                    if let Some(ref name) = self.peer_name {
+                        res.extend_from_slice(name);
+                        res.push(b' ');
+                    }
+                    res.extend_from_slice(literal);
+                    res.push(b')');
+                }
+                res.extend_from_slice(b"\r\n\t");
+            }
+            (&None, &Some(ref literal)) => {
+                res.extend_from_slice(b" from ");
+                res.extend_from_slice(literal);
+                res.extend_from_slice(b"\r\n\t");
+            }
+            (&None, &None) => res.push(b' ')
+        }
+        res.extend_from_slice(b"by ");
+        res.extend_from_slice(&self.hostname);
+        res.extend_from_slice(b" with ");
+        res.extend_from_slice(self.protocol.as_bytes());
+        if let Some(ref cipher) = self.cipher {
+            res.extend_from_slice(b"\r\n\t(using ");
+            res.extend_from_slice(cipher);
+            res.push(b')');
+        }
+        if let Some(ref id) = self.id {
+            res.extend_from_slice(b" id ");
+            res.extend_from_slice(id);
+        }
+        if let Some(ref recipient) = self.recipient {
+            res.extend_from_slice(b"\r\n\tfor <");
+            res.extend_from_slice(recipient);
+            res.push(b'>');
+        }
+        res.extend_from_slice(b";\r\n\t");
+        res.extend_from_slice(date::rfc5322(self.date).as_bytes());
+        res.extend_from_slice(b"\r\n");
+        res
+    }
+}
+
+
+//------------ peer_name -----------------------------------------------------
+
+/// Looks up the name of *addr* in the reverse DNS.
+///
+/// Only a name that resolves back to *addr* is returned. Lookups that
+/// fail are treated as if there was no name.
+pub fn peer_name(resolver: &Resolver, addr: IpAddr) -> Option<Vec<u8>> {
+    let addr = unmap(addr);
+    let names = match resolver.lookup_ptr(addr) {
+        Ok(names) => names,
+        Err(_) => return None
+    };
+    for name in names {
+        let confirmed = match addr {
+            IpAddr::V4(addr) => {
+                resolver.lookup_a(&name).map(|res| res.contains(&addr))
+            }
+            IpAddr::V6(addr) => {
+                resolver.lookup_aaaa(&name).map(|res| res.contains(&addr))
+            }
+        };
+        if let Ok(true) = confirmed {
+            let mut name = name.to_ascii_lowercase();
+            if name.last() == Some(&b'.') {
+                name.pop();
+            }
+            return Some(name)
+        }
+    }
+    None
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the address literal of RFC 5321, section 4.1.3, for *addr*.
+fn address_literal(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => format!("[{}]", addr).into_bytes(),
+        IpAddr::V6(addr) => format!("[IPv6:{}]", addr).into_bytes(),
+    }
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use ::net::dns::{Record, Zone};
+    use super::*;
+
+    #[test]
+    fn to_field() {
+        let mut trace = Trace::new(b"mx.test", 1476612000);
+        assert_eq!(trace.to_field(),
+                   &b"Received: by mx.test with SMTP;\r\n\
+                      \tSun, 16 Oct 2016 10:00:00 +0000\r\n"[..]);
+
+        trace.set_peer("::ffff:192.0.2.1".parse().unwrap());
+        trace.set_protocol(true, true, false);
+        assert_eq!(trace.to_field(),
+                   &b"Received: from [192.0.2.1]\r\n\
+                      \tby mx.test with ESMTPS;\r\n\
+                      \tSun, 16 Oct 2016 10:00:00 +0000\r\n"[..]);
+
+        trace.set_helo(b"client.test");
+        trace.set_peer_name(b"mail.client.test");
+        trace.set_protocol(true, true, true);
+        trace.set_id(b"1234");
+        trace.set_recipient(b"b@mx.test");
+        assert_eq!(trace.to_field(),
+                   &b"Received: from client.test \
+                      (mail.client.test [192.0.2.1])\r\n\
+                      \tby mx.test with ESMTPSA id 1234\r\n\
+                      \tfor <b@mx.test>;\r\n\
+                      \tSun, 16 Oct 2016 10:00:00 +0000\r\n"[..]);
+
+        trace.set_cipher(b"TLSv1.2 with cipher AES256-SHA (256/256 bits)");
+        assert_eq!(trace.to_field(),
+                   &b"Received: from client.test \
+                      (mail.client.test [192.0.2.1])\r\n\
+                      \tby mx.test with ESMTPSA\r\n\
+                      \t(using TLSv1.2 with cipher AES256-SHA \
+                      (256/256 bits)) id 1234\r\n\
+                      \tfor <b@mx.test>;\r\n\
+                      \tSun, 16 Oct 2016 10:00:00 +0000\r\n"[..]);
+
+        let mut trace = Trace::new(b"mx.test", 1476612000);
+        trace.set_helo(b"client.test");
+        trace.set_peer("2001:db8::1".parse().unwrap());
+        trace.set_protocol(false, true, true);
+        assert_eq!(trace.to_field(),
+                   &b"Received: from client.test ([IPv6:2001:db8::1])\r\n\
+                      \tby mx.test with SMTP;\r\n\
+                      \tSun, 16 Oct 2016 10:00:00 +0000\r\n"[..]);
+    }
+
+    #[test]
+    fn peer_name() {
+        let mut zone = Zone::new();
+        zone.insert(b"1.2.0.192.in-addr.arpa",
+                    Record::Ptr(b"Mail.Client.Test.".to_vec()));
+        zone.insert(b"mail.client.test",
+                    Record::A(Ipv4Addr::new(192, 0, 2, 1)));
+        zone.insert(b"2.2.0.192.in-addr.arpa",
+                    Record::Ptr(b"forged.client.test".to_vec()));
+        zone.insert(b"forged.client.test",
+                    Record::A(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(super::peer_name(&zone, "192.0.2.1".parse().unwrap()),
+                   Some(b"mail.client.test".to_vec()));
+        assert_eq!(super::peer_name(&zone, "192.0.2.2".parse().unwrap()),
+                   None);
+        assert_eq!(super::peer_name(&zone, "192.0.2.3".parse().unwrap()),
+                   None);
+    }
+}
@@ -1,14 +1,17 @@
 //! Netmachines handlers.
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::Rc;
+use std::time::Duration;
 use netmachines::{AcceptHandler, Next, TransportHandler};
 use netmachines::sockets::HybridStream;
 use rotor::Notifier;
+use ::net::tls::TlsInfo;
+use super::access::{Admission, Ticket};
 use super::buf::{RecvBuf, SendBuf};
 use super::config::Config;
 use super::protocol::{Protocol, SessionHandler};
-use super::session::{Action, Session};
+use super::session::{Action, Phase, Session};
 
 
 //------------ Accept --------------------------------------------------------
@@ -28,10 +31,17 @@ impl<T: HybridStream, P: Protocol> AcceptHandler<T> for Accept<P> {
     type Output = Transport<P>;
 
     fn accept(&mut self, addr: &SocketAddr)
-              -> Option<(<P::Session as SessionHandler<P>>::Seed,
+              -> Option<(Option<(<P::Session as SessionHandler<P>>::Seed,
+                                 Ticket, IpAddr)>,
                          Rc<Config>)> {
+        let ticket = match self.config.access().check(addr.ip()) {
+            Admission::Accept(ticket) => ticket,
+            Admission::Reject => return Some((None, self.config.clone())),
+            Admission::Refuse => return None
+        };
         self.protocol.accept(addr)
-                     .map(|session| (session, self.config.clone()))
+                     .map(|session| (Some((session, ticket, addr.ip())),
+                                     self.config.clone()))
     }
 }
 
@@ -40,10 +50,19 @@ impl<T: HybridStream, P: Protocol> AcceptHandler<T> for Accept<P> {
 
 pub struct Transport<P: Protocol> {
     session: Session<P>,
+    config: Rc<Config>,
     plot: Plot,
     tls: Tls,
     recv: RecvBuf,
-    send: SendBuf
+    send: SendBuf,
+
+    /// Have we timed out already?
+    timed_out: bool,
+
+    /// The admission of the connection.
+    ///
+    /// This is only kept so it is dropped when the connection goes.
+    _ticket: Option<Ticket>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -55,17 +74,38 @@ enum Tls {
 
 
 impl<P: Protocol> Transport<P> {
-    fn new(session: Session<P>, plot: Plot, recv: RecvBuf, send: SendBuf)
-           -> Self {
-        Transport { session: session, plot: plot, tls: Tls::Clear,
-                    recv: recv, send: send }
+    fn new(session: Session<P>, config: Rc<Config>, plot: Plot,
+           recv: RecvBuf, send: SendBuf, ticket: Option<Ticket>) -> Self {
+        Transport { session: session, config: config, plot: plot,
+                    tls: Tls::Clear, recv: recv, send: send,
+                    timed_out: false, _ticket: ticket }
     }
 
     fn next(self) -> Next<Self> {
+        let timeout = self.timeout();
         match self.plot {
-            Plot::Read => Next::read(self),
-            Plot::Wait => Next::wait(self),
-            Plot::Write(_) => Next::write(self),
+            Plot::Read => Next::read(self).timeout(timeout),
+            Plot::Wait => Next::wait(self).timeout(timeout),
+            Plot::Write(_) => Next::write(self).timeout(timeout),
+        }
+    }
+
+    /// Determines the timeout for whatever we are waiting for now.
+    ///
+    /// Since this is called anew after each read, the timeout starts over
+    /// whenever something arrives.
+    fn timeout(&self) -> Duration {
+        let timeouts = self.config.timeouts();
+        if self.timed_out {
+            // We are trying to get rid of the 421 reply. Don't wait
+            // forever for that either.
+            return timeouts.command
+        }
+        match self.session.phase() {
+            Phase::Greeting => timeouts.greeting,
+            Phase::Command => timeouts.command,
+            Phase::Data => timeouts.data_block,
+            Phase::Processing => timeouts.data_termination
         }
     }
 
@@ -104,10 +144,25 @@ impl<P: Protocol> Transport<P> {
                                                   &mut self.send,
                                                   self.tls == Tls::Secure);
         self.session = session;
+        self.proceed(action, sock)
+    }
+
+    /// Acts on what the session wants to happen next.
+    ///
+    /// Replies to pipelined commands are collected for as long as there
+    /// are more commands in the receive buffer. Whenever we run out of
+    /// input, everything collected so far is sent before reading again.
+    fn proceed<T: HybridStream>(self, action: Action, sock: &mut T)
+                                -> Next<Self> {
         match action {
             Action::Collect if !self.recv.is_empty() => self.recv(sock),
             _ => {
-                let plot = Plot::from(action);
+                let plot = match Plot::from(action) {
+                    Plot::Read if !self.send.is_empty() => {
+                        Plot::Write(AndThen::Read)
+                    }
+                    plot => plot
+                };
                 match plot {
                     Plot::Read | Plot::Wait => self.next_plot(plot),
                     Plot::Write(then) => {
@@ -119,9 +174,12 @@ impl<P: Protocol> Transport<P> {
         }
     }
 
-    fn confirm_tls<T: HybridStream>(mut self, sock: &mut T) -> Next<Self> {
+    fn confirm_tls<T>(mut self, sock: &mut T) -> Next<Self>
+                   where T: HybridStream + TlsInfo {
         self.tls = Tls::Secure;
-        let (session, action) = self.session.confirm_tls(sock.get_peer_cert());
+        let cipher = sock.current_cipher().map(String::into_bytes);
+        let (session, action) = self.session.confirm_tls(sock.get_peer_cert(),
+                                                         cipher);
         self.session = session;
         let plot = Plot::from(action);
         match plot {
@@ -133,17 +191,33 @@ impl<P: Protocol> Transport<P> {
 }
 
 
-impl<T: HybridStream, P: Protocol> TransportHandler<T> for Transport<P> {
-    type Seed = (<P::Session as SessionHandler<P>>::Seed, Rc<Config>);
+impl<T, P> TransportHandler<T> for Transport<P>
+     where T: HybridStream + TlsInfo, P: Protocol {
+    /// The seed is `None` if access control has rejected the connection.
+    type Seed = (Option<(<P::Session as SessionHandler<P>>::Seed, Ticket,
+                         IpAddr)>,
+                 Rc<Config>);
 
     fn create(seed: Self::Seed, _sock: &mut T, notifier: Notifier)
               -> Next<Self> {
         let (seed, config) = seed;
         let recv = RecvBuf::new();
         let mut send = SendBuf::new();
-        let (session, action) = Session::new(seed, config, notifier,
-                                             &mut send);
-        Transport::new(session, Plot::from(action), recv, send).next()
+        let (session, action, ticket) = match seed {
+            Some((seed, ticket, peer)) => {
+                let (session, action) = Session::new(seed, peer,
+                                                     config.clone(),
+                                                     notifier, &mut send);
+                (session, action, Some(ticket))
+            }
+            None => {
+                let (session, action) = Session::reject(config.clone(),
+                                                        &mut send);
+                (session, action, None)
+            }
+        };
+        Transport::new(session, config, Plot::from(action), recv, send,
+                       ticket).next()
     }
 
     fn readable(mut self, sock: &mut T) -> Next<Self> {
@@ -179,18 +253,27 @@ impl<T: HybridStream, P: Protocol> TransportHandler<T> for Transport<P> {
     }
 
     fn wakeup(mut self, sock: &mut T) -> Next<Self> {
-        let (session, action) = self.session.wakeup(&mut self.send,
+        let more = !self.recv.is_empty();
+        let (session, action) = self.session.wakeup(&mut self.send, more,
                                                     self.tls == Tls::Secure);
         self.session = session;
-        let plot = Plot::from(action);
-        match plot {
-            Plot::Read => self.recv(sock),
-            Plot::Wait => self.next_plot(plot),
-            Plot::Write(then) => {
-                if self.send.is_empty() { self.and_then(then, sock) }
-                else { self.next_plot(plot) }
-            }
+        match action {
+            // Whatever the client sent while we were waiting still needs
+            // processing.
+            Action::Read => self.recv(sock),
+            action => self.proceed(action, sock)
+        }
+    }
+
+    fn timeout(mut self, _sock: &mut T) -> Next<Self> {
+        if self.timed_out {
+            return Next::remove()
         }
+        debug!("SMTP connection: timeout");
+        self.timed_out = true;
+        let (session, action) = self.session.timeout(&mut self.send);
+        self.session = session;
+        self.next_plot(Plot::from(action))
     }
 }
 
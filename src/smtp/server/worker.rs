@@ -0,0 +1,372 @@
+//! Running blocking lookups off the event loop.
+//!
+//! Protocol implementations often need to ask something that takes a
+//! while, such as a user database or a directory server, before they
+//! can decide what to do. Since sessions run on the event loop, they
+//! must not block. Instead, they hand the lookup to a `Pool` which runs
+//! it on one of its worker threads, defer their decision and resolve it
+//! once the session’s machine has been woken up with the result.
+//!
+//! For this to work, the session needs to keep the notifier it was
+//! given in `SessionHandler::start()`. A lookup during RCPT could then
+//! look something like this:
+//!
+//! ```ignore
+//! type Recipient = DeferredReply<bool, Mail, Result<Mail, Session>>;
+//!
+//! fn recipient(self, path: syntax::RcptPath, ...)
+//!              -> Hesitant<Result<Mail, Session>, Self::Recipient> {
+//!     let db = self.db.clone();
+//!     let pending = self.pool.run(&self.notifier,
+//!                                 move || db.has_user(&path));
+//!     Hesitant::Defer(DeferredReply::new(pending, self, Mail::resolve))
+//! }
+//! ```
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+use rotor::Notifier;
+use super::protocol::{Hesitant, Undecided, UndecidedReply};
+use super::reply::ReplyBuf;
+
+
+//------------ Pool ----------------------------------------------------------
+
+/// A pool of threads running blocking jobs.
+///
+/// Values of this type are cheap to clone. All clones share the same
+/// threads which finish once the last clone is gone and all queued jobs
+/// have been processed.
+///
+#[derive(Clone)]
+pub struct Pool {
+    jobs: Sender<Box<Job>>,
+}
+
+impl Pool {
+    /// Creates a new pool with *size* threads.
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "worker pool needs at least one thread");
+        let (tx, rx) = channel::<Box<Job>>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..size {
+            spawn_worker(rx.clone());
+        }
+        Pool { jobs: tx }
+    }
+
+    /// Runs *op* on a worker thread.
+    ///
+    /// Once *op* has finished, the machine behind *notifier* is woken up.
+    /// The result can then be taken from the returned value. This also
+    /// happens if *op* panics, only that there won’t be a result.
+    pub fn run<F, T>(&self, notifier: &Notifier, op: F) -> Pending<T>
+               where F: FnOnce() -> T + Send + 'static,
+                     T: Send + 'static {
+        let (tx, rx) = channel();
+        let completion = Completion { result: Some(tx),
+                                      notifier: notifier.clone() };
+        let job = move || completion.finish(op());
+        // If all workers are dead, the job is dropped right here which
+        // resolves the pending value to `None` and wakes up the machine.
+        let _ = self.jobs.send(Box::new(job));
+        Pending { result: rx }
+    }
+}
+
+
+//------------ Job -----------------------------------------------------------
+
+/// Something a worker can run.
+///
+/// This is `FnOnce()` in a way that can be called when boxed.
+trait Job: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Job for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+
+//------------ Completion ----------------------------------------------------
+
+/// Delivers the result of a job and wakes up the machine waiting for it.
+///
+/// The wakeup happens when the value is dropped, so it also happens if
+/// the job panics or is never run at all. The result sender is dropped
+/// before that so the machine will find the pending value resolved.
+struct Completion<T> {
+    result: Option<Sender<T>>,
+    notifier: Notifier,
+}
+
+impl<T> Completion<T> {
+    fn finish(mut self, res: T) {
+        if let Some(result) = self.result.take() {
+            // If the receiving side is gone, nobody cares anymore.
+            let _ = result.send(res);
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        self.result = None;
+        let _ = self.notifier.wakeup();
+    }
+}
+
+
+//------------ Workers -------------------------------------------------------
+
+fn spawn_worker(jobs: Arc<Mutex<Receiver<Box<Job>>>>) {
+    thread::spawn(move || {
+        let sentinel = Sentinel(Some(jobs.clone()));
+        loop {
+            let job = match jobs.lock() {
+                Ok(jobs) => jobs.recv(),
+                Err(_) => break,
+            };
+            match job {
+                Ok(job) => job.run(),
+                Err(_) => break,
+            }
+        }
+        sentinel.cancel();
+    });
+}
+
+/// Replaces a worker that has died from a panicking job.
+///
+/// The job’s pending value will resolve to `None` since the result
+/// sender has been dropped during unwinding.
+struct Sentinel(Option<Arc<Mutex<Receiver<Box<Job>>>>>);
+
+impl Sentinel {
+    fn cancel(mut self) {
+        self.0 = None
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if let Some(jobs) = self.0.take() {
+            if thread::panicking() {
+                spawn_worker(jobs)
+            }
+        }
+    }
+}
+
+
+//------------ Pending -------------------------------------------------------
+
+/// The result of a job that may not be available yet.
+pub struct Pending<T> {
+    result: Receiver<T>,
+}
+
+impl<T> Pending<T> {
+    /// Takes the result if it is available.
+    ///
+    /// Returns `Hesitant::Defer(self)` if the job hasn’t finished yet.
+    /// The final value is `None` if the job failed to produce a result
+    /// because it panicked or there were no workers left.
+    pub fn poll(self) -> Hesitant<Option<T>, Self> {
+        match self.result.try_recv() {
+            Ok(res) => Hesitant::Final(Some(res)),
+            Err(TryRecvError::Empty) => Hesitant::Defer(self),
+            Err(TryRecvError::Disconnected) => Hesitant::Final(None),
+        }
+    }
+
+    /// Blocks until the result is available.
+    ///
+    /// This must not be used on the event loop.
+    pub fn wait(self) -> Option<T> {
+        self.result.recv().ok()
+    }
+}
+
+
+//------------ Deferred ------------------------------------------------------
+
+/// A deferred decision waiting for the result of a job.
+///
+/// The value keeps whatever state is needed to make the decision in
+/// addition to the pending result. Once the result is in, the function
+/// *resolve* is called with both to make the final decision.
+///
+/// Since *resolve* is a function and not a closure, the type can be
+/// named as an associated type of the protocol traits.
+pub struct Deferred<T, S, R> {
+    pending: Pending<T>,
+    state: S,
+    resolve: fn(S, Option<T>) -> R,
+}
+
+impl<T, S, R> Deferred<T, S, R> {
+    pub fn new(pending: Pending<T>, state: S,
+               resolve: fn(S, Option<T>) -> R) -> Self {
+        Deferred { pending: pending, state: state, resolve: resolve }
+    }
+}
+
+impl<T, S, R> Undecided<R> for Deferred<T, S, R> {
+    fn wakeup(self) -> Hesitant<R, Self> {
+        let Deferred { pending, state, resolve } = self;
+        match pending.poll() {
+            Hesitant::Final(res) => Hesitant::Final(resolve(state, res)),
+            Hesitant::Defer(pending) => {
+                Hesitant::Defer(Deferred::new(pending, state, resolve))
+            }
+        }
+    }
+}
+
+
+//------------ DeferredReply -------------------------------------------------
+
+/// A deferred decision that produces a reply.
+///
+/// This is the same as `Deferred` except that *resolve* also receives
+/// the reply buffer for the reply to the command.
+pub struct DeferredReply<T, S, R> {
+    pending: Pending<T>,
+    state: S,
+    resolve: fn(S, Option<T>, ReplyBuf) -> R,
+}
+
+impl<T, S, R> DeferredReply<T, S, R> {
+    pub fn new(pending: Pending<T>, state: S,
+               resolve: fn(S, Option<T>, ReplyBuf) -> R) -> Self {
+        DeferredReply { pending: pending, state: state, resolve: resolve }
+    }
+}
+
+impl<T, S, R> UndecidedReply<R> for DeferredReply<T, S, R> {
+    fn wakeup(self, reply: ReplyBuf) -> Hesitant<R, Self> {
+        let DeferredReply { pending, state, resolve } = self;
+        match pending.poll() {
+            Hesitant::Final(res) => Hesitant::Final(resolve(state, res,
+                                                            reply)),
+            Hesitant::Defer(pending) => {
+                Hesitant::Defer(DeferredReply::new(pending, state, resolve))
+            }
+        }
+    }
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use rotor::{self, EventSet, Loop, Machine, Response, Scope, Void};
+    use ::net::test::notifier;
+    use ::smtp::server::protocol::{Hesitant, Undecided};
+    use super::*;
+
+    fn add(base: u32, res: Option<u32>) -> Option<u32> {
+        res.map(|res| base + res)
+    }
+
+    #[test]
+    fn run() {
+        let (_lp, notifier) = notifier();
+        let pool = Pool::new(2);
+        let results: Vec<_> = (0..8u32).map(|i| {
+            pool.run(&notifier, move || i * 2)
+        }).collect();
+        for (i, res) in results.into_iter().enumerate() {
+            assert_eq!(res.wait(), Some(i as u32 * 2));
+        }
+    }
+
+    /// Creates a notifier whose first wakeup can be waited for.
+    ///
+    /// The notifier belongs to a loop on a thread of its own which ends
+    /// after sending the wakeup to the returned receiver.
+    fn waker() -> (Notifier, Receiver<()>) {
+        let (tx, rx) = channel();
+        let (notifier_tx, notifier_rx) = channel();
+        thread::spawn(move || {
+            let mut lp = Loop::new(&rotor::Config::new()).unwrap();
+            lp.add_machine_with(|scope| {
+                notifier_tx.send(scope.notifier()).unwrap();
+                Response::ok(Waker(tx))
+            }).unwrap();
+            lp.run(()).unwrap();
+        });
+        (notifier_rx.recv().unwrap(), rx)
+    }
+
+    /// A machine that reports its first wakeup and ends the loop.
+    struct Waker(Sender<()>);
+
+    impl Machine for Waker {
+        type Context = ();
+        type Seed = Void;
+
+        fn create(seed: Void, _scope: &mut Scope<()>)
+                  -> Response<Self, Void> {
+            match seed { }
+        }
+
+        fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+                 -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn wakeup(self, scope: &mut Scope<()>) -> Response<Self, Void> {
+            let _ = self.0.send(());
+            scope.shutdown_loop();
+            Response::done()
+        }
+    }
+
+    #[test]
+    fn panicking_job() {
+        let (notifier, woken) = waker();
+        let pool = Pool::new(1);
+        let res = pool.run(&notifier, || -> u32 { panic!("job failed") });
+        woken.recv_timeout(Duration::from_secs(5)).unwrap();
+        match res.poll() {
+            Hesitant::Final(None) => { }
+            _ => panic!("failed job has a result")
+        }
+
+        // The worker has been replaced.
+        assert_eq!(pool.run(&notifier, || 7).wait(), Some(7));
+    }
+
+    #[test]
+    fn deferred() {
+        let (_lp, notifier) = notifier();
+        let pool = Pool::new(1);
+        let pending = pool.run(&notifier, || 2);
+        let mut deferred = Deferred::new(pending, 40, add);
+        loop {
+            deferred = match deferred.wakeup() {
+                Hesitant::Final(res) => {
+                    assert_eq!(res, Some(42));
+                    break
+                }
+                Hesitant::Defer(deferred) => deferred
+            }
+        }
+    }
+}
@@ -0,0 +1,1340 @@
+//! Sender Policy Framework.
+//!
+//! This module checks whether a client is allowed to send mail for a
+//! domain as described in RFC 7208. Two identities are checked: the
+//! domain the client gave in HELO or EHLO and the domain of the reverse
+//! path given in MAIL. Both are collected in a `Query`. Its `check()`
+//! method evaluates the SPF records of the domains and returns the
+//! outcome as an `Spf` value that can be turned into a Received-SPF
+//! header field.
+//!
+//! Checking involves DNS lookups and therefore blocks. It should happen
+//! on a worker pool.
+//!
+//! All mechanisms and modifiers of RFC 7208 are supported, including
+//! macros and the limits on the number of lookups.
+
+use std::ascii::AsciiExt;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use ::net::dns::{self, Resolver};
+use ::smtp::server::access::{unmap, Network};
+use ::smtp::syntax::{MailboxDomain, ReversePath};
+use ::util::date;
+use ::util::text::is_within;
+
+
+/// The maximum number of terms causing DNS lookups.
+const MAX_LOOKUPS: usize = 10;
+
+/// The maximum number of lookups without any answers.
+const MAX_VOID_LOOKUPS: usize = 2;
+
+/// The maximum number of names looked at by the mx and ptr mechanisms.
+const MAX_NAMES: usize = 10;
+
+/// The number of seconds evaluating the records of an identity may take.
+///
+/// RFC 7208, section 4.6.4 asks for at least twenty.
+const MAX_SECONDS: u64 = 20;
+
+
+//------------ Query ---------------------------------------------------------
+
+/// The identities of a client to check.
+#[derive(Clone, Debug)]
+pub struct Query {
+    ip: IpAddr,
+
+    /// The HELO identity as given by the client.
+    helo: Vec<u8>,
+
+    /// Is the HELO identity a domain rather than an address literal?
+    helo_is_domain: bool,
+
+    /// The local part and domain of the reverse path.
+    ///
+    /// This is `None` for the null reverse path.
+    sender: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Query {
+    /// Creates a query for the client at *ip* that greeted with *helo*.
+    pub fn new(ip: IpAddr, helo: &MailboxDomain) -> Self {
+        let (name, is_domain) = match *helo {
+            MailboxDomain::Domain(ref domain) => {
+                (domain.as_bytes().to_vec(), true)
+            }
+            MailboxDomain::Address(_) => (helo.to_string().into_bytes(), false)
+        };
+        Query { ip: unmap(ip), helo: name, helo_is_domain: is_domain,
+                sender: None }
+    }
+
+    /// Sets the reverse path given in MAIL.
+    pub fn set_sender(&mut self, path: &ReversePath) {
+        self.sender = match *path {
+            ReversePath::Path(ref path) => {
+                let mailbox = path.mailbox();
+                Some((mailbox.local().to_string().into_bytes(),
+                      mailbox.domain().to_string().into_bytes()))
+            }
+            ReversePath::Empty => None
+        }
+    }
+
+    /// Checks the identities.
+    ///
+    /// The HELO identity is checked first. If that fails, its result is
+    /// returned. Otherwise the result for the MAIL identity is returned
+    /// unless the reverse path is null in which case the HELO identity
+    /// takes its place.
+    pub fn check(&self, resolver: &Resolver) -> Spf {
+        let helo = if self.helo_is_domain {
+            Some(self.check_identity(resolver, Identity::Helo,
+                                     b"postmaster", &self.helo))
+        }
+        else { None };
+        let helo_failed = helo.as_ref().map_or(false, |helo| {
+            helo.result == Outcome::Fail
+        });
+        if helo_failed {
+            return helo.unwrap()
+        }
+        if let Some((ref local, ref domain)) = self.sender {
+            return self.check_identity(resolver, Identity::MailFrom, local,
+                                       domain)
+        }
+        match helo {
+            Some(helo) => helo,
+            None => {
+                let mut sender = b"postmaster@".to_vec();
+                sender.extend_from_slice(&self.helo);
+                Spf { result: Outcome::None, identity: Identity::Helo,
+                      ip: self.ip, sender: sender,
+                      envelope_from: self.envelope_from(),
+                      helo: self.helo.clone(), domain: self.helo.clone(),
+                      explanation: None }
+            }
+        }
+    }
+
+    fn check_identity(&self, resolver: &Resolver, identity: Identity,
+                      local: &[u8], domain: &[u8]) -> Spf {
+        let mut sender = local.to_vec();
+        sender.push(b'@');
+        sender.extend_from_slice(domain);
+        let (result, explanation) = {
+            let mut eval = Evaluator::new(resolver, self.ip, &sender, local,
+                                          domain, &self.helo);
+            let result = eval.check_host(domain);
+            (result, eval.explanation.take())
+        };
+        let explanation = if result == Outcome::Fail { explanation }
+                          else { None };
+        Spf { result: result, identity: identity, ip: self.ip,
+              sender: sender, envelope_from: self.envelope_from(),
+              helo: self.helo.clone(), domain: domain.into(),
+              explanation: explanation }
+    }
+
+    /// Returns the mailbox of the reverse path or nothing for a null one.
+    fn envelope_from(&self) -> Vec<u8> {
+        match self.sender {
+            Some((ref local, ref domain)) => {
+                let mut res = local.clone();
+                res.push(b'@');
+                res.extend_from_slice(domain);
+                res
+            }
+            None => Vec::new()
+        }
+    }
+}
+
+
+//------------ Spf -----------------------------------------------------------
+
+/// The result of checking a client.
+#[derive(Clone, Debug)]
+pub struct Spf {
+    pub result: Outcome,
+
+    /// The identity the result is for.
+    pub identity: Identity,
+
+    /// The address of the client.
+    pub ip: IpAddr,
+
+    /// The mailbox of the identity.
+    pub sender: Vec<u8>,
+
+    /// The mailbox of the reverse path.
+    ///
+    /// This is empty if the reverse path was null.
+    pub envelope_from: Vec<u8>,
+
+    /// The HELO identity as given by the client.
+    pub helo: Vec<u8>,
+
+    /// The domain whose records were checked.
+    pub domain: Vec<u8>,
+
+    /// The explanation published by the domain if the result is a fail.
+    pub explanation: Option<Vec<u8>>,
+}
+
+impl Spf {
+    /// Returns a Received-SPF header field for the result.
+    ///
+    /// The field is complete including the final line break.
+    pub fn received_spf(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        res.extend_from_slice(b"Received-SPF: ");
+        res.extend_from_slice(self.result.as_str().as_bytes());
+        res.extend_from_slice(b" (");
+        let comment = self.comment();
+        for ch in comment.bytes() {
+            if ch == b'(' || ch == b')' || ch == b'\\' {
+                res.push(b'\\')
+            }
+            res.push(ch)
+        }
+        res.extend_from_slice(b")\r\n\tclient-ip=");
+        res.extend_from_slice(self.ip.to_string().as_bytes());
+        res.extend_from_slice(b"; envelope-from=");
+        push_quoted(&mut res, &self.envelope_from);
+        res.extend_from_slice(b"; helo=");
+        push_quoted(&mut res, &self.helo);
+        res.extend_from_slice(b";\r\n\tidentity=");
+        res.extend_from_slice(self.identity.as_str().as_bytes());
+        res.extend_from_slice(b";\r\n");
+        res
+    }
+
+    fn comment(&self) -> String {
+        let sender = String::from_utf8_lossy(&self.sender);
+        match self.result {
+            Outcome::Pass => {
+                format!("domain of {} designates {} as permitted sender",
+                        sender, self.ip)
+            }
+            Outcome::Fail => {
+                format!("domain of {} does not designate {} as permitted \
+                         sender", sender, self.ip)
+            }
+            Outcome::SoftFail => {
+                format!("domain of transitioning {} does not designate {} \
+                         as permitted sender", sender, self.ip)
+            }
+            Outcome::Neutral => {
+                format!("{} is neither permitted nor denied by domain of {}",
+                        self.ip, sender)
+            }
+            Outcome::None => {
+                format!("domain of {} does not publish SPF records", sender)
+            }
+            Outcome::TempError => {
+                format!("temporary error while checking domain of {}",
+                        sender)
+            }
+            Outcome::PermError => {
+                format!("permanent error in SPF records of domain of {}",
+                        sender)
+            }
+        }
+    }
+}
+
+/// Appends *value* as a quoted string.
+fn push_quoted(target: &mut Vec<u8>, value: &[u8]) {
+    target.push(b'"');
+    for &ch in value {
+        if ch == b'"' || ch == b'\\' {
+            target.push(b'\\')
+        }
+        target.push(ch)
+    }
+    target.push(b'"');
+}
+
+
+//------------ Outcome -------------------------------------------------------
+
+/// The possible results of an SPF check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// No records were published or the domain was invalid.
+    None,
+
+    /// The domain makes no assertion about the client.
+    Neutral,
+
+    /// The client is authorized to use the domain.
+    Pass,
+
+    /// The client is not authorized to use the domain.
+    Fail,
+
+    /// The client is probably not authorized to use the domain.
+    SoftFail,
+
+    /// A transient error happened during the check.
+    TempError,
+
+    /// The domain’s records could not be interpreted.
+    PermError,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::None => "none",
+            Outcome::Neutral => "neutral",
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+            Outcome::SoftFail => "softfail",
+            Outcome::TempError => "temperror",
+            Outcome::PermError => "permerror",
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+//------------ Identity ------------------------------------------------------
+
+/// The identity that has been checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Identity {
+    Helo,
+    MailFrom,
+}
+
+impl Identity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Identity::Helo => "helo",
+            Identity::MailFrom => "mailfrom",
+        }
+    }
+}
+
+
+//------------ Evaluator -----------------------------------------------------
+
+/// The state of evaluating the records for one identity.
+///
+/// This implements the check_host() function of RFC 7208. Errors that
+/// end evaluation are returned as the `Err` variant of results with
+/// either `Outcome::TempError` or `Outcome::PermError`. Once
+/// `MAX_SECONDS` have passed, further lookups end in a temporary error.
+struct Evaluator<'a> {
+    resolver: &'a Resolver,
+    ip: IpAddr,
+    sender: &'a [u8],
+    local: &'a [u8],
+    sender_domain: &'a [u8],
+    helo: &'a [u8],
+
+    /// The number of terms that caused DNS lookups so far.
+    lookups: usize,
+
+    /// The number of lookups that returned nothing so far.
+    void_lookups: usize,
+
+    /// The explanation of the last fail.
+    explanation: Option<Vec<u8>>,
+
+    /// When evaluation has to give up.
+    deadline: Instant,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(resolver: &'a Resolver, ip: IpAddr, sender: &'a [u8],
+           local: &'a [u8], sender_domain: &'a [u8], helo: &'a [u8])
+           -> Self {
+        Evaluator { resolver: resolver, ip: ip, sender: sender,
+                    local: local, sender_domain: sender_domain, helo: helo,
+                    lookups: 0, void_lookups: 0, explanation: None,
+                    deadline: Instant::now()
+                                  + Duration::from_secs(MAX_SECONDS) }
+    }
+
+    fn check_host(&mut self, domain: &[u8]) -> Outcome {
+        if !is_valid_domain(domain) {
+            return Outcome::None
+        }
+        let record = match self.record(domain) {
+            Ok(record) => record,
+            Err(outcome) => return outcome
+        };
+        let terms = match Terms::parse(&record) {
+            Some(terms) => terms,
+            None => return Outcome::PermError
+        };
+        match self.evaluate(domain, &terms) {
+            Ok(outcome) => outcome,
+            Err(outcome) => outcome
+        }
+    }
+
+    /// Returns the SPF record of *domain*.
+    fn record(&mut self, domain: &[u8]) -> Result<Vec<u8>, Outcome> {
+        try!(self.check_time());
+        let mut records = match self.resolver.lookup_txt(domain) {
+            Ok(records) => records,
+            Err(dns::Error::NotFound) | Err(dns::Error::InvalidName) => {
+                return Err(Outcome::None)
+            }
+            Err(_) => return Err(Outcome::TempError)
+        };
+        records.retain(|record| is_spf_record(record));
+        match records.len() {
+            0 => Err(Outcome::None),
+            1 => Ok(records.pop().unwrap()),
+            _ => Err(Outcome::PermError)
+        }
+    }
+
+    fn evaluate(&mut self, domain: &[u8], terms: &Terms)
+                -> Result<Outcome, Outcome> {
+        for &(outcome, ref mechanism) in &terms.directives {
+            if try!(self.matches(domain, mechanism)) {
+                if outcome == Outcome::Fail {
+                    self.explanation = match terms.exp {
+                        Some(exp) => self.explain(domain, exp),
+                        None => None
+                    };
+                }
+                return Ok(outcome)
+            }
+        }
+        match terms.redirect {
+            Some(target) => {
+                try!(self.count_lookup());
+                let target = try!(self.expand_domain(target, domain));
+                match self.check_host(&target) {
+                    Outcome::None => Err(Outcome::PermError),
+                    outcome => Ok(outcome)
+                }
+            }
+            None => Ok(Outcome::Neutral)
+        }
+    }
+
+    fn matches(&mut self, domain: &[u8], mechanism: &Mechanism)
+               -> Result<bool, Outcome> {
+        match *mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Include(spec) => {
+                try!(self.count_lookup());
+                let target = try!(self.expand_domain(spec, domain));
+                let outcome = self.check_host(&target);
+                // Explanations of included records are never used.
+                self.explanation = None;
+                match outcome {
+                    Outcome::Pass => Ok(true),
+                    Outcome::Fail | Outcome::SoftFail
+                        | Outcome::Neutral => Ok(false),
+                    Outcome::TempError => Err(Outcome::TempError),
+                    Outcome::PermError
+                        | Outcome::None => Err(Outcome::PermError),
+                }
+            }
+            Mechanism::A(spec, cidr4, cidr6) => {
+                try!(self.count_lookup());
+                let target = try!(self.target(spec, domain));
+                let addrs = try!(self.addresses(&target));
+                Ok(addrs.into_iter().any(|addr| {
+                    self.in_network(addr, cidr4, cidr6)
+                }))
+            }
+            Mechanism::Mx(spec, cidr4, cidr6) => {
+                try!(self.count_lookup());
+                let target = try!(self.target(spec, domain));
+                let resolver = self.resolver;
+                let names = try!(self.lookup(|| resolver.lookup_mx(&target)));
+                if names.len() > MAX_NAMES {
+                    return Err(Outcome::PermError)
+                }
+                for name in names {
+                    let addrs = try!(self.addresses(&name));
+                    if addrs.into_iter().any(|addr| {
+                        self.in_network(addr, cidr4, cidr6)
+                    }) {
+                        return Ok(true)
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(spec) => {
+                try!(self.count_lookup());
+                let target = try!(self.target(spec, domain));
+                try!(self.check_time());
+                Ok(self.validated_names().iter().any(|name| {
+                    is_within(name, &target)
+                }))
+            }
+            Mechanism::Ip(ref network) => Ok(network.contains(self.ip)),
+            Mechanism::Exists(spec) => {
+                try!(self.count_lookup());
+                let target = try!(self.expand_domain(spec, domain));
+                let resolver = self.resolver;
+                let addrs = try!(self.lookup(|| resolver.lookup_a(&target)));
+                Ok(!addrs.is_empty())
+            }
+        }
+    }
+
+    /// Returns the explanation for a fail in *domain*.
+    ///
+    /// Any error simply means there is no explanation.
+    fn explain(&self, domain: &[u8], spec: &[u8]) -> Option<Vec<u8>> {
+        let target = match self.expand_domain(spec, domain) {
+            Ok(target) => target,
+            Err(_) => return None
+        };
+        let mut records = match self.resolver.lookup_txt(&target) {
+            Ok(records) => records,
+            Err(_) => return None
+        };
+        if records.len() != 1 {
+            return None
+        }
+        let record = records.pop().unwrap();
+        self.expand(&record, domain, true).ok()
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Outcome> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS { Err(Outcome::PermError) }
+        else { Ok(()) }
+    }
+
+    /// Returns a temporary error if evaluation has taken too long.
+    fn check_time(&self) -> Result<(), Outcome> {
+        if Instant::now() >= self.deadline { Err(Outcome::TempError) }
+        else { Ok(()) }
+    }
+
+    /// Performs a lookup for a mechanism.
+    ///
+    /// Names that don’t exist are treated as having no records but count
+    /// as void lookups. Other errors are temporary errors.
+    fn lookup<T, F>(&mut self, op: F) -> Result<Vec<T>, Outcome>
+              where F: FnOnce() -> Result<Vec<T>, dns::Error> {
+        try!(self.check_time());
+        let res = match op() {
+            Ok(res) => res,
+            Err(dns::Error::NotFound) | Err(dns::Error::InvalidName) => {
+                Vec::new()
+            }
+            Err(_) => return Err(Outcome::TempError)
+        };
+        if res.is_empty() {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err(Outcome::PermError)
+            }
+        }
+        Ok(res)
+    }
+
+    /// Returns the addresses of *name* in the client’s address family.
+    fn addresses(&mut self, name: &[u8]) -> Result<Vec<IpAddr>, Outcome> {
+        let resolver = self.resolver;
+        match self.ip {
+            IpAddr::V4(_) => {
+                let addrs = try!(self.lookup(|| resolver.lookup_a(name)));
+                Ok(addrs.into_iter().map(IpAddr::V4).collect())
+            }
+            IpAddr::V6(_) => {
+                let addrs = try!(self.lookup(|| resolver.lookup_aaaa(name)));
+                Ok(addrs.into_iter().map(IpAddr::V6).collect())
+            }
+        }
+    }
+
+    /// Returns whether the client is in the network around *addr*.
+    fn in_network(&self, addr: IpAddr, cidr4: u8, cidr6: u8) -> bool {
+        let prefix = match addr {
+            IpAddr::V4(_) => cidr4,
+            IpAddr::V6(_) => cidr6,
+        };
+        match Network::new(addr, prefix) {
+            Some(network) => network.contains(self.ip),
+            None => false
+        }
+    }
+
+    /// Returns the names of the client that point back to it.
+    ///
+    /// Lookup errors are ignored.
+    fn validated_names(&self) -> Vec<Vec<u8>> {
+        let names = match self.resolver.lookup_ptr(self.ip) {
+            Ok(names) => names,
+            Err(_) => return Vec::new()
+        };
+        names.into_iter().take(MAX_NAMES).filter(|name| {
+            match self.ip {
+                IpAddr::V4(ip) => {
+                    self.resolver.lookup_a(name)
+                        .map(|addrs| addrs.contains(&ip)).unwrap_or(false)
+                }
+                IpAddr::V6(ip) => {
+                    self.resolver.lookup_aaaa(name)
+                        .map(|addrs| addrs.contains(&ip)).unwrap_or(false)
+                }
+            }
+        }).collect()
+    }
+
+    /// Returns the target of a mechanism with an optional domain-spec.
+    fn target(&self, spec: Option<&[u8]>, domain: &[u8])
+              -> Result<Vec<u8>, Outcome> {
+        match spec {
+            Some(spec) => self.expand_domain(spec, domain),
+            None => Ok(domain.into())
+        }
+    }
+
+    /// Expands a domain-spec and shortens the result if necessary.
+    fn expand_domain(&self, spec: &[u8], domain: &[u8])
+                     -> Result<Vec<u8>, Outcome> {
+        let mut res = try!(self.expand(spec, domain, false));
+        if res.ends_with(b".") {
+            res.pop();
+        }
+        while res.len() > 253 {
+            match res.iter().position(|ch| *ch == b'.') {
+                Some(pos) => { res.drain(..pos + 1); }
+                None => return Err(Outcome::PermError)
+            }
+        }
+        Ok(res)
+    }
+
+    /// Expands the macros in *spec*.
+    ///
+    /// If *exp* is `true`, the macros only allowed in explanations are
+    /// available, too.
+    fn expand(&self, spec: &[u8], domain: &[u8], exp: bool)
+              -> Result<Vec<u8>, Outcome> {
+        let mut res = Vec::with_capacity(spec.len());
+        let mut pos = 0;
+        while pos < spec.len() {
+            if spec[pos] != b'%' {
+                res.push(spec[pos]);
+                pos += 1;
+                continue
+            }
+            match spec.get(pos + 1) {
+                Some(&b'%') => res.push(b'%'),
+                Some(&b'_') => res.push(b' '),
+                Some(&b'-') => res.extend_from_slice(b"%20"),
+                Some(&b'{') => {
+                    let end = match spec[pos..].iter()
+                                               .position(|ch| *ch == b'}') {
+                        Some(end) => pos + end,
+                        None => return Err(Outcome::PermError)
+                    };
+                    try!(self.expand_macro(&spec[pos + 2..end], domain, exp,
+                                           &mut res));
+                    pos = end + 1;
+                    continue
+                }
+                _ => return Err(Outcome::PermError)
+            }
+            pos += 2;
+        }
+        Ok(res)
+    }
+
+    /// Expands the macro with the content *body* between the braces.
+    fn expand_macro(&self, body: &[u8], domain: &[u8], exp: bool,
+                    target: &mut Vec<u8>) -> Result<(), Outcome> {
+        let (letter, rest) = match body.split_first() {
+            Some((letter, rest)) => (*letter, rest),
+            None => return Err(Outcome::PermError)
+        };
+        let value = match letter.to_ascii_lowercase() {
+            b's' => self.sender.to_vec(),
+            b'l' => self.local.to_vec(),
+            b'o' => self.sender_domain.to_vec(),
+            b'd' => domain.to_vec(),
+            b'i' => dotted_ip(self.ip),
+            b'p' => self.validated_name(domain),
+            b'v' => match self.ip {
+                IpAddr::V4(_) => b"in-addr".to_vec(),
+                IpAddr::V6(_) => b"ip6".to_vec(),
+            },
+            b'h' => self.helo.to_vec(),
+            b'c' if exp => self.ip.to_string().into_bytes(),
+            b'r' if exp => b"unknown".to_vec(),
+            b't' if exp => date::now().to_string().into_bytes(),
+            _ => return Err(Outcome::PermError)
+        };
+
+        let digits = rest.iter().take_while(|ch| is_digit(**ch)).count();
+        let keep = if digits > 0 {
+            let keep = rest[..digits].iter().fold(0usize, |res, ch| {
+                res.saturating_mul(10).saturating_add((*ch - b'0') as usize)
+            });
+            if keep == 0 {
+                return Err(Outcome::PermError)
+            }
+            Some(keep)
+        }
+        else { None };
+        let rest = &rest[digits..];
+        let (reverse, delimiters) = match rest.first() {
+            Some(&b'r') | Some(&b'R') => (true, &rest[1..]),
+            _ => (false, rest)
+        };
+        if !delimiters.iter().all(|ch| b".-+,/_=".contains(ch)) {
+            return Err(Outcome::PermError)
+        }
+        let delimiters = if delimiters.is_empty() { &b"."[..] }
+                         else { delimiters };
+
+        let mut parts: Vec<&[u8]> = value.split(|ch| {
+            delimiters.contains(ch)
+        }).collect();
+        if reverse {
+            parts.reverse()
+        }
+        if let Some(keep) = keep {
+            if keep < parts.len() {
+                let skip = parts.len() - keep;
+                parts.drain(..skip);
+            }
+        }
+        let value = parts.join(&b"."[..]);
+        if letter.is_ascii_uppercase() {
+            for ch in value {
+                if is_unreserved(ch) {
+                    target.push(ch)
+                }
+                else {
+                    target.extend_from_slice(format!("%{:02X}", ch)
+                                                 .as_bytes())
+                }
+            }
+        }
+        else {
+            target.extend_from_slice(&value)
+        }
+        Ok(())
+    }
+
+    /// Returns the validated name of the client for the p macro.
+    ///
+    /// Prefers *domain* itself, then its subdomains, then any name.
+    fn validated_name(&self, domain: &[u8]) -> Vec<u8> {
+        let names = self.validated_names();
+        if let Some(name) = names.iter().find(|name| {
+            name.eq_ignore_ascii_case(domain)
+        }) {
+            return name.clone()
+        }
+        if let Some(name) = names.iter().find(|name| is_within(name, domain)) {
+            return name.clone()
+        }
+        match names.into_iter().next() {
+            Some(name) => name,
+            None => b"unknown".to_vec()
+        }
+    }
+}
+
+
+//------------ Terms ---------------------------------------------------------
+
+/// A parsed SPF record.
+struct Terms<'a> {
+    /// The mechanisms with the outcome of their qualifier.
+    directives: Vec<(Outcome, Mechanism<'a>)>,
+    redirect: Option<&'a [u8]>,
+    exp: Option<&'a [u8]>,
+}
+
+impl<'a> Terms<'a> {
+    /// Parses a record.
+    ///
+    /// Returns `None` if there is a syntax error anywhere.
+    fn parse(record: &'a [u8]) -> Option<Self> {
+        let mut res = Terms { directives: Vec::new(), redirect: None,
+                              exp: None };
+        let terms = record[6..].split(|ch| *ch == b' ')
+                               .filter(|term| !term.is_empty());
+        for term in terms {
+            let (outcome, rest) = match term[0] {
+                b'+' => (Some(Outcome::Pass), &term[1..]),
+                b'-' => (Some(Outcome::Fail), &term[1..]),
+                b'~' => (Some(Outcome::SoftFail), &term[1..]),
+                b'?' => (Some(Outcome::Neutral), &term[1..]),
+                _ => (None, term)
+            };
+            let len = rest.iter().position(|ch| !is_name_char(*ch))
+                          .unwrap_or(rest.len());
+            let (name, args) = rest.split_at(len);
+            if name.is_empty() {
+                return None
+            }
+            let name = name.to_ascii_lowercase();
+            if outcome.is_none() && args.first() == Some(&b'=') {
+                let value = &args[1..];
+                if !is_macro_string(value) {
+                    return None
+                }
+                match &name[..] {
+                    b"redirect" => {
+                        if res.redirect.is_some() || value.is_empty() {
+                            return None
+                        }
+                        res.redirect = Some(value)
+                    }
+                    b"exp" => {
+                        if res.exp.is_some() || value.is_empty() {
+                            return None
+                        }
+                        res.exp = Some(value)
+                    }
+                    _ => {
+                        // Unknown modifiers are ignored.
+                        if !is_alpha(name[0]) {
+                            return None
+                        }
+                    }
+                }
+                continue
+            }
+            let mechanism = match Mechanism::parse(&name, args) {
+                Some(mechanism) => mechanism,
+                None => return None
+            };
+            res.directives.push((outcome.unwrap_or(Outcome::Pass),
+                                 mechanism));
+        }
+        Some(res)
+    }
+}
+
+
+//------------ Mechanism -----------------------------------------------------
+
+enum Mechanism<'a> {
+    All,
+    Include(&'a [u8]),
+    A(Option<&'a [u8]>, u8, u8),
+    Mx(Option<&'a [u8]>, u8, u8),
+    Ptr(Option<&'a [u8]>),
+    Ip(Network),
+    Exists(&'a [u8]),
+}
+
+impl<'a> Mechanism<'a> {
+    /// Parses a mechanism from its lower-case name and its arguments.
+    fn parse(name: &[u8], args: &'a [u8]) -> Option<Self> {
+        match name {
+            b"all" if args.is_empty() => Some(Mechanism::All),
+            b"include" => domain_spec(args).map(Mechanism::Include),
+            b"a" => {
+                domain_cidr(args).map(|(spec, cidr4, cidr6)| {
+                    Mechanism::A(spec, cidr4, cidr6)
+                })
+            }
+            b"mx" => {
+                domain_cidr(args).map(|(spec, cidr4, cidr6)| {
+                    Mechanism::Mx(spec, cidr4, cidr6)
+                })
+            }
+            b"ptr" if args.is_empty() => Some(Mechanism::Ptr(None)),
+            b"ptr" => domain_spec(args).map(|spec| Mechanism::Ptr(Some(spec))),
+            b"ip4" | b"ip6" if args.starts_with(b":") => {
+                match Network::parse(&args[1..]) {
+                    Some(network) => match (name, network.addr()) {
+                        (b"ip4", IpAddr::V4(_)) | (b"ip6", IpAddr::V6(_)) => {
+                            Some(Mechanism::Ip(network))
+                        }
+                        _ => None
+                    },
+                    None => None
+                }
+            }
+            b"exists" => domain_spec(args).map(Mechanism::Exists),
+            _ => None
+        }
+    }
+}
+
+/// Parses a required domain-spec given after a colon.
+fn domain_spec(args: &[u8]) -> Option<&[u8]> {
+    if args.len() < 2 || args[0] != b':' || !is_macro_string(&args[1..]) {
+        None
+    }
+    else {
+        Some(&args[1..])
+    }
+}
+
+/// Parses an optional domain-spec followed by optional CIDR lengths.
+fn domain_cidr(args: &[u8]) -> Option<(Option<&[u8]>, u8, u8)> {
+    let (spec, cidr) = if args.starts_with(b":") {
+        let rest = &args[1..];
+        let pos = cidr_start(rest);
+        if pos == 0 || !is_macro_string(&rest[..pos]) {
+            return None
+        }
+        (Some(&rest[..pos]), &rest[pos..])
+    }
+    else {
+        (None, args)
+    };
+    let (cidr4, cidr6) = match cidr.windows(2).position(|w| w == b"//") {
+        Some(pos) => (&cidr[..pos], Some(&cidr[pos + 2..])),
+        None => (cidr, None)
+    };
+    let cidr4 = if cidr4.is_empty() { Some(32) }
+                else if cidr4[0] == b'/' { cidr_len(&cidr4[1..], 32) }
+                else { None };
+    let cidr6 = match cidr6 {
+        Some(cidr6) => cidr_len(cidr6, 128),
+        None => Some(128)
+    };
+    match (cidr4, cidr6) {
+        (Some(cidr4), Some(cidr6)) => Some((spec, cidr4, cidr6)),
+        _ => None
+    }
+}
+
+/// Returns the position of the first slash outside of a macro.
+fn cidr_start(spec: &[u8]) -> usize {
+    let mut in_macro = false;
+    for (i, ch) in spec.iter().enumerate() {
+        match *ch {
+            b'{' if i > 0 && spec[i - 1] == b'%' => in_macro = true,
+            b'}' => in_macro = false,
+            b'/' if !in_macro => return i,
+            _ => { }
+        }
+    }
+    spec.len()
+}
+
+fn cidr_len(digits: &[u8], max: u8) -> Option<u8> {
+    if digits.is_empty() || digits.len() > 3
+            || !digits.iter().all(|ch| is_digit(*ch))
+            || (digits.len() > 1 && digits[0] == b'0') {
+        return None
+    }
+    let len = digits.iter().fold(0u16, |res, ch| {
+        res * 10 + (*ch - b'0') as u16
+    });
+    if len > max as u16 { None }
+    else { Some(len as u8) }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns whether a TXT record is an SPF record.
+fn is_spf_record(record: &[u8]) -> bool {
+    record.len() >= 6 && record[..6].eq_ignore_ascii_case(b"v=spf1")
+        && (record.len() == 6 || record[6] == b' ')
+}
+
+/// Returns whether *domain* is a multi-label domain name.
+fn is_valid_domain(domain: &[u8]) -> bool {
+    let domain = if domain.ends_with(b".") { &domain[..domain.len() - 1] }
+                 else { domain };
+    if domain.is_empty() || domain.len() > 253 || !domain.contains(&b'.') {
+        return false
+    }
+    domain.split(|ch| *ch == b'.').all(|label| {
+        !label.is_empty() && label.len() <= 63
+            && label.iter().all(|ch| {
+                is_alpha(*ch) || is_digit(*ch) || *ch == b'-' || *ch == b'_'
+            })
+    })
+}
+
+/// Checks the syntax of a macro-string.
+fn is_macro_string(spec: &[u8]) -> bool {
+    let mut pos = 0;
+    while pos < spec.len() {
+        let ch = spec[pos];
+        if ch != b'%' {
+            if ch < 0x21 || ch > 0x7e {
+                return false
+            }
+            pos += 1;
+            continue
+        }
+        match spec.get(pos + 1) {
+            Some(&b'%') | Some(&b'_') | Some(&b'-') => pos += 2,
+            Some(&b'{') => {
+                let end = match spec[pos..].iter().position(|ch| *ch == b'}') {
+                    Some(end) => pos + end,
+                    None => return false
+                };
+                let body = &spec[pos + 2..end];
+                if body.is_empty()
+                        || !b"slodiphvcrt".contains(&body[0]
+                                                    .to_ascii_lowercase()) {
+                    return false
+                }
+                let rest = &body[1..];
+                let digits = rest.iter().take_while(|ch| is_digit(**ch))
+                                 .count();
+                let rest = &rest[digits..];
+                let rest = if rest.first().map_or(false, |ch| {
+                    *ch == b'r' || *ch == b'R'
+                }) { &rest[1..] } else { rest };
+                if !rest.iter().all(|ch| b".-+,/_=".contains(ch)) {
+                    return false
+                }
+                pos = end + 1;
+            }
+            _ => return false
+        }
+    }
+    true
+}
+
+/// Returns the client address for the i macro.
+///
+/// IPv6 addresses are written as dot-separated nibbles.
+fn dotted_ip(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(_) => ip.to_string().into_bytes(),
+        IpAddr::V6(addr) => {
+            let mut res = Vec::with_capacity(63);
+            for seg in &addr.segments() {
+                for shift in &[12, 8, 4, 0] {
+                    if !res.is_empty() {
+                        res.push(b'.')
+                    }
+                    res.push(b"0123456789abcdef"[(seg >> shift) as usize
+                                                 & 0x0f]);
+                }
+            }
+            res
+        }
+    }
+}
+
+fn is_alpha(ch: u8) -> bool {
+    (ch >= b'a' && ch <= b'z') || (ch >= b'A' && ch <= b'Z')
+}
+
+fn is_digit(ch: u8) -> bool {
+    ch >= b'0' && ch <= b'9'
+}
+
+fn is_name_char(ch: u8) -> bool {
+    is_alpha(ch) || is_digit(ch) || ch == b'-' || ch == b'_' || ch == b'.'
+}
+
+fn is_unreserved(ch: u8) -> bool {
+    is_alpha(ch) || is_digit(ch) || ch == b'-' || ch == b'.' || ch == b'_'
+        || ch == b'~'
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use std::time::Instant;
+    use nom::IResult;
+    use ::net::dns::{self, Record, RecordType, Resolver, Zone};
+    use ::smtp::syntax::{MailboxDomain, ReversePath};
+    use super::*;
+    use super::Evaluator;
+
+    fn txt(zone: &mut Zone, name: &str, text: &str) {
+        zone.insert(name.as_bytes(), Record::Txt(text.as_bytes().into()))
+    }
+
+    fn addr(zone: &mut Zone, name: &str, addr: &str) {
+        match addr.parse().unwrap() {
+            IpAddr::V4(addr) => zone.insert(name.as_bytes(), Record::A(addr)),
+            IpAddr::V6(addr) => {
+                zone.insert(name.as_bytes(), Record::Aaaa(addr))
+            }
+        }
+    }
+
+    fn check(resolver: &Resolver, ip: &str, sender: &str) -> Outcome {
+        let at = sender.rfind('@').unwrap();
+        let (local, domain) = (&sender[..at], &sender[at + 1..]);
+        let mut eval = Evaluator::new(resolver, ip.parse().unwrap(),
+                                      sender.as_bytes(), local.as_bytes(),
+                                      domain.as_bytes(), b"client.test");
+        eval.check_host(domain.as_bytes())
+    }
+
+    #[test]
+    fn mechanisms() {
+        let mut zone = Zone::new();
+        txt(&mut zone, "example.com",
+            "v=spf1 ip4:192.0.2.0/24 ip6:2001:db8::/32 a/28 \
+             mx:mail.example.com//64 -all");
+        addr(&mut zone, "example.com", "198.51.100.16");
+        zone.insert(b"mail.example.com",
+                    Record::Mx(10, b"mx.example.com".to_vec()));
+        addr(&mut zone, "mx.example.com", "203.0.113.5");
+        addr(&mut zone, "mx.example.com", "2001:db9::5");
+        let check = |ip| check(&zone, ip, "a@example.com");
+        assert_eq!(check("192.0.2.7"), Outcome::Pass);
+        assert_eq!(check("::ffff:192.0.2.7"), Outcome::Pass);
+        assert_eq!(check("2001:db8::1"), Outcome::Pass);
+        assert_eq!(check("198.51.100.20"), Outcome::Pass);
+        assert_eq!(check("198.51.100.40"), Outcome::Fail);
+        assert_eq!(check("203.0.113.5"), Outcome::Pass);
+        assert_eq!(check("203.0.113.6"), Outcome::Fail);
+        assert_eq!(check("2001:db9::ffff"), Outcome::Pass);
+        assert_eq!(check("2001:db9:0:1::5"), Outcome::Fail);
+    }
+
+    #[test]
+    fn indirection() {
+        let mut zone = Zone::new();
+        txt(&mut zone, "example.org", "v=spf1 include:_spf.example.com ~all");
+        txt(&mut zone, "_spf.example.com", "v=spf1 ip4:192.0.2.1 -all");
+        txt(&mut zone, "example.net",
+            "v=spf1 exists:%{ir}.%{l1r+}._ex.%{d} redirect=example.org");
+        addr(&mut zone, "3.2.0.192.bob._ex.example.net", "127.0.0.2");
+        txt(&mut zone, "example.edu", "v=spf1 ptr -all");
+        zone.insert(b"5.2.0.192.in-addr.arpa",
+                    Record::Ptr(b"host.example.edu".to_vec()));
+        zone.insert(b"6.2.0.192.in-addr.arpa",
+                    Record::Ptr(b"host.example.edu".to_vec()));
+        addr(&mut zone, "host.example.edu", "192.0.2.5");
+
+        assert_eq!(check(&zone, "192.0.2.1", "a@example.org"),
+                   Outcome::Pass);
+        assert_eq!(check(&zone, "192.0.2.2", "a@example.org"),
+                   Outcome::SoftFail);
+        assert_eq!(check(&zone, "192.0.2.3", "bob+x@example.net"),
+                   Outcome::Pass);
+        assert_eq!(check(&zone, "192.0.2.1", "alice@example.net"),
+                   Outcome::Pass);
+        assert_eq!(check(&zone, "192.0.2.3", "alice@example.net"),
+                   Outcome::SoftFail);
+        assert_eq!(check(&zone, "192.0.2.5", "a@example.edu"),
+                   Outcome::Pass);
+        assert_eq!(check(&zone, "192.0.2.6", "a@example.edu"),
+                   Outcome::Fail);
+    }
+
+    #[test]
+    fn errors() {
+        let mut zone = Zone::new();
+        txt(&mut zone, "plain.example", "v=spf1");
+        txt(&mut zone, "other.example", "v=spf10 -all");
+        txt(&mut zone, "twice.example", "v=spf1 -all");
+        txt(&mut zone, "twice.example", "v=spf1 +all");
+        txt(&mut zone, "syntax.example", "v=spf1 foo:bar -all");
+        txt(&mut zone, "cidr.example", "v=spf1 a/33 -all");
+        txt(&mut zone, "unknown.example", "v=spf1 moo=cow");
+        txt(&mut zone, "include.example", "v=spf1 include:none.example");
+        txt(&mut zone, "redirect.example", "v=spf1 redirect=none.example");
+        txt(&mut zone, "void.example",
+            "v=spf1 a:x1.void.example a:x2.void.example \
+             a:x3.void.example +all");
+        for i in 0..12 {
+            txt(&mut zone, &format!("l{}.example", i),
+                &format!("v=spf1 include:l{}.example", i + 1));
+        }
+        txt(&mut zone, "l12.example", "v=spf1 +all");
+        for i in 0..11 {
+            txt(&mut zone, &format!("s{}.example", i),
+                &format!("v=spf1 include:s{}.example", i + 1));
+        }
+
+        let check = |domain: &str| {
+            check(&zone, "192.0.2.1", &format!("a@{}", domain))
+        };
+        assert_eq!(check("none.example"), Outcome::None);
+        assert_eq!(check("[192.0.2.1]"), Outcome::None);
+        assert_eq!(check("plain.example"), Outcome::Neutral);
+        assert_eq!(check("other.example"), Outcome::None);
+        assert_eq!(check("twice.example"), Outcome::PermError);
+        assert_eq!(check("syntax.example"), Outcome::PermError);
+        assert_eq!(check("cidr.example"), Outcome::PermError);
+        assert_eq!(check("unknown.example"), Outcome::Neutral);
+        assert_eq!(check("include.example"), Outcome::PermError);
+        assert_eq!(check("redirect.example"), Outcome::PermError);
+        assert_eq!(check("void.example"), Outcome::PermError);
+        assert_eq!(check("l0.example"), Outcome::PermError);
+        assert_eq!(check("l2.example"), Outcome::Pass);
+        // The last record includes a name without a record.
+        assert_eq!(check("s1.example"), Outcome::PermError);
+    }
+
+    struct Broken;
+
+    impl Resolver for Broken {
+        fn query(&self, _name: &[u8], _rtype: RecordType)
+                 -> Result<Vec<Record>, dns::Error> {
+            Err(dns::Error::Timeout)
+        }
+    }
+
+    #[test]
+    fn temperror() {
+        assert_eq!(check(&Broken, "192.0.2.1", "a@example.com"),
+                   Outcome::TempError);
+    }
+
+    #[test]
+    fn macros() {
+        let zone = Zone::new();
+        let expand = |ip: &str, spec: &str| {
+            let eval = Evaluator::new(&zone, ip.parse().unwrap(),
+                                      b"strong-bad@email.example.com",
+                                      b"strong-bad", b"email.example.com",
+                                      b"mx.example.org");
+            eval.expand(spec.as_bytes(), b"email.example.com", false)
+                .map(|res| String::from_utf8(res).unwrap())
+        };
+        let ip4 = |spec| expand("192.0.2.3", spec).unwrap();
+        assert_eq!(ip4("%{s}"), "strong-bad@email.example.com");
+        assert_eq!(ip4("%{o}"), "email.example.com");
+        assert_eq!(ip4("%{d}"), "email.example.com");
+        assert_eq!(ip4("%{d4}"), "email.example.com");
+        assert_eq!(ip4("%{d3}"), "email.example.com");
+        assert_eq!(ip4("%{d2}"), "example.com");
+        assert_eq!(ip4("%{d1}"), "com");
+        assert_eq!(ip4("%{dr}"), "com.example.email");
+        assert_eq!(ip4("%{d2r}"), "example.email");
+        assert_eq!(ip4("%{l}"), "strong-bad");
+        assert_eq!(ip4("%{l-}"), "strong.bad");
+        assert_eq!(ip4("%{lr}"), "strong-bad");
+        assert_eq!(ip4("%{lr-}"), "bad.strong");
+        assert_eq!(ip4("%{l1r-}"), "strong");
+        assert_eq!(ip4("%{ir}.%{v}._spf.%{d2}"),
+                   "3.2.0.192.in-addr._spf.example.com");
+        assert_eq!(ip4("%{lr-}.lp._spf.%{d2}"),
+                   "bad.strong.lp._spf.example.com");
+        assert_eq!(ip4("%{lr-}.lp.%{ir}.%{v}._spf.%{d2}"),
+                   "bad.strong.lp.3.2.0.192.in-addr._spf.example.com");
+        assert_eq!(ip4("%{ir}.%{v}.%{l1r-}.lp._spf.%{d2}"),
+                   "3.2.0.192.in-addr.strong.lp._spf.example.com");
+        assert_eq!(ip4("%{d2}.trusted-domains.example.net"),
+                   "example.com.trusted-domains.example.net");
+        assert_eq!(ip4("%{h}%%%_%-%{S}"),
+                   "mx.example.org% %20strong-bad%40email.example.com");
+        assert_eq!(expand("2001:db8::cb01", "%{ir}.%{v}._spf.%{d2}")
+                       .unwrap(),
+                   "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.\
+                    0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com");
+        assert!(expand("192.0.2.3", "%{c}").is_err());
+        assert!(expand("192.0.2.3", "%{d0}").is_err());
+        assert!(expand("192.0.2.3", "%{x}").is_err());
+        assert!(expand("192.0.2.3", "%x").is_err());
+    }
+
+    #[test]
+    fn explanation() {
+        let mut zone = Zone::new();
+        txt(&mut zone, "example.com",
+            "v=spf1 ip4:192.0.2.1 -all exp=explain._spf.%{d}");
+        txt(&mut zone, "explain._spf.example.com",
+            "%{c} is not one of %{d}'s designated mail servers.");
+        let query = |ip: &str| {
+            let mut query = Query::new(ip.parse().unwrap(),
+                                       &parse_helo(b"[192.0.2.3]\r\n"));
+            query.set_sender(&parse_path(b"<a@example.com>\r\n"));
+            query.check(&zone)
+        };
+        let spf = query("192.0.2.3");
+        assert_eq!(spf.result, Outcome::Fail);
+        assert_eq!(spf.explanation,
+                   Some(b"192.0.2.3 is not one of example.com's \
+                          designated mail servers.".to_vec()));
+        assert_eq!(query("192.0.2.1").explanation, None);
+    }
+
+    fn parse_helo(input: &[u8]) -> MailboxDomain {
+        match MailboxDomain::parse(input) {
+            IResult::Done(_, res) => res,
+            _ => panic!("bad domain")
+        }
+    }
+
+    fn parse_path(input: &[u8]) -> ReversePath {
+        match ReversePath::parse(input) {
+            IResult::Done(_, res) => res,
+            _ => panic!("bad path")
+        }
+    }
+
+    #[test]
+    fn query() {
+        let mut zone = Zone::new();
+        txt(&mut zone, "example.com", "v=spf1 ip4:192.0.2.1 -all");
+        txt(&mut zone, "bad.test", "v=spf1 -all");
+        txt(&mut zone, "good.test", "v=spf1 ip4:192.0.2.0/24 -all");
+        let query = |helo: &[u8], path: &[u8]| {
+            let mut query = Query::new("192.0.2.3".parse().unwrap(),
+                                       &parse_helo(helo));
+            query.set_sender(&parse_path(path));
+            query.check(&zone)
+        };
+
+        let spf = query(b"client.test\r\n", b"<a@example.com>\r\n");
+        assert_eq!(spf.result, Outcome::Fail);
+        assert_eq!(spf.identity, Identity::MailFrom);
+        assert_eq!(spf.received_spf(),
+                   &b"Received-SPF: fail (domain of a@example.com does not \
+                      designate 192.0.2.3 as permitted sender)\r\n\
+                      \tclient-ip=192.0.2.3; \
+                      envelope-from=\"a@example.com\"; \
+                      helo=\"client.test\";\r\n\
+                      \tidentity=mailfrom;\r\n"[..]);
+
+        // A failing HELO identity wins.
+        let spf = query(b"bad.test\r\n", b"<a@good.test>\r\n");
+        assert_eq!(spf.result, Outcome::Fail);
+        assert_eq!(spf.identity, Identity::Helo);
+        assert_eq!(spf.sender, b"postmaster@bad.test");
+        assert_eq!(spf.received_spf(),
+                   &b"Received-SPF: fail (domain of postmaster@bad.test \
+                      does not designate 192.0.2.3 as permitted sender)\r\n\
+                      \tclient-ip=192.0.2.3; \
+                      envelope-from=\"a@good.test\"; \
+                      helo=\"bad.test\";\r\n\
+                      \tidentity=helo;\r\n"[..]);
+
+        // The null reverse path uses the HELO identity.
+        let spf = query(b"good.test\r\n", b"<>\r\n");
+        assert_eq!(spf.result, Outcome::Pass);
+        assert_eq!(spf.identity, Identity::Helo);
+        assert_eq!(spf.envelope_from, b"");
+        let spf = query(b"[192.0.2.3]\r\n", b"<>\r\n");
+        assert_eq!(spf.result, Outcome::None);
+    }
+
+    #[test]
+    fn deadline() {
+        let mut zone = Zone::new();
+        txt(&mut zone, "example.com", "v=spf1 a -all");
+        addr(&mut zone, "example.com", "192.0.2.1");
+        let mut eval = Evaluator::new(&zone, "192.0.2.1".parse().unwrap(),
+                                      b"a@example.com", b"a",
+                                      b"example.com", b"client.test");
+        eval.deadline = Instant::now();
+        assert_eq!(eval.check_host(b"example.com"), Outcome::TempError);
+    }
+}
@@ -66,12 +66,18 @@ pub enum Command<'a> {
     Noop,
     Quit,
 
+    // RFC 1985
+    Etrn(EtrnNode<'a>),
+
     // RFC 3207
     StartTls,
 
     // RFC 4954
     Auth { mechanism: &'a[u8], initial: Option<&'a[u8]> },
 
+    // RFC 3030
+    Bdat { size: u64, last: bool },
+
     // Command errors
     Unrecognized,
     ParameterError,
@@ -101,7 +107,7 @@ impl<'a> Command<'a> {
                              || Command::Rcpt(path, params))
              ) |
              empty_command!(b"DATA", Command::Data) |
-             empty_command!(b"DATA", Command::Rset) |
+             empty_command!(b"RSET", Command::Rset) |
              command!(b"VRFY",
                       chain!(word: call!(Word::parse) ~
                              params: call!(VrfyParameters::parse),
@@ -112,25 +118,54 @@ impl<'a> Command<'a> {
                              params: call!(ExpnParameters::parse),
                              || Command::Expn(word, params))
              ) |
-             command!(b"HELP",
-                      map!(opt!(call!(Word::parse)), |res| Command::Help(res))
+             // The argument to HELP is optional, so `command!()` won’t do.
+             chain!(call!(text, b"HELP") ~
+                    res: alt!(map!(wspcrlf, |_| Command::Help(None)) |
+                              chain!(wsps ~ word: call!(Word::parse) ~
+                                     wspcrlf,
+                                     || Command::Help(Some(word))) |
+                              map!(take_until_and_consume!(b"\r\n"),
+                                   |_| Command::ParameterError)),
+                    || res
+             ) |
+             // NOOP may have an argument which is ignored.
+             chain!(call!(text, b"NOOP") ~
+                    alt!(wspcrlf |
+                         chain!(wsps ~ take_until_and_consume!(b"\r\n"),
+                                || ())),
+                    || Command::Noop
              ) |
-             empty_command!(b"NOOP", Command::Noop) |
              empty_command!(b"QUIT", Command::Quit) |
+             command!(b"ETRN",
+                      map!(call!(EtrnNode::parse), |res| Command::Etrn(res))
+             ) |
              empty_command!(b"STARTTLS", Command::StartTls) |
              command!(b"AUTH",
-                      chain!(mechanism: call!(atom) ~ wsps ~
-                             initial: opt!(call!(atom)),
+                      chain!(mechanism: call!(atom) ~
+                             initial: opt!(chain!(wsps ~ res: call!(atom),
+                                                  || res)),
                              || Command::Auth { mechanism: mechanism,
                                                 initial: initial })
              ) |
+             command!(b"BDAT",
+                      chain!(size: u64_digits ~
+                             last: opt!(chain!(wsps ~ call!(text, b"LAST"),
+                                               || ())),
+                             || Command::Bdat { size: size,
+                                                last: last.is_some() })
+             ) |
              map!(take_until_and_consume!(b"\r\n"), |_| Command::Unrecognized)
         )
     }
 
+    /// Returns whether the command may be followed by further commands.
+    ///
+    /// These are the commands that RFC 2920 allows anywhere in a group of
+    /// pipelined commands plus BDAT which RFC 3030 allows to be pipelined.
     pub fn allow_pipeline(&self) -> bool {
         match *self {
-            Command::Mail(_,_) | Command::Rcpt(_,_) | Command::Rset => true,
+            Command::Mail(_,_) | Command::Rcpt(_,_) | Command::Rset
+                | Command::Bdat { .. } => true,
             _ => false
         }
     }
@@ -145,7 +180,7 @@ pub struct MailParameters<'a> {
     pub size: Option<u64>,
     pub ret: Option<RetValue>,
     pub envid: Option<Xtext<'a>>,
-    pub auth: Option<Mailbox<'a>>,
+    pub auth: Option<Xtext<'a>>,
     pub smtputf8: Option<()>,
 }
 
@@ -254,13 +289,14 @@ impl<'a> MailParameters<'a> {
 
     /// Parses the mail-parameter AUTH.
     ///
-    /// > auth-mail-parameter = "AUTH=" Mailbox
+    /// > auth-mail-parameter = "AUTH=" xtext
     ///
-    /// Defined in RFC 4954, section 5.
+    /// Defined in RFC 4954, section 5. The xtext contains either a
+    /// mailbox or `<>`.
     ///
-    fn parse_auth(input: &[u8]) -> IResult<&[u8], Mailbox> {
+    fn parse_auth(input: &[u8]) -> IResult<&[u8], Xtext> {
         chain!(input,
-               call!(text, b"AUTH=") ~ res: call!(Mailbox::parse),
+               call!(text, b"AUTH=") ~ res: call!(Xtext::parse),
                || res)
     }
 
@@ -280,7 +316,7 @@ impl<'a> MailParameters<'a> {
 ///
 /// See RFC 6152, section 2, and RFC 3030, section 3.
 ///
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BodyValue {
     SevenBit,
     EightBitMime,
@@ -297,12 +333,22 @@ impl BodyValue {
     }
 }
 
+impl fmt::Display for BodyValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            BodyValue::SevenBit => "7BIT",
+            BodyValue::EightBitMime => "8BITMIME",
+            BodyValue::BinaryMime => "BINARYMIME",
+        })
+    }
+}
+
 
 /// The RET parameter of the ESMTP MAIL command
 ///
 /// See RFC 3461, section 4.3.
 ///
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RetValue {
     Full,
     Hdrs,
@@ -317,6 +363,15 @@ impl RetValue {
     }
 }
 
+impl fmt::Display for RetValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            RetValue::Full => "FULL",
+            RetValue::Hdrs => "HDRS",
+        })
+    }
+}
+
 
 //------------ RcptPath -----------------------------------------------------
 
@@ -344,6 +399,19 @@ impl<'a> RcptPath<'a> {
     }
 }
 
+/// Displays the path without the angle brackets.
+impl<'a> fmt::Display for RcptPath<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RcptPath::DomainPostmaster(ref domain) => {
+                write!(f, "Postmaster@{}", domain)
+            }
+            RcptPath::Postmaster => f.write_str("Postmaster"),
+            RcptPath::ForwardPath(ref path) => path.fmt(f)
+        }
+    }
+}
+
 //------------ RcptParameters -----------------------------------------------
 
 #[derive(Debug)]
@@ -410,7 +478,7 @@ impl<'a> RcptParameters<'a> {
 ///
 /// See RFC 3461, section 4.1.
 ///
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct NotifyValue {
     pub success: bool,
     pub failure: bool,
@@ -467,6 +535,25 @@ impl NotifyValue {
     }
 }
 
+impl fmt::Display for NotifyValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let values = [(self.success, "SUCCESS"), (self.failure, "FAILURE"),
+                      (self.delay, "DELAY")];
+        let mut first = true;
+        for &(set, value) in &values {
+            if set {
+                if !first { try!(f.write_str(",")) }
+                try!(f.write_str(value));
+                first = false;
+            }
+        }
+        if first {
+            try!(f.write_str("NEVER"));
+        }
+        Ok(())
+    }
+}
+
 
 /// The Orcpt parameter to the ESMTP RCPT command
 ///
@@ -491,12 +578,18 @@ impl<'a> OrcptParameter<'a> {
     }
 }
 
+impl<'a> fmt::Display for OrcptParameter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{};{}", self.addr_type, self.addr)
+    }
+}
+
 
 /// DSN Address Types
 ///
 /// https://www.iana.org/assignments/dsn-types/dsn-types.xhtml#dsn-types-1
 ///
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum DsnAddressType {
     Rfc822,
     X400,
@@ -512,6 +605,16 @@ impl DsnAddressType {
     }
 }
 
+impl fmt::Display for DsnAddressType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            DsnAddressType::Rfc822 => "rfc822",
+            DsnAddressType::X400 => "x400",
+            DsnAddressType::Utf8 => "utf-8",
+        })
+    }
+}
+
 
 //------------ VrfyParameters -----------------------------------------------
 
@@ -547,6 +650,50 @@ impl ExpnParameters {
 }
 
 
+//------------ EtrnNode -----------------------------------------------------
+
+/// The argument of the ETRN command.
+///
+/// > etrn            = "ETRN" SP [ "@" / "#" ] node
+///
+/// Defined in RFC 1985.
+///
+#[derive(Debug)]
+pub enum EtrnNode<'a> {
+    /// Mail for this domain.
+    Domain(Domain<'a>),
+
+    /// Mail for this domain and all its subdomains, given with `"@"`.
+    Subdomains(Domain<'a>),
+
+    /// A named queue, given with `"#"`.
+    Queue(&'a [u8]),
+}
+
+impl<'a> EtrnNode<'a> {
+    pub fn parse(input: &'a [u8]) -> IResult<&'a [u8], EtrnNode<'a>> {
+        alt!(input,
+             chain!(call!(chr, b'@') ~ res: call!(Domain::parse),
+                    || EtrnNode::Subdomains(res)) |
+             chain!(call!(chr, b'#') ~ res: call!(atom),
+                    || EtrnNode::Queue(res)) |
+             call!(Domain::parse) => { |res| EtrnNode::Domain(res) })
+    }
+}
+
+impl<'a> fmt::Display for EtrnNode<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EtrnNode::Domain(ref domain) => domain.fmt(f),
+            EtrnNode::Subdomains(ref domain) => write!(f, "@{}", domain),
+            EtrnNode::Queue(name) => {
+                write!(f, "#{}", String::from_utf8_lossy(name))
+            }
+        }
+    }
+}
+
+
 //------------ Xtext --------------------------------------------------------
 
 #[derive(Debug)]
@@ -566,6 +713,47 @@ impl<'a> Xtext<'a> {
                                        call!(opt_cat_chrs, test_xchar));
         Done(output, Xtext(res))
     }
+
+    /// Returns the xtext in its encoded form.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0
+    }
+
+    /// Returns the decoded xtext.
+    ///
+    /// Broken hexchars are left as they are.
+    pub fn decode(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(self.0.len());
+        let mut i = 0;
+        while i < self.0.len() {
+            if self.0[i] == b'+' && i + 2 < self.0.len() {
+                if let (Some(hi), Some(lo)) = (hex_value(self.0[i + 1]),
+                                               hex_value(self.0[i + 2])) {
+                    res.push(hi << 4 | lo);
+                    i += 3;
+                    continue
+                }
+            }
+            res.push(self.0[i]);
+            i += 1;
+        }
+        res
+    }
+}
+
+impl<'a> fmt::Display for Xtext<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Xtext is always ASCII.
+        f.write_str(&String::from_utf8_lossy(self.0))
+    }
+}
+
+fn hex_value(ch: u8) -> Option<u8> {
+    match ch {
+        b'0' ... b'9' => Some(ch - b'0'),
+        b'A' ... b'F' => Some(ch - b'A' + 10),
+        _ => None
+    }
 }
 
 fn test_xchar(chr: u8) -> Result<u8, ErrorKind> {
@@ -594,6 +782,18 @@ impl<'a> ReversePath<'a> {
     }
 }
 
+/// Displays the path without the angle brackets.
+///
+/// The null reverse path becomes the empty string.
+impl<'a> fmt::Display for ReversePath<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReversePath::Path(ref path) => path.fmt(f),
+            ReversePath::Empty => Ok(())
+        }
+    }
+}
+
 
 //------------ Path ---------------------------------------------------------
 
@@ -609,6 +809,17 @@ impl<'a> Path<'a> {
                           || Path(mailbox)),
                    call!(chr, b'>'))
     }
+
+    pub fn mailbox(&self) -> &Mailbox<'a> {
+        &self.0
+    }
+}
+
+/// Displays the path without the angle brackets and source route.
+impl<'a> fmt::Display for Path<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
 }
 
 fn a_d_l(input: &[u8]) -> IResult<&[u8], ()> {
@@ -653,6 +864,10 @@ impl<'a> Domain<'a> {
         let (left, right) = input.split_at(input.len() - output.len());
         Done(right, Domain(left))
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0
+    }
 }
 
 impl<'a> fmt::Display for Domain<'a> {
@@ -680,7 +895,8 @@ impl<'a> fmt::Display for Domain<'a> {
 
 fn sub_domain(input: &[u8]) -> IResult<&[u8], &[u8]> {
     let (output, _) = try_parse!(input, alpha_digit);
-    let (output, _) = try_parse!(output, ldh_str);
+    // Labels may be a single character, so the rest is optional.
+    let (output, _) = try_parse!(output, call!(opt_cat_chrs, test_ldh_lead));
     let (left, right) = input.split_at(input.len() - output.len());
     Done(right, left)
 }
@@ -731,6 +947,20 @@ impl<'a> Mailbox<'a> {
                domain: call!(MailboxDomain::parse),
                || Mailbox { local: local, domain: domain })
     }
+
+    pub fn local(&self) -> &LocalPart<'a> {
+        &self.local
+    }
+
+    pub fn domain(&self) -> &MailboxDomain<'a> {
+        &self.domain
+    }
+}
+
+impl<'a> fmt::Display for Mailbox<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.local, self.domain)
+    }
 }
 
 
@@ -766,11 +996,22 @@ impl<'a> LocalPart<'a> {
     }
 }
 
+impl<'a> fmt::Display for LocalPart<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LocalPart::Dotted(local) => {
+                f.write_str(&String::from_utf8_lossy(local))
+            }
+            LocalPart::Quoted(ref quoted) => quoted.fmt(f)
+        }
+    }
+}
+
 
 pub fn test_atext(chr: u8) -> Result<u8, ErrorKind> {
-    if (chr >= 0x21 && chr <= 0x27) || chr == 0x2A || chr == 0x2B ||
-       chr == 0x2D || (chr >= 0x2F && chr <= 0x39) || chr == 0x3D ||
-       chr == 0x3F || (chr >= 0x41 && chr <= 0x5A) ||
+    if chr == 0x21 || (chr >= 0x23 && chr <= 0x27) || chr == 0x2A ||
+       chr == 0x2B || chr == 0x2D || (chr >= 0x2F && chr <= 0x39) ||
+       chr == 0x3D || chr == 0x3F || (chr >= 0x41 && chr <= 0x5A) ||
        (chr >= 0x5E && chr <= 0x7E) || chr >= 0x80
     {
         Ok(chr)
@@ -799,6 +1040,17 @@ impl<'a> QuotedString<'a> {
                        call!(chr, b'"')));
             Done(output, QuotedString(res))
     }
+
+    /// Returns the content of the quoted string with escapes still in.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl<'a> fmt::Display for QuotedString<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", String::from_utf8_lossy(self.0))
+    }
 }
 
 fn qtext(chr: u8) -> Result<u8, ErrorKind> {
@@ -876,7 +1128,7 @@ impl<'a> fmt::Display for AddressLiteral<'a> {
                 use std::str::from_utf8_unchecked;
                 let tag = unsafe { from_utf8_unchecked(tag) };
                 let content = unsafe { from_utf8_unchecked(content) };
-                try!(write!(f, "{}:{}", tag, content));
+                try!(write!(f, "[{}:{}]", tag, content));
             }
         }
         Ok(())
@@ -932,7 +1184,7 @@ pub fn ldh_str(input: &[u8]) -> IResult<&[u8], &[u8]> {
 /// 
 /// This also processes enhanced status codes.
 ///
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Reply {
     pub code: u16,
     pub status: Option<(u16, u16, u16)>,
@@ -945,6 +1197,21 @@ impl Reply {
         Reply { code: code, status: status, text: text }
     }
 
+    /// Returns whether this is a positive completion reply.
+    pub fn is_positive(&self) -> bool {
+        self.code >= 200 && self.code < 300
+    }
+
+    /// Returns whether this is a transient negative reply.
+    pub fn is_transient(&self) -> bool {
+        self.code >= 400 && self.code < 500
+    }
+
+    /// Returns whether this is a permanent negative reply.
+    pub fn is_permanent(&self) -> bool {
+        self.code >= 500
+    }
+
     pub fn parse(mut input: &[u8]) -> IResult<&[u8], Reply> {
         let (rest, (mut res, sep)) = try_parse!(input,
             chain!(code: call!(three_digits) ~
@@ -1010,9 +1277,133 @@ fn status_component(mut input: &[u8]) -> IResult<&[u8], u16> {
 #[cfg(test)]
 mod test {
     use std::net::{Ipv4Addr, Ipv6Addr};
-    use nom::IResult::Done;
+    use nom::IResult::{Done, Error, Incomplete};
     use super::*;
 
+    /// Parses *input* as a command and returns what kind it is.
+    fn command_kind(input: &[u8]) -> &'static str {
+        match Command::parse(input) {
+            Done(rest, cmd) => {
+                assert!(rest.is_empty(), "{} left {:?}",
+                        String::from_utf8_lossy(input),
+                        String::from_utf8_lossy(rest));
+                match cmd {
+                    Command::Ehlo(_) => "EHLO",
+                    Command::Helo(_) => "HELO",
+                    Command::Mail(_, _) => "MAIL",
+                    Command::Rcpt(_, _) => "RCPT",
+                    Command::Data => "DATA",
+                    Command::Rset => "RSET",
+                    Command::Vrfy(_, _) => "VRFY",
+                    Command::Expn(_, _) => "EXPN",
+                    Command::Help(_) => "HELP",
+                    Command::Noop => "NOOP",
+                    Command::Quit => "QUIT",
+                    Command::Etrn(_) => "ETRN",
+                    Command::StartTls => "STARTTLS",
+                    Command::Auth { .. } => "AUTH",
+                    Command::Bdat { .. } => "BDAT",
+                    Command::Unrecognized => "unrecognized",
+                    Command::ParameterError => "parameter error",
+                }
+            }
+            Incomplete(_) => "incomplete",
+            Error(_) => "error",
+        }
+    }
+
+    #[test]
+    fn command_conformance() {
+        let table: &[(&[u8], &str)] = &[
+            // Every verb in its basic form.
+            (b"EHLO mail.example.com\r\n", "EHLO"),
+            (b"EHLO [192.0.2.1]\r\n", "EHLO"),
+            (b"HELO mail.example.com\r\n", "HELO"),
+            (b"MAIL FROM:<>\r\n", "MAIL"),
+            (b"MAIL FROM:<me@example.com>\r\n", "MAIL"),
+            (b"MAIL FROM:<me@example.com> SIZE=1000 BODY=8BITMIME\r\n",
+             "MAIL"),
+            (b"MAIL FROM:<me@example.com> RET=HDRS ENVID=x+2By\r\n", "MAIL"),
+            (b"RCPT TO:<you@example.com>\r\n", "RCPT"),
+            (b"RCPT TO:<you@example.com> NOTIFY=SUCCESS,FAILURE\r\n",
+             "RCPT"),
+            (b"DATA\r\n", "DATA"),
+            (b"RSET\r\n", "RSET"),
+            (b"VRFY postmaster\r\n", "VRFY"),
+            (b"EXPN staff\r\n", "EXPN"),
+            (b"HELP\r\n", "HELP"),
+            (b"HELP MAIL\r\n", "HELP"),
+            (b"NOOP\r\n", "NOOP"),
+            (b"NOOP is ignored\r\n", "NOOP"),
+            (b"QUIT\r\n", "QUIT"),
+            (b"ETRN example.com\r\n", "ETRN"),
+            (b"STARTTLS\r\n", "STARTTLS"),
+            (b"AUTH PLAIN\r\n", "AUTH"),
+            (b"AUTH PLAIN AHRlc3QAdGVzdA==\r\n", "AUTH"),
+            (b"BDAT 1000\r\n", "BDAT"),
+            (b"BDAT 0 LAST\r\n", "BDAT"),
+
+            // Verbs and keywords are case-insensitive.
+            (b"ehlo mail.example.com\r\n", "EHLO"),
+            (b"Helo mail.example.com\r\n", "HELO"),
+            (b"mail from:<me@example.com> size=1000\r\n", "MAIL"),
+            (b"rcpt to:<you@example.com>\r\n", "RCPT"),
+            (b"data\r\n", "DATA"),
+            (b"rset\r\n", "RSET"),
+            (b"RsEt\r\n", "RSET"),
+            (b"vrfy postmaster\r\n", "VRFY"),
+            (b"expn staff\r\n", "EXPN"),
+            (b"help\r\n", "HELP"),
+            (b"noop\r\n", "NOOP"),
+            (b"quit\r\n", "QUIT"),
+            (b"etrn example.com\r\n", "ETRN"),
+            (b"starttls\r\n", "STARTTLS"),
+            (b"auth plain\r\n", "AUTH"),
+            (b"bdat 10 last\r\n", "BDAT"),
+
+            // Trailing white space is fine.
+            (b"RSET \r\n", "RSET"),
+            (b"QUIT\t\r\n", "QUIT"),
+
+            // Missing, surplus, or broken parameters.
+            (b"EHLO\r\n", "parameter error"),
+            (b"HELO\r\n", "parameter error"),
+            (b"MAIL\r\n", "parameter error"),
+            (b"MAIL FROM:\r\n", "parameter error"),
+            (b"MAIL TO:<me@example.com>\r\n", "parameter error"),
+            (b"MAIL FROM:<me@example.com> SIZE=big\r\n", "parameter error"),
+            (b"RCPT TO:\r\n", "parameter error"),
+            (b"RCPT TO:<you@example.com> NOTIFY=SOMETIMES\r\n",
+             "parameter error"),
+            (b"DATA now\r\n", "parameter error"),
+            (b"RSET all\r\n", "parameter error"),
+            (b"VRFY\r\n", "parameter error"),
+            (b"EXPN\r\n", "parameter error"),
+            (b"HELP me please\r\n", "parameter error"),
+            (b"QUIT now\r\n", "parameter error"),
+            (b"ETRN\r\n", "parameter error"),
+            (b"STARTTLS now\r\n", "parameter error"),
+            (b"AUTH\r\n", "parameter error"),
+            (b"BDAT\r\n", "parameter error"),
+            (b"BDAT many\r\n", "parameter error"),
+
+            // Unknown commands.
+            (b"TURN\r\n", "unrecognized"),
+            (b"XYZZY plugh\r\n", "unrecognized"),
+            (b"\r\n", "unrecognized"),
+
+            // Commands that haven’t fully arrived yet.
+            (b"RSET", "incomplete"),
+            (b"RS", "incomplete"),
+            (b"MAIL FROM:<me@example.com>", "incomplete"),
+        ];
+        for &(input, expected) in table {
+            let kind = command_kind(input);
+            assert!(kind == expected, "{}: expected {}, got {}",
+                    String::from_utf8_lossy(input), expected, kind);
+        }
+    }
+
     #[test]
     fn address_literal_good() {
         assert_eq!(AddressLiteral::parse(b"[127.0.0.1]"),
@@ -1027,6 +1418,78 @@ mod test {
                                                 content: b"bar"}));
     }
 
+    #[test]
+    fn domain_good() {
+        match Domain::parse(b"a.b-c.example>") {
+            Done(rest, domain) => {
+                assert_eq!(rest, &b">"[..]);
+                assert_eq!(domain.to_string(), "a.b-c.example");
+            }
+            res => panic!("{:?}", res)
+        }
+    }
+
+    #[test]
+    fn local_part_good() {
+        match LocalPart::parse(b"\"john@doe\"@example.com") {
+            Done(rest, LocalPart::Quoted(_)) => {
+                assert_eq!(rest, &b"@example.com"[..])
+            }
+            res => panic!("{:?}", res)
+        }
+        assert!(test_atext(b'"').is_err());
+    }
+
+    #[test]
+    fn path_display() {
+        match ReversePath::parse(b"<@a.example:foo.bar@example.com> ") {
+            Done(_, path) => assert_eq!(path.to_string(),
+                                        "foo.bar@example.com"),
+            _ => panic!()
+        }
+        match ReversePath::parse(b"<> ") {
+            Done(_, path) => assert_eq!(path.to_string(), ""),
+            _ => panic!()
+        }
+        match RcptPath::parse(b"<\"foo bar\"@[127.0.0.1]> ") {
+            Done(_, path) => assert_eq!(path.to_string(),
+                                        "\"foo bar\"@[127.0.0.1]"),
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn etrn_good() {
+        match Command::parse(b"ETRN example.com\r\n") {
+            Done(_, Command::Etrn(EtrnNode::Domain(domain)))
+                => assert_eq!(domain.as_bytes(), b"example.com"),
+            _ => panic!()
+        }
+        match Command::parse(b"ETRN @example.com\r\n") {
+            Done(_, Command::Etrn(EtrnNode::Subdomains(domain)))
+                => assert_eq!(domain.as_bytes(), b"example.com"),
+            _ => panic!()
+        }
+        match Command::parse(b"ETRN #queue1\r\n") {
+            Done(_, Command::Etrn(EtrnNode::Queue(name)))
+                => assert_eq!(name, b"queue1"),
+            _ => panic!()
+        }
+        match Command::parse(b"ETRN\r\n") {
+            Done(_, Command::ParameterError) => { }
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn xtext_decode() {
+        match Xtext::parse(b"foo+2Bbar+3D+zz ") {
+            Done(_, xtext) => assert_eq!(xtext.decode(),
+                                         b"foo+bar=+zz".to_vec()),
+            _ => panic!()
+        }
+    }
+
     #[test]
     fn reply_good() {
         assert_eq!(Reply::parse(b"250 2.2.1 Ok\r\n"),
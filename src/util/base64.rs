@@ -0,0 +1,150 @@
+//! Base 64 encoding and decoding.
+//!
+//! This implements the encoding defined in RFC 4648, section 4, which is
+//! what SASL, DKIM, and friends use.
+
+//------------ Encoding -----------------------------------------------------
+
+const ALPHABET: &'static [u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                                      abcdefghijklmnopqrstuvwxyz\
+                                      0123456789+/";
+
+/// Encodes *data* returning the encoded bytes.
+///
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b0 = chunk[0] as usize;
+        let b1 = if chunk.len() > 1 { chunk[1] as usize } else { 0 };
+        let b2 = if chunk.len() > 2 { chunk[2] as usize } else { 0 };
+        res.push(ALPHABET[b0 >> 2]);
+        res.push(ALPHABET[((b0 & 0x03) << 4) | (b1 >> 4)]);
+        if chunk.len() > 1 {
+            res.push(ALPHABET[((b1 & 0x0F) << 2) | (b2 >> 6)]);
+        }
+        else {
+            res.push(b'=');
+        }
+        if chunk.len() > 2 {
+            res.push(ALPHABET[b2 & 0x3F]);
+        }
+        else {
+            res.push(b'=');
+        }
+    }
+    res
+}
+
+
+//------------ Decoding -----------------------------------------------------
+
+/// Decodes *data* returning the decoded bytes.
+///
+/// The input must be properly padded. White space is not allowed.
+///
+pub fn decode(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    decode_impl(data, false)
+}
+
+/// Decodes *data* skipping over any white space.
+///
+/// This is useful for values that may be folded, such as the `b=` and
+/// `p=` tags in DKIM.
+///
+pub fn decode_lenient(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    decode_impl(data, true)
+}
+
+fn decode_impl(data: &[u8], skip_space: bool)
+               -> Result<Vec<u8>, DecodeError> {
+    let mut res = Vec::with_capacity(data.len() / 4 * 3);
+    let mut quad = [0u8; 4];
+    let mut len = 0;
+    let mut pad = 0;
+    for &ch in data {
+        if skip_space && (ch == b' ' || ch == b'\t' || ch == b'\r' ||
+                          ch == b'\n') {
+            continue
+        }
+        if ch == b'=' {
+            if len < 2 { return Err(DecodeError) }
+            pad += 1;
+            quad[len] = 0;
+            len += 1;
+        }
+        else {
+            if pad > 0 { return Err(DecodeError) }
+            quad[len] = try!(decode_char(ch));
+            len += 1;
+        }
+        if len == 4 {
+            res.push((quad[0] << 2) | (quad[1] >> 4));
+            if pad < 2 {
+                res.push((quad[1] << 4) | (quad[2] >> 2));
+            }
+            if pad < 1 {
+                res.push((quad[2] << 6) | quad[3]);
+            }
+            len = 0;
+            if pad > 0 { pad = 3 }
+        }
+    }
+    if len != 0 {
+        return Err(DecodeError)
+    }
+    Ok(res)
+}
+
+fn decode_char(ch: u8) -> Result<u8, DecodeError> {
+    match ch {
+        b'A' ... b'Z' => Ok(ch - b'A'),
+        b'a' ... b'z' => Ok(ch - b'a' + 26),
+        b'0' ... b'9' => Ok(ch - b'0' + 52),
+        b'+' => Ok(62),
+        b'/' => Ok(63),
+        _ => Err(DecodeError)
+    }
+}
+
+
+//------------ DecodeError --------------------------------------------------
+
+/// The input to decoding was not valid base 64.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecodeError;
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_good() {
+        assert_eq!(encode(b""), b"".to_vec());
+        assert_eq!(encode(b"f"), b"Zg==".to_vec());
+        assert_eq!(encode(b"fo"), b"Zm8=".to_vec());
+        assert_eq!(encode(b"foo"), b"Zm9v".to_vec());
+        assert_eq!(encode(b"foob"), b"Zm9vYg==".to_vec());
+        assert_eq!(encode(b"fooba"), b"Zm9vYmE=".to_vec());
+        assert_eq!(encode(b"foobar"), b"Zm9vYmFy".to_vec());
+    }
+
+    #[test]
+    fn decode_good() {
+        assert_eq!(decode(b""), Ok(b"".to_vec()));
+        assert_eq!(decode(b"Zg=="), Ok(b"f".to_vec()));
+        assert_eq!(decode(b"Zm8="), Ok(b"fo".to_vec()));
+        assert_eq!(decode(b"Zm9vYmFy"), Ok(b"foobar".to_vec()));
+        assert_eq!(decode_lenient(b"Zm9v\r\n YmE="), Ok(b"fooba".to_vec()));
+    }
+
+    #[test]
+    fn decode_bad() {
+        assert_eq!(decode(b"Zg="), Err(DecodeError));
+        assert_eq!(decode(b"Z==="), Err(DecodeError));
+        assert_eq!(decode(b"Zg==Zg=="), Err(DecodeError));
+        assert_eq!(decode(b"Zm9v YmFy"), Err(DecodeError));
+    }
+}
@@ -0,0 +1,66 @@
+//! Formatting of dates.
+//!
+//! Only what mail needs: the date-time of RFC 5322, section 3.3, always
+//! in UTC.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+const DAYS: [&'static str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue",
+                                 "Wed"];
+const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May",
+                                    "Jun", "Jul", "Aug", "Sep", "Oct",
+                                    "Nov", "Dec"];
+
+
+/// Returns the current time in seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+                     .map(|now| now.as_secs())
+                     .unwrap_or(0)
+}
+
+/// Formats a time given in seconds since the epoch as an RFC 5322 date.
+///
+pub fn rfc5322(secs: u64) -> String {
+    let days = secs / 86400;
+    let rem = secs % 86400;
+    let (year, month, day) = civil(days);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+            DAYS[(days % 7) as usize], day, MONTHS[month - 1], year,
+            rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+/// Converts days since the epoch into year, month, and day.
+///
+/// This is the algorithm from Howard Hinnant’s ‘chrono-Compatible
+/// Low-Level Date Algorithms,’ restricted to dates after the epoch.
+fn civil(days: u64) -> (u64, usize, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as usize, day)
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format() {
+        assert_eq!(rfc5322(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(rfc5322(951782400), "Tue, 29 Feb 2000 00:00:00 +0000");
+        assert_eq!(rfc5322(1234567890), "Fri, 13 Feb 2009 23:31:30 +0000");
+        assert_eq!(rfc5322(4107542399), "Sun, 28 Feb 2100 23:59:59 +0000");
+        assert_eq!(rfc5322(4107542400), "Mon, 01 Mar 2100 00:00:00 +0000");
+    }
+}
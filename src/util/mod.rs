@@ -1,2 +1,6 @@
 pub mod abnf;
+pub mod base64;
+pub mod date;
 pub mod scribe;
+pub mod text;
+#[cfg(any(test, feature = "test-harness"))] pub mod test;
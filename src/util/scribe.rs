@@ -92,6 +92,19 @@ pub trait Scribe {
 }
 
 
+//--- Scribe implementations
+
+impl Scribe for Vec<u8> {
+    fn scribble_bytes(&mut self, buf: &[u8]) {
+        self.extend_from_slice(buf)
+    }
+
+    fn scribble_octet(&mut self, v: u8) {
+        self.push(v)
+    }
+}
+
+
 //------------ Scribble -----------------------------------------------------
 
 /// A trait for a type that can be scribbled.
@@ -0,0 +1,49 @@
+//! Testing tools.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use openssl::crypto::rand::rand_bytes;
+
+
+//------------ TempDir -------------------------------------------------------
+
+/// A temporary directory that is removed again when dropped.
+///
+/// Each directory gets a name of its own, so tests running in parallel
+/// or left over from earlier runs don’t get in each other’s way.
+///
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates a new, empty directory whose name starts with *name*.
+    pub fn new(name: &str) -> Self {
+        let mut dir = format!("cloudship-test-{}-", name);
+        for ch in rand_bytes(8) {
+            dir.push_str(&format!("{:02x}", ch));
+        }
+        let path = ::std::env::temp_dir().join(dir);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Returns the path of *name* inside the directory.
+    pub fn join<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
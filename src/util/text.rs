@@ -0,0 +1,125 @@
+//! Handling text in octet slices.
+//!
+//! Configuration files and header fields are dealt with as octets since
+//! they needn’t be valid UTF-8.
+
+use std::ascii::AsciiExt;
+use std::io;
+
+
+/// Returns whether *ch* is white space inside a line.
+///
+/// Besides space and tab, this includes the carriage return so that
+/// files with CRLF line endings can be read, too.
+pub fn is_space(ch: u8) -> bool {
+    ch == b' ' || ch == b'\t' || ch == b'\r'
+}
+
+/// Removes white space inside a line from both ends of *s*.
+pub fn trim(s: &[u8]) -> &[u8] {
+    trim_matches(s, is_space)
+}
+
+/// Removes folding white space from both ends of *s*.
+///
+/// This is white space inside a line plus line feeds.
+pub fn trim_fws(s: &[u8]) -> &[u8] {
+    trim_matches(s, |ch| is_space(ch) || ch == b'\n')
+}
+
+/// Returns whether the domain *name* is *domain* or one of its subdomains.
+///
+/// Domain names are compared ignoring ASCII case.
+pub fn is_within(name: &[u8], domain: &[u8]) -> bool {
+    if name.len() == domain.len() {
+        name.eq_ignore_ascii_case(domain)
+    }
+    else if name.len() > domain.len() {
+        let (head, tail) = name.split_at(name.len() - domain.len());
+        head.ends_with(b".") && tail.eq_ignore_ascii_case(domain)
+    }
+    else {
+        false
+    }
+}
+
+/// Returns an error for invalid content in a file.
+///
+/// The message says what kind of *file* it is and *what* is wrong.
+pub fn invalid(file: &str, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("invalid {}: {}", file, what))
+}
+
+fn trim_matches<F: Fn(u8) -> bool>(mut s: &[u8], strip: F) -> &[u8] {
+    while let Some((&ch, rest)) = s.split_first() {
+        if !strip(ch) { break }
+        s = rest
+    }
+    while let Some((&ch, rest)) = s.split_last() {
+        if !strip(ch) { break }
+        s = rest
+    }
+    s
+}
+
+
+//------------ Words ---------------------------------------------------------
+
+/// An iterator over the white space separated words of a line.
+///
+/// Stops at a `#` at the beginning of a word.
+pub struct Words<'a> {
+    line: &'a [u8],
+}
+
+impl<'a> Words<'a> {
+    pub fn new(line: &'a [u8]) -> Self {
+        Words { line: line }
+    }
+}
+
+impl<'a> Iterator for Words<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let start = match self.line.iter().position(|ch| !is_space(*ch)) {
+            Some(start) => start,
+            None => return None
+        };
+        let line = &self.line[start..];
+        if line[0] == b'#' {
+            self.line = b"";
+            return None
+        }
+        let end = line.iter().position(|ch| is_space(*ch))
+                      .unwrap_or(line.len());
+        self.line = &line[end..];
+        Some(&line[..end])
+    }
+}
+
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trim() {
+        assert_eq!(super::trim(b" \tfoo bar\r"), b"foo bar");
+        assert_eq!(super::trim(b"foo\n"), b"foo\n");
+        assert_eq!(super::trim(b" \t "), b"");
+        assert_eq!(trim_fws(b"\r\n\tfoo\r\n bar \r\n"), b"foo\r\n bar");
+        assert_eq!(trim_fws(b""), b"");
+    }
+
+    #[test]
+    fn is_within() {
+        assert!(super::is_within(b"example.com", b"Example.COM"));
+        assert!(super::is_within(b"mail.example.com", b"example.com"));
+        assert!(!super::is_within(b"badexample.com", b"example.com"));
+        assert!(!super::is_within(b"example.com", b"mail.example.com"));
+    }
+}